@@ -0,0 +1,32 @@
+//! A Redis server. The binary runs it from the command line, and `Server`
+//! runs it inside another program. The binary doubles as a minimal client,
+//! see `redis_cli`, a load generator, see `redis_bench`, and a sentinel
+//! failing a master over to one of its replicas, see `redis_sentinel`.
+//! Integration tests run servers through `redis_testing::TestServer`.
+
+pub mod redis_aof;
+pub mod redis_bench;
+pub mod redis_cli;
+pub mod redis_cluster;
+pub mod redis_commands;
+pub mod redis_config;
+pub mod redis_db;
+pub mod redis_evict;
+pub mod redis_functions;
+pub mod redis_info;
+pub mod redis_io;
+pub mod redis_latency;
+pub mod redis_log;
+pub mod redis_metrics;
+pub mod redis_net;
+pub mod redis_registry;
+pub mod redis_resp;
+pub mod redis_sentinel;
+pub mod redis_server;
+pub mod redis_slowlog;
+pub mod redis_storage;
+pub mod redis_stream;
+pub mod redis_testing;
+pub mod redis_value;
+
+pub use redis_net::{Server, ServerBuilder, ServerHandle};
@@ -1,93 +1,554 @@
-pub mod redis_commands;
-pub mod redis_db;
-pub mod redis_server;
+use anyhow::{anyhow, bail, Context, Result};
+use redis_starter_rust::redis_aof::FsyncPolicy;
+use redis_starter_rust::redis_bench::{self, BenchOptions};
+use redis_starter_rust::redis_cli;
+use redis_starter_rust::redis_cluster::{self, Cluster};
+use redis_starter_rust::redis_evict::{self, EvictionPolicy};
+use redis_starter_rust::redis_io::{IoBackend, TlsAuthClients, TlsFiles};
+use redis_starter_rust::redis_log::{self, LogLevel};
+use redis_starter_rust::redis_sentinel::{Sentinel, SentinelOptions};
+use redis_starter_rust::redis_server::{RedisCliArgs, Role};
+use redis_starter_rust::ServerBuilder;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::warn;
 
-use std::sync::Arc;
+fn main() {
+    let opts = cli_options();
+    let cli_args = match parse_cli_args(&opts) {
+        Ok(Mode::Server(cli_args)) => cli_args,
+        Ok(Mode::Cli(addr, args)) => {
+            if let Err(e) = tokio_runtime().block_on(redis_cli::run(&addr, args)) {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(Mode::Bench(addr, bench_opts)) => {
+            if let Err(e) = tokio_runtime().block_on(redis_bench::run(&addr, &bench_opts)) {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(Mode::Sentinel(loglevel, logfile, opts)) => {
+            if let Err(e) = redis_log::init(loglevel, &logfile) {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+            return tokio_runtime().block_on(sentinel(opts));
+        }
+        Ok(Mode::Help) => {
+            print!("{}", opts.usage(USAGE));
+            return;
+        }
+        Err(e) => {
+            eprintln!("{:#}\n", e);
+            eprint!("{}", opts.usage(USAGE));
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = redis_log::init(cli_args.loglevel, &cli_args.logfile) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+    // io_uring connections are served on a runtime of their own. Elsewhere
+    // than Linux, the server refuses to start with it.
+    #[cfg(target_os = "linux")]
+    if cli_args.io_backend == IoBackend::IoUring {
+        let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                eprintln!("Could not set up io_uring: {}", e);
+                std::process::exit(1);
+            }
+        };
+        return runtime.block_on(serve(*cli_args));
+    }
+    tokio_runtime().block_on(serve(*cli_args))
+}
 
-use redis_commands::Command;
-use redis_server::{Redis, RedisCliArgs, Role};
-use tokio::{net::{TcpListener, TcpStream}, sync::broadcast::{self, Sender}};
+fn tokio_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Runtime::new().expect("Could not start the tokio runtime")
+}
 
-#[tokio::main]
-async fn main() {
-    let cli_args = parse_cli_args();
-    let port = cli_args.port.clone();
-    let redis_server = Redis::new(cli_args).await;
-    let (tx, _rx) = broadcast::channel::<Command>(8);
-    let sender = Arc::new(tx);
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
-        .await
-        .unwrap();
+/// Runs the server until it's shut down.
+async fn serve(cli_args: RedisCliArgs) {
+    let server = match ServerBuilder::from(cli_args).spawn().await {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    // SIGTERM and SIGINT shut the server down like SHUTDOWN does. If it
+    // fails the server keeps running.
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
     loop {
-        let redis_server_clone = redis_server.clone();
-        let sender = Arc::clone(&sender);
-        if let Ok((stream, _)) = listener.accept().await {
-            tokio::spawn(async move {
-                handle_stream(stream, redis_server_clone, sender).await;
-            });
+        tokio::select! {
+            _ = sigterm.recv() => warn!("Received SIGTERM, scheduling shutdown"),
+            _ = tokio::signal::ctrl_c() => warn!("Received SIGINT, scheduling shutdown"),
+            _ = server.stopped() => std::process::exit(0),
+        }
+        match server.shutdown().await {
+            Ok(()) => std::process::exit(0),
+            Err(e) => warn!("Error trying to shut down, not exiting: {:?}", e),
         }
     }
 }
 
-fn parse_cli_args() -> RedisCliArgs {
-    let args: Vec<String> = std::env::args().collect();
+/// Runs a sentinel until SIGTERM or SIGINT.
+async fn sentinel(opts: SentinelOptions) {
+    let _sentinel = match Sentinel::spawn(opts).await {
+        Ok(sentinel) => sentinel,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = sigterm.recv() => warn!("Received SIGTERM, exiting"),
+        _ = tokio::signal::ctrl_c() => warn!("Received SIGINT, exiting"),
+    }
+}
+
+const USAGE: &str = "Usage: redis-starter-rust [options]
+       redis-starter-rust --cli HOST:PORT [command [arg ...]]
+       redis-starter-rust --bench HOST:PORT [benchmark options]
+       redis-starter-rust --sentinel --sentinel-monitor \"NAME HOST PORT QUORUM\" [options]";
+
+/// What the binary was asked to do.
+enum Mode {
+    Help,
+    Server(Box<RedisCliArgs>),
+    /// Run as a client of the server at the address, sending it the command
+    /// given after the options, or else the ones read from stdin.
+    Cli(String, Vec<String>),
+    /// Run a benchmark against the server at the address.
+    Bench(String, BenchOptions),
+    /// Monitor a master and fail it over when it's down, logging at the
+    /// level to the file.
+    Sentinel(LogLevel, String, SentinelOptions),
+}
+
+fn cli_options() -> getopts::Options {
     let mut opts = getopts::Options::new();
+    // Whatever follows --cli HOST:PORT is a command for the server, which
+    // may well look like an option, as the -1 of LRANGE l 0 -1 does.
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    opts.optflag("h", "help", "print this help and exit");
+    opts.optopt(
+        "",
+        "cli",
+        "connect to a server instead of running one",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "bench",
+        "benchmark a server instead of running one",
+        "HOST:PORT",
+    );
+    opts.optflag(
+        "",
+        "sentinel",
+        "monitor a master and fail it over instead of running a server",
+    );
+    opts.optopt(
+        "",
+        "sentinel-monitor",
+        "sentinel: the master to monitor, and how many sentinels agree it's down",
+        "NAME HOST PORT QUORUM",
+    );
+    opts.optmulti(
+        "",
+        "sentinel-peer",
+        "sentinel: another sentinel monitoring the master",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "sentinel-down-after-milliseconds",
+        "sentinel: how long the master can go unreachable",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "sentinel-failover-timeout",
+        "sentinel: how long to wait before another failover attempt",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "clients",
+        "benchmark: connections open at the same time",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "requests",
+        "benchmark: requests sent per test",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "pipeline",
+        "benchmark: requests each connection sends at once",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "data-size",
+        "benchmark: size of SET values in bytes",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "keyspace",
+        "benchmark: use random keys out of this many, 0 for a single key",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "tests",
+        "benchmark: tests to run, separated by commas",
+        "ping,set,get",
+    );
     opts.optopt("d", "dir", "set persistence directory", "DIR");
     opts.optopt("f", "dbfilename", "set persistence filename", "FILENAME");
     opts.optopt("p", "port", "set port number for redis to run on", "PORT");
+    opts.optopt(
+        "",
+        "bind",
+        "addresses to listen on, separated by spaces",
+        "ADDRESSES",
+    );
+    opts.optopt(
+        "",
+        "protected-mode",
+        "only accept loopback clients unless bind is set",
+        "yes|no",
+    );
+    opts.optopt(
+        "",
+        "maxclients",
+        "most clients connected at the same time",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "timeout",
+        "close clients idle for this many seconds, 0 to never",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "tcp-keepalive",
+        "seconds between TCP keepalive probes, 0 to disable",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "loglevel",
+        "how much to log",
+        "debug|verbose|notice|warning|nothing",
+    );
+    opts.optopt(
+        "",
+        "logfile",
+        "file to append the log to, empty for stdout",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "metrics-port",
+        "serve prometheus metrics over http on this port, 0 to disable",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "io-backend",
+        "how client connections are served",
+        "tokio|io-uring",
+    );
+    opts.optopt("", "tls-port", "the port TLS clients connect to", "PORT");
+    opts.optopt("", "tls-cert-file", "the server's TLS certificate", "FILE");
+    opts.optopt("", "tls-key-file", "the key of the TLS certificate", "FILE");
+    opts.optopt(
+        "",
+        "tls-ca-cert-file",
+        "CA certificates TLS peers are checked against",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "tls-auth-clients",
+        "whether TLS clients need a certificate",
+        "yes|no|optional",
+    );
+    opts.optopt(
+        "",
+        "tls-replication",
+        "connect to the master over TLS",
+        "yes|no",
+    );
     opts.optopt("r", "replicaof", "set master url", "REPLICAOF");
-    let cli_opts = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => panic!("{}", f.to_string()),
-    };
+    opts.optopt("s", "save", "set snapshotting rules", "SAVE");
+    opts.optopt("", "rdbchecksum", "verify rdb checksums on load", "yes|no");
+    opts.optopt("", "appendonly", "enable the append only file", "yes|no");
+    opts.optopt(
+        "",
+        "appendfsync",
+        "set the aof fsync policy",
+        "always|everysec|no",
+    );
+    opts.optopt("", "appendfilename", "set the aof filename", "FILENAME");
+    opts.optopt(
+        "",
+        "aof-use-rdb-preamble",
+        "start rewritten aof files with an rdb snapshot",
+        "yes|no",
+    );
+    opts.optopt(
+        "",
+        "repl-diskless-sync",
+        "send full resyncs to replicas without saving to disk",
+        "yes|no",
+    );
+    opts.optopt(
+        "",
+        "repl-diskless-sync-delay",
+        "seconds to wait for more replicas before a diskless sync",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "replica-announce-ip",
+        "the address a replica announces to its master",
+        "IP",
+    );
+    opts.optopt(
+        "",
+        "cluster-announce-ip",
+        "the address a cluster node gives the other nodes",
+        "IP",
+    );
+    opts.optopt(
+        "",
+        "replica-announce-port",
+        "the port a replica announces to its master",
+        "PORT",
+    );
+    opts.optopt("", "cluster-enabled", "route keys by hash slot", "yes|no");
+    opts.optopt(
+        "",
+        "cluster-slots",
+        "hash slots served by this node",
+        "RANGES",
+    );
+    opts.optopt(
+        "",
+        "cluster-config-file",
+        "file the cluster node table is saved to",
+        "FILENAME",
+    );
+    opts.optmulti(
+        "",
+        "cluster-node",
+        "hash slots served by another node",
+        "RANGES HOST:PORT",
+    );
+    opts.optopt(
+        "",
+        "maxmemory",
+        "memory limit for the dataset, 0 for none",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "maxmemory-policy",
+        "how keys are evicted once maxmemory is reached",
+        "POLICY",
+    );
+    opts
+}
+
+/// Parses the value of option `name`, or returns `default` if it isn't given.
+fn parse_opt<T: std::str::FromStr>(
+    cli_opts: &getopts::Matches,
+    name: &str,
+    default: T,
+) -> Result<T> {
+    match cli_opts.opt_str(name) {
+        Some(val) => val
+            .parse()
+            .map_err(|_| anyhow!("Invalid value for --{}: {}", name, val)),
+        None => Ok(default),
+    }
+}
+
+/// Parses the command line.
+fn parse_cli_args(opts: &getopts::Options) -> Result<Mode> {
+    let args: Vec<String> = std::env::args().collect();
+    let cli_opts = opts.parse(&args[1..])?;
+    if cli_opts.opt_present("h") {
+        return Ok(Mode::Help);
+    }
+    if let Some(addr) = cli_opts.opt_str("cli") {
+        return Ok(Mode::Cli(addr, cli_opts.free));
+    }
+    if let Some(addr) = cli_opts.opt_str("bench") {
+        let defaults = BenchOptions::default();
+        let workloads = match cli_opts.opt_str("tests") {
+            Some(tests) => tests
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<_>>>()?,
+            None => defaults.workloads,
+        };
+        return Ok(Mode::Bench(
+            addr,
+            BenchOptions {
+                clients: parse_opt(&cli_opts, "clients", defaults.clients)?,
+                requests: parse_opt(&cli_opts, "requests", defaults.requests)?,
+                pipeline: parse_opt(&cli_opts, "pipeline", defaults.pipeline)?,
+                data_size: parse_opt(&cli_opts, "data-size", defaults.data_size)?,
+                keyspace: parse_opt(&cli_opts, "keyspace", defaults.keyspace)?,
+                workloads,
+            },
+        ));
+    }
+    if cli_opts.opt_present("sentinel") {
+        return Ok(Mode::Sentinel(
+            parse_opt(&cli_opts, "loglevel", LogLevel::Notice)?,
+            cli_opts.opt_str("logfile").unwrap_or_default(),
+            parse_sentinel_opts(&cli_opts)?,
+        ));
+    }
     let dir = cli_opts.opt_str("d");
     let file_name = cli_opts.opt_str("f");
     let replica_of = cli_opts.opt_str("r");
-    let port = if let Some(port) = cli_opts.opt_str("p") {
-        port
-    } else {
-        "6379".to_string()
+    let port = parse_opt::<u16>(&cli_opts, "port", 6379)?.to_string();
+    let bind: Vec<String> = cli_opts
+        .opt_str("bind")
+        .unwrap_or_else(|| "127.0.0.1 -::1".to_string())
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    let cluster_announce_ip = cli_opts.opt_str("cluster-announce-ip").unwrap_or_default();
+    let cluster = match cli_opts.opt_str("cluster-enabled").as_deref() {
+        Some("yes") => Some(
+            Cluster::new(
+                &redis_cluster::announced_host(&cluster_announce_ip, &bind),
+                &port,
+                &cli_opts.opt_str("cluster-slots").unwrap_or_default(),
+                &cli_opts.opt_strs("cluster-node"),
+            )
+            .context("Invalid cluster configuration")?,
+        ),
+        _ => None,
     };
     let mut args = RedisCliArgs {
         dir,
         file_name,
         port,
+        bind,
+        maxclients: parse_opt(&cli_opts, "maxclients", 10000)?,
+        timeout: parse_opt(&cli_opts, "timeout", 0)?,
+        tcp_keepalive: parse_opt(&cli_opts, "tcp-keepalive", 300)?,
+        protected_mode: cli_opts.opt_str("protected-mode").as_deref() != Some("no"),
         master_host: None,
         master_port: None,
         role: Role::Primary,
+        save: cli_opts
+            .opt_str("s")
+            .unwrap_or_else(|| "3600 1 300 100 60 10000".to_string()),
+        rdbchecksum: cli_opts.opt_str("rdbchecksum").as_deref() != Some("no"),
+        appendonly: cli_opts.opt_str("appendonly").as_deref() == Some("yes"),
+        appendfsync: parse_opt(&cli_opts, "appendfsync", FsyncPolicy::EverySec)?,
+        appendfilename: cli_opts
+            .opt_str("appendfilename")
+            .unwrap_or_else(|| "appendonly.aof".to_string()),
+        aof_use_rdb_preamble: cli_opts.opt_str("aof-use-rdb-preamble").as_deref() != Some("no"),
+        repl_diskless_sync: cli_opts.opt_str("repl-diskless-sync").as_deref() != Some("no"),
+        repl_diskless_sync_delay: parse_opt(&cli_opts, "repl-diskless-sync-delay", 0)?,
+        replica_announce_ip: cli_opts.opt_str("replica-announce-ip").unwrap_or_default(),
+        replica_announce_port: parse_opt(&cli_opts, "replica-announce-port", 0)?,
+        cluster,
+        cluster_announce_ip,
+        cluster_config_file: cli_opts
+            .opt_str("cluster-config-file")
+            .unwrap_or_else(|| "nodes.conf".to_string()),
+        maxmemory: match cli_opts.opt_str("maxmemory") {
+            Some(maxmemory) => redis_evict::parse_memory(&maxmemory)
+                .ok_or_else(|| anyhow!("Invalid value for --maxmemory: {}", maxmemory))?,
+            None => 0,
+        },
+        maxmemory_policy: parse_opt(&cli_opts, "maxmemory-policy", EvictionPolicy::NoEviction)?,
+        loglevel: parse_opt(&cli_opts, "loglevel", LogLevel::Notice)?,
+        logfile: cli_opts.opt_str("logfile").unwrap_or_default(),
+        metrics_port: parse_opt(&cli_opts, "metrics-port", 0)?,
+        io_backend: parse_opt(&cli_opts, "io-backend", IoBackend::Tokio)?,
+        tls_port: match parse_opt::<u16>(&cli_opts, "tls-port", 0)? {
+            0 => None,
+            tls_port => Some(tls_port),
+        },
+        tls_files: TlsFiles {
+            cert_file: cli_opts.opt_str("tls-cert-file").unwrap_or_default(),
+            key_file: cli_opts.opt_str("tls-key-file").unwrap_or_default(),
+            ca_cert_file: cli_opts.opt_str("tls-ca-cert-file").unwrap_or_default(),
+        },
+        tls_auth_clients: parse_opt(&cli_opts, "tls-auth-clients", TlsAuthClients::Yes)?,
+        tls_replication: cli_opts.opt_str("tls-replication").as_deref() == Some("yes"),
     };
     if let Some(replica_of) = replica_of {
         let replica_of: Vec<&str> = replica_of.split(" ").collect();
         if replica_of.len() != 2 {
-            panic!("Invalid replicaof argument");
+            bail!("Invalid value for --replicaof, expected \"<host> <port>\"");
         }
         args.master_host = Some(replica_of[0].to_string());
         args.master_port = Some(replica_of[1].to_string());
         args.role = Role::Replica
     }
-    args
+    Ok(Mode::Server(Box::new(args)))
 }
 
-async fn handle_stream(stream: TcpStream, mut redis_server: Redis, sender: Arc::<Sender<Command>>) {
-    loop {
-        if let Err(_) = stream.readable().await {
-            continue;
-        }
-        let mut buf = [0; 512];
-        match stream.try_read(&mut buf) {
-            Ok(n) => {
-                if n == 0 {
-                    break;
-                }
-            }
-            Err(_e) => {
-                continue;
-            }
-        }
-        let req = String::from_utf8_lossy(&buf).to_string();
-        let commands = Command::deserialize(&req);
-        for command in commands {
-            redis_server.execute(command, &stream, Arc::clone(&sender)).await;
-        }
-    }
+fn parse_sentinel_opts(cli_opts: &getopts::Matches) -> Result<SentinelOptions> {
+    let defaults = SentinelOptions::default();
+    let monitor = cli_opts
+        .opt_str("sentinel-monitor")
+        .ok_or_else(|| anyhow!("--sentinel needs --sentinel-monitor"))?;
+    let (master_name, master_host, master_port, quorum) =
+        match monitor.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, host, port, quorum] => (name, host, port, quorum),
+            _ => bail!(
+                "Invalid value for --sentinel-monitor, expected \"<name> <host> <port> <quorum>\""
+            ),
+        };
+    let millis = |name: &str, default: Duration| -> Result<Duration> {
+        Ok(Duration::from_millis(parse_opt(
+            cli_opts,
+            name,
+            default.as_millis() as u64,
+        )?))
+    };
+    Ok(SentinelOptions {
+        port: parse_opt(cli_opts, "port", defaults.port)?,
+        bind: match cli_opts.opt_str("bind") {
+            Some(bind) => bind.split_whitespace().map(str::to_string).collect(),
+            None => defaults.bind,
+        },
+        master_name: master_name.to_string(),
+        master_host: master_host.to_string(),
+        master_port: master_port
+            .parse()
+            .map_err(|_| anyhow!("Invalid master port {}", master_port))?,
+        quorum: quorum
+            .parse()
+            .map_err(|_| anyhow!("Invalid quorum {}", quorum))?,
+        peers: cli_opts.opt_strs("sentinel-peer"),
+        down_after: millis("sentinel-down-after-milliseconds", defaults.down_after)?,
+        failover_timeout: millis("sentinel-failover-timeout", defaults.failover_timeout)?,
+    })
 }
@@ -0,0 +1,286 @@
+use crate::redis_commands::Command;
+use crate::redis_db::{Dataset, RedisDB};
+use crate::redis_resp::Value;
+use crate::redis_value::RedisValue;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => bail!("Invalid appendfsync policy {}", policy),
+        }
+    }
+}
+
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::EverySec => write!(f, "everysec"),
+            FsyncPolicy::No => write!(f, "no"),
+        }
+    }
+}
+
+pub struct AofContents {
+    pub preamble: Option<Dataset>,
+    pub commands: Vec<Command>,
+}
+
+/// The append-only file. Every write command is appended in RESP format and
+/// replayed on startup.
+pub struct RedisAof {
+    dir: String,
+    file_name: String,
+    file: File,
+    fsync: FsyncPolicy,
+    last_fsync: Instant,
+    pending_fsync: bool,
+    rewrite_buffer: Option<Vec<u8>>,
+    last_rewrite_ok: bool,
+}
+
+impl RedisAof {
+    pub fn open(dir: String, file_name: String, fsync: FsyncPolicy) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{}/{}", dir, file_name))
+            .context("Error while opening aof file")?;
+        Ok(Self {
+            dir,
+            file_name,
+            file,
+            fsync,
+            last_fsync: Instant::now(),
+            pending_fsync: false,
+            rewrite_buffer: None,
+            last_rewrite_ok: true,
+        })
+    }
+
+    fn path(&self) -> String {
+        format!("{}/{}", self.dir, self.file_name)
+    }
+
+    pub fn set_fsync(&mut self, fsync: FsyncPolicy) {
+        self.fsync = fsync;
+    }
+
+    pub fn append(&mut self, command: &Command) -> Result<()> {
+        let entry = Self::entry(command);
+        if entry.is_empty() {
+            return Ok(());
+        }
+        self.file
+            .write_all(&entry)
+            .context("Error while writing aof file")?;
+        if let Some(rewrite_buffer) = self.rewrite_buffer.as_mut() {
+            rewrite_buffer.extend_from_slice(&entry);
+        }
+        match self.fsync {
+            FsyncPolicy::Always => self.fsync()?,
+            FsyncPolicy::EverySec => self.pending_fsync = true,
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    /// Called periodically; flushes appended data to disk at most once per
+    /// second under the everysec policy.
+    pub fn fsync_if_due(&mut self) -> Result<()> {
+        if self.pending_fsync && self.last_fsync.elapsed() >= Duration::from_secs(1) {
+            self.fsync()?;
+        }
+        Ok(())
+    }
+
+    pub fn fsync(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .context("Error while syncing aof file")?;
+        self.last_fsync = Instant::now();
+        self.pending_fsync = false;
+        Ok(())
+    }
+
+    /// Relative expiries are logged as absolute PXAT timestamps, so replaying
+    /// the file later doesn't extend the key's lifetime.
+    fn entry(command: &Command) -> Vec<u8> {
+        match command {
+            Command::Set(key, val, Some(exp), _) => {
+                let ms = exp
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+                    .to_string();
+                Value::Array(vec![
+                    Value::bulk("SET"),
+                    Value::bulk_bytes(key),
+                    Value::Bytes(val.to_vec()),
+                    Value::bulk("PXAT"),
+                    Value::bulk(ms),
+                ])
+                .serialize()
+            }
+            _ => command.serialize(),
+        }
+    }
+
+    /// The commands that recreate `key` from scratch. Collections are written
+    /// with a single variadic command, followed by a PEXPIREAT if they expire.
+    /// A stream keeps more than its entries, such as the last ID it handed
+    /// out, so it's restored from its DUMP payload instead.
+    pub fn commands_for(key: Bytes, val: RedisValue, exp: Option<SystemTime>) -> Vec<Command> {
+        let command = match val {
+            RedisValue::Stream(_) => {
+                let payload = RedisDB::dump_value(&val);
+                Command::Restore(key.clone(), 0, payload, true, false)
+            }
+            RedisValue::String(val) => return vec![Command::Set(key, val, exp, false)],
+            RedisValue::List(list) => Command::RPush(key.clone(), list.into_iter().collect()),
+            RedisValue::Set(set) => Command::SAdd(key.clone(), set.into_iter().collect()),
+            RedisValue::Hash(hash) => Command::HSet(key.clone(), hash.into_iter().collect()),
+            RedisValue::ZSet(zset) => Command::ZAdd(
+                key.clone(),
+                zset.into_iter()
+                    .map(|(member, score)| (score, member))
+                    .collect(),
+            ),
+        };
+        match exp {
+            Some(exp) => vec![command, Command::PExpireAt(key, exp, vec![])],
+            None => vec![command],
+        }
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    pub fn last_rewrite_ok(&self) -> bool {
+        self.last_rewrite_ok
+    }
+
+    /// Starts buffering appended commands so they can be added to the
+    /// rewritten file. Returns the temp file the rewrite should be written to,
+    /// or None if a rewrite is already running.
+    pub fn start_rewrite(&mut self) -> Option<String> {
+        if self.rewrite_in_progress() {
+            return None;
+        }
+        self.rewrite_buffer = Some(Vec::new());
+        Some(format!(
+            "{}/temp-rewriteaof-{}.aof",
+            self.dir,
+            std::process::id()
+        ))
+    }
+
+    /// Writes the smallest command stream that recreates the dataset, or an
+    /// RDB snapshot of it when `use_rdb_preamble` is set.
+    pub fn write_rewrite(
+        path: &str,
+        kivals: HashMap<Bytes, RedisValue>,
+        exp_map: &HashMap<Bytes, SystemTime>,
+        libraries: Vec<Bytes>,
+        use_rdb_preamble: bool,
+    ) -> Result<()> {
+        let mut file = File::create(path).context("Error while creating temp aof file")?;
+        if use_rdb_preamble {
+            file.write_all(&RedisDB::serialize_rdb(&kivals, exp_map, &libraries))
+                .context("Error while writing temp aof file")?;
+            file.sync_all()
+                .context("Error while syncing temp aof file")?;
+            return Ok(());
+        }
+        for code in libraries {
+            file.write_all(&Self::entry(&Command::FunctionLoad(code, false)))
+                .context("Error while writing temp aof file")?;
+        }
+        let now = SystemTime::now();
+        for (key, val) in kivals {
+            let exp = exp_map.get(&key).cloned();
+            if matches!(exp, Some(exp) if exp <= now) {
+                continue;
+            }
+            for command in Self::commands_for(key, val, exp) {
+                file.write_all(&Self::entry(&command))
+                    .context("Error while writing temp aof file")?;
+            }
+        }
+        file.sync_all()
+            .context("Error while syncing temp aof file")?;
+        Ok(())
+    }
+
+    /// Appends the commands buffered during the rewrite to the temp file and
+    /// swaps it in place of the current AOF.
+    pub fn finish_rewrite(&mut self, temp_path: &str, res: Result<()>) -> Result<()> {
+        let rewrite_buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let res = res.and_then(|_| {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(temp_path)
+                .context("Error while opening temp aof file")?;
+            file.write_all(&rewrite_buffer)
+                .context("Error while writing temp aof file")?;
+            file.sync_all()
+                .context("Error while syncing temp aof file")?;
+            std::fs::rename(temp_path, self.path())
+                .context("Error while renaming temp aof file")?;
+            self.file = file;
+            self.pending_fsync = false;
+            Ok(())
+        });
+        if res.is_err() {
+            let _ = std::fs::remove_file(temp_path);
+        }
+        self.last_rewrite_ok = res.is_ok();
+        res
+    }
+
+    /// Reads the AOF back. A file written with an RDB preamble yields the
+    /// snapshot it starts with, followed by the commands appended after it.
+    pub fn read(&self, verify_checksum: bool) -> Result<AofContents> {
+        let mut file = File::open(self.path()).context("Error while opening aof file")?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)
+            .context("Error while reading aof file")?;
+        let mut contents = AofContents {
+            preamble: None,
+            commands: Vec::new(),
+        };
+        if buffer.starts_with(b"REDIS") {
+            let mut redis_db = RedisDB::new(self.dir.clone(), self.file_name.clone());
+            redis_db.set_verify_checksum(verify_checksum);
+            let (dataset, consumed) = redis_db
+                .parse_rdb(&buffer)
+                .context("Error while reading aof rdb preamble")?;
+            contents.preamble = Some(dataset);
+            buffer.drain(0..consumed);
+        }
+        if !buffer.is_empty() {
+            contents.commands =
+                Command::deserialize(&buffer).context("Error while reading aof file")?;
+        }
+        Ok(contents)
+    }
+}
@@ -0,0 +1,227 @@
+use crate::redis_cli::Connection;
+use crate::redis_resp::Value;
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A command the benchmark sends over and over.
+#[derive(Copy, Clone, Debug)]
+pub enum Workload {
+    Ping,
+    Set,
+    Get,
+}
+
+impl std::str::FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(workload: &str) -> Result<Self> {
+        match workload.to_lowercase().as_str() {
+            "ping" => Ok(Workload::Ping),
+            "set" => Ok(Workload::Set),
+            "get" => Ok(Workload::Get),
+            _ => bail!("Invalid benchmark test {}", workload),
+        }
+    }
+}
+
+impl std::fmt::Display for Workload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Workload::Ping => write!(f, "PING"),
+            Workload::Set => write!(f, "SET"),
+            Workload::Get => write!(f, "GET"),
+        }
+    }
+}
+
+pub struct BenchOptions {
+    /// Connections open at the same time.
+    pub clients: usize,
+    /// Requests sent per workload, across all connections.
+    pub requests: u64,
+    /// Requests each connection sends before waiting for their replies.
+    pub pipeline: usize,
+    /// Size of SET values in bytes.
+    pub data_size: usize,
+    /// Keys are picked at random out of this many, or the same key is used
+    /// for every request if it's 0.
+    pub keyspace: u64,
+    pub workloads: Vec<Workload>,
+}
+
+impl Default for BenchOptions {
+    /// The same defaults as redis-benchmark.
+    fn default() -> Self {
+        BenchOptions {
+            clients: 50,
+            requests: 100000,
+            pipeline: 1,
+            data_size: 3,
+            keyspace: 0,
+            workloads: vec![Workload::Ping, Workload::Set, Workload::Get],
+        }
+    }
+}
+
+/// Runs every workload against the server at `addr` in turn, printing a
+/// report like redis-benchmark's after each one.
+pub async fn run(addr: &str, opts: &BenchOptions) -> Result<()> {
+    for &workload in &opts.workloads {
+        let report = bench(addr, workload, opts).await?;
+        println!("====== {} ======", workload);
+        print!("{}", report.render(opts));
+        println!();
+    }
+    Ok(())
+}
+
+/// Sends `opts.requests` requests of `workload`, spread over `opts.clients`
+/// connections that each keep `opts.pipeline` requests in flight.
+pub async fn bench(addr: &str, workload: Workload, opts: &BenchOptions) -> Result<Report> {
+    let mut conns = Vec::with_capacity(opts.clients);
+    for _ in 0..opts.clients.max(1) {
+        conns.push(Connection::connect(addr).await?);
+    }
+    let remaining = Arc::new(AtomicU64::new(opts.requests));
+    let start = Instant::now();
+    let tasks = conns
+        .into_iter()
+        .enumerate()
+        .map(|(n, conn)| {
+            let client = Client {
+                conn,
+                workload,
+                value: Value::Bytes(vec![b'x'; opts.data_size]),
+                keyspace: opts.keyspace,
+                pipeline: opts.pipeline.max(1) as u64,
+                rng: 0x9e37_79b9_7f4a_7c15 ^ (n as u64 + 1),
+            };
+            tokio::spawn(client.run(Arc::clone(&remaining)))
+        })
+        .collect::<Vec<_>>();
+    let mut latencies = Vec::new();
+    for task in tasks {
+        latencies.extend(task.await??);
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+    Ok(Report { elapsed, latencies })
+}
+
+struct Client {
+    conn: Connection,
+    workload: Workload,
+    value: Value,
+    keyspace: u64,
+    pipeline: u64,
+    /// Xorshift state for picking keys.
+    rng: u64,
+}
+
+impl Client {
+    /// Sends batches of requests until there are none left to send, and
+    /// returns the latency of each one. A request's latency is the time from
+    /// its batch being sent until its reply arrives.
+    async fn run(mut self, remaining: Arc<AtomicU64>) -> Result<Vec<Duration>> {
+        let mut latencies = Vec::new();
+        let pipeline = self.pipeline;
+        loop {
+            let claimed = remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n > 0).then(|| n - n.min(pipeline))
+            });
+            let batch = match claimed {
+                Ok(n) => n.min(pipeline),
+                Err(_) => return Ok(latencies),
+            };
+            let mut req = Vec::new();
+            for _ in 0..batch {
+                req.extend_from_slice(&self.request().serialize());
+            }
+            let sent = Instant::now();
+            self.conn.send(&req).await?;
+            for _ in 0..batch {
+                if let Value::Error(e) = self.conn.read_reply().await? {
+                    bail!("{} failed: {}", self.workload, e);
+                }
+                latencies.push(sent.elapsed());
+            }
+        }
+    }
+
+    fn request(&mut self) -> Value {
+        let key = Value::bulk(format!("key:{:012}", self.next_key()));
+        match self.workload {
+            Workload::Ping => Value::Array(vec![Value::bulk("PING")]),
+            Workload::Set => Value::Array(vec![Value::bulk("SET"), key, self.value.clone()]),
+            Workload::Get => Value::Array(vec![Value::bulk("GET"), key]),
+        }
+    }
+
+    fn next_key(&mut self) -> u64 {
+        if self.keyspace == 0 {
+            return 0;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng % self.keyspace
+    }
+}
+
+pub struct Report {
+    pub elapsed: Duration,
+    /// The latency of every request, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn requests_per_sec(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    fn render(&self, opts: &BenchOptions) -> String {
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        let avg = match self.latencies.len() {
+            0 => 0.0,
+            len => ms(self.latencies.iter().sum::<Duration>()) / len as f64,
+        };
+        format!(
+            "  {} requests completed in {:.2} seconds\n  \
+             {} parallel clients\n  \
+             {} bytes payload\n  \
+             pipeline {}\n\n  \
+             throughput summary: {:.2} requests per second\n  \
+             latency summary (msec):\n  \
+             {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}\n  \
+             {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3}\n",
+            self.latencies.len(),
+            self.elapsed.as_secs_f64(),
+            opts.clients,
+            opts.data_size,
+            opts.pipeline,
+            self.requests_per_sec(),
+            "avg",
+            "min",
+            "p50",
+            "p95",
+            "p99",
+            "max",
+            avg,
+            ms(self.percentile(0.0)),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(95.0)),
+            ms(self.percentile(99.0)),
+            ms(self.percentile(100.0)),
+        )
+    }
+}
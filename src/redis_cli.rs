@@ -0,0 +1,219 @@
+use crate::redis_resp::Value;
+use anyhow::{bail, Context, Result};
+use std::io::IsTerminal;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// A minimal redis-cli. Runs `args` as a single command if given, or else
+/// every command read from stdin, one per line, printing the replies the way
+/// redis-cli does.
+pub async fn run(addr: &str, args: Vec<String>) -> Result<()> {
+    let mut conn = Connection::connect(addr).await?;
+    if !args.is_empty() {
+        let args = args.into_iter().map(String::into_bytes).collect();
+        println!("{}", format_reply(&conn.call(args).await?, 0));
+        return Ok(());
+    }
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        if interactive {
+            print!("{}> ", addr);
+            std::io::Write::flush(&mut std::io::stdout())?;
+        }
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let args = match split_args(&line) {
+            Some(args) => args,
+            None => {
+                println!("Invalid argument(s)");
+                continue;
+            }
+        };
+        match args.first().map(|arg| arg.to_ascii_lowercase()) {
+            None => continue,
+            Some(cmd) if cmd == b"quit" || cmd == b"exit" => return Ok(()),
+            Some(_) => {}
+        }
+        println!("{}", format_reply(&conn.call(args).await?, 0));
+    }
+}
+
+/// A client connection, sending commands and reading back their replies.
+pub struct Connection {
+    stream: TcpStream,
+    /// Bytes read past the last reply.
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    pub async fn connect(addr: &str) -> Result<Self> {
+        Ok(Connection {
+            stream: TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("Could not connect to Redis at {}", addr))?,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Sends a command as an array of bulk strings and waits for the reply.
+    pub async fn call(&mut self, args: Vec<Vec<u8>>) -> Result<Value> {
+        let req = Value::Array(args.into_iter().map(Value::Bytes).collect());
+        self.send(&req.serialize()).await?;
+        self.read_reply().await
+    }
+
+    /// Writes already serialized commands, e.g. a pipeline of them.
+    pub async fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(self.stream.write_all(bytes).await?)
+    }
+
+    pub async fn read_reply(&mut self) -> Result<Value> {
+        loop {
+            if let Some((reply, consumed)) = Value::parse(&self.buf)? {
+                self.buf.drain(..consumed);
+                return Ok(reply);
+            }
+            let mut chunk = [0; 4096];
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                bail!("Server closed the connection");
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Splits a line into arguments like redis-cli does. Arguments are separated
+/// by spaces, and may be "double quoted", with escapes such as \n and \xff,
+/// or 'single quoted'. Returns None if a quote isn't closed.
+pub fn split_args(line: &str) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first = match chars.next() {
+            Some(c) => c,
+            None => return Some(args),
+        };
+        let mut arg = Vec::new();
+        match first {
+            '"' => loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => arg.push(b'\n'),
+                        'r' => arg.push(b'\r'),
+                        't' => arg.push(b'\t'),
+                        'b' => arg.push(8),
+                        'a' => arg.push(7),
+                        'x' => {
+                            let hex = [chars.next()?, chars.next()?].iter().collect::<String>();
+                            arg.push(u8::from_str_radix(&hex, 16).ok()?);
+                        }
+                        c => push_char(&mut arg, c),
+                    },
+                    c => push_char(&mut arg, c),
+                }
+            },
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' => break,
+                    '\\' if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        arg.push(b'\'');
+                    }
+                    c => push_char(&mut arg, c),
+                }
+            },
+            c => {
+                push_char(&mut arg, c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    push_char(&mut arg, c);
+                }
+            }
+        }
+        // A closing quote has to be followed by a space or the end of line.
+        if matches!(first, '"' | '\'') && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    let mut utf8 = [0; 4];
+    arg.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+}
+
+/// Renders a reply like redis-cli. Nested arrays are indented by `indent`
+/// columns, the width of the numbering of the arrays they are in.
+pub fn format_reply(reply: &Value, indent: usize) -> String {
+    match reply {
+        Value::SimpleString(str) => str.clone(),
+        Value::BulkString(str) => quote(str.as_bytes()),
+        Value::Bytes(bytes) => quote(bytes),
+        Value::Integer(num) => format!("(integer) {}", num),
+        Value::Error(msg) => format!("(error) {}", msg),
+        Value::Nil => "(nil)".to_string(),
+        Value::Array(items) if items.is_empty() => "(empty array)".to_string(),
+        Value::Array(items) => format_items(
+            items
+                .iter()
+                .map(|item| (")", format_reply_at(item, items.len(), indent))),
+            items.len(),
+            indent,
+        ),
+        Value::Map(entries) if entries.is_empty() => "(empty hash)".to_string(),
+        Value::Map(entries) => format_items(
+            entries.iter().map(|(key, val)| {
+                let key = format_reply_at(key, entries.len(), indent);
+                ("#", format!("{} => {}", key, format_reply(val, indent)))
+            }),
+            entries.len(),
+            indent,
+        ),
+    }
+}
+
+fn format_reply_at(reply: &Value, len: usize, indent: usize) -> String {
+    format_reply(reply, indent + len.to_string().len() + 2)
+}
+
+fn format_items<'a>(
+    items: impl Iterator<Item = (&'a str, String)>,
+    len: usize,
+    indent: usize,
+) -> String {
+    let width = len.to_string().len();
+    items
+        .enumerate()
+        .map(|(i, (sep, item))| {
+            let pad = if i == 0 { 0 } else { indent };
+            format!("{:pad$}{:>width$}{} {}", "", i + 1, sep, item)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Quotes a bulk string, escaping anything that isn't printable ASCII.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            7 => out.push_str("\\a"),
+            8 => out.push_str("\\b"),
+            b if b.is_ascii_graphic() || b == b' ' => out.push(b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
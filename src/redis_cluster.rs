@@ -0,0 +1,589 @@
+use crate::redis_commands::SetSlot;
+use crate::redis_resp::Value;
+use crate::redis_server::random_id;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+
+/// The number of hash slots keys are spread over.
+pub const CLUSTER_SLOTS: usize = 16384;
+
+/// Nodes listen for cluster traffic on their port plus this offset. Here
+/// it is only reported, as nodes talk to each other over the regular port.
+const CLUSTER_PORT_INCR: u32 = 10000;
+
+/// Inclusive `(start, end)` slot ranges.
+type SlotRanges = Vec<(u16, u16)>;
+
+/// Where a command's keys have to be served from.
+pub enum Route {
+    /// Every key hashes to a slot this node serves (or there are no keys).
+    Local,
+    CrossSlot,
+    Moved(u16, String),
+    /// The slot is being migrated to the node at this address, and the keys
+    /// are already gone from this one.
+    Ask(u16, String),
+    /// The slot is being migrated, and only some of the keys are left here.
+    TryAgain,
+    Unassigned(u16),
+}
+
+#[derive(Clone)]
+pub struct ClusterNode {
+    pub id: String,
+    pub host: String,
+    pub port: String,
+    /// Set until the node has been greeted. Until then its id is only a
+    /// placeholder, so other nodes ignore it.
+    pub handshake: bool,
+}
+
+impl ClusterNode {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// This node's view of the cluster: the known nodes, and which of them
+/// serves each hash slot.
+pub struct Cluster {
+    myself: ClusterNode,
+    /// The other nodes.
+    nodes: Vec<ClusterNode>,
+    /// The id of the node serving each slot.
+    slots: Vec<Option<String>>,
+    /// Slots this node serves that are being moved elsewhere, with the id of
+    /// the node they are moving to.
+    migrating: BTreeMap<u16, String>,
+    /// Slots being moved to this node, with the id of the node serving them.
+    importing: BTreeMap<u16, String>,
+}
+
+impl Cluster {
+    /// Builds the slot table from the ranges this node serves, such as
+    /// "0-5460,6000", and those served by other nodes, given as
+    /// "<ranges> <host:port>". Nodes given this way get a placeholder id until
+    /// they are met. This node is reached by other nodes at `host`.
+    pub fn new(host: &str, port: &str, my_slots: &str, nodes: &[String]) -> Result<Self> {
+        let mut cluster = Cluster {
+            myself: ClusterNode {
+                id: random_id(),
+                host: host.to_string(),
+                port: port.to_string(),
+                handshake: false,
+            },
+            nodes: Vec::new(),
+            slots: vec![None; CLUSTER_SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        };
+        let myid = cluster.myself.id.clone();
+        cluster.assign(&parse_slot_ranges(my_slots)?, &myid);
+        for node in nodes {
+            let (ranges, addr) = node
+                .split_once(' ')
+                .with_context(|| format!("Invalid cluster node {:?}", node))?;
+            let (host, port) = addr
+                .rsplit_once(':')
+                .with_context(|| format!("Invalid cluster node {:?}", node))?;
+            let node = ClusterNode {
+                id: random_id(),
+                host: host.to_string(),
+                port: port.to_string(),
+                handshake: true,
+            };
+            cluster.assign(&parse_slot_ranges(ranges)?, &node.id);
+            cluster.nodes.push(node);
+        }
+        Ok(cluster)
+    }
+
+    fn assign(&mut self, ranges: &[(u16, u16)], id: &str) {
+        for (start, end) in ranges {
+            self.slots[*start as usize..=*end as usize].fill(Some(id.to_string()));
+        }
+    }
+
+    pub fn myid(&self) -> &str {
+        &self.myself.id
+    }
+
+    /// The address other nodes reach this one at.
+    pub fn host(&self) -> &str {
+        &self.myself.host
+    }
+
+    /// Checks that `keys` all hash to the same slot, and that this node
+    /// serves it. While the slot is migrating, keys that `exists` no longer
+    /// finds here are asked for on the node they are moving to. A slot being
+    /// imported is only served once the client sent ASKING.
+    pub fn route<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        asking: bool,
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Route {
+        let keys = keys.into_iter().collect::<Vec<_>>();
+        let slot = match keys.first() {
+            Some(key) => key_hash_slot(key),
+            None => return Route::Local,
+        };
+        if keys.iter().any(|key| key_hash_slot(key) != slot) {
+            return Route::CrossSlot;
+        }
+        let owner = match &self.slots[slot as usize] {
+            Some(owner) => owner,
+            None => return Route::Unassigned(slot),
+        };
+        if *owner == self.myself.id {
+            let target = match self.migrating.get(&slot).and_then(|id| self.node(id)) {
+                Some(target) => target,
+                None => return Route::Local,
+            };
+            let found = keys.iter().filter(|key| exists(key)).count();
+            return match found {
+                0 => Route::Ask(slot, target.addr()),
+                found if found < keys.len() => Route::TryAgain,
+                _ => Route::Local,
+            };
+        }
+        if asking && self.importing.contains_key(&slot) {
+            return Route::Local;
+        }
+        match self.node(owner) {
+            Some(node) => Route::Moved(slot, node.addr()),
+            None => Route::Unassigned(slot),
+        }
+    }
+
+    fn node(&self, id: &str) -> Option<&ClusterNode> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// CLUSTER SETSLOT: starts or ends moving `slot` between this node and
+    /// another.
+    pub fn set_slot(&mut self, slot: u16, action: &SetSlot) -> Result<(), Value> {
+        let mine = self.slots[slot as usize].as_deref() == Some(self.myself.id.as_str());
+        let id = match action {
+            SetSlot::Migrating(id) | SetSlot::Importing(id) | SetSlot::Node(id) => id,
+            SetSlot::Stable => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                return Ok(());
+            }
+        };
+        // Placeholder ids of nodes that haven't been greeted yet don't count.
+        let known = self.node(id).is_some_and(|node| !node.handshake);
+        if *id != self.myself.id && !known {
+            return Err(Value::error(format!("ERR I don't know about node {}", id)));
+        }
+        match action {
+            SetSlot::Migrating(_) if !mine => Err(Value::error(format!(
+                "ERR I'm not the owner of hash slot {}",
+                slot
+            ))),
+            SetSlot::Migrating(_) if *id == self.myself.id => {
+                Err(Value::error("ERR Target node can't be this node"))
+            }
+            SetSlot::Migrating(_) => {
+                self.migrating.insert(slot, id.clone());
+                Ok(())
+            }
+            SetSlot::Importing(_) if mine => Err(Value::error(format!(
+                "ERR I'm already the owner of hash slot {}",
+                slot
+            ))),
+            SetSlot::Importing(_) if *id == self.myself.id => {
+                Err(Value::error("ERR Source node can't be this node"))
+            }
+            SetSlot::Importing(_) => {
+                self.importing.insert(slot, id.clone());
+                Ok(())
+            }
+            _ => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                self.slots[slot as usize] = Some(id.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// The contiguous slot ranges served by `id`.
+    fn ranges_of(&self, id: &str) -> SlotRanges {
+        let mut ranges: SlotRanges = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() != Some(id) {
+                continue;
+            }
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    fn all_nodes(&self) -> impl Iterator<Item = &ClusterNode> {
+        std::iter::once(&self.myself).chain(self.nodes.iter())
+    }
+
+    /// The CLUSTER NODES description of the cluster, one line per node. It
+    /// doubles as the nodes.conf format.
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        for node in self.all_nodes() {
+            let flags = if node.id == self.myself.id {
+                "myself,master"
+            } else if node.handshake {
+                "handshake"
+            } else {
+                "master"
+            };
+            let cport = node.port.parse::<u32>().unwrap_or(0) + CLUSTER_PORT_INCR;
+            out.push_str(&format!(
+                "{} {}@{} {} - 0 0 0 connected",
+                node.id,
+                node.addr(),
+                cport,
+                flags
+            ));
+            for (start, end) in self.ranges_of(&node.id) {
+                match start == end {
+                    true => out.push_str(&format!(" {}", start)),
+                    false => out.push_str(&format!(" {}-{}", start, end)),
+                }
+            }
+            if node.id == self.myself.id {
+                for (slot, id) in &self.migrating {
+                    out.push_str(&format!(" [{}->-{}]", slot, id));
+                }
+                for (slot, id) in &self.importing {
+                    out.push_str(&format!(" [{}-<-{}]", slot, id));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Merges another node's CLUSTER NODES description into this view. The
+    /// line flagged `myself` comes from the node that sent it, and is trusted
+    /// for the slots that node serves. Other lines only add nodes and slots
+    /// this node doesn't know about yet. Returns the nodes that were added.
+    pub fn merge(&mut self, description: &str) -> Vec<ClusterNode> {
+        let mut added = Vec::new();
+        for line in description.lines() {
+            let (node, flags, ranges) = match parse_node_line(line) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            if node.id == self.myself.id || node.addr() == self.myself.addr() || node.handshake {
+                continue;
+            }
+            let sender = flags.split(',').any(|flag| flag == "myself");
+            // A node only known from the command line so far is matched by
+            // address, and takes the real id.
+            let known = self
+                .nodes
+                .iter()
+                .position(|known| known.id == node.id)
+                .or_else(|| {
+                    self.nodes
+                        .iter()
+                        .position(|known| sender && known.addr() == node.addr())
+                });
+            match known {
+                Some(i) => {
+                    self.nodes[i].handshake = false;
+                    let old_id = std::mem::replace(&mut self.nodes[i].id, node.id.clone());
+                    for owner in self.slots.iter_mut().flatten() {
+                        if *owner == old_id {
+                            *owner = node.id.clone();
+                        }
+                    }
+                }
+                None => {
+                    self.nodes.push(node.clone());
+                    added.push(node.clone());
+                }
+            }
+            for (start, end) in ranges {
+                for slot in &mut self.slots[start as usize..=end as usize] {
+                    let mine = slot.as_deref() == Some(self.myself.id.as_str());
+                    if (sender && !mine) || slot.is_none() {
+                        *slot = Some(node.id.clone());
+                    }
+                }
+            }
+        }
+        added
+    }
+
+    /// Adds a node by address, before its id is known.
+    pub fn meet(&mut self, host: &str, port: &str) {
+        let addr = format!("{}:{}", host, port);
+        if addr == self.myself.addr() || self.nodes.iter().any(|node| node.addr() == addr) {
+            return;
+        }
+        self.nodes.push(ClusterNode {
+            id: random_id(),
+            host: host.to_string(),
+            port: port.to_string(),
+            handshake: true,
+        });
+    }
+
+    /// The addresses of the nodes that still have to be greeted.
+    pub fn handshakes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|node| node.handshake)
+            .map(ClusterNode::addr)
+            .collect()
+    }
+
+    pub fn info(&self) -> String {
+        let assigned = self.slots.iter().filter(|owner| owner.is_some()).count();
+        let size = self
+            .all_nodes()
+            .filter(|node| !self.ranges_of(&node.id).is_empty())
+            .count();
+        let state = if assigned == CLUSTER_SLOTS {
+            "ok"
+        } else {
+            "fail"
+        };
+        format!(
+            "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\ncluster_current_epoch:0\r\ncluster_my_epoch:0\r\n",
+            state,
+            assigned,
+            assigned,
+            self.nodes.len() + 1,
+            size
+        )
+    }
+
+    /// The CLUSTER SLOTS reply: each slot range with the node serving it.
+    pub fn slots_reply(&self) -> Value {
+        let mut ranges = Vec::new();
+        for node in self.all_nodes() {
+            for (start, end) in self.ranges_of(&node.id) {
+                ranges.push((start, end, node));
+            }
+        }
+        ranges.sort_by_key(|(start, ..)| *start);
+        Value::Array(
+            ranges
+                .into_iter()
+                .map(|(start, end, node)| {
+                    Value::Array(vec![
+                        Value::Integer(start as i64),
+                        Value::Integer(end as i64),
+                        Value::Array(vec![
+                            Value::bulk(&node.host),
+                            Value::Integer(node.port.parse().unwrap_or(0)),
+                            Value::bulk(&node.id),
+                        ]),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// The CLUSTER SHARDS reply. Without replicas, every node is a shard of
+    /// its own.
+    pub fn shards_reply(&self) -> Value {
+        Value::Array(
+            self.all_nodes()
+                .map(|node| {
+                    let slots = self
+                        .ranges_of(&node.id)
+                        .into_iter()
+                        .flat_map(|(start, end)| {
+                            [Value::Integer(start as i64), Value::Integer(end as i64)]
+                        })
+                        .collect();
+                    let port = node.port.parse().unwrap_or(0);
+                    Value::Map(vec![
+                        (Value::bulk("slots"), Value::Array(slots)),
+                        (
+                            Value::bulk("nodes"),
+                            Value::Array(vec![Value::Map(vec![
+                                (Value::bulk("id"), Value::bulk(&node.id)),
+                                (Value::bulk("port"), Value::Integer(port)),
+                                (Value::bulk("ip"), Value::bulk(&node.host)),
+                                (Value::bulk("endpoint"), Value::bulk(&node.host)),
+                                (Value::bulk("role"), Value::bulk("master")),
+                                (Value::bulk("replication-offset"), Value::Integer(0)),
+                                (Value::bulk("health"), Value::bulk("online")),
+                            ])]),
+                        ),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut file = File::create(path).context("Error while creating nodes.conf")?;
+        file.write_all(self.describe().as_bytes())
+            .context("Error while writing nodes.conf")?;
+        file.write_all(b"vars currentEpoch 0 lastVoteEpoch 0\n")
+            .context("Error while writing nodes.conf")?;
+        file.sync_all().context("Error while syncing nodes.conf")?;
+        Ok(())
+    }
+
+    /// Reads the node table saved to `path` by a previous run, or None if
+    /// there isn't one yet. This node's address is taken from `host` rather
+    /// than the file, as it may be configured differently this time.
+    pub fn load(path: &str, host: &str) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Error while reading nodes.conf"),
+        };
+        let mut myself = None;
+        let mut nodes = Vec::new();
+        let mut slots = vec![None; CLUSTER_SLOTS];
+        let mut migrating = BTreeMap::new();
+        let mut importing = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.starts_with("vars ")) {
+            let (node, flags, ranges) = parse_node_line(line)
+                .with_context(|| format!("Invalid nodes.conf line {:?}", line))?;
+            for (start, end) in ranges {
+                slots[start as usize..=end as usize].fill(Some(node.id.clone()));
+            }
+            if !flags.split(',').any(|flag| flag == "myself") {
+                nodes.push(node);
+                continue;
+            }
+            for migration in line.split_whitespace().filter(|part| part.starts_with('[')) {
+                let migration = migration.trim_start_matches('[').trim_end_matches(']');
+                let (slot, to, id) = match migration.split_once("->-") {
+                    Some((slot, id)) => (slot, true, id),
+                    None => match migration.split_once("-<-") {
+                        Some((slot, id)) => (slot, false, id),
+                        None => bail!("Invalid nodes.conf line {:?}", line),
+                    },
+                };
+                let slot = slot
+                    .parse::<u16>()
+                    .with_context(|| format!("Invalid nodes.conf line {:?}", line))?;
+                match to {
+                    true => migrating.insert(slot, id.to_string()),
+                    false => importing.insert(slot, id.to_string()),
+                };
+            }
+            myself = Some(ClusterNode {
+                host: host.to_string(),
+                ..node
+            });
+        }
+        let myself = match myself {
+            Some(myself) => myself,
+            None => bail!("nodes.conf doesn't describe this node"),
+        };
+        Ok(Some(Cluster {
+            myself,
+            nodes,
+            slots,
+            migrating,
+            importing,
+        }))
+    }
+}
+
+/// The address a node gives the other nodes: `announce_ip` if set, or else
+/// the first address in `bind` that isn't a wildcard. A node listening on
+/// every address falls back to loopback.
+pub fn announced_host(announce_ip: &str, bind: &[String]) -> String {
+    if !announce_ip.is_empty() {
+        return announce_ip.to_string();
+    }
+    bind.iter()
+        // A leading `-` only marks the address as optional.
+        .map(|addr| addr.trim_start_matches('-'))
+        .find(|addr| !matches!(*addr, "*" | "0.0.0.0" | "::" | "::*"))
+        .unwrap_or("127.0.0.1")
+        .to_string()
+}
+
+/// Parses a CLUSTER NODES line into the node, its flags and its slot
+/// ranges.
+fn parse_node_line(line: &str) -> Option<(ClusterNode, &str, SlotRanges)> {
+    let mut parts = line.split_whitespace();
+    let id = parts.next()?;
+    let addr = parts.next()?;
+    let flags = parts.next()?;
+    let addr = addr.split('@').next()?;
+    let (host, port) = addr.rsplit_once(':')?;
+    // Skipping master, ping-sent, pong-recv, config-epoch and link-state,
+    // and the `[slot->-id]` migrations that follow the slot ranges.
+    let ranges = parts
+        .skip(5)
+        .filter(|part| !part.starts_with('['))
+        .collect::<Vec<_>>();
+    let ranges = parse_slot_ranges(&ranges.join(",")).ok()?;
+    let node = ClusterNode {
+        id: id.to_string(),
+        host: host.to_string(),
+        port: port.to_string(),
+        handshake: flags.split(',').any(|flag| flag == "handshake"),
+    };
+    Some((node, flags, ranges))
+}
+
+/// Parses comma separated slots and `start-end` ranges.
+fn parse_slot_ranges(ranges: &str) -> Result<SlotRanges> {
+    let mut parsed = Vec::new();
+    for range in ranges.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start = start
+            .trim()
+            .parse::<u16>()
+            .with_context(|| format!("Invalid slot range {:?}", range))?;
+        let end = end
+            .trim()
+            .parse::<u16>()
+            .with_context(|| format!("Invalid slot range {:?}", range))?;
+        if start > end || end as usize >= CLUSTER_SLOTS {
+            bail!("Invalid slot range {:?}", range);
+        }
+        parsed.push((start, end));
+    }
+    Ok(parsed)
+}
+
+/// The slot a key belongs to. When the key contains a non-empty `{...}`
+/// hash tag, only the tag is hashed, so related keys can share a slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|b| *b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|b| *b == b'}') {
+            Some(0) | None => key,
+            Some(len) => &key[open + 1..open + 1 + len],
+        },
+        None => key,
+    };
+    crc16(hashed) % CLUSTER_SLOTS as u16
+}
+
+/// CRC16/XMODEM, the variant Redis Cluster uses for key hashing.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
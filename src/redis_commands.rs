@@ -1,234 +1,1043 @@
-use std::{iter::Peekable, slice::Iter, str::Split, time::SystemTime};
+use bytes::{Bytes, BytesMut};
+use std::time::SystemTime;
+
+use crate::redis_registry::{self, Flag, ParseError};
+use crate::redis_resp::{self, Value};
+use crate::redis_stream::{Fields, NewId, StreamId, Trim};
 
 #[derive(Clone)]
 pub enum Command {
-    Echo(String),
+    Echo(Bytes),
     Ping,
-    Get(String),
-    Set(String, String, Option<SystemTime>),
-    ConfigGet(String),
-    Keys(String),
-    Info(String),
-    ReplConf(String, String),
+    Get(Bytes),
+    /// SET, with its expiry and whether KEEPTTL asked to keep the key's
+    /// current one instead.
+    Set(Bytes, Bytes, Option<SystemTime>, bool),
+    /// CONFIG GET pattern [pattern ...].
+    ConfigGet(Vec<String>),
+    /// CONFIG SET param value [param value ...].
+    ConfigSet(Vec<(String, String)>),
+    ConfigResetStat,
+    Keys(Bytes),
+    /// INFO [section ...], where no section means the default ones.
+    Info(Vec<String>),
+    /// REPLCONF option value [option value ...]
+    ReplConf(Vec<(String, String)>),
     Psync(String, String),
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Type(Bytes),
+    /// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, with the expiry turned into
+    /// an absolute time and the NX, XX, GT and LT conditions it was given,
+    /// all of which have to hold.
+    PExpireAt(Bytes, SystemTime, Vec<ExpireCondition>),
+    /// EXPIRETIME key, or PEXPIRETIME key when true.
+    ExpireTime(Bytes, bool),
+    RPush(Bytes, Vec<Bytes>),
+    LRange(Bytes, i64, i64),
+    SAdd(Bytes, Vec<Bytes>),
+    SMembers(Bytes),
+    SRem(Bytes, Vec<Bytes>),
+    /// SPOP key [count], where no count pops a single member.
+    SPop(Bytes, Option<i64>),
+    /// SRANDMEMBER key [count]. A negative count may repeat members.
+    SRandMember(Bytes, Option<i64>),
+    /// SMISMEMBER key member [member ...].
+    SMIsMember(Bytes, Vec<Bytes>),
+    /// SINTERCARD numkeys key [key ...] [LIMIT limit], where a limit of 0
+    /// counts the whole intersection.
+    SInterCard(Vec<Bytes>, usize),
+    /// HRANDFIELD key [count [WITHVALUES]].
+    HRandField(Bytes, Option<i64>, bool),
+    /// ZRANDMEMBER key [count [WITHSCORES]].
+    ZRandMember(Bytes, Option<i64>, bool),
+    HSet(Bytes, Vec<(Bytes, Bytes)>),
+    HGetAll(Bytes),
+    ZAdd(Bytes, Vec<(f64, Bytes)>),
+    ZRange(Bytes, i64, i64, bool),
+    /// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold] id field value
+    /// [field value ...].
+    XAdd {
+        key: Bytes,
+        id: NewId,
+        fields: Fields,
+        no_mkstream: bool,
+        trim: Option<Trim>,
+    },
+    XLen(Bytes),
+    /// XRANGE key start end [COUNT count], with exclusive bounds already
+    /// turned into inclusive ones. None for a bound that leaves the range
+    /// empty, such as one starting after the greatest possible ID.
+    XRange(Bytes, Option<(StreamId, StreamId)>, Option<usize>),
+    /// XGROUP CREATE key group id|$ [MKSTREAM] [ENTRIESREAD entries-read],
+    /// where a start of None stands for $, the stream's last ID.
+    XGroupCreate {
+        key: Bytes,
+        group: Bytes,
+        start: Option<StreamId>,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    /// XGROUP CREATECONSUMER key group consumer.
+    XGroupCreateConsumer(Bytes, Bytes, Bytes),
+    /// XGROUP DELCONSUMER key group consumer.
+    XGroupDelConsumer(Bytes, Bytes, Bytes),
+    /// XGROUP DESTROY key group.
+    XGroupDestroy(Bytes, Bytes),
+    /// XREADGROUP GROUP group consumer [COUNT count] [NOACK] STREAMS key
+    /// [key ...] id [id ...], where an ID of None stands for >, the entries
+    /// never delivered to the group.
+    XReadGroup {
+        group: Bytes,
+        consumer: Bytes,
+        count: Option<usize>,
+        no_ack: bool,
+        streams: Vec<(Bytes, Option<StreamId>)>,
+    },
+    /// XACK key group id [id ...].
+    XAck(Bytes, Bytes, Vec<StreamId>),
+    XInfoStream(Bytes),
+    XInfoGroups(Bytes),
+    /// XINFO CONSUMERS key group.
+    XInfoConsumers(Bytes, Bytes),
+    Del(Vec<Bytes>),
+    /// UNLINK key [key ...]: like DEL, but big values are freed on a
+    /// background task.
+    Unlink(Vec<Bytes>),
+    /// TOUCH key [key ...]: updates the access time of the keys.
+    Touch(Vec<Bytes>),
+    Wait(usize, u64),
+    /// REPLICAOF host port, or REPLICAOF NO ONE when None.
+    ReplicaOf(Option<(String, String)>),
+    Dump(Bytes),
+    /// RESTORE key ttl payload, along with the REPLACE and ABSTTL flags.
+    Restore(Bytes, u64, Vec<u8>, bool, bool),
+    Migrate {
+        host: String,
+        port: String,
+        keys: Vec<Bytes>,
+        db: u64,
+        timeout: u64,
+        copy: bool,
+        replace: bool,
+    },
+    ClusterInfo,
+    ClusterMyId,
+    ClusterNodes,
+    ClusterSlots,
+    ClusterShards,
+    ClusterMeet(String, String),
+    /// Sent by a node meeting this one, with its CLUSTER NODES description.
+    ClusterHello(String),
+    /// CLUSTER SETSLOT slot, with what to do with it.
+    ClusterSetSlot(u16, SetSlot),
+    /// ASKING: lets the next command be served from a slot being imported.
+    Asking,
+    ObjectEncoding(Bytes),
+    ObjectIdleTime(Bytes),
+    ObjectRefCount(Bytes),
+    ObjectFreq(Bytes),
+    /// MEMORY USAGE key [SAMPLES count], where 0 samples every element.
+    MemoryUsage(Bytes, usize),
+    MemoryStats,
+    MemoryDoctor,
+    /// FAILOVER [TO host port] [TIMEOUT ms] [ABORT].
+    Failover {
+        to: Option<(String, String)>,
+        timeout: u64,
+        abort: bool,
+    },
+    /// SHUTDOWN [NOSAVE|SAVE]: Some(true) for SAVE, Some(false) for NOSAVE
+    /// and None to save only if save points are configured.
+    Shutdown(Option<bool>),
+    /// SLOWLOG GET [count], where None returns every entry.
+    SlowLogGet(Option<usize>),
+    SlowLogLen,
+    SlowLogReset,
+    LatencyLatest,
+    LatencyHistory(String),
+    /// LATENCY RESET [event ...], where no event resets all of them.
+    LatencyReset(Vec<String>),
+    LatencyDoctor,
+    /// SORT key [BY pattern] [LIMIT offset count] [GET pattern ...]
+    /// [ASC|DESC] [ALPHA] [STORE destination].
+    Sort {
+        key: Bytes,
+        by: Option<Bytes>,
+        limit: Option<(i64, i64)>,
+        get: Vec<Bytes>,
+        desc: bool,
+        alpha: bool,
+        store: Option<Bytes>,
+    },
+    /// DEBUG SLEEP seconds, which may be fractional.
+    DebugSleep(f64),
+    DebugObject(Bytes),
+    DebugSetActiveExpire(bool),
+    DebugChangeReplId,
+    CommandCount,
+    CommandList,
+    /// CLIENT PAUSE timeout [WRITE|ALL], with the timeout in milliseconds
+    /// and whether only writes are paused.
+    ClientPause(u64, bool),
+    ClientUnpause,
+    ClientId,
+    ClientSetName(String),
+    ClientGetName,
+    /// RESET, which puts the connection back the way it was when the client
+    /// connected.
+    Reset,
+    /// COMMAND INFO [command ...], where no command describes all of them.
+    CommandInfo(Vec<String>),
+    /// FUNCTION LOAD [REPLACE] code.
+    FunctionLoad(Bytes, bool),
+    FunctionDelete(String),
+    FunctionFlush,
+    /// FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE].
+    FunctionList {
+        pattern: Option<String>,
+        with_code: bool,
+    },
+    FunctionDump,
+    /// FUNCTION KILL, which stops the functions running that haven't written
+    /// anything yet.
+    FunctionKill,
+    /// FUNCTION RESTORE payload [FLUSH|APPEND|REPLACE].
+    FunctionRestore(Vec<u8>, RestorePolicy),
+    /// FCALL function numkeys [key ...] [arg ...], or FCALL_RO when
+    /// `read_only` is set.
+    FCall {
+        function: String,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+}
+
+/// When an EXPIRE is allowed to set the expiry of a key.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExpireCondition {
+    /// Only if the key has no expiry.
+    Nx,
+    /// Only if the key already has an expiry.
+    Xx,
+    /// Only if the new expiry is later than the current one.
+    Gt,
+    /// Only if the new expiry is earlier than the current one.
+    Lt,
+}
+
+impl ExpireCondition {
+    /// The error for conditions that can't be given together. XX can go
+    /// with GT or LT, but NX goes with nothing else.
+    pub fn conflict(conditions: &[ExpireCondition]) -> Option<&'static str> {
+        if conditions.contains(&ExpireCondition::Nx) && conditions.len() > 1 {
+            return Some("ERR NX and XX, GT or LT options at the same time are not compatible");
+        }
+        if conditions.contains(&ExpireCondition::Gt) && conditions.contains(&ExpireCondition::Lt) {
+            return Some("ERR GT and LT options at the same time are not compatible");
+        }
+        None
+    }
+}
+
+/// What CLUSTER SETSLOT does with a slot.
+#[derive(Clone, PartialEq)]
+pub enum SetSlot {
+    /// Starts moving the slot this node serves to the node with this id.
+    Migrating(String),
+    /// Starts taking the slot over from the node with this id.
+    Importing(String),
+    /// Assigns the slot to the node with this id, ending any migration.
+    Node(String),
+    /// Ends any migration, leaving the slot where it is.
+    Stable,
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RestorePolicy {
+    /// Keeps them, and fails if a restored library has the same name as one.
+    Append,
+    /// Replaces those with the same name as a restored library.
+    Replace,
+    /// Deletes them all first.
+    Flush,
 }
 
 impl Command {
-    pub fn deserialize(req: &str) -> Vec<Self> {
-        let req = RedisDataType::deserialize(req);
-        match req {
-            RedisDataType::Array(arr) => {
-                let mut arr_iter: Peekable<Iter<'_, RedisDataType>> = arr.iter().peekable();
-                return Self::parse_req(&mut arr_iter);
-            }
-            _ => {
-                panic!("Invalid data type")
-            }
+    /// Parses every command in `req`, such as a whole AOF, stopping at the
+    /// first one that isn't valid.
+    pub fn deserialize(req: &[u8]) -> anyhow::Result<Vec<Self>> {
+        let mut buf = BytesMut::from(req);
+        let mut commands = Vec::new();
+        while let Some((args, _)) = redis_resp::parse_request(&mut buf)? {
+            commands.extend(Self::from_args(&args)?);
         }
+        Ok(commands)
+    }
+
+    /// Parses a single request's arguments, which gives None if there are
+    /// none.
+    pub fn from_args(args: &[Bytes]) -> Result<Option<Self>, ParseError> {
+        redis_registry::parse(args)
     }
 
-    pub fn serialize(&self) -> String {
+    /// The name the command is registered under, with the subcommand of
+    /// container commands such as CONFIG appended as in "config|get".
+    pub fn name(&self) -> &'static str {
         match self {
-            Command::Echo(echo) => {
-                format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", echo.len(), echo)
-            }
-            Command::Ping => {
-                format!("*1\r\n$4\r\nPING\r\n")
-            }
-            Command::Get(_) => todo!(),
-            Command::Set(key, val, system_time) => {
-                let cmd = format!(
-                    "$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}",
-                    key.len(),
-                    key,
-                    val.len(),
-                    val
-                );
-                match system_time {
-                    Some(exp) => match exp.elapsed() {
-                        Ok(_) => "".to_string(),
-                        Err(e) => {
-                            let durr = e.duration().as_millis();
-                            format!(
-                                "*5\r\n{}\r\n$2\r\npx\r\n${}\r\n{}\r\n",
-                                cmd,
-                                durr.to_string().len(),
-                                durr
-                            )
-                        }
-                    },
-                    None => format!("*3\r\n{}\r\n", cmd),
-                }
-            }
-            Command::ConfigGet(_) => todo!(),
-            Command::Keys(_) => todo!(),
-            Command::Info(_) => todo!(),
-            Command::ReplConf(key, val) => format!(
-                "*3\r\n$8\r\nREPLCONF\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                key.len(),
-                key,
-                val.len(),
-                val
-            ),
-            Command::Psync(repl_id, offset) => format!(
-                "*3\r\n$5\r\nPSYNC\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                repl_id.len(),
-                repl_id,
-                offset.len(),
-                offset
-            ),
+            Command::Echo(_) => "echo",
+            Command::Ping => "ping",
+            Command::Get(_) => "get",
+            Command::Set(..) => "set",
+            Command::ConfigGet(_) => "config|get",
+            Command::ConfigSet(_) => "config|set",
+            Command::ConfigResetStat => "config|resetstat",
+            Command::Keys(_) => "keys",
+            Command::Info(_) => "info",
+            Command::ReplConf(..) => "replconf",
+            Command::Psync(..) => "psync",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+            Command::Type(_) => "type",
+            Command::PExpireAt(..) => "pexpireat",
+            Command::ExpireTime(_, false) => "expiretime",
+            Command::ExpireTime(_, true) => "pexpiretime",
+            Command::RPush(..) => "rpush",
+            Command::LRange(..) => "lrange",
+            Command::SAdd(..) => "sadd",
+            Command::SMembers(_) => "smembers",
+            Command::SRem(..) => "srem",
+            Command::SPop(..) => "spop",
+            Command::SRandMember(..) => "srandmember",
+            Command::SMIsMember(..) => "smismember",
+            Command::SInterCard(..) => "sintercard",
+            Command::HRandField(..) => "hrandfield",
+            Command::ZRandMember(..) => "zrandmember",
+            Command::HSet(..) => "hset",
+            Command::HGetAll(_) => "hgetall",
+            Command::ZAdd(..) => "zadd",
+            Command::ZRange(..) => "zrange",
+            Command::XAdd { .. } => "xadd",
+            Command::XLen(_) => "xlen",
+            Command::XRange(..) => "xrange",
+            Command::XGroupCreate { .. } => "xgroup|create",
+            Command::XGroupCreateConsumer(..) => "xgroup|createconsumer",
+            Command::XGroupDelConsumer(..) => "xgroup|delconsumer",
+            Command::XGroupDestroy(..) => "xgroup|destroy",
+            Command::XReadGroup { .. } => "xreadgroup",
+            Command::XAck(..) => "xack",
+            Command::XInfoStream(_) => "xinfo|stream",
+            Command::XInfoGroups(_) => "xinfo|groups",
+            Command::XInfoConsumers(..) => "xinfo|consumers",
+            Command::Del(_) => "del",
+            Command::Unlink(_) => "unlink",
+            Command::Touch(_) => "touch",
+            Command::Wait(..) => "wait",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::Dump(_) => "dump",
+            Command::Restore(..) => "restore",
+            Command::Migrate { .. } => "migrate",
+            Command::ClusterInfo => "cluster|info",
+            Command::ClusterMyId => "cluster|myid",
+            Command::ClusterNodes => "cluster|nodes",
+            Command::ClusterSlots => "cluster|slots",
+            Command::ClusterShards => "cluster|shards",
+            Command::ClusterMeet(..) => "cluster|meet",
+            Command::ClusterHello(_) => "cluster|hello",
+            Command::ClusterSetSlot(..) => "cluster|setslot",
+            Command::Asking => "asking",
+            Command::ObjectEncoding(_) => "object|encoding",
+            Command::ObjectIdleTime(_) => "object|idletime",
+            Command::ObjectRefCount(_) => "object|refcount",
+            Command::ObjectFreq(_) => "object|freq",
+            Command::MemoryUsage(..) => "memory|usage",
+            Command::MemoryStats => "memory|stats",
+            Command::MemoryDoctor => "memory|doctor",
+            Command::Failover { .. } => "failover",
+            Command::Shutdown(_) => "shutdown",
+            Command::SlowLogGet(_) => "slowlog|get",
+            Command::SlowLogLen => "slowlog|len",
+            Command::SlowLogReset => "slowlog|reset",
+            Command::LatencyLatest => "latency|latest",
+            Command::LatencyHistory(_) => "latency|history",
+            Command::LatencyReset(_) => "latency|reset",
+            Command::LatencyDoctor => "latency|doctor",
+            Command::Sort { .. } => "sort",
+            Command::DebugSleep(_) => "debug|sleep",
+            Command::DebugObject(_) => "debug|object",
+            Command::DebugSetActiveExpire(_) => "debug|set-active-expire",
+            Command::DebugChangeReplId => "debug|change-repl-id",
+            Command::CommandCount => "command|count",
+            Command::ClientPause(..) => "client|pause",
+            Command::ClientUnpause => "client|unpause",
+            Command::ClientId => "client|id",
+            Command::ClientSetName(_) => "client|setname",
+            Command::ClientGetName => "client|getname",
+            Command::Reset => "reset",
+            Command::CommandList => "command|list",
+            Command::CommandInfo(_) => "command|info",
+            Command::FunctionLoad(..) => "function|load",
+            Command::FunctionDelete(_) => "function|delete",
+            Command::FunctionFlush => "function|flush",
+            Command::FunctionList { .. } => "function|list",
+            Command::FunctionDump => "function|dump",
+            Command::FunctionKill => "function|kill",
+            Command::FunctionRestore(..) => "function|restore",
+            Command::FCall {
+                read_only: false, ..
+            } => "fcall",
+            Command::FCall {
+                read_only: true, ..
+            } => "fcall_ro",
         }
     }
 
-    fn parse_req(data_stream: &mut Peekable<Iter<'_, RedisDataType>>) -> Vec<Command> {
-        let mut commands: Vec<Command> = Vec::new();
-        while let Some(item) = data_stream.next() {
-            match &item {
-                RedisDataType::SimpleString(str) | RedisDataType::BulkString(str) => {
-                    if str == "PING" || str == "ping" {
-                        commands.push(Command::Ping);
-                    } else if str == "ECHO" || str == "echo" {
-                        let message = Self::get_next_string(data_stream).unwrap();
-                        commands.push(Command::Echo(message));
-                    } else if str == "GET" || str == "get" {
-                        let key = Self::get_next_string(data_stream).unwrap();
-                        commands.push(Command::Get(key));
-                    } else if str == "SET" || str == "set" {
-                        let key = Self::get_next_string(data_stream).unwrap();
-                        let value = Self::get_next_string(data_stream).unwrap();
-                        let mut exp: Option<SystemTime> = None;
-                        if let Some(next_str) = Self::peek_next_string(data_stream) {
-                            if next_str == "PX" || next_str == "px" {
-                                let _ = Self::get_next_string(data_stream).unwrap();
-                                let px = Self::get_next_string(data_stream).unwrap();
-                                let duration = px.parse::<u64>().unwrap();
-                                exp = std::time::SystemTime::now()
-                                    .checked_add(std::time::Duration::from_millis(duration as u64));
-                            }
-                        }
-                        commands.push(Command::Set(key, value, exp));
-                    } else if str == "CONFIG" || str == "config" {
-                        let cmd = Self::get_next_string(data_stream).unwrap();
-                        if cmd == "GET" || cmd == "get" {
-                            let key = Self::get_next_string(data_stream).unwrap();
-                            commands.push(Command::ConfigGet(key));
-                        }
-                    } else if str == "KEYS" || str == "keys" {
-                        let pattern = Self::get_next_string(data_stream).unwrap();
-                        commands.push(Command::Keys(pattern));
-                    } else if str == "INFO" || str == "info" {
-                        if let Some(section) = Self::peek_next_string(data_stream) {
-                            if section == "replication" || section == "REPLICATION" {
-                                commands.push(Command::Info(section));
-                            } else {
-                                commands.push(Command::Info("all".to_string()));
-                            }
-                        } else {
-                            commands.push(Command::Info("all".to_string()));
-                        }
-                    } else if str == "REPLCONF" || str == "replconf" {
-                        let key = Self::get_next_string(data_stream).unwrap();
-                        let val = Self::get_next_string(data_stream).unwrap();
-                        commands.push(Command::ReplConf(key, val));
-                    } else if str == "PSYNC" || str == "psync" {
-                        let key = Self::get_next_string(data_stream).unwrap();
-                        let val = Self::get_next_string(data_stream).unwrap();
-                        commands.push(Command::Psync(key, val));
-                    }
-                }
-                RedisDataType::Array(arr) => {
-                    let mut arr_iter = arr.iter().peekable();
-                    let mut arr_resp = Self::parse_req(&mut arr_iter);
-                    commands.append(&mut arr_resp);
-                }
-            }
-        }
-        return commands;
+    /// Whether the command's registry entry has `flag`.
+    pub fn has(&self, flag: Flag) -> bool {
+        redis_registry::lookup(self.name()).is_some_and(|spec| spec.has(flag))
     }
 
-    fn peek_next_string(data_stream: &mut Peekable<Iter<'_, RedisDataType>>) -> Option<String> {
-        if let Some(message) = data_stream.peek() {
-            match message {
-                RedisDataType::SimpleString(msg) => Some(msg.to_string()),
-                RedisDataType::BulkString(msg) => Some(msg.to_string()),
-                RedisDataType::Array(_) => None,
-            }
-        } else {
-            None
+    /// Whether the command modifies the dataset, and so has to be logged to
+    /// the AOF and propagated to replicas.
+    pub fn is_write(&self) -> bool {
+        match self {
+            // Without STORE, SORT only reads.
+            Command::Sort { store: None, .. } => false,
+            // MIGRATE logs the DEL of the keys it moved itself.
+            Command::Migrate { .. } => false,
+            _ => self.has(Flag::Write),
         }
     }
 
-    fn get_next_string(data_stream: &mut Peekable<Iter<'_, RedisDataType>>) -> Option<String> {
-        if let Some(message) = data_stream.next() {
-            match message {
-                RedisDataType::SimpleString(msg) => Some(msg.to_string()),
-                RedisDataType::BulkString(msg) => Some(msg.to_string()),
-                RedisDataType::Array(_) => None,
-            }
-        } else {
-            None
-        }
+    /// Writes that can grow the dataset. They are refused with -OOM when
+    /// maxmemory is reached and nothing can be evicted.
+    pub fn may_grow(&self) -> bool {
+        self.is_write() && self.has(Flag::DenyOom)
     }
-}
 
-#[derive(Debug)]
-enum RedisDataType {
-    SimpleString(String),
-    BulkString(String),
-    Array(Vec<RedisDataType>),
-}
+    /// Commands that may take a while before replying, so replies held back
+    /// for earlier requests are written out first. PSYNC hands the
+    /// connection over to a replica.
+    pub fn may_block(&self) -> bool {
+        matches!(
+            self,
+            Command::Wait(..)
+                | Command::DebugSleep(_)
+                | Command::Failover { .. }
+                | Command::Psync(..)
+        )
+    }
 
-impl RedisDataType {
-    #[allow(dead_code)]
-    fn serialize(&self) -> String {
+    /// Commands that only read keys, and count as an access to them. They may
+    /// still expire a key lazily.
+    pub fn is_read(&self) -> bool {
         match self {
-            RedisDataType::SimpleString(str) => format!("+{}\r\n", str),
-            RedisDataType::BulkString(str) => format!("${}\r\n{}\r\n", str.len(), str),
-            RedisDataType::Array(arr) => {
-                let mut serialized_arr = format!("*{}\r\n", arr.len());
-                for item in arr {
-                    serialized_arr.push_str(&item.serialize());
-                }
-                serialized_arr
+            Command::Sort { store: None, .. } => true,
+            // Looking at how a key is stored doesn't count as using it.
+            Command::ObjectEncoding(_)
+            | Command::ObjectIdleTime(_)
+            | Command::ObjectRefCount(_)
+            | Command::ObjectFreq(_)
+            | Command::MemoryUsage(..) => false,
+            _ => self.has(Flag::ReadOnly),
+        }
+    }
+
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Get(key)
+            | Command::Set(key, ..)
+            | Command::Type(key)
+            | Command::PExpireAt(key, ..)
+            | Command::ExpireTime(key, _)
+            | Command::RPush(key, _)
+            | Command::LRange(key, ..)
+            | Command::SAdd(key, _)
+            | Command::SMembers(key)
+            | Command::SRem(key, _)
+            | Command::SPop(key, _)
+            | Command::SRandMember(key, _)
+            | Command::SMIsMember(key, _)
+            | Command::HRandField(key, ..)
+            | Command::ZRandMember(key, ..)
+            | Command::HSet(key, _)
+            | Command::HGetAll(key)
+            | Command::ZAdd(key, _)
+            | Command::ZRange(key, ..)
+            | Command::XAdd { key, .. }
+            | Command::XLen(key)
+            | Command::XRange(key, ..)
+            | Command::XGroupCreate { key, .. }
+            | Command::XGroupCreateConsumer(key, ..)
+            | Command::XGroupDelConsumer(key, ..)
+            | Command::XGroupDestroy(key, _)
+            | Command::XAck(key, ..)
+            | Command::XInfoStream(key)
+            | Command::XInfoGroups(key)
+            | Command::XInfoConsumers(key, _)
+            | Command::Dump(key)
+            | Command::Restore(key, ..)
+            | Command::ObjectEncoding(key)
+            | Command::ObjectIdleTime(key)
+            | Command::ObjectRefCount(key)
+            | Command::ObjectFreq(key)
+            | Command::MemoryUsage(key, _) => vec![key.as_ref()],
+            Command::Del(keys)
+            | Command::Unlink(keys)
+            | Command::SInterCard(keys, _)
+            | Command::Touch(keys)
+            | Command::Migrate { keys, .. }
+            | Command::FCall { keys, .. } => keys.iter().map(Bytes::as_ref).collect(),
+            Command::XReadGroup { streams, .. } => {
+                streams.iter().map(|(key, _)| key.as_ref()).collect()
+            }
+            Command::Sort { key, store, .. } => {
+                let mut keys = vec![key.as_ref()];
+                keys.extend(store.as_deref());
+                keys
             }
+            _ => vec![],
         }
     }
 
-    fn deserialize(data: &str) -> Self {
-        let mut tokens = data.split("\r\n");
-        Self::parse_req(None, &mut tokens).pop().unwrap()
+    pub fn serialize(&self) -> Vec<u8> {
+        match self.to_value() {
+            Some(value) => value.serialize(),
+            None => Vec::new(),
+        }
     }
 
-    fn parse_req(arr_len: Option<usize>, tokens: &mut Split<'_, &str>) -> Vec<RedisDataType> {
-        let mut redis_data_stream: Vec<RedisDataType> = Vec::new();
-        let mut count = 0;
-        while let Some(token) = tokens.next() {
-            if let Some(first_byte) = token.chars().next() {
-                if first_byte == '+' {
-                    let simple_string = (&token[1..]).to_string();
-                    redis_data_stream.push(RedisDataType::SimpleString(simple_string));
-                } else if first_byte == '*' {
-                    if let Ok(array_len) = token[1..].parse::<usize>() {
-                        let array = Self::parse_req(Some(array_len), tokens);
-                        redis_data_stream.push(RedisDataType::Array(array));
+    /// Builds the RESP array for this command. Returns None for a SET whose
+    /// expiry has already passed, as there is nothing left to send.
+    fn to_value(&self) -> Option<Value> {
+        let value = match self {
+            Command::Echo(echo) => with_key(&["ECHO"], echo, []),
+            Command::Ping => Value::bulk_array(["PING"]),
+            Command::Get(key) => with_key(&["GET"], key, []),
+            Command::Set(key, val, system_time, keep_ttl) => {
+                let mut args = vec![
+                    Value::bulk("SET"),
+                    Value::bulk_bytes(key),
+                    Value::bulk_bytes(val),
+                ];
+                if let Some(exp) = system_time {
+                    let px = match exp.duration_since(SystemTime::now()) {
+                        Ok(durr) => durr.as_millis().to_string(),
+                        Err(_) => return None,
+                    };
+                    args.extend([Value::bulk("px"), Value::bulk(px)]);
+                } else if *keep_ttl {
+                    args.push(Value::bulk("KEEPTTL"));
+                }
+                Value::Array(args)
+            }
+            Command::ConfigGet(patterns) => Value::bulk_array(
+                ["CONFIG".to_string(), "GET".to_string()]
+                    .into_iter()
+                    .chain(patterns.iter().cloned()),
+            ),
+            Command::ConfigSet(params) => Value::bulk_array(
+                ["CONFIG".to_string(), "SET".to_string()].into_iter().chain(
+                    params
+                        .iter()
+                        .flat_map(|(key, val)| [key.clone(), val.clone()]),
+                ),
+            ),
+            Command::ConfigResetStat => Value::bulk_array(["CONFIG", "RESETSTAT"]),
+            Command::Keys(pattern) => with_key(&["KEYS"], pattern, []),
+            Command::Info(sections) => {
+                Value::bulk_array(["INFO".to_string()].into_iter().chain(sections.clone()))
+            }
+            Command::ReplConf(options) => Value::bulk_array(
+                ["REPLCONF".to_string()].into_iter().chain(
+                    options
+                        .iter()
+                        .flat_map(|(key, val)| [key.clone(), val.clone()]),
+                ),
+            ),
+            Command::Psync(repl_id, offset) => Value::bulk_array(["PSYNC", repl_id, offset]),
+            Command::Save => Value::bulk_array(["SAVE"]),
+            Command::BgSave => Value::bulk_array(["BGSAVE"]),
+            Command::BgRewriteAof => Value::bulk_array(["BGREWRITEAOF"]),
+            Command::Type(key) => with_key(&["TYPE"], key, []),
+            Command::PExpireAt(key, exp, conditions) => {
+                let ms = exp
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+                    .to_string();
+                let conditions = conditions.iter().map(|condition| match condition {
+                    ExpireCondition::Nx => "NX",
+                    ExpireCondition::Xx => "XX",
+                    ExpireCondition::Gt => "GT",
+                    ExpireCondition::Lt => "LT",
+                });
+                with_key(
+                    &["PEXPIREAT"],
+                    key,
+                    [ms.as_str()].into_iter().chain(conditions).map(Value::bulk),
+                )
+            }
+            Command::ExpireTime(key, false) => with_key(&["EXPIRETIME"], key, []),
+            Command::ExpireTime(key, true) => with_key(&["PEXPIRETIME"], key, []),
+            Command::RPush(key, items) => {
+                with_key(&["RPUSH"], key, items.iter().map(Value::bulk_bytes))
+            }
+            Command::LRange(key, start, stop) => with_key(
+                &["LRANGE"],
+                key,
+                [
+                    Value::bulk(start.to_string()),
+                    Value::bulk(stop.to_string()),
+                ],
+            ),
+            Command::SAdd(key, members) => {
+                with_key(&["SADD"], key, members.iter().map(Value::bulk_bytes))
+            }
+            Command::SMembers(key) => with_key(&["SMEMBERS"], key, []),
+            Command::SRem(key, members) => {
+                with_key(&["SREM"], key, members.iter().map(Value::bulk_bytes))
+            }
+            Command::SPop(key, count) => with_key(
+                &["SPOP"],
+                key,
+                count.map(|count| Value::bulk(count.to_string())),
+            ),
+            Command::SRandMember(key, count) => with_key(
+                &["SRANDMEMBER"],
+                key,
+                count.map(|count| Value::bulk(count.to_string())),
+            ),
+            Command::SMIsMember(key, members) => {
+                with_key(&["SMISMEMBER"], key, members.iter().map(Value::bulk_bytes))
+            }
+            Command::SInterCard(keys, limit) => {
+                let mut args = vec![
+                    Value::bulk("SINTERCARD"),
+                    Value::bulk(keys.len().to_string()),
+                ];
+                args.extend(keys.iter().map(Value::bulk_bytes));
+                if *limit > 0 {
+                    args.extend([Value::bulk("LIMIT"), Value::bulk(limit.to_string())]);
+                }
+                Value::Array(args)
+            }
+            Command::HRandField(key, count, with_values) => {
+                let mut args = count
+                    .map(|count| count.to_string())
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                if *with_values {
+                    args.push("WITHVALUES".to_string());
+                }
+                with_key(&["HRANDFIELD"], key, args.into_iter().map(Value::bulk))
+            }
+            Command::ZRandMember(key, count, with_scores) => {
+                let mut args = count
+                    .map(|count| count.to_string())
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                if *with_scores {
+                    args.push("WITHSCORES".to_string());
+                }
+                with_key(&["ZRANDMEMBER"], key, args.into_iter().map(Value::bulk))
+            }
+            Command::HSet(key, fields) => with_key(
+                &["HSET"],
+                key,
+                fields
+                    .iter()
+                    .flat_map(|(field, val)| [Value::bulk_bytes(field), Value::bulk_bytes(val)]),
+            ),
+            Command::HGetAll(key) => with_key(&["HGETALL"], key, []),
+            Command::ZAdd(key, members) => with_key(
+                &["ZADD"],
+                key,
+                members.iter().flat_map(|(score, member)| {
+                    [Value::bulk(score.to_string()), Value::bulk_bytes(member)]
+                }),
+            ),
+            Command::Del(keys) => Value::Array(
+                vec![Value::bulk("DEL")]
+                    .into_iter()
+                    .chain(keys.iter().map(Value::bulk_bytes))
+                    .collect(),
+            ),
+            Command::Unlink(keys) => Value::Array(
+                vec![Value::bulk("UNLINK")]
+                    .into_iter()
+                    .chain(keys.iter().map(Value::bulk_bytes))
+                    .collect(),
+            ),
+            Command::Touch(keys) => Value::Array(
+                vec![Value::bulk("TOUCH")]
+                    .into_iter()
+                    .chain(keys.iter().map(Value::bulk_bytes))
+                    .collect(),
+            ),
+            Command::Dump(key) => with_key(&["DUMP"], key, []),
+            Command::ReplicaOf(Some((host, port))) => Value::bulk_array(["REPLICAOF", host, port]),
+            Command::ReplicaOf(None) => Value::bulk_array(["REPLICAOF", "NO", "ONE"]),
+            Command::Wait(numreplicas, timeout) => Value::bulk_array([
+                "WAIT".to_string(),
+                numreplicas.to_string(),
+                timeout.to_string(),
+            ]),
+            Command::Restore(key, ttl, payload, replace, absttl) => {
+                let mut args = vec![
+                    Value::bulk("RESTORE"),
+                    Value::bulk_bytes(key),
+                    Value::BulkString(ttl.to_string()),
+                    Value::Bytes(payload.clone()),
+                ];
+                if *replace {
+                    args.push(Value::bulk("REPLACE"));
+                }
+                if *absttl {
+                    args.push(Value::bulk("ABSTTL"));
+                }
+                Value::Array(args)
+            }
+            Command::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout,
+                copy,
+                replace,
+            } => {
+                let mut args = vec![
+                    "MIGRATE".to_string(),
+                    host.clone(),
+                    port.clone(),
+                    String::new(),
+                    db.to_string(),
+                    timeout.to_string(),
+                ];
+                if *copy {
+                    args.push("COPY".to_string());
+                }
+                if *replace {
+                    args.push("REPLACE".to_string());
+                }
+                args.push("KEYS".to_string());
+                let mut args = args.into_iter().map(Value::bulk).collect::<Vec<_>>();
+                args.extend(keys.iter().map(Value::bulk_bytes));
+                Value::Array(args)
+            }
+            Command::ClusterInfo => Value::bulk_array(["CLUSTER", "INFO"]),
+            Command::ClusterMyId => Value::bulk_array(["CLUSTER", "MYID"]),
+            Command::ClusterNodes => Value::bulk_array(["CLUSTER", "NODES"]),
+            Command::ClusterSlots => Value::bulk_array(["CLUSTER", "SLOTS"]),
+            Command::ClusterShards => Value::bulk_array(["CLUSTER", "SHARDS"]),
+            Command::ClusterMeet(host, port) => Value::bulk_array(["CLUSTER", "MEET", host, port]),
+            Command::ClusterHello(description) => {
+                Value::bulk_array(["CLUSTER", "HELLO", description])
+            }
+            Command::ClusterSetSlot(slot, action) => {
+                let slot = slot.to_string();
+                let slot = slot.as_str();
+                match action {
+                    SetSlot::Migrating(id) => {
+                        Value::bulk_array(["CLUSTER", "SETSLOT", slot, "MIGRATING", id.as_str()])
                     }
-                } else if first_byte == '$' {
-                    if let Ok(bulk_str_len) = token[1..].parse::<usize>() {
-                        if let Some(bulk_str) = tokens.next() {
-                            let bulk_string = bulk_str.to_string();
-                            assert_eq!(bulk_string.len(), bulk_str_len);
-                            redis_data_stream.push(RedisDataType::BulkString(bulk_string));
-                        }
+                    SetSlot::Importing(id) => {
+                        Value::bulk_array(["CLUSTER", "SETSLOT", slot, "IMPORTING", id.as_str()])
                     }
+                    SetSlot::Node(id) => {
+                        Value::bulk_array(["CLUSTER", "SETSLOT", slot, "NODE", id.as_str()])
+                    }
+                    SetSlot::Stable => Value::bulk_array(["CLUSTER", "SETSLOT", slot, "STABLE"]),
                 }
             }
-            count += 1;
-            if let Some(n) = arr_len {
-                if count == n {
-                    return redis_data_stream;
+            Command::Asking => Value::bulk_array(["ASKING"]),
+            Command::ObjectEncoding(key) => with_key(&["OBJECT", "ENCODING"], key, []),
+            Command::ObjectIdleTime(key) => with_key(&["OBJECT", "IDLETIME"], key, []),
+            Command::ObjectRefCount(key) => with_key(&["OBJECT", "REFCOUNT"], key, []),
+            Command::ObjectFreq(key) => with_key(&["OBJECT", "FREQ"], key, []),
+            Command::MemoryUsage(key, samples) => with_key(
+                &["MEMORY", "USAGE"],
+                key,
+                [Value::bulk("SAMPLES"), Value::bulk(samples.to_string())],
+            ),
+            Command::MemoryStats => Value::bulk_array(["MEMORY", "STATS"]),
+            Command::MemoryDoctor => Value::bulk_array(["MEMORY", "DOCTOR"]),
+            Command::Failover { to, timeout, abort } => {
+                let mut args = vec!["FAILOVER".to_string()];
+                if let Some((host, port)) = to {
+                    args.extend(["TO".to_string(), host.clone(), port.clone()]);
+                }
+                if *timeout != 0 {
+                    args.extend(["TIMEOUT".to_string(), timeout.to_string()]);
                 }
+                if *abort {
+                    args.push("ABORT".to_string());
+                }
+                Value::bulk_array(args)
             }
-        }
-        redis_data_stream
+            Command::Shutdown(save) => match save {
+                Some(true) => Value::bulk_array(["SHUTDOWN", "SAVE"]),
+                Some(false) => Value::bulk_array(["SHUTDOWN", "NOSAVE"]),
+                None => Value::bulk_array(["SHUTDOWN"]),
+            },
+            Command::SlowLogGet(count) => match count {
+                Some(count) => Value::bulk_array(["SLOWLOG", "GET", &count.to_string()]),
+                None => Value::bulk_array(["SLOWLOG", "GET", "-1"]),
+            },
+            Command::SlowLogLen => Value::bulk_array(["SLOWLOG", "LEN"]),
+            Command::SlowLogReset => Value::bulk_array(["SLOWLOG", "RESET"]),
+            Command::LatencyLatest => Value::bulk_array(["LATENCY", "LATEST"]),
+            Command::LatencyHistory(event) => Value::bulk_array(["LATENCY", "HISTORY", event]),
+            Command::LatencyReset(events) => Value::bulk_array(
+                ["LATENCY", "RESET"]
+                    .into_iter()
+                    .chain(events.iter().map(String::as_str)),
+            ),
+            Command::LatencyDoctor => Value::bulk_array(["LATENCY", "DOCTOR"]),
+            Command::DebugSleep(seconds) => {
+                Value::bulk_array(["DEBUG", "SLEEP", &seconds.to_string()])
+            }
+            Command::DebugObject(key) => with_key(&["DEBUG", "OBJECT"], key, []),
+            Command::Sort {
+                key,
+                by,
+                limit,
+                get,
+                desc,
+                alpha,
+                store,
+            } => {
+                let mut args = vec![Value::bulk("SORT"), Value::bulk_bytes(key)];
+                if let Some(by) = by {
+                    args.extend([Value::bulk("BY"), Value::bulk_bytes(by)]);
+                }
+                if let Some((offset, count)) = limit {
+                    args.extend(
+                        ["LIMIT".to_string(), offset.to_string(), count.to_string()]
+                            .map(Value::bulk),
+                    );
+                }
+                for pattern in get {
+                    args.extend([Value::bulk("GET"), Value::bulk_bytes(pattern)]);
+                }
+                if *desc {
+                    args.push(Value::bulk("DESC"));
+                }
+                if *alpha {
+                    args.push(Value::bulk("ALPHA"));
+                }
+                if let Some(store) = store {
+                    args.extend([Value::bulk("STORE"), Value::bulk_bytes(store)]);
+                }
+                Value::Array(args)
+            }
+            Command::DebugSetActiveExpire(enabled) => match enabled {
+                true => Value::bulk_array(["DEBUG", "SET-ACTIVE-EXPIRE", "1"]),
+                false => Value::bulk_array(["DEBUG", "SET-ACTIVE-EXPIRE", "0"]),
+            },
+            Command::DebugChangeReplId => Value::bulk_array(["DEBUG", "CHANGE-REPL-ID"]),
+            Command::CommandCount => Value::bulk_array(["COMMAND", "COUNT"]),
+            Command::ClientPause(timeout, write_only) => Value::bulk_array([
+                "CLIENT",
+                "PAUSE",
+                &timeout.to_string(),
+                if *write_only { "WRITE" } else { "ALL" },
+            ]),
+            Command::ClientUnpause => Value::bulk_array(["CLIENT", "UNPAUSE"]),
+            Command::ClientId => Value::bulk_array(["CLIENT", "ID"]),
+            Command::ClientSetName(name) => Value::bulk_array(["CLIENT", "SETNAME", name]),
+            Command::ClientGetName => Value::bulk_array(["CLIENT", "GETNAME"]),
+            Command::Reset => Value::bulk_array(["RESET"]),
+            Command::CommandList => Value::bulk_array(["COMMAND", "LIST"]),
+            Command::CommandInfo(names) => Value::bulk_array(
+                ["COMMAND".to_string(), "INFO".to_string()]
+                    .into_iter()
+                    .chain(names.iter().cloned()),
+            ),
+            Command::FunctionLoad(code, replace) => match replace {
+                true => with_key(&["FUNCTION", "LOAD", "REPLACE"], code, []),
+                false => with_key(&["FUNCTION", "LOAD"], code, []),
+            },
+            Command::FunctionDelete(name) => Value::bulk_array(["FUNCTION", "DELETE", name]),
+            Command::FunctionFlush => Value::bulk_array(["FUNCTION", "FLUSH"]),
+            Command::FunctionList { pattern, with_code } => {
+                let mut args = vec!["FUNCTION".to_string(), "LIST".to_string()];
+                if let Some(pattern) = pattern {
+                    args.extend(["LIBRARYNAME".to_string(), pattern.clone()]);
+                }
+                if *with_code {
+                    args.push("WITHCODE".to_string());
+                }
+                Value::bulk_array(args)
+            }
+            Command::FunctionDump => Value::bulk_array(["FUNCTION", "DUMP"]),
+            Command::FunctionKill => Value::bulk_array(["FUNCTION", "KILL"]),
+            Command::FunctionRestore(payload, policy) => Value::Array(vec![
+                Value::bulk("FUNCTION"),
+                Value::bulk("RESTORE"),
+                Value::Bytes(payload.clone()),
+                Value::bulk(match policy {
+                    RestorePolicy::Append => "APPEND",
+                    RestorePolicy::Replace => "REPLACE",
+                    RestorePolicy::Flush => "FLUSH",
+                }),
+            ]),
+            Command::FCall {
+                function,
+                keys,
+                args,
+                read_only,
+            } => {
+                let name = if *read_only { "FCALL_RO" } else { "FCALL" };
+                let mut all = vec![
+                    Value::bulk(name),
+                    Value::bulk(function),
+                    Value::bulk(keys.len().to_string()),
+                ];
+                all.extend(keys.iter().chain(args).map(Value::bulk_bytes));
+                Value::Array(all)
+            }
+            Command::ZRange(key, start, stop, with_scores) => {
+                let mut args = vec![start.to_string(), stop.to_string()];
+                if *with_scores {
+                    args.push("WITHSCORES".to_string());
+                }
+                with_key(&["ZRANGE"], key, args.into_iter().map(Value::bulk))
+            }
+            Command::XAdd {
+                key,
+                id,
+                fields,
+                no_mkstream,
+                trim,
+            } => {
+                let mut args = Vec::new();
+                if *no_mkstream {
+                    args.push(Value::bulk("NOMKSTREAM"));
+                }
+                match trim {
+                    Some(Trim::MaxLen(max_len)) => {
+                        args.extend(["MAXLEN", "="].map(Value::bulk));
+                        args.push(Value::bulk(max_len.to_string()));
+                    }
+                    Some(Trim::MinId(min_id)) => {
+                        args.extend(["MINID", "="].map(Value::bulk));
+                        args.push(Value::bulk(min_id.to_string()));
+                    }
+                    None => {}
+                }
+                args.push(Value::bulk(match id {
+                    NewId::Auto => "*".to_string(),
+                    NewId::AutoSeq(ms) => format!("{}-*", ms),
+                    NewId::Explicit(id) => id.to_string(),
+                }));
+                args.extend(
+                    fields.iter().flat_map(|(field, val)| {
+                        [Value::bulk_bytes(field), Value::bulk_bytes(val)]
+                    }),
+                );
+                with_key(&["XADD"], key, args)
+            }
+            Command::XLen(key) => with_key(&["XLEN"], key, []),
+            Command::XRange(key, range, count) => {
+                let (start, end) = range.unwrap_or((StreamId::MAX, StreamId::MIN));
+                let mut args = vec![start.to_string(), end.to_string()];
+                if let Some(count) = count {
+                    args.extend(["COUNT".to_string(), count.to_string()]);
+                }
+                with_key(&["XRANGE"], key, args.into_iter().map(Value::bulk))
+            }
+            Command::XGroupCreate {
+                key,
+                group,
+                start,
+                mkstream,
+                entries_read,
+            } => {
+                let mut args = vec![
+                    Value::bulk_bytes(group),
+                    Value::bulk(start.map_or("$".to_string(), |id| id.to_string())),
+                ];
+                if *mkstream {
+                    args.push(Value::bulk("MKSTREAM"));
+                }
+                if let Some(entries_read) = entries_read {
+                    args.push(Value::bulk("ENTRIESREAD"));
+                    args.push(Value::bulk(entries_read.to_string()));
+                }
+                with_key(&["XGROUP", "CREATE"], key, args)
+            }
+            Command::XGroupCreateConsumer(key, group, consumer) => with_key(
+                &["XGROUP", "CREATECONSUMER"],
+                key,
+                [Value::bulk_bytes(group), Value::bulk_bytes(consumer)],
+            ),
+            Command::XGroupDelConsumer(key, group, consumer) => with_key(
+                &["XGROUP", "DELCONSUMER"],
+                key,
+                [Value::bulk_bytes(group), Value::bulk_bytes(consumer)],
+            ),
+            Command::XGroupDestroy(key, group) => {
+                with_key(&["XGROUP", "DESTROY"], key, [Value::bulk_bytes(group)])
+            }
+            Command::XReadGroup {
+                group,
+                consumer,
+                count,
+                no_ack,
+                streams,
+            } => {
+                let mut args = vec![
+                    Value::bulk("XREADGROUP"),
+                    Value::bulk("GROUP"),
+                    Value::bulk_bytes(group),
+                    Value::bulk_bytes(consumer),
+                ];
+                if let Some(count) = count {
+                    args.extend([Value::bulk("COUNT"), Value::bulk(count.to_string())]);
+                }
+                if *no_ack {
+                    args.push(Value::bulk("NOACK"));
+                }
+                args.push(Value::bulk("STREAMS"));
+                args.extend(streams.iter().map(|(key, _)| Value::bulk_bytes(key)));
+                args.extend(
+                    streams.iter().map(|(_, id)| {
+                        Value::bulk(id.map_or(">".to_string(), |id| id.to_string()))
+                    }),
+                );
+                Value::Array(args)
+            }
+            Command::XAck(key, group, ids) => with_key(
+                &["XACK"],
+                key,
+                std::iter::once(Value::bulk_bytes(group))
+                    .chain(ids.iter().map(|id| Value::bulk(id.to_string()))),
+            ),
+            Command::XInfoStream(key) => with_key(&["XINFO", "STREAM"], key, []),
+            Command::XInfoGroups(key) => with_key(&["XINFO", "GROUPS"], key, []),
+            Command::XInfoConsumers(key, group) => {
+                with_key(&["XINFO", "CONSUMERS"], key, [Value::bulk_bytes(group)])
+            }
+        };
+        Some(value)
     }
 }
+
+/// The RESP array for a command given by `words`, such as OBJECT ENCODING,
+/// followed by a key and then `rest`.
+fn with_key(words: &[&str], key: &[u8], rest: impl IntoIterator<Item = Value>) -> Value {
+    let mut args = words
+        .iter()
+        .map(|word| Value::bulk(*word))
+        .collect::<Vec<_>>();
+    args.push(Value::bulk_bytes(key));
+    args.extend(rest);
+    Value::Array(args)
+}
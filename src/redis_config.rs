@@ -0,0 +1,372 @@
+use crate::redis_evict::parse_memory;
+use crate::redis_value::glob_match;
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+/// The kind of value a config parameter holds, which decides how CONFIG SET
+/// validates and normalizes it.
+pub enum ConfigType {
+    /// "yes" or "no".
+    Bool,
+    /// A non-negative integer.
+    Int,
+    /// An integer that may be negative.
+    SignedInt,
+    /// An amount of memory such as "100mb", stored in bytes.
+    Memory,
+    /// One of a fixed set of values.
+    Enum(&'static [&'static str]),
+    /// `save` rules, see `parse_save_rules`.
+    Save,
+    /// Output buffer limits per client class, see
+    /// `parse_output_buffer_limits`.
+    OutputBufferLimits,
+    /// An existing directory.
+    Dir,
+    String,
+}
+
+pub struct ConfigParam {
+    pub name: &'static str,
+    pub kind: ConfigType,
+    /// Whether CONFIG SET may change the parameter while the server runs.
+    pub mutable: bool,
+    /// The value used unless it is given on the command line.
+    pub default: &'static str,
+}
+
+const fn param(
+    name: &'static str,
+    kind: ConfigType,
+    mutable: bool,
+    default: &'static str,
+) -> ConfigParam {
+    ConfigParam {
+        name,
+        kind,
+        mutable,
+        default,
+    }
+}
+
+/// Every parameter the server knows about.
+pub const PARAMS: &[ConfigParam] = &[
+    param("port", ConfigType::Int, false, "6379"),
+    param("bind", ConfigType::String, false, "127.0.0.1 -::1"),
+    param("protected-mode", ConfigType::Bool, true, "yes"),
+    param("maxclients", ConfigType::Int, true, "10000"),
+    param("timeout", ConfigType::Int, true, "0"),
+    param("tcp-keepalive", ConfigType::Int, true, "300"),
+    param("databases", ConfigType::Int, false, "16"),
+    param("daemonize", ConfigType::Bool, false, "no"),
+    param(
+        "loglevel",
+        ConfigType::Enum(&["debug", "verbose", "notice", "warning", "nothing"]),
+        true,
+        "notice",
+    ),
+    param("logfile", ConfigType::String, false, ""),
+    param("dir", ConfigType::Dir, true, "."),
+    param("dbfilename", ConfigType::String, true, "dump.rdb"),
+    param("save", ConfigType::Save, true, "3600 1 300 100 60 10000"),
+    param("rdbchecksum", ConfigType::Bool, false, "yes"),
+    param("appendonly", ConfigType::Bool, true, "no"),
+    param(
+        "appendfsync",
+        ConfigType::Enum(&["always", "everysec", "no"]),
+        true,
+        "everysec",
+    ),
+    param(
+        "appendfilename",
+        ConfigType::String,
+        false,
+        "appendonly.aof",
+    ),
+    param("aof-use-rdb-preamble", ConfigType::Bool, true, "yes"),
+    param("repl-diskless-sync", ConfigType::Bool, true, "yes"),
+    param("repl-diskless-sync-delay", ConfigType::Int, true, "0"),
+    param("repl-ping-replica-period", ConfigType::Int, true, "10"),
+    param("repl-timeout", ConfigType::Int, true, "60"),
+    param("replica-read-only", ConfigType::Bool, true, "yes"),
+    param("replica-announce-ip", ConfigType::String, true, ""),
+    param("replica-announce-port", ConfigType::Int, true, "0"),
+    param("cluster-announce-ip", ConfigType::String, false, ""),
+    param(
+        "cluster-config-file",
+        ConfigType::String,
+        false,
+        "nodes.conf",
+    ),
+    param("maxmemory", ConfigType::Memory, true, "0"),
+    param(
+        "maxmemory-policy",
+        ConfigType::Enum(&[
+            "noeviction",
+            "allkeys-lru",
+            "volatile-lru",
+            "allkeys-lfu",
+            "volatile-lfu",
+            "allkeys-random",
+            "volatile-random",
+            "volatile-ttl",
+        ]),
+        true,
+        "noeviction",
+    ),
+    param("lfu-log-factor", ConfigType::Int, true, "10"),
+    param("lfu-decay-time", ConfigType::Int, true, "1"),
+    param("cluster-enabled", ConfigType::Bool, false, "no"),
+    param(
+        "slowlog-log-slower-than",
+        ConfigType::SignedInt,
+        true,
+        "10000",
+    ),
+    param("slowlog-max-len", ConfigType::Int, true, "128"),
+    param(
+        "client-output-buffer-limit",
+        ConfigType::OutputBufferLimits,
+        true,
+        "normal 0 0 0 slave 268435456 67108864 60 pubsub 33554432 8388608 60",
+    ),
+    param("latency-monitor-threshold", ConfigType::Int, true, "0"),
+    param("metrics-port", ConfigType::Int, false, "0"),
+    param(
+        "io-backend",
+        ConfigType::Enum(&["tokio", "io-uring"]),
+        false,
+        "tokio",
+    ),
+    param("tls-port", ConfigType::Int, false, "0"),
+    param("tls-cert-file", ConfigType::String, false, ""),
+    param("tls-key-file", ConfigType::String, false, ""),
+    param("tls-ca-cert-file", ConfigType::String, false, ""),
+    param(
+        "tls-auth-clients",
+        ConfigType::Enum(&["yes", "no", "optional"]),
+        false,
+        "yes",
+    ),
+    param("tls-replication", ConfigType::Bool, false, "no"),
+];
+
+/// The parameters whose names match any of the glob `patterns`.
+pub fn matching(patterns: &[String]) -> impl Iterator<Item = &'static ConfigParam> + '_ {
+    PARAMS.iter().filter(|param| {
+        patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, param.name, true))
+    })
+}
+
+pub fn lookup(name: &str) -> Option<&'static ConfigParam> {
+    PARAMS
+        .iter()
+        .find(|param| param.name.eq_ignore_ascii_case(name))
+}
+
+impl ConfigParam {
+    /// Checks a value given to CONFIG SET and returns it the way it is
+    /// stored, e.g. memory amounts in bytes.
+    pub fn validate(&self, value: &str) -> Result<String> {
+        if !self.mutable {
+            bail!("can't set immutable config");
+        }
+        match &self.kind {
+            ConfigType::Bool => match value.to_lowercase().as_str() {
+                val @ ("yes" | "no") => Ok(val.to_string()),
+                _ => bail!("argument must be 'yes' or 'no'"),
+            },
+            ConfigType::Int => match value.parse::<u64>() {
+                Ok(num) => Ok(num.to_string()),
+                Err(_) => bail!("argument couldn't be parsed into an integer"),
+            },
+            ConfigType::SignedInt => match value.parse::<i64>() {
+                Ok(num) => Ok(num.to_string()),
+                Err(_) => bail!("argument couldn't be parsed into an integer"),
+            },
+            ConfigType::Memory => match parse_memory(value) {
+                Some(bytes) => Ok(bytes.to_string()),
+                None => bail!("argument must be a memory value"),
+            },
+            ConfigType::Enum(values) => {
+                let value = value.to_lowercase();
+                match values.contains(&value.as_str()) {
+                    true => Ok(value),
+                    false => bail!(
+                        "argument(s) must be one of the following: {}",
+                        values.join(", ")
+                    ),
+                }
+            }
+            ConfigType::Save => match parse_save_rules(value) {
+                Some(_) => Ok(value.to_string()),
+                None => bail!("Invalid save parameters"),
+            },
+            ConfigType::OutputBufferLimits => match parse_output_buffer_limits(value) {
+                Some(limits) => Ok(format_output_buffer_limits(&limits)),
+                None => bail!("Wrong format"),
+            },
+            ConfigType::Dir => match std::path::Path::new(value).is_dir() {
+                true => Ok(value.to_string()),
+                false => bail!("No such file or directory"),
+            },
+            ConfigType::String => Ok(value.to_string()),
+        }
+    }
+}
+
+/// Parses a `save` config value such as "3600 1 300 100" into
+/// (seconds, changes) pairs. An empty value disables snapshotting.
+pub fn parse_save_rules(rules: &str) -> Option<Vec<(u64, u64)>> {
+    let nums = rules
+        .split_whitespace()
+        .map(|num| num.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if nums.len() % 2 != 0 {
+        return None;
+    }
+    Some(nums.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// A class of clients sharing output buffer limits.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClientClass {
+    Normal,
+    Replica,
+    Pubsub,
+}
+
+impl ClientClass {
+    const ALL: [ClientClass; 3] = [
+        ClientClass::Normal,
+        ClientClass::Replica,
+        ClientClass::Pubsub,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ClientClass::Normal => "normal",
+            ClientClass::Replica => "slave",
+            ClientClass::Pubsub => "pubsub",
+        }
+    }
+}
+
+/// How much output may pile up for a client before it's disconnected: any
+/// more than `hard` bytes, or more than `soft` bytes for longer than
+/// `soft_seconds`. A limit of 0 is no limit.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Whether `len` bytes of pending output break the limit.
+    /// `over_soft_since` keeps track of when the output went over the soft
+    /// limit, and is cleared once it's back under.
+    pub fn is_exceeded(&self, len: usize, over_soft_since: &mut Option<Instant>) -> bool {
+        let len = len as u64;
+        if self.hard > 0 && len > self.hard {
+            return true;
+        }
+        if self.soft == 0 || len <= self.soft {
+            *over_soft_since = None;
+            return false;
+        }
+        over_soft_since.get_or_insert_with(Instant::now).elapsed()
+            > Duration::from_secs(self.soft_seconds)
+    }
+}
+
+/// Parses a `client-output-buffer-limit` value such as
+/// "normal 0 0 0 replica 256mb 64mb 60", a class followed by its hard and
+/// soft limits and the soft limit's seconds, for any number of classes.
+fn parse_output_buffer_limits(value: &str) -> Option<Vec<(ClientClass, OutputBufferLimit)>> {
+    let words = value.split_whitespace().collect::<Vec<_>>();
+    if words.is_empty() || words.len() % 4 != 0 {
+        return None;
+    }
+    words
+        .chunks(4)
+        .map(|chunk| {
+            let class = match chunk[0].to_lowercase().as_str() {
+                "normal" => ClientClass::Normal,
+                "slave" | "replica" => ClientClass::Replica,
+                "pubsub" => ClientClass::Pubsub,
+                _ => return None,
+            };
+            let limit = OutputBufferLimit {
+                hard: parse_memory(chunk[1])?,
+                soft: parse_memory(chunk[2])?,
+                soft_seconds: chunk[3].parse().ok()?,
+            };
+            Some((class, limit))
+        })
+        .collect()
+}
+
+/// The output buffer limits of every client class.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OutputBufferLimits {
+    normal: OutputBufferLimit,
+    replica: OutputBufferLimit,
+    pubsub: OutputBufferLimit,
+}
+
+impl OutputBufferLimits {
+    /// The limits in a `client-output-buffer-limit` value. The last mention
+    /// of a class wins, and classes left out have their default limits.
+    pub fn from_config(value: &str) -> Self {
+        let mut limits = OutputBufferLimits::default();
+        let default = lookup("client-output-buffer-limit").map_or("", |param| param.default);
+        for value in [default, value] {
+            for (class, limit) in parse_output_buffer_limits(value).unwrap_or_default() {
+                *limits.get_mut(class) = limit;
+            }
+        }
+        limits
+    }
+
+    pub fn get(&self, class: ClientClass) -> OutputBufferLimit {
+        match class {
+            ClientClass::Normal => self.normal,
+            ClientClass::Replica => self.replica,
+            ClientClass::Pubsub => self.pubsub,
+        }
+    }
+
+    fn get_mut(&mut self, class: ClientClass) -> &mut OutputBufferLimit {
+        match class {
+            ClientClass::Normal => &mut self.normal,
+            ClientClass::Replica => &mut self.replica,
+            ClientClass::Pubsub => &mut self.pubsub,
+        }
+    }
+}
+
+impl std::fmt::Display for OutputBufferLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limits = ClientClass::ALL.map(|class| (class, self.get(class)));
+        write!(f, "{}", format_output_buffer_limits(&limits))
+    }
+}
+
+fn format_output_buffer_limits(limits: &[(ClientClass, OutputBufferLimit)]) -> String {
+    limits
+        .iter()
+        .map(|(class, limit)| {
+            format!(
+                "{} {} {} {}",
+                class.name(),
+                limit.hard,
+                limit.soft,
+                limit.soft_seconds
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,7 +1,11 @@
+use crate::redis_stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use crate::redis_value::{as_int, RedisValue};
 use anyhow::{bail, Context, Result};
-use std::collections::HashMap;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime};
 
 enum RDBOpCodes {
@@ -11,6 +15,8 @@ enum RDBOpCodes {
     ExpireTimeMs,
     ResizeDB,
     Aux,
+    /// A function library, as its code.
+    Function2,
 }
 
 impl RDBOpCodes {
@@ -22,11 +28,11 @@ impl RDBOpCodes {
             0xFC => Ok(RDBOpCodes::ExpireTimeMs),
             0xFB => Ok(RDBOpCodes::ResizeDB),
             0xFA => Ok(RDBOpCodes::Aux),
+            0xF5 => Ok(RDBOpCodes::Function2),
             _ => bail!("Invalid RDB opcode {}", value),
         }
     }
 
-    #[allow(dead_code)]
     fn to_u8(&self) -> u8 {
         match self {
             RDBOpCodes::Eof => 0xFF,
@@ -35,6 +41,7 @@ impl RDBOpCodes {
             RDBOpCodes::ExpireTimeMs => 0xFC,
             RDBOpCodes::ResizeDB => 0xFB,
             RDBOpCodes::Aux => 0xFA,
+            RDBOpCodes::Function2 => 0xF5,
         }
     }
 }
@@ -42,8 +49,10 @@ impl RDBOpCodes {
 enum RDBLenEncodings {
     SixBit(u64),
     FourteenBit(u64),
+    ThirtyTwoBit(u64),
     SixtyFourBit(u64),
-    SpecialEncoding(u32),
+    SpecialEncoding(i32),
+    Lzf,
 }
 
 impl RDBLenEncodings {
@@ -58,127 +67,797 @@ impl RDBLenEncodings {
                 let value = ((first_6_bits as u16) << 8) | next_byte as u16;
                 Ok(RDBLenEncodings::FourteenBit(value as u64))
             }
+            // 0x80 is followed by a 32 bit length and 0x81 by a 64 bit one,
+            // both big endian.
             128 => {
+                let width = match first_byte {
+                    0x80 => 4,
+                    0x81 => 8,
+                    _ => bail!("Invalid RDB length encoding {}", first_byte),
+                };
                 let mut val: u64 = 0;
-                for _ in 0..4 {
+                for _ in 0..width {
                     let next_byte = bites.next().context("Iter reached end")?;
                     val = (val << 8) | next_byte as u64;
                 }
-                Ok(RDBLenEncodings::SixtyFourBit(val))
+                match width {
+                    4 => Ok(RDBLenEncodings::ThirtyTwoBit(val)),
+                    _ => Ok(RDBLenEncodings::SixtyFourBit(val)),
+                }
             }
             192 => {
                 let last_6_bits = first_byte & 63;
-                if last_6_bits == 0 {
-                    let next_byte = bites.next().context("Iter reached end")?;
-                    return Ok(RDBLenEncodings::SpecialEncoding(next_byte as u32));
-                } else if last_6_bits < 3 {
-                    let mut val: u32 = 0;
-                    for _ in 0..last_6_bits {
+                match last_6_bits {
+                    0 => {
                         let next_byte = bites.next().context("Iter reached end")?;
-                        val = (val << 8) | next_byte as u32;
+                        Ok(RDBLenEncodings::SpecialEncoding(next_byte as i8 as i32))
+                    }
+                    1 => {
+                        let arr = bites.take(2).collect::<Vec<u8>>();
+                        let arr = arr.try_into().ok().context("Iter reached end")?;
+                        Ok(RDBLenEncodings::SpecialEncoding(
+                            i16::from_le_bytes(arr) as i32
+                        ))
+                    }
+                    2 => {
+                        let arr = bites.take(4).collect::<Vec<u8>>();
+                        let arr = arr.try_into().ok().context("Iter reached end")?;
+                        Ok(RDBLenEncodings::SpecialEncoding(i32::from_le_bytes(arr)))
                     }
-                    return Ok(RDBLenEncodings::SpecialEncoding(val));
+                    3 => Ok(RDBLenEncodings::Lzf),
+                    _ => bail!("Special encoding: {}", last_6_bits),
                 }
-
-                bail!("Special encoding: {}", last_6_bits);
             }
             _ => bail!("Invalid RDB length encoding"),
         }
     }
+}
+
+impl RDBLenEncodings {
+    fn encode(len: usize, out: &mut Vec<u8>) {
+        if len < 64 {
+            out.push(len as u8);
+        } else if len < 16384 {
+            out.push(64 | (len >> 8) as u8);
+            out.push(len as u8);
+        } else if let Ok(len) = u32::try_from(len) {
+            out.push(0x80);
+            out.extend_from_slice(&len.to_be_bytes());
+        } else {
+            out.push(0x81);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+}
 
-    #[allow(dead_code)]
-    fn to_string(&self) -> String {
+impl std::fmt::Display for RDBLenEncodings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RDBLenEncodings::SixBit(num) => num.to_string(),
-            RDBLenEncodings::FourteenBit(num) => num.to_string(),
-            RDBLenEncodings::SixtyFourBit(num) => num.to_string(),
-            RDBLenEncodings::SpecialEncoding(num) => num.to_string(),
+            RDBLenEncodings::SixBit(num) => write!(f, "{}", num),
+            RDBLenEncodings::FourteenBit(num) => write!(f, "{}", num),
+            RDBLenEncodings::ThirtyTwoBit(num) => write!(f, "{}", num),
+            RDBLenEncodings::SixtyFourBit(num) => write!(f, "{}", num),
+            RDBLenEncodings::SpecialEncoding(num) => write!(f, "{}", num),
+            RDBLenEncodings::Lzf => write!(f, "LZF"),
         }
     }
 }
 
 enum RDBValueEncodings {
     String,
-    // List,
-    // Set,
-    // SortedSet,
-    // Hash,
-    // ZipMap,
-    // ZipList,
-    // IntSet,
-    // SortedSetZipList,
-    // HashMapZipList,
-    // ListQuickList,
+    List,
+    Set,
+    SortedSet,
+    Hash,
+    SortedSet2,
+    HashZipMap,
+    ListZipList,
+    SetIntSet,
+    SortedSetZipList,
+    HashZipList,
+    ListQuickList,
+    HashListPack,
+    SortedSetListPack,
+    ListQuickList2,
+    SetListPack,
+    /// Streams as listpacks of entries, keyed by the ID of their first one.
+    StreamListPacks,
+    /// Adds the first ID, the greatest deleted ID and the number of entries
+    /// ever added.
+    StreamListPacks2,
+    /// Adds when consumers were last active.
+    StreamListPacks3,
 }
 
 impl RDBValueEncodings {
     fn from_u8(value: &u8) -> Result<RDBValueEncodings> {
         match value {
             0 => Ok(RDBValueEncodings::String),
+            1 => Ok(RDBValueEncodings::List),
+            2 => Ok(RDBValueEncodings::Set),
+            3 => Ok(RDBValueEncodings::SortedSet),
+            4 => Ok(RDBValueEncodings::Hash),
+            5 => Ok(RDBValueEncodings::SortedSet2),
+            9 => Ok(RDBValueEncodings::HashZipMap),
+            10 => Ok(RDBValueEncodings::ListZipList),
+            11 => Ok(RDBValueEncodings::SetIntSet),
+            12 => Ok(RDBValueEncodings::SortedSetZipList),
+            13 => Ok(RDBValueEncodings::HashZipList),
+            14 => Ok(RDBValueEncodings::ListQuickList),
+            16 => Ok(RDBValueEncodings::HashListPack),
+            17 => Ok(RDBValueEncodings::SortedSetListPack),
+            18 => Ok(RDBValueEncodings::ListQuickList2),
+            20 => Ok(RDBValueEncodings::SetListPack),
+            15 => Ok(RDBValueEncodings::StreamListPacks),
+            19 => Ok(RDBValueEncodings::StreamListPacks2),
+            21 => Ok(RDBValueEncodings::StreamListPacks3),
             e => bail!("Invalid RDB value encoding {}", e),
         }
     }
-}
 
-enum StringEncoding {
-    Int32(u32),
-    LenPrefixed(LenPrefixedString),
-    #[allow(dead_code)]
-    LZF,
+    fn to_u8(&self) -> u8 {
+        match self {
+            RDBValueEncodings::String => 0,
+            RDBValueEncodings::List => 1,
+            RDBValueEncodings::Set => 2,
+            RDBValueEncodings::SortedSet => 3,
+            RDBValueEncodings::Hash => 4,
+            RDBValueEncodings::SortedSet2 => 5,
+            RDBValueEncodings::HashZipMap => 9,
+            RDBValueEncodings::ListZipList => 10,
+            RDBValueEncodings::SetIntSet => 11,
+            RDBValueEncodings::SortedSetZipList => 12,
+            RDBValueEncodings::HashZipList => 13,
+            RDBValueEncodings::ListQuickList => 14,
+            RDBValueEncodings::HashListPack => 16,
+            RDBValueEncodings::SortedSetListPack => 17,
+            RDBValueEncodings::ListQuickList2 => 18,
+            RDBValueEncodings::SetListPack => 20,
+            RDBValueEncodings::StreamListPacks => 15,
+            RDBValueEncodings::StreamListPacks2 => 19,
+            RDBValueEncodings::StreamListPacks3 => 21,
+        }
+    }
 }
 
-struct LenPrefixedString {
-    #[allow(dead_code)]
-    len: u32,
-    value: String,
-}
+/// How strings are stored in an RDB: length prefixed, as an integer, or LZF
+/// compressed.
+struct StringEncoding;
 
 impl StringEncoding {
-    fn from_u8(bites: &mut impl Iterator<Item = u8>) -> Result<StringEncoding> {
-        let len_encoding = RDBLenEncodings::from_u8(bites)?;
-        match len_encoding {
+    /// Reads a string as raw bytes. Collection encodings such as ziplists and
+    /// listpacks are stored as (possibly compressed) strings too.
+    fn read_blob(bites: &mut impl Iterator<Item = u8>) -> Result<Vec<u8>> {
+        match RDBLenEncodings::from_u8(bites)? {
             RDBLenEncodings::SixBit(num)
             | RDBLenEncodings::FourteenBit(num)
+            | RDBLenEncodings::ThirtyTwoBit(num)
             | RDBLenEncodings::SixtyFourBit(num) => {
-                let mut val: Vec<u8> = Vec::new();
-                for _ in 0..num {
-                    let byte = bites.next().context("Iter reached end")?;
-                    val.push(byte);
-                }
-                let lps = LenPrefixedString {
-                    len: num as u32,
-                    value: String::from_utf8(val).context("Invalid utf8")?,
-                };
-                Ok(StringEncoding::LenPrefixed(lps))
+                let blob = bites.take(num as usize).collect::<Vec<u8>>();
+                if blob.len() != num as usize {
+                    bail!("Iter reached end");
+                }
+                Ok(blob)
+            }
+            RDBLenEncodings::SpecialEncoding(num) => Ok(num.to_string().into_bytes()),
+            RDBLenEncodings::Lzf => {
+                let compressed_len = Self::read_len(bites)?;
+                let len = Self::read_len(bites)?;
+                let compressed = bites.take(compressed_len).collect::<Vec<u8>>();
+                if compressed.len() != compressed_len {
+                    bail!("Iter reached end");
+                }
+                lzf_decompress(&compressed, len)
             }
-            RDBLenEncodings::SpecialEncoding(num) => Ok(StringEncoding::Int32(num)),
         }
     }
-    fn to_string(&self) -> String {
-        match self {
-            StringEncoding::Int32(num) => num.to_string(),
-            StringEncoding::LenPrefixed(lps) => lps.value.clone(),
-            StringEncoding::LZF => "LZF".to_string(),
+
+    /// Reads a key, a member or any other string that's kept as is.
+    fn read_bytes(bites: &mut impl Iterator<Item = u8>) -> Result<Bytes> {
+        Self::read_blob(bites).map(Bytes::from)
+    }
+
+    fn read_len(bites: &mut impl Iterator<Item = u8>) -> Result<usize> {
+        match RDBLenEncodings::from_u8(bites)? {
+            RDBLenEncodings::SixBit(num)
+            | RDBLenEncodings::FourteenBit(num)
+            | RDBLenEncodings::ThirtyTwoBit(num)
+            | RDBLenEncodings::SixtyFourBit(num) => Ok(num as usize),
+            _ => bail!("Expected a plain length encoding"),
+        }
+    }
+}
+
+impl StringEncoding {
+    /// Strings longer than 20 bytes are LZF compressed when that actually
+    /// makes them smaller, matching what Redis does with rdbcompression on.
+    fn encode(value: impl AsRef<[u8]>, out: &mut Vec<u8>) {
+        let value = value.as_ref();
+        if value.len() > 20 {
+            if let Some(compressed) = lzf_compress(value) {
+                out.push(192 | 3);
+                RDBLenEncodings::encode(compressed.len(), out);
+                RDBLenEncodings::encode(value.len(), out);
+                out.extend_from_slice(&compressed);
+                return;
+            }
+        }
+        RDBLenEncodings::encode(value.len(), out);
+        out.extend_from_slice(value);
+    }
+}
+
+/// The most an LZF block can expand: a three byte back reference copies up
+/// to 264 bytes.
+const LZF_MAX_EXPANSION: usize = 88;
+
+/// Decompresses an LZF block. Each control byte starts either a run of
+/// literals (values below 32) or a back reference into the output produced
+/// so far.
+fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>> {
+    // The length is read from the payload, so it's only trusted as far as
+    // the compressed bytes could actually produce it.
+    if expected_len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        bail!(
+            "LZF block of {} bytes can't decompress to {} bytes",
+            input.len(),
+            expected_len
+        );
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(expected_len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = ctrl + 1;
+            if i + run > input.len() {
+                bail!("Invalid LZF literal run");
+            }
+            if out.len() + run > expected_len {
+                bail!("LZF block decompresses past {} bytes", expected_len);
+            }
+            out.extend_from_slice(&input[i..i + run]);
+            i += run;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).context("Invalid LZF back reference")? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).context("Invalid LZF back reference")? as usize;
+            i += 1;
+            let offset = ((ctrl & 31) << 8) + low + 1;
+            if offset > out.len() {
+                bail!("Invalid LZF back reference");
+            }
+            if out.len() + len + 2 > expected_len {
+                bail!("LZF block decompresses past {} bytes", expected_len);
+            }
+            let start = out.len() - offset;
+            // The reference may overlap the bytes being written, so copy one
+            // byte at a time.
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != expected_len {
+        bail!(
+            "LZF decompressed to {} bytes, expected {}",
+            out.len(),
+            expected_len
+        );
+    }
+    Ok(out)
+}
+
+/// Compresses `input` with LZF. Returns None when compressing does not save
+/// any space.
+fn lzf_compress(input: &[u8]) -> Option<Vec<u8>> {
+    const HASH_SIZE: usize = 1 << 14;
+    const MAX_OFFSET: usize = 1 << 13;
+    const MAX_MATCH: usize = 264;
+
+    fn flush_literals(literals: &mut Vec<u8>, out: &mut Vec<u8>) {
+        if !literals.is_empty() {
+            out.push((literals.len() - 1) as u8);
+            out.append(literals);
+        }
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(input.len());
+    let mut literals: Vec<u8> = Vec::with_capacity(32);
+    // Last position (plus one) at which each 3 byte sequence was seen.
+    let mut table = vec![0usize; HASH_SIZE];
+    let mut i = 0;
+    while i + 2 < input.len() {
+        let seq = (input[i] as usize) << 16 | (input[i + 1] as usize) << 8 | input[i + 2] as usize;
+        let hash = (seq.wrapping_mul(2654435761) >> 8) & (HASH_SIZE - 1);
+        let candidate = table[hash];
+        table[hash] = i + 1;
+        if candidate > 0 {
+            let reference = candidate - 1;
+            let offset = i - reference - 1;
+            if offset < MAX_OFFSET && input[reference..reference + 3] == input[i..i + 3] {
+                let max_len = MAX_MATCH.min(input.len() - i);
+                let mut len = 3;
+                while len < max_len && input[reference + len] == input[i + len] {
+                    len += 1;
+                }
+                flush_literals(&mut literals, &mut out);
+                let encoded_len = len - 2;
+                if encoded_len < 7 {
+                    out.push((encoded_len << 5) as u8 | (offset >> 8) as u8);
+                } else {
+                    out.push((7 << 5) as u8 | (offset >> 8) as u8);
+                    out.push((encoded_len - 7) as u8);
+                }
+                out.push(offset as u8);
+                i += len;
+                if out.len() >= input.len() {
+                    return None;
+                }
+                continue;
+            }
+        }
+        literals.push(input[i]);
+        if literals.len() == 32 {
+            flush_literals(&mut literals, &mut out);
+        }
+        i += 1;
+    }
+    for byte in &input[i..] {
+        literals.push(*byte);
+        if literals.len() == 32 {
+            flush_literals(&mut literals, &mut out);
+        }
+    }
+    flush_literals(&mut literals, &mut out);
+    if out.len() >= input.len() {
+        return None;
+    }
+    Some(out)
+}
+
+/// A string read out of a collection encoding, copied so it doesn't keep the
+/// whole encoding around.
+fn blob_string(bytes: &[u8]) -> Bytes {
+    Bytes::copy_from_slice(bytes)
+}
+
+/// An integer entry of a collection encoding, which holds it as text.
+fn int_entry(num: impl ToString) -> Bytes {
+    Bytes::from(num.to_string())
+}
+
+fn blob_int<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N]> {
+    bytes
+        .get(at..at + N)
+        .and_then(|slice| slice.try_into().ok())
+        .context("Truncated collection encoding")
+}
+
+/// Decodes a ziplist, the compact encoding Redis used for small lists, hashes
+/// and sorted sets before 7.0. Each entry starts with the length of the
+/// previous entry, followed by an encoding byte for a string or an integer.
+fn ziplist_entries(bytes: &[u8]) -> Result<Vec<Bytes>> {
+    let count = u16::from_le_bytes(blob_int(bytes, 8)?) as usize;
+    let mut entries = Vec::with_capacity(count);
+    let mut i = 10;
+    loop {
+        let prev_len = *bytes.get(i).context("Truncated ziplist")?;
+        if prev_len == 0xFF {
+            break;
+        }
+        i += if prev_len < 254 { 1 } else { 5 };
+        let enc = *bytes.get(i).context("Truncated ziplist")?;
+        i += 1;
+        let (entry, len) = match enc >> 6 {
+            0 => {
+                let len = (enc & 63) as usize;
+                (
+                    blob_string(bytes.get(i..i + len).context("Truncated ziplist")?),
+                    len,
+                )
+            }
+            1 => {
+                let len = (((enc & 63) as usize) << 8)
+                    | *bytes.get(i).context("Truncated ziplist")? as usize;
+                let entry = bytes.get(i + 1..i + 1 + len).context("Truncated ziplist")?;
+                (blob_string(entry), len + 1)
+            }
+            2 => {
+                let len = u32::from_be_bytes(blob_int(bytes, i)?) as usize;
+                let entry = bytes.get(i + 4..i + 4 + len).context("Truncated ziplist")?;
+                (blob_string(entry), len + 4)
+            }
+            _ => match enc {
+                0xC0 => (int_entry(i16::from_le_bytes(blob_int(bytes, i)?)), 2),
+                0xD0 => (int_entry(i32::from_le_bytes(blob_int(bytes, i)?)), 4),
+                0xE0 => (int_entry(i64::from_le_bytes(blob_int(bytes, i)?)), 8),
+                0xF0 => {
+                    let [a, b, c] = blob_int::<3>(bytes, i)?;
+                    (int_entry(i32::from_le_bytes([0, a, b, c]) >> 8), 3)
+                }
+                0xFE => (
+                    int_entry(*bytes.get(i).context("Truncated ziplist")? as i8),
+                    1,
+                ),
+                0xF1..=0xFD => (int_entry((enc & 15) - 1), 0),
+                _ => bail!("Invalid ziplist entry encoding {}", enc),
+            },
+        };
+        entries.push(entry);
+        i += len;
+    }
+    Ok(entries)
+}
+
+/// Decodes a listpack, which replaced the ziplist in Redis 7.0. Entries carry
+/// their own length at the end (the backlen) instead of the previous entry's
+/// at the start.
+fn listpack_entries(bytes: &[u8]) -> Result<Vec<Bytes>> {
+    let mut entries = Vec::new();
+    let mut i = 6;
+    loop {
+        let enc = *bytes.get(i).context("Truncated listpack")?;
+        if enc == 0xFF {
+            break;
         }
+        let (entry, len) = if enc & 0x80 == 0 {
+            (int_entry(enc & 0x7F), 1)
+        } else if enc & 0xC0 == 0x80 {
+            let len = (enc & 0x3F) as usize;
+            let entry = bytes
+                .get(i + 1..i + 1 + len)
+                .context("Truncated listpack")?;
+            (blob_string(entry), len + 1)
+        } else if enc & 0xE0 == 0xC0 {
+            let low = *bytes.get(i + 1).context("Truncated listpack")?;
+            // A 13 bit two's complement integer.
+            let num = ((((enc & 0x1F) as i16) << 8 | low as i16) << 3) >> 3;
+            (int_entry(num), 2)
+        } else if enc & 0xF0 == 0xE0 {
+            let low = *bytes.get(i + 1).context("Truncated listpack")?;
+            let len = ((enc & 0x0F) as usize) << 8 | low as usize;
+            let entry = bytes
+                .get(i + 2..i + 2 + len)
+                .context("Truncated listpack")?;
+            (blob_string(entry), len + 2)
+        } else {
+            match enc {
+                0xF0 => {
+                    let len = u32::from_le_bytes(blob_int(bytes, i + 1)?) as usize;
+                    let entry = bytes
+                        .get(i + 5..i + 5 + len)
+                        .context("Truncated listpack")?;
+                    (blob_string(entry), len + 5)
+                }
+                0xF1 => (int_entry(i16::from_le_bytes(blob_int(bytes, i + 1)?)), 3),
+                0xF2 => {
+                    let [a, b, c] = blob_int::<3>(bytes, i + 1)?;
+                    (int_entry(i32::from_le_bytes([0, a, b, c]) >> 8), 4)
+                }
+                0xF3 => (int_entry(i32::from_le_bytes(blob_int(bytes, i + 1)?)), 5),
+                0xF4 => (int_entry(i64::from_le_bytes(blob_int(bytes, i + 1)?)), 9),
+                _ => bail!("Invalid listpack entry encoding {}", enc),
+            }
+        };
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        entries.push(entry);
+        i += len + backlen;
     }
+    Ok(entries)
+}
+
+/// An element of a listpack being built.
+enum ListpackEntry<'a> {
+    Int(i64),
+    Str(&'a [u8]),
 }
 
+/// Encodes a listpack, the reverse of `listpack_entries`. Integers take the
+/// smallest of the encodings used here that holds them.
+fn listpack(entries: &[ListpackEntry]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for entry in entries {
+        let start = body.len();
+        match *entry {
+            ListpackEntry::Int(num @ 0..=127) => body.push(num as u8),
+            ListpackEntry::Int(num @ -4096..=4095) => {
+                let num = num as u16 & 0x1FFF;
+                body.extend_from_slice(&[0xC0 | (num >> 8) as u8, num as u8]);
+            }
+            ListpackEntry::Int(num) => match i32::try_from(num) {
+                Ok(num) => {
+                    body.push(0xF3);
+                    body.extend_from_slice(&num.to_le_bytes());
+                }
+                Err(_) => {
+                    body.push(0xF4);
+                    body.extend_from_slice(&num.to_le_bytes());
+                }
+            },
+            ListpackEntry::Str(str) if str.len() < 64 => {
+                body.push(0x80 | str.len() as u8);
+                body.extend_from_slice(str);
+            }
+            ListpackEntry::Str(str) if str.len() < 4096 => {
+                body.extend_from_slice(&[0xE0 | (str.len() >> 8) as u8, str.len() as u8]);
+                body.extend_from_slice(str);
+            }
+            ListpackEntry::Str(str) => {
+                body.push(0xF0);
+                body.extend_from_slice(&(str.len() as u32).to_le_bytes());
+                body.extend_from_slice(str);
+            }
+        }
+        // The backlen: the entry's length in 7 bit groups, most significant
+        // first, with the high bit set on all but the first.
+        let len = body.len() - start;
+        let groups = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for group in (0..groups).rev() {
+            let bits = (len >> (7 * group)) as u8 & 0x7F;
+            body.push(if group == groups - 1 {
+                bits
+            } else {
+                bits | 0x80
+            });
+        }
+    }
+    let mut out = Vec::with_capacity(body.len() + 7);
+    out.extend_from_slice(&(body.len() as u32 + 7).to_le_bytes());
+    out.extend_from_slice(&(entries.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    out.extend_from_slice(&body);
+    out.push(0xFF);
+    out
+}
+
+/// How many entries a stream listpack is written with at most, as with
+/// Redis' default stream-node-max-entries.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// Set on a stream entry that was deleted but is still in its listpack.
+const STREAM_ITEM_DELETED: i64 = 1;
+
+/// Set on a stream entry with the same fields as the node's first entry,
+/// which are then left out.
+const STREAM_ITEM_SAME_FIELDS: i64 = 2;
+
+/// Decodes the entries of a stream listpack whose master entry has the ID
+/// `master`. The master entry comes first: the number of entries, the
+/// number deleted, the master fields and a 0. Each entry then has flags, its
+/// ID as a difference from the master's, its fields and values, and a count
+/// of its listpack elements for walking backwards.
+fn stream_node_entries(master: StreamId, node: &[u8], stream: &mut Stream) -> Result<()> {
+    fn next(elements: &mut impl Iterator<Item = Bytes>) -> Result<Bytes> {
+        elements.next().context("Truncated stream node")
+    }
+    fn int(elements: &mut impl Iterator<Item = Bytes>) -> Result<i64> {
+        as_int(&next(elements)?).context("Invalid stream node")
+    }
+    let elements = &mut listpack_entries(node)?.into_iter();
+    let count = int(elements)?;
+    let deleted = int(elements)?;
+    let master_fields = (0..int(elements)?)
+        .map(|_| next(elements))
+        .collect::<Result<Vec<_>>>()?;
+    if int(elements)? != 0 {
+        bail!("Invalid stream master entry");
+    }
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = int(elements)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(int(elements)? as u64),
+            seq: master.seq.wrapping_add(int(elements)? as u64),
+        };
+        let fields = match flags & STREAM_ITEM_SAME_FIELDS {
+            0 => (0..int(elements)?)
+                .map(|_| Ok((next(elements)?, next(elements)?)))
+                .collect::<Result<Vec<_>>>()?,
+            _ => master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next(elements)?)))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        let _element_count = int(elements)?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Encodes up to STREAM_NODE_MAX_ENTRIES entries of a stream as a listpack,
+/// with the first one as the master entry. Every entry lists its fields, so
+/// none is flagged as having the master's.
+fn stream_node(entries: &[(&StreamId, &Vec<(Bytes, Bytes)>)]) -> Vec<u8> {
+    use ListpackEntry::{Int, Str};
+    let (master, master_fields) = entries[0];
+    let mut elements = vec![Int(entries.len() as i64), Int(0)];
+    elements.push(Int(master_fields.len() as i64));
+    elements.extend(master_fields.iter().map(|(field, _)| Str(field)));
+    elements.push(Int(0));
+    for (id, fields) in entries {
+        elements.extend([
+            Int(0),
+            Int(id.ms.wrapping_sub(master.ms) as i64),
+            Int(id.seq.wrapping_sub(master.seq) as i64),
+            Int(fields.len() as i64),
+        ]);
+        for (field, val) in fields.iter() {
+            elements.extend([Str(field), Str(val)]);
+        }
+        elements.push(Int(fields.len() as i64 * 2 + 4));
+    }
+    listpack(&elements)
+}
+
+/// Decodes an intset: a sorted array of 2, 4 or 8 byte little endian integers.
+fn intset_entries(bytes: &[u8]) -> Result<Vec<Bytes>> {
+    let width = u32::from_le_bytes(blob_int(bytes, 0)?) as usize;
+    let count = u32::from_le_bytes(blob_int(bytes, 4)?) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        bail!("Invalid intset encoding {}", width);
+    }
+    if count.checked_mul(width).and_then(|len| len.checked_add(8)) != Some(bytes.len()) {
+        bail!("Intset of {} entries doesn't match its length", count);
+    }
+    let mut entries = Vec::with_capacity(count);
+    for n in 0..count {
+        let at = 8 + n * width;
+        let entry = match width {
+            2 => i16::from_le_bytes(blob_int(bytes, at)?) as i64,
+            4 => i32::from_le_bytes(blob_int(bytes, at)?) as i64,
+            8 => i64::from_le_bytes(blob_int(bytes, at)?),
+            _ => bail!("Invalid intset encoding {}", width),
+        };
+        entries.push(int_entry(entry));
+    }
+    Ok(entries)
+}
+
+/// Decodes a zipmap, the small hash encoding used before Redis 2.6, into
+/// alternating fields and values.
+fn zipmap_entries(bytes: &[u8]) -> Result<Vec<Bytes>> {
+    let mut entries = Vec::new();
+    let mut i = 1;
+    loop {
+        let len = *bytes.get(i).context("Truncated zipmap")?;
+        if len == 0xFF {
+            break;
+        }
+        let (len, len_size) = match len {
+            0..=253 => (len as usize, 1),
+            _ => (u32::from_le_bytes(blob_int(bytes, i + 1)?) as usize, 5),
+        };
+        i += len_size;
+        // Values are followed by a count of unused bytes to skip.
+        let free = if entries.len() % 2 == 1 {
+            let free = *bytes.get(i).context("Truncated zipmap")? as usize;
+            i += 1;
+            free
+        } else {
+            0
+        };
+        entries.push(blob_string(
+            bytes.get(i..i + len).context("Truncated zipmap")?,
+        ));
+        i += len + free;
+    }
+    Ok(entries)
+}
+
+fn entries_to_hash(entries: Vec<Bytes>) -> HashMap<Bytes, Bytes> {
+    let mut entries = entries.into_iter();
+    let mut hash = HashMap::new();
+    while let (Some(field), Some(val)) = (entries.next(), entries.next()) {
+        hash.insert(field, val);
+    }
+    hash
+}
+
+fn entries_to_zset(entries: Vec<Bytes>) -> Result<HashMap<Bytes, f64>> {
+    let mut entries = entries.into_iter();
+    let mut zset = HashMap::new();
+    while let (Some(member), Some(score)) = (entries.next(), entries.next()) {
+        let score = parse_score(&score)?;
+        zset.insert(member, score);
+    }
+    Ok(zset)
+}
+
+/// A sorted set score stored as text.
+fn parse_score(score: &[u8]) -> Result<f64> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse::<f64>().ok())
+        .context("Invalid sorted set score")
+}
+
+/// Lookup table for CRC64 with the Jones polynomial (reflected), the variant
+/// Redis uses for RDB checksums.
+const CRC64_TABLE: [u64; 256] = {
+    const POLY: u64 = 0x95ac9329ac4bc9b5;
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |crc, byte| {
+        CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The RDB version written by this server, as of Redis 7.2.
+const RDB_VERSION: u16 = 11;
+
+/// Keys with their values, the expiry of those keys that have one, and the
+/// code of every function library.
+pub type Dataset = (
+    HashMap<Bytes, RedisValue>,
+    HashMap<Bytes, SystemTime>,
+    Vec<Bytes>,
+);
+
 pub struct RedisDB {
     dir: String,
     file_name: String,
+    verify_checksum: bool,
 }
 
 impl RedisDB {
     pub fn new(dir: String, file_name: String) -> Self {
-        Self { dir, file_name }
+        Self {
+            dir,
+            file_name,
+            verify_checksum: true,
+        }
+    }
+
+    /// Controls whether the CRC64 trailing an RDB payload is checked on read.
+    pub fn set_verify_checksum(&mut self, verify_checksum: bool) {
+        self.verify_checksum = verify_checksum;
     }
 
     fn get_next_opcode(&self, bite: &u8) -> Result<RDBOpCodes> {
         RDBOpCodes::from_u8(bite)
     }
 
-    fn get_rbd_bytes(&self) -> Result<Vec<u8>> {
-        let path = format!("{}/{}", self.dir, self.file_name);
+    fn path(&self) -> String {
+        format!("{}/{}", self.dir, self.file_name)
+    }
+
+    pub fn get_rbd_bytes(&self) -> Result<Vec<u8>> {
+        let path = self.path();
         let mut file = File::open(path).context("Error while opening rdb file")?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)
@@ -194,16 +873,19 @@ impl RedisDB {
         let expiry = match self.get_next_opcode(&next_byte) {
             Err(_) => None,
             Ok(opcode) => match opcode {
+                // Seconds, as a 32 bit unix time, written before RDB 5.
                 RDBOpCodes::ExpireTime => {
                     let _ = byte_iter.next().context("Iter reached end")?;
                     let arr = byte_iter.take(4).collect::<Vec<u8>>();
-                    let expiry = u64::from_le_bytes(arr.try_into().unwrap());
+                    let arr = arr.try_into().ok().context("Iter reached end")?;
+                    let expiry = u32::from_le_bytes(arr) as u64;
                     SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(expiry))
                 }
                 RDBOpCodes::ExpireTimeMs => {
                     let _ = byte_iter.next().context("Iter reached end")?;
                     let arr = byte_iter.take(8).collect::<Vec<u8>>();
-                    let expiry = u64::from_le_bytes(arr.try_into().unwrap());
+                    let arr = arr.try_into().ok().context("Iter reached end")?;
+                    let expiry = u64::from_le_bytes(arr);
                     SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(expiry))
                 }
                 _ => None,
@@ -212,38 +894,68 @@ impl RedisDB {
         Ok(expiry)
     }
 
-    pub fn read_rdb(&mut self) -> Result<(HashMap<String, String>, HashMap<String, SystemTime>)> {
-        let mut bytes = self.get_rbd_bytes()?;
-        let magic_string = bytes.drain(0..5).collect::<Vec<u8>>();
-        if magic_string != b"REDIS" {
+    pub fn read_rdb(&mut self) -> Result<Dataset> {
+        let bytes = self.get_rbd_bytes()?;
+        let (dataset, _) = self.parse_rdb(&bytes)?;
+        Ok(dataset)
+    }
+
+    /// Parses an RDB payload held in memory. Besides the dataset, returns the
+    /// number of bytes the payload took up (including the trailing checksum),
+    /// for callers that have more data after it.
+    pub fn parse_rdb(&mut self, bytes: &[u8]) -> Result<(Dataset, usize)> {
+        if bytes.len() < 9 || &bytes[0..5] != b"REDIS" {
             bail!("Invalid RDB file");
         }
-        let _version = bytes.drain(0..4).collect::<Vec<u8>>();
-        let mut byte_iter = bytes.into_iter().peekable();
+        let version = std::str::from_utf8(&bytes[5..9])
+            .ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .context("Invalid RDB version")?;
+        let mut byte_iter = bytes[9..].iter().copied().peekable();
         let mut next_byte = byte_iter.next().context("Iter reached end")?;
 
-        let mut kivals: HashMap<String, String> = HashMap::new();
-        let mut exp_map: HashMap<String, SystemTime> = HashMap::new();
+        let mut kivals: HashMap<Bytes, RedisValue> = HashMap::new();
+        let mut exp_map: HashMap<Bytes, SystemTime> = HashMap::new();
+        let mut libraries: Vec<Bytes> = Vec::new();
 
         #[allow(irrefutable_let_patterns)]
         while let opcode = self.get_next_opcode(&next_byte)? {
             match opcode {
                 RDBOpCodes::Eof => {
-                    return Ok((kivals, exp_map));
+                    let eof_end = bytes.len() - byte_iter.len();
+                    // Checksums were added in RDB version 5.
+                    if version < 5 {
+                        return Ok(((kivals, exp_map, libraries), eof_end));
+                    }
+                    let checksum = bytes
+                        .get(eof_end..eof_end + 8)
+                        .context("Checksum missing after EOF")?;
+                    let checksum = u64::from_le_bytes(checksum.try_into()?);
+                    // A zero checksum means the writer had checksums disabled.
+                    if self.verify_checksum && checksum != 0 {
+                        let expected = crc64(&bytes[..eof_end]);
+                        if checksum != expected {
+                            bail!(
+                                "RDB checksum mismatch: expected {:x}, found {:x}",
+                                expected,
+                                checksum
+                            );
+                        }
+                    }
+                    return Ok(((kivals, exp_map, libraries), eof_end + 8));
                 }
                 RDBOpCodes::SelectDB => {
                     let _db_number = RDBLenEncodings::from_u8(&mut byte_iter)?;
-                    let opcode =
-                        self.get_next_opcode(&byte_iter.next().context("Iter reached end")?)?;
-                    if let RDBOpCodes::ResizeDB = opcode {
-                    } else {
-                        bail!("Invalid RDB opcode lol")
+                    // Size hints were added in RDB 7, older files go straight
+                    // to the keys.
+                    if byte_iter.peek() == Some(&RDBOpCodes::ResizeDB.to_u8()) {
+                        byte_iter.next();
+                        let _db_size = RDBLenEncodings::from_u8(&mut byte_iter)?;
+                        let _exp_size = RDBLenEncodings::from_u8(&mut byte_iter)?;
                     }
-                    let _db_size = RDBLenEncodings::from_u8(&mut byte_iter)?;
-                    let _exp_size = RDBLenEncodings::from_u8(&mut byte_iter)?;
 
                     loop {
-                        let peeked_byte = byte_iter.peek().context("Iter reached end")?.clone();
+                        let peeked_byte = *byte_iter.peek().context("Iter reached end")?;
                         let expiry_arg = self.get_expiry(peeked_byte, &mut byte_iter)?;
                         let (k, v) = self.load_key_val(&mut byte_iter)?;
                         kivals.insert(k.clone(), v);
@@ -251,11 +963,12 @@ impl RedisDB {
                             exp_map.insert(k, expiry);
                         }
                         if let Some(next_byte) = byte_iter.peek() {
-                            match self.get_next_opcode(&next_byte) {
+                            match self.get_next_opcode(next_byte) {
                                 Ok(opcode) => match opcode {
                                     RDBOpCodes::SelectDB
                                     | RDBOpCodes::Aux
                                     | RDBOpCodes::ResizeDB
+                                    | RDBOpCodes::Function2
                                     | RDBOpCodes::Eof => break,
                                     _ => continue,
                                 },
@@ -265,23 +978,20 @@ impl RedisDB {
                     }
                 }
                 RDBOpCodes::Aux => loop {
-                    let key_string_encoding = StringEncoding::from_u8(&mut byte_iter)?;
-                    let _key = key_string_encoding.to_string();
-                    let val_string_encoding = StringEncoding::from_u8(&mut byte_iter)?;
-                    let _val = val_string_encoding.to_string();
+                    let _key = StringEncoding::read_blob(&mut byte_iter)?;
+                    let _val = StringEncoding::read_blob(&mut byte_iter)?;
                     let nb = byte_iter.peek().context("Iter reached end")?;
-                    if let RDBOpCodes::SelectDB =
-                        self.get_next_opcode(&nb).unwrap_or(RDBOpCodes::Aux)
-                    {
-                        break;
-                    }
                     if let RDBOpCodes::Aux =
-                        self.get_next_opcode(&nb).unwrap_or(RDBOpCodes::SelectDB)
+                        self.get_next_opcode(nb).unwrap_or(RDBOpCodes::SelectDB)
                     {
                         byte_iter.next().context("Iter reached end")?;
                         continue;
                     }
+                    break;
                 },
+                RDBOpCodes::Function2 => {
+                    libraries.push(StringEncoding::read_bytes(&mut byte_iter)?);
+                }
                 RDBOpCodes::ResizeDB => bail!("ResizeDB should come after select DB"),
                 RDBOpCodes::ExpireTime => bail!("ExpireTime should come after select DB"),
                 RDBOpCodes::ExpireTimeMs => bail!("ExpireTimeMs should come after select DB"),
@@ -292,17 +1002,798 @@ impl RedisDB {
         bail!("End of file not found");
     }
 
-    fn load_key_val(&mut self, bites: &mut impl Iterator<Item = u8>) -> Result<(String, String)> {
+    fn load_key_val(
+        &mut self,
+        bites: &mut impl Iterator<Item = u8>,
+    ) -> Result<(Bytes, RedisValue)> {
         let val_type_byte = bites.next().context("Iter reached end")?;
+        let key = StringEncoding::read_bytes(bites)?;
+        let val = Self::load_value(val_type_byte, bites)?;
+        Ok((key, val))
+    }
+
+    fn load_value(val_type_byte: u8, bites: &mut impl Iterator<Item = u8>) -> Result<RedisValue> {
         let val_encoding = RDBValueEncodings::from_u8(&val_type_byte)?;
-        let key_string_encoding = StringEncoding::from_u8(bites)?;
-        let key = key_string_encoding.to_string();
-        match val_encoding {
+        let val = match val_encoding {
             RDBValueEncodings::String => {
-                let val_string_encoding = StringEncoding::from_u8(bites)?;
-                let val = val_string_encoding.to_string();
-                Ok((key, val))
+                RedisValue::String(StringEncoding::read_blob(bites)?.into())
+            }
+            RDBValueEncodings::List => {
+                // Lengths are read from the data being loaded, which can't be
+                // trusted, so nothing is reserved up front.
+                let len = StringEncoding::read_len(bites)?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(StringEncoding::read_bytes(bites)?);
+                }
+                RedisValue::List(list)
+            }
+            RDBValueEncodings::Set => {
+                let len = StringEncoding::read_len(bites)?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(StringEncoding::read_bytes(bites)?);
+                }
+                RedisValue::Set(set)
+            }
+            RDBValueEncodings::Hash => {
+                let len = StringEncoding::read_len(bites)?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    let field = StringEncoding::read_bytes(bites)?;
+                    let val = StringEncoding::read_bytes(bites)?;
+                    hash.insert(field, val);
+                }
+                RedisValue::Hash(hash)
+            }
+            RDBValueEncodings::SortedSet | RDBValueEncodings::SortedSet2 => {
+                let len = StringEncoding::read_len(bites)?;
+                let mut zset = HashMap::new();
+                for _ in 0..len {
+                    let member = StringEncoding::read_bytes(bites)?;
+                    let score = match val_encoding {
+                        RDBValueEncodings::SortedSet2 => {
+                            let arr = bites.take(8).collect::<Vec<u8>>();
+                            let arr = arr.try_into().ok().context("Iter reached end")?;
+                            f64::from_le_bytes(arr)
+                        }
+                        _ => Self::load_string_score(bites)?,
+                    };
+                    zset.insert(member, score);
+                }
+                RedisValue::ZSet(zset)
+            }
+            RDBValueEncodings::ListZipList => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::List(ziplist_entries(&blob)?.into())
+            }
+            RDBValueEncodings::ListQuickList => {
+                let len = StringEncoding::read_len(bites)?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    let blob = StringEncoding::read_blob(bites)?;
+                    list.extend(ziplist_entries(&blob)?);
+                }
+                RedisValue::List(list)
+            }
+            RDBValueEncodings::ListQuickList2 => {
+                let len = StringEncoding::read_len(bites)?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    // Nodes are either a single large element stored as is
+                    // (1) or a listpack of elements (2).
+                    let container = StringEncoding::read_len(bites)?;
+                    let blob = StringEncoding::read_blob(bites)?;
+                    match container {
+                        1 => list.push_back(Bytes::from(blob)),
+                        2 => list.extend(listpack_entries(&blob)?),
+                        _ => bail!("Invalid quicklist container {}", container),
+                    }
+                }
+                RedisValue::List(list)
+            }
+            RDBValueEncodings::SetIntSet => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::Set(intset_entries(&blob)?.into_iter().collect())
+            }
+            RDBValueEncodings::SetListPack => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::Set(listpack_entries(&blob)?.into_iter().collect())
+            }
+            RDBValueEncodings::HashZipMap => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::Hash(entries_to_hash(zipmap_entries(&blob)?))
+            }
+            RDBValueEncodings::HashZipList => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::Hash(entries_to_hash(ziplist_entries(&blob)?))
+            }
+            RDBValueEncodings::HashListPack => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::Hash(entries_to_hash(listpack_entries(&blob)?))
+            }
+            RDBValueEncodings::SortedSetZipList => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::ZSet(entries_to_zset(ziplist_entries(&blob)?)?)
+            }
+            RDBValueEncodings::SortedSetListPack => {
+                let blob = StringEncoding::read_blob(bites)?;
+                RedisValue::ZSet(entries_to_zset(listpack_entries(&blob)?)?)
+            }
+            RDBValueEncodings::StreamListPacks
+            | RDBValueEncodings::StreamListPacks2
+            | RDBValueEncodings::StreamListPacks3 => {
+                RedisValue::Stream(Self::load_stream(&val_encoding, bites)?)
+            }
+        };
+        Ok(val)
+    }
+
+    /// Reads a stream: its listpacks, then what's known about the entries
+    /// that were added and deleted.
+    fn load_stream(
+        encoding: &RDBValueEncodings,
+        bites: &mut impl Iterator<Item = u8>,
+    ) -> Result<Stream> {
+        let mut stream = Stream::default();
+        let nodes = StringEncoding::read_len(bites)?;
+        for _ in 0..nodes {
+            let master = StringEncoding::read_blob(bites)?;
+            let master = master.try_into().ok().context("Invalid stream node key")?;
+            let node = StringEncoding::read_blob(bites)?;
+            stream_node_entries(StreamId::from_be_bytes(master), &node, &mut stream)?;
+        }
+        let len = StringEncoding::read_len(bites)?;
+        stream.last_id = Self::load_stream_id(bites)?;
+        match encoding {
+            RDBValueEncodings::StreamListPacks => stream.entries_added = len as u64,
+            _ => {
+                let _first_id = Self::load_stream_id(bites)?;
+                stream.max_deleted_id = Self::load_stream_id(bites)?;
+                stream.entries_added = StringEncoding::read_len(bites)? as u64;
+            }
+        }
+        for _ in 0..StringEncoding::read_len(bites)? {
+            let name = StringEncoding::read_bytes(bites)?;
+            let group = Self::load_consumer_group(encoding, bites)?;
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
+    }
+
+    /// Reads a consumer group: its last ID, how far it has read, its pending
+    /// entries, and then its consumers along with the IDs of the ones
+    /// pending for each.
+    fn load_consumer_group(
+        encoding: &RDBValueEncodings,
+        bites: &mut impl Iterator<Item = u8>,
+    ) -> Result<ConsumerGroup> {
+        let last_id = Self::load_stream_id(bites)?;
+        let entries_read = match encoding {
+            RDBValueEncodings::StreamListPacks => None,
+            _ => Some(StringEncoding::read_len(bites)? as u64).filter(|read| *read != u64::MAX),
+        };
+        let mut pending = BTreeMap::new();
+        for _ in 0..StringEncoding::read_len(bites)? {
+            let id = Self::load_raw_stream_id(bites)?;
+            let delivery_time = Self::load_millis(bites)?;
+            let delivery_count = StringEncoding::read_len(bites)? as u64;
+            pending.insert(id, (delivery_time, delivery_count));
+        }
+        let mut group = ConsumerGroup {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        };
+        for _ in 0..StringEncoding::read_len(bites)? {
+            let name = StringEncoding::read_bytes(bites)?;
+            let seen_time = Self::load_millis(bites)?;
+            let active_time = match encoding {
+                RDBValueEncodings::StreamListPacks3 => {
+                    Some(Self::load_millis(bites)?).filter(|time| *time != u64::MAX)
+                }
+                _ => Some(seen_time),
+            };
+            for _ in 0..StringEncoding::read_len(bites)? {
+                let id = Self::load_raw_stream_id(bites)?;
+                let (delivery_time, delivery_count) = pending
+                    .remove(&id)
+                    .context("Stream consumer has an entry the group doesn't")?;
+                group.pending.insert(
+                    id,
+                    PendingEntry {
+                        consumer: name.clone(),
+                        delivery_time,
+                        delivery_count,
+                    },
+                );
+            }
+            group.consumers.insert(
+                name,
+                Consumer {
+                    seen_time,
+                    active_time,
+                },
+            );
+        }
+        if !pending.is_empty() {
+            bail!("Stream group has entries pending for no consumer");
+        }
+        Ok(group)
+    }
+
+    /// A stream ID as pending entries are keyed by: 16 big endian bytes.
+    fn load_raw_stream_id(bites: &mut impl Iterator<Item = u8>) -> Result<StreamId> {
+        let raw = bites.take(16).collect::<Vec<u8>>();
+        let raw = raw.try_into().ok().context("Iter reached end")?;
+        Ok(StreamId::from_be_bytes(raw))
+    }
+
+    /// A unix time in milliseconds, as 8 little endian bytes.
+    fn load_millis(bites: &mut impl Iterator<Item = u8>) -> Result<u64> {
+        let arr = bites.take(8).collect::<Vec<u8>>();
+        let arr = arr.try_into().ok().context("Iter reached end")?;
+        Ok(u64::from_le_bytes(arr))
+    }
+
+    fn load_stream_id(bites: &mut impl Iterator<Item = u8>) -> Result<StreamId> {
+        Ok(StreamId {
+            ms: StringEncoding::read_len(bites)? as u64,
+            seq: StringEncoding::read_len(bites)? as u64,
+        })
+    }
+
+    fn encode_stream_id(id: StreamId, out: &mut Vec<u8>) {
+        RDBLenEncodings::encode(id.ms as usize, out);
+        RDBLenEncodings::encode(id.seq as usize, out);
+    }
+
+    /// Serializes a single value the way DUMP does: the RDB encoding of the
+    /// value, followed by the RDB version and a CRC64 of everything before it.
+    pub fn dump_value(val: &RedisValue) -> Vec<u8> {
+        let mut out = vec![Self::value_encoding(val).to_u8()];
+        Self::encode_value(val, &mut out);
+        out.extend_from_slice(&RDB_VERSION.to_le_bytes());
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// The length of the value's RDB encoding, as DEBUG OBJECT reports it.
+    pub fn serialized_length(val: &RedisValue) -> usize {
+        let mut out = Vec::new();
+        Self::encode_value(val, &mut out);
+        out.len()
+    }
+
+    /// Reads back a payload produced by `dump_value`.
+    pub fn restore_value(payload: &[u8]) -> Result<RedisValue> {
+        let body = Self::dump_body(payload)?;
+        let mut bites = body.iter().copied();
+        let val_type_byte = bites.next().context("Iter reached end")?;
+        let val = Self::load_value(val_type_byte, &mut bites)?;
+        if bites.next().is_some() {
+            bail!("Trailing bytes in DUMP payload");
+        }
+        Ok(val)
+    }
+
+    /// Serializes function libraries the way FUNCTION DUMP does: the code of
+    /// each one as it's stored in an RDB, followed by the same footer as
+    /// `dump_value`.
+    pub fn dump_functions(libraries: &[Bytes]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for code in libraries {
+            out.push(RDBOpCodes::Function2.to_u8());
+            StringEncoding::encode(code, &mut out);
+        }
+        out.extend_from_slice(&RDB_VERSION.to_le_bytes());
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Reads back the library code in a payload produced by `dump_functions`.
+    pub fn restore_functions(payload: &[u8]) -> Result<Vec<Bytes>> {
+        let body = Self::dump_body(payload)?;
+        let mut bites = body.iter().copied();
+        let mut libraries = Vec::new();
+        while let Some(opcode) = bites.next() {
+            match RDBOpCodes::from_u8(&opcode)? {
+                RDBOpCodes::Function2 => {
+                    libraries.push(StringEncoding::read_bytes(&mut bites)?);
+                }
+                _ => bail!("Unexpected opcode {} in function payload", opcode),
+            }
+        }
+        Ok(libraries)
+    }
+
+    /// Checks the footer of a DUMP payload, rejecting the payload if it was
+    /// written by a newer RDB version or the checksum doesn't match, and
+    /// returns what comes before the footer.
+    fn dump_body(payload: &[u8]) -> Result<&[u8]> {
+        if payload.len() < 10 {
+            bail!("DUMP payload too short");
+        }
+        let (body, footer) = payload.split_at(payload.len() - 10);
+        let version = u16::from_le_bytes([footer[0], footer[1]]);
+        if version > RDB_VERSION {
+            bail!("DUMP payload version {} is not supported", version);
+        }
+        let checksum = u64::from_le_bytes(footer[2..].try_into()?);
+        if checksum != crc64(&payload[..payload.len() - 8]) {
+            bail!("DUMP payload checksum mismatch");
+        }
+        Ok(body)
+    }
+
+    /// Scores in the original sorted set encoding are strings prefixed with a
+    /// one byte length, where 253, 254 and 255 stand for NaN, +inf and -inf.
+    fn load_string_score(bites: &mut impl Iterator<Item = u8>) -> Result<f64> {
+        let len = bites.next().context("Iter reached end")?;
+        match len {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let score = bites.take(len as usize).collect::<Vec<u8>>();
+                parse_score(&score)
+            }
+        }
+    }
+
+    fn encode_value(val: &RedisValue, out: &mut Vec<u8>) {
+        match val {
+            RedisValue::String(val) => StringEncoding::encode(val, out),
+            RedisValue::List(list) => {
+                RDBLenEncodings::encode(list.len(), out);
+                for item in list {
+                    StringEncoding::encode(item, out);
+                }
+            }
+            RedisValue::Set(set) => {
+                RDBLenEncodings::encode(set.len(), out);
+                for member in set {
+                    StringEncoding::encode(member, out);
+                }
+            }
+            RedisValue::Hash(hash) => {
+                RDBLenEncodings::encode(hash.len(), out);
+                for (field, val) in hash {
+                    StringEncoding::encode(field, out);
+                    StringEncoding::encode(val, out);
+                }
+            }
+            RedisValue::ZSet(zset) => {
+                RDBLenEncodings::encode(zset.len(), out);
+                for (member, score) in zset {
+                    StringEncoding::encode(member, out);
+                    out.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            RedisValue::Stream(stream) => {
+                let entries = stream.entries.iter().collect::<Vec<_>>();
+                let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+                RDBLenEncodings::encode(nodes.len(), out);
+                for node in nodes {
+                    StringEncoding::encode(node[0].0.to_be_bytes(), out);
+                    StringEncoding::encode(stream_node(node), out);
+                }
+                RDBLenEncodings::encode(entries.len(), out);
+                Self::encode_stream_id(stream.last_id, out);
+                let first_id = entries.first().map_or(StreamId::MIN, |(id, _)| **id);
+                Self::encode_stream_id(first_id, out);
+                Self::encode_stream_id(stream.max_deleted_id, out);
+                RDBLenEncodings::encode(stream.entries_added as usize, out);
+                RDBLenEncodings::encode(stream.groups.len(), out);
+                for (name, group) in &stream.groups {
+                    StringEncoding::encode(name, out);
+                    Self::encode_stream_id(group.last_id, out);
+                    RDBLenEncodings::encode(group.entries_read.unwrap_or(u64::MAX) as usize, out);
+                    RDBLenEncodings::encode(group.pending.len(), out);
+                    for (id, pending) in &group.pending {
+                        out.extend_from_slice(&id.to_be_bytes());
+                        out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+                        RDBLenEncodings::encode(pending.delivery_count as usize, out);
+                    }
+                    RDBLenEncodings::encode(group.consumers.len(), out);
+                    for (name, consumer) in &group.consumers {
+                        StringEncoding::encode(name, out);
+                        out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+                        let active_time = consumer.active_time.unwrap_or(u64::MAX);
+                        out.extend_from_slice(&active_time.to_le_bytes());
+                        let pending = group
+                            .pending
+                            .iter()
+                            .filter(|(_, pending)| pending.consumer == name)
+                            .collect::<Vec<_>>();
+                        RDBLenEncodings::encode(pending.len(), out);
+                        for (id, _) in pending {
+                            out.extend_from_slice(&id.to_be_bytes());
+                        }
+                    }
+                }
             }
         }
     }
+
+    fn value_encoding(val: &RedisValue) -> RDBValueEncodings {
+        match val {
+            RedisValue::String(_) => RDBValueEncodings::String,
+            RedisValue::List(_) => RDBValueEncodings::List,
+            RedisValue::Set(_) => RDBValueEncodings::Set,
+            RedisValue::Hash(_) => RDBValueEncodings::Hash,
+            RedisValue::ZSet(_) => RDBValueEncodings::SortedSet2,
+            RedisValue::Stream(_) => RDBValueEncodings::StreamListPacks3,
+        }
+    }
+
+    /// Serializes a dataset into an RDB payload. Keys whose expiry has already
+    /// passed are left out.
+    pub fn serialize_rdb(
+        kivals: &HashMap<Bytes, RedisValue>,
+        exp_map: &HashMap<Bytes, SystemTime>,
+        libraries: &[Bytes],
+    ) -> Vec<u8> {
+        let now = SystemTime::now();
+        let live_keys = kivals
+            .iter()
+            .filter(|(key, _)| !matches!(exp_map.get(*key), Some(exp) if *exp <= now))
+            .collect::<Vec<_>>();
+
+        let mut out: Vec<u8> = Vec::new();
+        out.extend_from_slice(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+        let ctime = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        for (key, val) in [
+            ("redis-ver", "7.2.0"),
+            ("redis-bits", "64"),
+            ("ctime", &ctime),
+        ] {
+            out.push(RDBOpCodes::Aux.to_u8());
+            StringEncoding::encode(key, &mut out);
+            StringEncoding::encode(val, &mut out);
+        }
+        for code in libraries {
+            out.push(RDBOpCodes::Function2.to_u8());
+            StringEncoding::encode(code, &mut out);
+        }
+
+        if !live_keys.is_empty() {
+            let exp_count = live_keys
+                .iter()
+                .filter(|(key, _)| exp_map.contains_key(*key))
+                .count();
+            out.push(RDBOpCodes::SelectDB.to_u8());
+            RDBLenEncodings::encode(0, &mut out);
+            out.push(RDBOpCodes::ResizeDB.to_u8());
+            RDBLenEncodings::encode(live_keys.len(), &mut out);
+            RDBLenEncodings::encode(exp_count, &mut out);
+            for (key, val) in live_keys {
+                if let Some(exp) = exp_map.get(key) {
+                    let ms = exp
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64;
+                    out.push(RDBOpCodes::ExpireTimeMs.to_u8());
+                    out.extend_from_slice(&ms.to_le_bytes());
+                }
+                out.push(Self::value_encoding(val).to_u8());
+                StringEncoding::encode(key, &mut out);
+                Self::encode_value(val, &mut out);
+            }
+        }
+
+        out.push(RDBOpCodes::Eof.to_u8());
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Writes the dataset to `<dir>/<dbfilename>`. The snapshot is written to a
+    /// temp file first and renamed over the old one, so a crash mid-write
+    /// never leaves a truncated RDB behind. Every call gets a temp file of its
+    /// own, so a SAVE running alongside a BGSAVE can't write into the same one.
+    pub fn write_rdb(
+        &self,
+        kivals: &HashMap<Bytes, RedisValue>,
+        exp_map: &HashMap<Bytes, SystemTime>,
+        libraries: &[Bytes],
+    ) -> Result<()> {
+        let bytes = Self::serialize_rdb(kivals, exp_map, libraries);
+        static WRITES: AtomicU64 = AtomicU64::new(0);
+        let temp_path = format!(
+            "{}/temp-{}-{}.rdb",
+            self.dir,
+            std::process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut file = File::create(&temp_path).context("Error while creating temp rdb file")?;
+        file.write_all(&bytes)
+            .context("Error while writing temp rdb file")?;
+        file.sync_all()
+            .context("Error while syncing temp rdb file")?;
+        std::fs::rename(&temp_path, self.path()).context("Error while renaming temp rdb file")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_matches_the_jones_check_value() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(b""), 0);
+    }
+
+    #[test]
+    fn lzf_literal_runs_decompress() {
+        // Two literal runs of three and two bytes.
+        let block = [2, b'a', b'b', b'c', 1, b'd', b'e'];
+        assert_eq!(lzf_decompress(&block, 5).unwrap(), b"abcde");
+    }
+
+    #[test]
+    fn lzf_back_references_decompress() {
+        // "abc", then a 7 byte reference 3 back that overlaps what it writes,
+        // and a long reference copying 9 bytes from the start.
+        let block = [2, b'a', b'b', b'c', 5 << 5, 2, 7 << 5, 0, 9];
+        assert_eq!(lzf_decompress(&block, 19).unwrap(), b"abcabcabcaabcabcabc");
+    }
+
+    #[test]
+    fn lzf_roundtrips_what_it_compresses() {
+        let input = "redis ".repeat(50);
+        let compressed = lzf_compress(input.as_bytes()).unwrap();
+        assert!(compressed.len() < input.len());
+        assert_eq!(
+            lzf_decompress(&compressed, input.len()).unwrap(),
+            input.as_bytes()
+        );
+        // Bytes that don't repeat aren't worth compressing.
+        assert!(lzf_compress(b"abcdefghijklmnopqrstuvwxyz").is_none());
+    }
+
+    #[test]
+    fn lzf_rejects_truncated_blocks() {
+        // A literal run missing its last byte.
+        assert!(lzf_decompress(&[2, b'a', b'b'], 3).is_err());
+        // A back reference missing its offset byte.
+        assert!(lzf_decompress(&[0, b'a', 1 << 5], 4).is_err());
+        // A long back reference missing its length byte.
+        assert!(lzf_decompress(&[0, b'a', 7 << 5], 10).is_err());
+        // A reference pointing before the start of the output.
+        assert!(lzf_decompress(&[0, b'a', 1 << 5, 5], 4).is_err());
+        // Output shorter than the declared length.
+        assert!(lzf_decompress(&[2, b'a', b'b', b'c'], 4).is_err());
+    }
+
+    #[test]
+    fn lzf_rejects_oversized_declared_lengths() {
+        // Two bytes can't expand to 4 GiB, so nothing is allocated for it.
+        assert!(lzf_decompress(&[0, b'a'], u32::MAX as usize).is_err());
+        // Output running past the declared length is cut off early.
+        assert!(lzf_decompress(&[2, b'a', b'b', b'c'], 2).is_err());
+        assert!(lzf_decompress(&[0, b'a', 7 << 5, 200, 0], 10).is_err());
+    }
+
+    // The fixtures in tests/fixtures were written by hand, byte for byte, to
+    // the layout Redis 2.4, 6.2 and 7.2 use: aux fields, size hints from RDB
+    // 7 on and checksums from RDB 5 on. No Redis binary was at hand to dump
+    // them, so these are self-consistency checks of the decoders against
+    // that layout rather than checks against real dumps.
+
+    fn load_fixture(file_name: &str) -> Dataset {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        RedisDB::new(dir.to_string(), file_name.to_string())
+            .read_rdb()
+            .unwrap_or_else(|e| panic!("can't load {}: {:?}", file_name, e))
+    }
+
+    fn list(items: &[&str]) -> RedisValue {
+        RedisValue::List(
+            items
+                .iter()
+                .map(|item| Bytes::from(item.to_string()))
+                .collect(),
+        )
+    }
+
+    fn set(members: &[&str]) -> RedisValue {
+        RedisValue::Set(
+            members
+                .iter()
+                .map(|member| Bytes::from(member.to_string()))
+                .collect(),
+        )
+    }
+
+    fn hash(pairs: &[(&str, &str)]) -> RedisValue {
+        RedisValue::Hash(
+            pairs
+                .iter()
+                .map(|(field, val)| (Bytes::from(field.to_string()), Bytes::from(val.to_string())))
+                .collect(),
+        )
+    }
+
+    fn zset(pairs: &[(&str, f64)]) -> RedisValue {
+        RedisValue::ZSet(
+            pairs
+                .iter()
+                .map(|(member, score)| (Bytes::from(member.to_string()), *score))
+                .collect(),
+        )
+    }
+
+    fn string(val: &str) -> RedisValue {
+        RedisValue::String(val.as_bytes().to_vec().into())
+    }
+
+    /// 2100-01-01, the expiry every fixture gives one key.
+    fn far_future() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(4_102_444_800)
+    }
+
+    /// A zipmap hash, a plain ziplist list and an intset, with an expiry in
+    /// seconds and neither size hints nor a checksum.
+    #[test]
+    fn loads_redis_2_4_encodings() {
+        let (kivals, exp_map, _) = load_fixture("redis-2.4.rdb");
+        let expected = HashMap::from([
+            (
+                Bytes::from("user"),
+                hash(&[("name", "redis"), ("year", "2009")]),
+            ),
+            (Bytes::from("list"), list(&["a", "2", "-300"])),
+            (Bytes::from("ids"), set(&["1", "2", "3"])),
+        ]);
+        assert_eq!(kivals, expected);
+        assert_eq!(
+            exp_map,
+            HashMap::from([(Bytes::from("list"), far_future())])
+        );
+    }
+
+    /// Ziplists as quicklist nodes, hashes and sorted sets, holding every
+    /// integer width and strings long enough to need a 5 byte previous length.
+    #[test]
+    fn loads_redis_6_2_encodings() {
+        let (kivals, exp_map, _) = load_fixture("redis-6.2.rdb");
+        let long_x = "x".repeat(100);
+        let long_y = "y".repeat(300);
+        let expected = HashMap::from([
+            (
+                Bytes::from("list"),
+                list(&[
+                    "hello",
+                    "0",
+                    "12",
+                    "100",
+                    "-1",
+                    "1000",
+                    "100000",
+                    "10000000",
+                    "10000000000",
+                    &long_x,
+                    &long_y,
+                    "end",
+                ]),
+            ),
+            (
+                Bytes::from("hash"),
+                hash(&[("field", "value"), ("n", "42")]),
+            ),
+            (
+                Bytes::from("zset"),
+                zset(&[("c", -3.0), ("a", 1.0), ("b", 2.5)]),
+            ),
+            (Bytes::from("smallints"), set(&["1", "2", "300"])),
+            (Bytes::from("bigints"), set(&["-1", "5000000000"])),
+            (Bytes::from("string"), string("value")),
+            (Bytes::from("counter"), string("12345")),
+        ]);
+        assert_eq!(kivals, expected);
+        assert_eq!(
+            exp_map,
+            HashMap::from([(Bytes::from("string"), far_future())])
+        );
+    }
+
+    /// Listpacks as quicklist nodes (one of them LZF compressed), hashes,
+    /// sorted sets and sets, next to an intset and a compressed string.
+    #[test]
+    fn loads_redis_7_2_encodings() {
+        let (kivals, exp_map, _) = load_fixture("redis-7.2.rdb");
+        let long_z = "z".repeat(70);
+        let expected = HashMap::from([
+            (
+                Bytes::from("list"),
+                list(&[
+                    "hello",
+                    "7",
+                    "-100",
+                    "1000",
+                    "100000",
+                    "10000000",
+                    "10000000000",
+                    &long_z,
+                ]),
+            ),
+            (Bytes::from("repeated"), list(&["hello"; 10])),
+            (
+                Bytes::from("hash"),
+                hash(&[("field", "value"), ("n", "42")]),
+            ),
+            (
+                Bytes::from("zset"),
+                zset(&[("c", -3.0), ("a", 1.0), ("b", 2.5)]),
+            ),
+            (Bytes::from("set"), set(&["x", "y", "z"])),
+            (Bytes::from("ints"), set(&["-5", "3", "70000"])),
+            (Bytes::from("string"), string("value")),
+            (Bytes::from("long"), string(&"abc".repeat(20))),
+        ]);
+        assert_eq!(kivals, expected);
+        assert_eq!(
+            exp_map,
+            HashMap::from([(Bytes::from("string"), far_future())])
+        );
+    }
+
+    fn load(type_byte: u8, payload: &[u8]) -> RedisValue {
+        RedisDB::load_value(type_byte, &mut payload.iter().copied()).unwrap()
+    }
+
+    /// The plain encodings: a length, then each element as a string, which
+    /// may itself be an integer encoded one.
+    #[test]
+    fn loads_plain_collection_encodings() {
+        assert_eq!(load(1, b"\x03\x01a\xc0\x07\x02bc"), list(&["a", "7", "bc"]));
+        assert_eq!(load(2, b"\x02\x01x\x01y"), set(&["x", "y"]));
+        assert_eq!(
+            load(4, b"\x02\x01f\x01v\x01n\xc0\x2a"),
+            hash(&[("f", "v"), ("n", "42")])
+        );
+        // Scores as strings, one of them the byte standing in for -inf.
+        assert_eq!(
+            load(3, b"\x03\x01a\x031.5\x01b\x02-2\x01c\xff"),
+            zset(&[("a", 1.5), ("b", -2.0), ("c", f64::NEG_INFINITY)])
+        );
+        let mut zset2 = b"\x01\x01a".to_vec();
+        zset2.extend(2.5f64.to_le_bytes());
+        assert_eq!(load(5, &zset2), zset(&[("a", 2.5)]));
+        // Running out of elements before the length says is an error.
+        assert!(RedisDB::load_value(1, &mut b"\x02\x01a".iter().copied()).is_err());
+    }
+
+    #[test]
+    fn concurrent_writes_get_temp_files_of_their_own() {
+        let dir = std::env::temp_dir().join(format!("redis-rs-rdb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let kivals = HashMap::from([(Bytes::from("k"), string("v"))]);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let db = RedisDB::new(dir.clone(), "dump.rdb".to_string());
+                    db.write_rdb(&kivals, &HashMap::new(), &[]).unwrap();
+                });
+            }
+        });
+        let (loaded, _, _) = RedisDB::new(dir.clone(), "dump.rdb".to_string())
+            .read_rdb()
+            .unwrap();
+        assert_eq!(loaded, kivals);
+        // Every temp file was renamed into place.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
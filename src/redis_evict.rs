@@ -0,0 +1,231 @@
+use crate::redis_storage::{Entry, Shard};
+use crate::redis_value::RedisValue;
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Rough per key overhead of the hash table entry and its bookkeeping, on
+/// top of the key and value themselves.
+const ENTRY_OVERHEAD: usize = 56;
+
+/// The LFU counter a new key starts with, so it isn't evicted before it had
+/// a chance to be accessed again.
+const LFU_INIT_VAL: u8 = 5;
+
+/// How a key has been accessed, for the LRU and LFU eviction policies.
+#[derive(Copy, Clone)]
+pub struct KeyAccess {
+    pub last: Instant,
+    /// A logarithmic access counter: the more often a key has been accessed,
+    /// the less likely another access is to increment it.
+    counter: u8,
+}
+
+impl KeyAccess {
+    pub fn new(now: Instant) -> Self {
+        KeyAccess {
+            last: now,
+            counter: LFU_INIT_VAL,
+        }
+    }
+
+    /// The access counter, decremented once for every `decay_time` minutes
+    /// the key went without being accessed. A `decay_time` of 0 never decays
+    /// it.
+    pub fn freq(&self, decay_time: u64) -> u8 {
+        if decay_time == 0 {
+            return self.counter;
+        }
+        let periods = self.last.elapsed().as_secs() / 60 / decay_time;
+        self.counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    /// Records another access, incrementing the counter with a probability
+    /// that shrinks as it grows and as `log_factor` does.
+    pub fn hit(&mut self, now: Instant, log_factor: u64, decay_time: u64) {
+        let counter = self.freq(decay_time);
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * log_factor as f64 + 1.0);
+        self.counter = match counter < u8::MAX && random_f64() < p {
+            true => counter + 1,
+            false => counter,
+        };
+        self.last = now;
+    }
+}
+
+/// Which keys to evict once the dataset grows past `maxmemory`.
+#[derive(Copy, Clone, PartialEq)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Ok(EvictionPolicy::VolatileLfu),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-random" => Ok(EvictionPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => bail!("Invalid maxmemory policy {}", policy),
+        }
+    }
+}
+
+impl std::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionPolicy::NoEviction => write!(f, "noeviction"),
+            EvictionPolicy::AllKeysLru => write!(f, "allkeys-lru"),
+            EvictionPolicy::VolatileLru => write!(f, "volatile-lru"),
+            EvictionPolicy::AllKeysLfu => write!(f, "allkeys-lfu"),
+            EvictionPolicy::VolatileLfu => write!(f, "volatile-lfu"),
+            EvictionPolicy::AllKeysRandom => write!(f, "allkeys-random"),
+            EvictionPolicy::VolatileRandom => write!(f, "volatile-random"),
+            EvictionPolicy::VolatileTtl => write!(f, "volatile-ttl"),
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn is_lfu(&self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu
+        )
+    }
+
+    /// Picks the next key to evict, along with the index of its shard, or
+    /// None if the policy doesn't allow evicting any of the keys left.
+    pub fn pick(&self, shards: &[&mut Shard], decay_time: u64) -> Option<(usize, Bytes)> {
+        let keys = || {
+            shards.iter().enumerate().flat_map(|(i, shard)| {
+                shard
+                    .db
+                    .iter()
+                    .map(move |(key, entry)| (i, key, entry.access))
+            })
+        };
+        let volatile = || {
+            shards.iter().enumerate().flat_map(|(i, shard)| {
+                shard
+                    .volatile()
+                    .filter_map(move |(key, _)| Some((i, key, shard.db.get(key)?.access)))
+            })
+        };
+        let (i, key, _) = match self {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => keys().min_by_key(|(_, _, access)| access.last),
+            EvictionPolicy::VolatileLru => volatile().min_by_key(|(_, _, access)| access.last),
+            EvictionPolicy::AllKeysLfu => {
+                keys().min_by_key(|(_, _, access)| access.freq(decay_time))
+            }
+            EvictionPolicy::VolatileLfu => {
+                volatile().min_by_key(|(_, _, access)| access.freq(decay_time))
+            }
+            EvictionPolicy::AllKeysRandom => keys().nth(random_index(keys().count())?),
+            EvictionPolicy::VolatileRandom => volatile().nth(random_index(volatile().count())?),
+            EvictionPolicy::VolatileTtl => {
+                // Each shard's soonest expiring key comes first in its index.
+                let (i, (key, _)) = shards
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, shard)| Some((i, shard.volatile().next()?)))
+                    .min_by_key(|(_, (_, exp))| *exp)?;
+                return Some((i, key.clone()));
+            }
+        }?;
+        Some((i, key.clone()))
+    }
+}
+
+/// Parses a memory amount such as "100mb" or "1gb" into bytes. Like Redis,
+/// "k", "m" and "g" are powers of 1000 while "kb", "mb" and "gb" are powers
+/// of 1024.
+pub fn parse_memory(amount: &str) -> Option<u64> {
+    let amount = amount.to_lowercase();
+    let split = amount
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(amount.len());
+    let (num, unit) = amount.split_at(split);
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Formats a byte count the way INFO shows it, e.g. "1.50M".
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut amount = bytes as f64;
+    let mut unit = 0;
+    while amount >= 1024.0 && unit < units.len() - 1 {
+        amount /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.2}{}", amount, units[unit]),
+    }
+}
+
+/// The approximate memory taken by one key and its value, measuring only
+/// `samples` elements of a collection (0 for all of them).
+pub fn entry_size(key: &[u8], val: &RedisValue, samples: usize) -> u64 {
+    (ENTRY_OVERHEAD + key.len() + val.mem_usage(samples)) as u64
+}
+
+/// The approximate memory taken by a shard of the dataset.
+pub fn used_memory(db: &HashMap<Bytes, Entry>) -> u64 {
+    db.iter()
+        .map(|(key, entry)| entry_size(key, &entry.value, 0))
+        .sum()
+}
+
+/// The memory a shard of the dataset takes up beyond the keys and values
+/// themselves.
+pub fn overhead(db: &HashMap<Bytes, Entry>) -> u64 {
+    (db.len() * ENTRY_OVERHEAD) as u64
+}
+
+fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+pub fn random_index(len: usize) -> Option<usize> {
+    match len {
+        0 => None,
+        len => Some(random_u64() as usize % len),
+    }
+}
+
+/// A random number in [0, 1).
+fn random_f64() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,657 @@
+use crate::redis_commands::RestorePolicy;
+use crate::redis_resp::Value;
+use crate::redis_value::glob_match;
+use bytes::Bytes;
+use mlua::{
+    Function as LuaFunction, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table,
+    Value as LuaValue, Variadic,
+};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// A command a running function sent through redis.call or redis.pcall,
+/// along with where its reply goes.
+pub type ScriptCall = (Vec<Bytes>, oneshot::Sender<Value>);
+
+/// How long a library's code may run while it's being loaded.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many Lua instructions run between checks for a timeout or a
+/// FUNCTION KILL.
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+/// The error a function stopped by FUNCTION KILL returns.
+const KILLED_REPLY: &str = "ERR Script killed by user with FUNCTION KILL...";
+
+/// The flags a function can be registered with. Only no-writes changes how
+/// a function runs, the others are accepted so libraries written for Redis
+/// load unchanged.
+const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// The name the runner below is kept under in the Lua registry.
+const RUNNER: &str = "runner";
+
+/// The parts of the redis table written in Lua. The chunk returns the runner
+/// every function is called through, which turns the errors the function
+/// raises into error replies.
+const PRELUDE: &str = r#"
+function redis.call(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+
+function redis.error_reply(msg)
+    return {err = msg}
+end
+
+function redis.status_reply(msg)
+    return {ok = msg}
+end
+
+return function(callback, keys, args)
+    local ok, reply = pcall(callback, keys, args)
+    if ok or (type(reply) == 'table' and reply.err) then
+        return reply
+    end
+    return {err = 'ERR ' .. tostring(reply)}
+end
+"#;
+
+/// The function libraries, loaded with FUNCTION LOAD and called with FCALL.
+#[derive(Clone, Default)]
+pub struct Functions {
+    libraries: BTreeMap<String, Arc<Library>>,
+}
+
+/// A library and the functions it registered. Every library has a Lua state
+/// of its own, so loading or deleting one never waits for a function of
+/// another to return.
+pub struct Library {
+    name: String,
+    code: Bytes,
+    functions: BTreeMap<String, Function>,
+    /// Only locked by the blocking task running one of the functions.
+    lua: Mutex<Lua>,
+}
+
+struct Function {
+    description: Option<String>,
+    flags: Vec<&'static str>,
+    callback: RegistryKey,
+}
+
+/// The functions a library registers while its code runs.
+struct Registration(Vec<(String, Function)>);
+
+/// Where redis.pcall sends commands while a function runs.
+struct ScriptCalls(mpsc::UnboundedSender<ScriptCall>);
+
+/// A function call in progress. FUNCTION KILL can stop it up until it runs
+/// its first write, after which stopping it would leave the writes half
+/// done, so it has to run to the end.
+#[derive(Default)]
+pub struct Running(AtomicU8);
+
+const RUNNING: u8 = 0;
+const WROTE: u8 = 1;
+const KILLED: u8 = 2;
+
+impl Running {
+    /// Called before each write the function makes. Fails if the function
+    /// was killed, in which case the write mustn't happen.
+    pub fn start_write(&self) -> Result<(), Value> {
+        match self
+            .0
+            .compare_exchange(RUNNING, WROTE, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Err(KILLED) => Err(Value::error(KILLED_REPLY)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stops the function, unless it already wrote something. Returns
+    /// whether it will stop.
+    pub fn kill(&self) -> bool {
+        match self
+            .0
+            .compare_exchange(RUNNING, KILLED, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => true,
+            Err(state) => state == KILLED,
+        }
+    }
+
+    fn is_killed(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == KILLED
+    }
+}
+
+impl Functions {
+    /// Adds a library loaded with [`Library::load`] and returns its name.
+    /// With `replace`, a library of the same name is replaced rather than
+    /// refused.
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<String, Value> {
+        let name = library.name.clone();
+        if !replace && self.libraries.contains_key(&name) {
+            return Err(Value::error(format!(
+                "ERR Library '{}' already exists",
+                name
+            )));
+        }
+        let taken = library.functions.keys().find(|function| {
+            self.libraries
+                .values()
+                .any(|other| other.name != name && other.functions.contains_key(*function))
+        });
+        if let Some(function) = taken {
+            return Err(Value::error(format!(
+                "ERR Function {} already exists",
+                function
+            )));
+        }
+        self.libraries.insert(name.clone(), Arc::new(library));
+        Ok(name)
+    }
+
+    /// Removes a library and its functions. Returns false if there is no
+    /// such library.
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.libraries.remove(name).is_some()
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    /// Adds the libraries of a FUNCTION DUMP payload. Nothing changes unless
+    /// every one of them can be added.
+    pub fn restore(&mut self, libraries: Vec<Library>, policy: RestorePolicy) -> Result<(), Value> {
+        let mut restored = match policy {
+            RestorePolicy::Flush => Functions::default(),
+            RestorePolicy::Append | RestorePolicy::Replace => self.clone(),
+        };
+        for library in libraries {
+            restored.add(library, policy == RestorePolicy::Replace)?;
+        }
+        *self = restored;
+        Ok(())
+    }
+
+    /// The code of every library, which is all it takes to load them again.
+    pub fn codes(&self) -> Vec<Bytes> {
+        self.libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    /// The library that registered `function`.
+    pub fn find(&self, function: &str) -> Option<Arc<Library>> {
+        self.libraries
+            .values()
+            .find(|library| library.functions.contains_key(function))
+            .cloned()
+    }
+
+    /// The FUNCTION LIST reply for the libraries whose name matches
+    /// `pattern`, or all of them.
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> Value {
+        let libraries = self
+            .libraries
+            .values()
+            .filter(|library| {
+                pattern.is_none_or(|pattern| glob_match(pattern, &library.name, false))
+            })
+            .map(|library| {
+                let functions = library
+                    .functions
+                    .iter()
+                    .map(|(name, function)| {
+                        Value::Map(vec![
+                            (Value::bulk("name"), Value::bulk(name)),
+                            (
+                                Value::bulk("description"),
+                                function
+                                    .description
+                                    .as_ref()
+                                    .map_or(Value::Nil, Value::bulk),
+                            ),
+                            (
+                                Value::bulk("flags"),
+                                Value::Array(
+                                    function
+                                        .flags
+                                        .iter()
+                                        .map(|flag| Value::SimpleString(flag.to_string()))
+                                        .collect(),
+                                ),
+                            ),
+                        ])
+                    })
+                    .collect();
+                let mut entry = vec![
+                    (Value::bulk("library_name"), Value::bulk(&library.name)),
+                    (Value::bulk("engine"), Value::bulk("LUA")),
+                    (Value::bulk("functions"), Value::Array(functions)),
+                ];
+                if with_code {
+                    entry.push((
+                        Value::bulk("library_code"),
+                        Value::bulk_bytes(&library.code),
+                    ));
+                }
+                Value::Map(entry)
+            })
+            .collect();
+        Value::Array(libraries)
+    }
+}
+
+impl Library {
+    /// Loads a library from its code, which starts with a
+    /// `#!lua name=<library>` line.
+    pub async fn load(code: Bytes) -> Result<Library, Value> {
+        Library::load_all(vec![code])
+            .await
+            .map(|mut libraries| libraries.remove(0))
+    }
+
+    /// Loads libraries until one fails. Their code may run for up to the
+    /// load timeout each, so it runs on a blocking task rather than the
+    /// executor.
+    pub async fn load_all(codes: Vec<Bytes>) -> Result<Vec<Library>, Value> {
+        tokio::task::spawn_blocking(move || {
+            codes
+                .iter()
+                .map(|code| Library::new(library_name(code)?, code))
+                .collect()
+        })
+        .await
+        .unwrap_or_else(|e| Err(Value::error(format!("ERR {}", e))))
+    }
+
+    /// Runs the library's code, which registers its functions.
+    fn new(name: String, code: &Bytes) -> Result<Self, Value> {
+        let lua = new_state().map_err(|e| Value::error(format!("ERR {}", root_cause(&e))))?;
+        // The metadata line isn't Lua, but the newline ending it is kept so
+        // errors point at the right line.
+        let body = &code[code.iter().position(|b| *b == b'\n').unwrap_or(code.len())..];
+        lua.set_app_data(Registration(Vec::new()));
+        let start = Instant::now();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| match start.elapsed() > LOAD_TIMEOUT {
+                true => Err(mlua::Error::RuntimeError(
+                    "FUNCTION LOAD timeout".to_string(),
+                )),
+                false => Ok(()),
+            },
+        );
+        let res = lua.load(body).set_name("@user_function").exec();
+        lua.remove_hook();
+        let registered = lua
+            .remove_app_data::<Registration>()
+            .map(|registration| registration.0)
+            .unwrap_or_default();
+        if let Err(e) = res {
+            return Err(match e {
+                mlua::Error::SyntaxError { message, .. } => {
+                    Value::error(format!("ERR Error compiling function: {}", message))
+                }
+                e => Value::error(format!("ERR {}", root_cause(&e))),
+            });
+        }
+        if registered.is_empty() {
+            return Err(Value::error("ERR No functions registered"));
+        }
+        Ok(Library {
+            name,
+            code: code.clone(),
+            functions: registered.into_iter().collect(),
+            lua: Mutex::new(lua),
+        })
+    }
+
+    /// Whether `function` was registered with the no-writes flag.
+    pub fn is_read_only(&self, function: &str) -> bool {
+        self.functions
+            .get(function)
+            .is_some_and(|function| function.flags.contains(&"no-writes"))
+    }
+
+    /// Calls `function` and returns its reply. It blocks until the function
+    /// returns, and every command the function calls is sent through `calls`
+    /// to be run by the server in the meantime. Killing `running` stops the
+    /// function at its next hook.
+    pub fn call(
+        &self,
+        function: &str,
+        keys: &[Bytes],
+        args: &[Bytes],
+        calls: mpsc::UnboundedSender<ScriptCall>,
+        running: Arc<Running>,
+    ) -> Value {
+        let function = match self.functions.get(function) {
+            Some(function) => function,
+            None => return Value::error("ERR Function not found"),
+        };
+        let lua = self.lua.lock().unwrap_or_else(PoisonError::into_inner);
+        lua.set_app_data(ScriptCalls(calls));
+        let hook_running = Arc::clone(&running);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| match hook_running.is_killed() {
+                true => Err(runtime_error(KILLED_REPLY)),
+                false => Ok(()),
+            },
+        );
+        let reply = (|| {
+            let runner: LuaFunction = lua.named_registry_value(RUNNER)?;
+            let callback: LuaFunction = lua.registry_value(&function.callback)?;
+            let strings = |items: &[Bytes]| {
+                items
+                    .iter()
+                    .map(|item| lua.create_string(item))
+                    .collect::<mlua::Result<Vec<_>>>()
+            };
+            runner.call::<_, LuaValue>((callback, strings(keys)?, strings(args)?))
+        })();
+        lua.remove_hook();
+        lua.remove_app_data::<ScriptCalls>();
+        // The runner turns the hook's error into an error reply of its own,
+        // and the function may even have caught it, so the reply is the same
+        // whatever the function returned.
+        if running.is_killed() {
+            return Value::error(KILLED_REPLY);
+        }
+        match reply {
+            Ok(reply) => to_reply(&reply),
+            Err(e) => Value::error(format!("ERR {}", root_cause(&e))),
+        }
+    }
+}
+
+/// A Lua state with the redis table, and only the standard libraries Redis
+/// gives functions.
+fn new_state() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    // Nothing a function runs may come from anywhere but its library, or
+    // reach into the environment of another function.
+    for name in [
+        "dofile",
+        "loadfile",
+        "load",
+        "loadstring",
+        "setfenv",
+        "getfenv",
+    ] {
+        globals.set(name, LuaValue::Nil)?;
+    }
+    globals
+        .get::<_, Table>("string")?
+        .set("dump", LuaValue::Nil)?;
+    let redis = lua.create_table()?;
+    redis.set("register_function", lua.create_function(register_function)?)?;
+    redis.set("pcall", lua.create_function(pcall)?)?;
+    redis.set("log", lua.create_function(log)?)?;
+    for (level, name) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+        .iter()
+        .enumerate()
+    {
+        redis.set(*name, level)?;
+    }
+    globals.set("redis", redis)?;
+    let runner: LuaFunction = lua.load(PRELUDE).set_name("@prelude").eval()?;
+    lua.set_named_registry_value(RUNNER, runner)?;
+    drop(globals);
+    Ok(lua)
+}
+
+/// redis.register_function(name, callback), or the same with a table holding
+/// function_name, callback, and optionally flags and a description.
+fn register_function<'lua>(lua: &'lua Lua, args: Variadic<LuaValue<'lua>>) -> mlua::Result<()> {
+    let (name, callback, flags, description) = match args.as_slice() {
+        [LuaValue::String(name), LuaValue::Function(callback)] => {
+            (name.to_str()?.to_string(), callback.clone(), None, None)
+        }
+        [LuaValue::Table(spec)] => (
+            spec.get::<_, Option<String>>("function_name")?
+                .ok_or_else(|| {
+                    runtime_error(
+                        "function_name argument given to redis.register_function must be a string",
+                    )
+                })?,
+            spec.get::<_, Option<LuaFunction>>("callback")?
+                .ok_or_else(|| {
+                    runtime_error(
+                        "callback argument given to redis.register_function must be a function",
+                    )
+                })?,
+            spec.get::<_, Option<Table>>("flags")?,
+            spec.get::<_, Option<String>>("description")?,
+        ),
+        _ => {
+            return Err(runtime_error(
+                "wrong arguments given to redis.register_function",
+            ))
+        }
+    };
+    if !is_valid_name(&name) {
+        return Err(runtime_error("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    let flags = match flags {
+        Some(flags) => flags
+            .sequence_values::<String>()
+            .map(|flag| {
+                let flag = flag?;
+                FUNCTION_FLAGS
+                    .iter()
+                    .find(|known| **known == flag)
+                    .copied()
+                    .ok_or_else(|| runtime_error("unknown flag given"))
+            })
+            .collect::<mlua::Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let mut registration = lua.app_data_mut::<Registration>().ok_or_else(|| {
+        runtime_error("redis.register_function can only be called on FUNCTION LOAD command")
+    })?;
+    if registration
+        .0
+        .iter()
+        .any(|(registered, _)| *registered == name)
+    {
+        return Err(runtime_error("Function already exists in the library"));
+    }
+    let callback = lua.create_registry_value(callback)?;
+    registration.0.push((
+        name,
+        Function {
+            description,
+            flags,
+            callback,
+        },
+    ));
+    Ok(())
+}
+
+/// redis.pcall(command, arg, ...): runs a command and returns its reply,
+/// errors included. redis.call, written in Lua, raises those instead.
+fn pcall<'lua>(lua: &'lua Lua, args: Variadic<LuaValue<'lua>>) -> mlua::Result<LuaValue<'lua>> {
+    let reply = match script_args(&args) {
+        Ok(args) => send_call(lua, args),
+        Err(reply) => reply,
+    };
+    to_lua(lua, &reply)
+}
+
+fn script_args(args: &[LuaValue]) -> Result<Vec<Bytes>, Value> {
+    if args.is_empty() {
+        return Err(Value::error(
+            "ERR Please specify at least one argument for this redis lib call",
+        ));
+    }
+    args.iter()
+        .map(|arg| match arg {
+            LuaValue::String(arg) => Ok(Bytes::copy_from_slice(arg.as_bytes())),
+            LuaValue::Integer(arg) => Ok(Bytes::from(arg.to_string())),
+            LuaValue::Number(arg) => Ok(Bytes::from(arg.to_string())),
+            _ => Err(Value::error(
+                "ERR Lua redis lib command arguments must be strings or integers",
+            )),
+        })
+        .collect()
+}
+
+/// Hands a command over to the server and waits for the reply.
+fn send_call(lua: &Lua, args: Vec<Bytes>) -> Value {
+    let calls = match lua.app_data_ref::<ScriptCalls>() {
+        Some(calls) => calls.0.clone(),
+        None => return Value::error("ERR redis.call can only be used from a running function"),
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if calls.send((args, reply_tx)).is_err() {
+        return Value::error("ERR The function's caller went away");
+    }
+    reply_rx
+        .blocking_recv()
+        .unwrap_or_else(|_| Value::error("ERR The function's caller went away"))
+}
+
+/// redis.log(level, message).
+fn log(_: &Lua, (level, message): (i64, String)) -> mlua::Result<()> {
+    match level {
+        0 | 1 => debug!("{}", message),
+        2 => info!("{}", message),
+        _ => warn!("{}", message),
+    }
+    Ok(())
+}
+
+/// Converts a reply to the Lua value a function sees, the way Redis does it
+/// for RESP2: nil becomes false, and status and error replies become tables
+/// with an ok or err field.
+fn to_lua<'lua>(lua: &'lua Lua, value: &Value) -> mlua::Result<LuaValue<'lua>> {
+    let value = match value {
+        Value::Integer(num) => LuaValue::Integer(*num as _),
+        Value::BulkString(str) => LuaValue::String(lua.create_string(str)?),
+        Value::Bytes(bytes) => LuaValue::String(lua.create_string(bytes)?),
+        Value::SimpleString(str) => LuaValue::Table(lua.create_table_from([("ok", str.as_str())])?),
+        Value::Error(msg) => LuaValue::Table(lua.create_table_from([("err", msg.as_str())])?),
+        Value::Nil => LuaValue::Boolean(false),
+        Value::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.raw_push(to_lua(lua, item)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        Value::Map(pairs) => {
+            let table = lua.create_table()?;
+            for (key, val) in pairs {
+                table.raw_push(to_lua(lua, key)?)?;
+                table.raw_push(to_lua(lua, val)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    };
+    Ok(value)
+}
+
+/// Converts what a function returned to the reply for FCALL. Numbers are
+/// truncated to integers, true is 1, false is nil, and arrays end at their
+/// first nil.
+fn to_reply(value: &LuaValue) -> Value {
+    match value {
+        LuaValue::Boolean(true) => Value::Integer(1),
+        LuaValue::Integer(num) => Value::Integer(*num),
+        LuaValue::Number(num) => Value::Integer(*num as i64),
+        LuaValue::String(str) => Value::bulk_bytes(str.as_bytes()),
+        LuaValue::Table(table) => {
+            if let Ok(LuaValue::String(msg)) = table.raw_get::<_, LuaValue>("err") {
+                return Value::error(msg.to_string_lossy());
+            }
+            if let Ok(LuaValue::String(status)) = table.raw_get::<_, LuaValue>("ok") {
+                return Value::SimpleString(status.to_string_lossy().to_string());
+            }
+            Value::Array(
+                table
+                    .clone()
+                    .sequence_values::<LuaValue>()
+                    .map_while(Result::ok)
+                    .map(|item| to_reply(&item))
+                    .collect(),
+            )
+        }
+        _ => Value::Nil,
+    }
+}
+
+/// Reads the library's name from the `#!<engine> name=<name>` line its code
+/// starts with.
+fn library_name(code: &[u8]) -> Result<String, Value> {
+    let metadata = code
+        .split(|b| *b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .map(|line| line.trim_end_matches('\r'))
+        .and_then(|line| line.strip_prefix("#!"))
+        .ok_or_else(|| Value::error("ERR Missing library metadata"))?;
+    let mut parts = metadata.split(' ').filter(|part| !part.is_empty());
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(Value::error(format!("ERR Engine '{}' not found", engine)));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value),
+            None => {
+                return Err(Value::error(format!(
+                    "ERR Invalid metadata value given: {}",
+                    part
+                )))
+            }
+        }
+    }
+    let name = name.ok_or_else(|| Value::error("ERR Library name was not given"))?;
+    if !is_valid_name(name) {
+        return Err(Value::error("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    Ok(name.to_string())
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+fn runtime_error(msg: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(msg.to_string())
+}
+
+/// The message of the error that started it all, without the Lua tracebacks
+/// it picked up on its way out of callbacks.
+fn root_cause(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => root_cause(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        e => e.to_string(),
+    }
+}
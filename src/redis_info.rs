@@ -0,0 +1,344 @@
+use crate::redis_metrics::{label_value, Metrics, LATENCY_BUCKETS_USEC};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many ops/sec samples are averaged for instantaneous_ops_per_sec.
+const OPS_SAMPLES: usize = 16;
+
+/// How many of its most recent latencies are kept per command to work out
+/// the percentiles in INFO latencystats.
+const LATENCY_SAMPLES: usize = 1024;
+
+/// How a command call ended, for INFO commandstats.
+pub enum CallOutcome {
+    Ok,
+    /// The command ran and replied with an error.
+    Failed,
+    /// The command was refused before it ran, e.g. with -OOM or -MOVED.
+    Rejected,
+}
+
+/// Call counts and timings of one command.
+#[derive(Default)]
+struct CommandStats {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+    latencies: VecDeque<u64>,
+    /// Calls per bucket of LATENCY_BUCKETS_USEC, for the metrics endpoint.
+    buckets: [u64; LATENCY_BUCKETS_USEC.len()],
+}
+
+/// The sections INFO returns when no section, or "default", is asked for.
+const DEFAULT_SECTIONS: [&str; 8] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cluster",
+    "keyspace",
+];
+
+/// Server wide counters reported by INFO.
+pub struct Stats {
+    pub started: Instant,
+    pub run_id: String,
+    pub connected_clients: u64,
+    pub total_connections_received: u64,
+    /// Connections refused because maxclients was reached.
+    pub rejected_connections: u64,
+    pub total_commands_processed: u64,
+    /// Keys deleted because they expired, lazily or by the active expire
+    /// cycle.
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    /// Lookups by read commands that found the key, and ones that didn't.
+    pub keyspace_hits: u64,
+    pub keyspace_misses: u64,
+    /// Clients disconnected for going over their output buffer limits.
+    pub client_output_buffer_limit_disconnections: u64,
+    /// (time, total_commands_processed) at each of the last few samples.
+    ops_samples: VecDeque<(Instant, u64)>,
+    /// Keyed by lowercase command name, with subcommands as "config|get".
+    commands: BTreeMap<String, CommandStats>,
+}
+
+impl Stats {
+    pub fn new(run_id: String) -> Self {
+        Stats {
+            started: Instant::now(),
+            run_id,
+            connected_clients: 0,
+            total_connections_received: 0,
+            rejected_connections: 0,
+            total_commands_processed: 0,
+            expired_keys: 0,
+            evicted_keys: 0,
+            keyspace_hits: 0,
+            keyspace_misses: 0,
+            client_output_buffer_limit_disconnections: 0,
+            ops_samples: VecDeque::new(),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Records one call of the command `name`. Rejected calls are only
+    /// counted as such, since the command never ran.
+    pub fn record(&mut self, name: String, duration: Duration, outcome: CallOutcome) {
+        let stats = self.commands.entry(name).or_default();
+        if let CallOutcome::Rejected = outcome {
+            stats.rejected_calls += 1;
+            return;
+        }
+        let usec = duration.as_micros() as u64;
+        stats.calls += 1;
+        stats.usec += usec;
+        if let CallOutcome::Failed = outcome {
+            stats.failed_calls += 1;
+        }
+        if stats.latencies.len() == LATENCY_SAMPLES {
+            stats.latencies.pop_front();
+        }
+        stats.latencies.push_back(usec);
+        if let Some(bucket) = LATENCY_BUCKETS_USEC.iter().position(|bound| usec <= *bound) {
+            stats.buckets[bucket] += 1;
+        }
+        self.total_commands_processed += 1;
+    }
+
+    /// CONFIG RESETSTAT: clears the counters, but not gauges such as the
+    /// number of connected clients.
+    pub fn reset(&mut self) {
+        self.total_connections_received = 0;
+        self.rejected_connections = 0;
+        self.total_commands_processed = 0;
+        self.expired_keys = 0;
+        self.evicted_keys = 0;
+        self.keyspace_hits = 0;
+        self.keyspace_misses = 0;
+        self.client_output_buffer_limit_disconnections = 0;
+        self.ops_samples.clear();
+        self.commands.clear();
+    }
+
+    pub fn commandstats(&self) -> InfoSection {
+        let mut section = InfoSection::new("Commandstats");
+        for (name, stats) in &self.commands {
+            section.field(
+                format!("cmdstat_{}", name),
+                format!(
+                    "calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                    stats.calls,
+                    stats.usec,
+                    stats.usec as f64 / stats.calls.max(1) as f64,
+                    stats.rejected_calls,
+                    stats.failed_calls
+                ),
+            );
+        }
+        section
+    }
+
+    pub fn latencystats(&self) -> InfoSection {
+        let mut section = InfoSection::new("Latencystats");
+        for (name, stats) in &self.commands {
+            if stats.latencies.is_empty() {
+                continue;
+            }
+            let mut latencies = stats.latencies.iter().copied().collect::<Vec<_>>();
+            latencies.sort_unstable();
+            let percentile = |p: f64| {
+                let rank = ((p / 100.0) * latencies.len() as f64).ceil() as usize;
+                latencies[rank.clamp(1, latencies.len()) - 1]
+            };
+            section.field(
+                format!("latency_percentiles_usec_{}", name),
+                format!(
+                    "p50={:.3},p99={:.3},p99.9={:.3}",
+                    percentile(50.0) as f64,
+                    percentile(99.0) as f64,
+                    percentile(99.9) as f64
+                ),
+            );
+        }
+        section
+    }
+
+    /// Adds the counters to the metrics page, along with the calls and
+    /// latency histogram of every command.
+    pub fn metrics(&self, metrics: &mut Metrics) {
+        metrics
+            .gauge(
+                "redis_uptime_seconds",
+                "Seconds since the server started.",
+                self.started.elapsed().as_secs(),
+            )
+            .gauge(
+                "redis_connected_clients",
+                "Clients currently connected.",
+                self.connected_clients,
+            )
+            .counter(
+                "redis_connections_received_total",
+                "Connections accepted.",
+                self.total_connections_received,
+            )
+            .counter(
+                "redis_rejected_connections_total",
+                "Connections refused because maxclients was reached.",
+                self.rejected_connections,
+            )
+            .counter(
+                "redis_commands_processed_total",
+                "Commands run.",
+                self.total_commands_processed,
+            )
+            .counter(
+                "redis_expired_keys_total",
+                "Keys deleted because they expired.",
+                self.expired_keys,
+            )
+            .counter(
+                "redis_evicted_keys_total",
+                "Keys evicted because of maxmemory.",
+                self.evicted_keys,
+            )
+            .counter(
+                "redis_keyspace_hits_total",
+                "Lookups by read commands that found the key.",
+                self.keyspace_hits,
+            )
+            .counter(
+                "redis_keyspace_misses_total",
+                "Lookups by read commands that didn't find the key.",
+                self.keyspace_misses,
+            );
+        let label = |name: &str| format!("cmd=\"{}\"", label_value(name));
+        metrics
+            .labeled(
+                "redis_command_calls_total",
+                "counter",
+                "Calls per command, including failed ones.",
+                self.commands
+                    .iter()
+                    .map(|(name, stats)| (label(name), stats.calls)),
+            )
+            .labeled(
+                "redis_command_failed_calls_total",
+                "counter",
+                "Calls per command that replied with an error.",
+                self.commands
+                    .iter()
+                    .map(|(name, stats)| (label(name), stats.failed_calls)),
+            )
+            .labeled(
+                "redis_command_rejected_calls_total",
+                "counter",
+                "Calls per command refused before running.",
+                self.commands
+                    .iter()
+                    .map(|(name, stats)| (label(name), stats.rejected_calls)),
+            )
+            .histogram(
+                "redis_command_duration_seconds",
+                "How long commands took to run.",
+                self.commands.iter().map(|(name, stats)| {
+                    (label(name), &stats.buckets[..], stats.calls, stats.usec)
+                }),
+            );
+    }
+
+    /// Records the number of commands processed so far. Called periodically
+    /// to work out instantaneous_ops_per_sec.
+    pub fn sample_ops(&mut self) {
+        if self.ops_samples.len() > OPS_SAMPLES {
+            self.ops_samples.pop_front();
+        }
+        self.ops_samples
+            .push_back((Instant::now(), self.total_commands_processed));
+    }
+
+    pub fn ops_per_sec(&self) -> u64 {
+        match (self.ops_samples.front(), self.ops_samples.back()) {
+            (Some((start, first)), Some((end, last))) if end > start => {
+                ((last - first) as f64 / (*end - *start).as_secs_f64()).round() as u64
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// One "# Name" section of INFO output.
+pub struct InfoSection {
+    name: &'static str,
+    fields: Vec<(String, String)>,
+}
+
+impl InfoSection {
+    pub fn new(name: &'static str) -> Self {
+        InfoSection {
+            name,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(&mut self, key: impl Into<String>, val: impl ToString) -> &mut Self {
+        self.fields.push((key.into(), val.to_string()));
+        self
+    }
+}
+
+/// Collects the INFO reply. Every subsystem checks whether its section was
+/// asked for and adds it, and the sections are rendered in the order they
+/// were added.
+pub struct InfoRegistry {
+    requested: Vec<String>,
+    sections: Vec<InfoSection>,
+}
+
+impl InfoRegistry {
+    /// `requested` holds the section names given to INFO. Besides section
+    /// names, "default", "all" and "everything" select groups of sections.
+    pub fn new(requested: &[String]) -> Self {
+        let requested = match requested.is_empty() {
+            true => vec!["default".to_string()],
+            false => requested.iter().map(|name| name.to_lowercase()).collect(),
+        };
+        InfoRegistry {
+            requested,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Whether the section called `name` was asked for.
+    pub fn wants(&self, name: &str) -> bool {
+        self.requested
+            .iter()
+            .any(|requested| match requested.as_str() {
+                "all" | "everything" => true,
+                "default" => DEFAULT_SECTIONS.contains(&name),
+                requested => requested == name,
+            })
+    }
+
+    pub fn add(&mut self, section: InfoSection) {
+        self.sections.push(section);
+    }
+
+    pub fn render(&self) -> String {
+        self.sections
+            .iter()
+            .map(|section| {
+                let mut out = format!("# {}\r\n", section.name);
+                for (key, val) in &section.fields {
+                    out.push_str(&format!("{}:{}\r\n", key, val));
+                }
+                out
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n")
+    }
+}
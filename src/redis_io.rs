@@ -0,0 +1,310 @@
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// How client connections are read from and written to. The listening
+/// sockets, replication links to a master and everything else always go
+/// through tokio.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum IoBackend {
+    /// Readiness based I/O on tokio's multi-threaded runtime.
+    #[default]
+    Tokio,
+    /// Reads and writes submitted to io_uring, on a single-threaded
+    /// tokio-uring runtime. Only available on Linux.
+    IoUring,
+}
+
+impl std::str::FromStr for IoBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self> {
+        match backend {
+            "tokio" => Ok(IoBackend::Tokio),
+            "io-uring" => Ok(IoBackend::IoUring),
+            _ => bail!("Invalid io-backend {}", backend),
+        }
+    }
+}
+
+impl std::fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoBackend::Tokio => write!(f, "tokio"),
+            IoBackend::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+/// A client connection. Buffers are handed over by value, as io_uring owns
+/// them until the kernel is done with them.
+pub trait Connection: Sized + 'static {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Reads into the spare capacity of `buf`, which the caller reserves, and
+    /// hands it back with the number of bytes appended. 0 means the peer
+    /// closed the connection.
+    fn recv(&self, buf: BytesMut) -> impl Future<Output = (io::Result<usize>, BytesMut)>;
+
+    /// Writes all of `bytes`.
+    fn send(&self, bytes: Vec<u8>) -> impl Future<Output = io::Result<()>>;
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    async fn recv(&self, mut buf: BytesMut) -> (io::Result<usize>, BytesMut) {
+        loop {
+            if let Err(e) = self.readable().await {
+                return (Err(e), buf);
+            }
+            match self.try_read_buf(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return (res, buf),
+            }
+        }
+    }
+
+    async fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            self.writable().await?;
+            match self.try_write(&bytes[offset..]) {
+                Ok(n) => offset += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A client connection over TLS. Reads and writes lock their own half of the
+/// stream, so replies can be sent while a read is pending.
+pub struct TlsConnection {
+    reader: Mutex<ReadHalf<TlsStream<TcpStream>>>,
+    writer: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    peer: SocketAddr,
+}
+
+impl TlsConnection {
+    /// Runs the server side of the handshake on a socket accepted by a
+    /// listener.
+    pub async fn accept(
+        acceptor: &TlsAcceptor,
+        stream: TcpStream,
+        peer: SocketAddr,
+    ) -> io::Result<Self> {
+        let stream = acceptor.accept(stream).await?;
+        let (reader, writer) = tokio::io::split(TlsStream::from(stream));
+        Ok(TlsConnection {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            peer,
+        })
+    }
+}
+
+impl Connection for TlsConnection {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    async fn recv(&self, mut buf: BytesMut) -> (io::Result<usize>, BytesMut) {
+        let res = self.reader.lock().await.read_buf(&mut buf).await;
+        (res, buf)
+    }
+
+    async fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&bytes).await?;
+        writer.flush().await
+    }
+}
+
+/// A link this server opens itself, such as a replica's to its master,
+/// which may or may not go over TLS.
+pub trait Link: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Link for S {}
+
+/// Whether TLS clients have to present a certificate the CA signed.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TlsAuthClients {
+    #[default]
+    Yes,
+    No,
+    /// A certificate is checked if the client presents one.
+    Optional,
+}
+
+impl std::str::FromStr for TlsAuthClients {
+    type Err = anyhow::Error;
+
+    fn from_str(auth: &str) -> Result<Self> {
+        match auth {
+            "yes" => Ok(TlsAuthClients::Yes),
+            "no" => Ok(TlsAuthClients::No),
+            "optional" => Ok(TlsAuthClients::Optional),
+            _ => bail!("Invalid tls-auth-clients {}", auth),
+        }
+    }
+}
+
+impl std::fmt::Display for TlsAuthClients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsAuthClients::Yes => write!(f, "yes"),
+            TlsAuthClients::No => write!(f, "no"),
+            TlsAuthClients::Optional => write!(f, "optional"),
+        }
+    }
+}
+
+/// The PEM files TLS is set up with: this server's certificate and key, and
+/// the CA certificates its peers' are checked against.
+#[derive(Clone, Debug, Default)]
+pub struct TlsFiles {
+    pub cert_file: String,
+    pub key_file: String,
+    pub ca_cert_file: String,
+}
+
+impl TlsFiles {
+    /// The server end of TLS connections, for a listener.
+    pub fn acceptor(&self, auth_clients: TlsAuthClients) -> Result<TlsAcceptor> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match auth_clients {
+            TlsAuthClients::No => builder.with_no_client_auth(),
+            auth_clients => {
+                let roots = Arc::new(self.roots()?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider());
+                let verifier = match auth_clients {
+                    TlsAuthClients::Optional => verifier.allow_unauthenticated(),
+                    _ => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+        };
+        let config = builder
+            .with_single_cert(self.certs()?, self.key()?)
+            .context("Invalid TLS certificate or key")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Connects to `host` over TLS, presenting this server's certificate if
+    /// it has one.
+    pub async fn connect(&self, host: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots()?);
+        let config = match self.cert_file.is_empty() {
+            true => builder.with_no_client_auth(),
+            false => builder
+                .with_client_auth_cert(self.certs()?, self.key()?)
+                .context("Invalid TLS certificate or key")?,
+        };
+        let name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("Invalid TLS server name {}", host))?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await
+            .context("TLS handshake failed")?;
+        Ok(TlsStream::from(stream))
+    }
+
+    fn certs(&self) -> Result<Vec<CertificateDer<'static>>> {
+        read_certs(&self.cert_file)
+    }
+
+    fn key(&self) -> Result<PrivateKeyDer<'static>> {
+        PrivateKeyDer::from_pem_file(&self.key_file)
+            .with_context(|| format!("Can't load TLS key from {}", self.key_file))
+    }
+
+    fn roots(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&self.ca_cert_file)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", self.ca_cert_file))?;
+        }
+        Ok(roots)
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .with_context(|| format!("Can't load TLS certificates from {}", path))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Writes out the replies held back in `out`, if there are any.
+pub async fn flush<C: Connection>(conn: &C, out: &mut Vec<u8>) -> io::Result<()> {
+    if out.is_empty() {
+        return Ok(());
+    }
+    conn.send(std::mem::take(out)).await
+}
+
+/// A connection served through io_uring. It has to be used from within
+/// `tokio_uring::start`.
+#[cfg(target_os = "linux")]
+pub struct UringStream {
+    stream: tokio_uring::net::TcpStream,
+    peer: SocketAddr,
+}
+
+#[cfg(target_os = "linux")]
+impl UringStream {
+    /// Takes over a socket accepted by a listener.
+    pub fn from_tcp(stream: TcpStream, peer: SocketAddr) -> io::Result<Self> {
+        // io_uring waits for the socket itself, so it's switched back to
+        // blocking mode rather than have reads fail with EAGAIN.
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok(UringStream {
+            stream: tokio_uring::net::TcpStream::from_std(stream),
+            peer,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Connection for UringStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    async fn recv(&self, buf: BytesMut) -> (io::Result<usize>, BytesMut) {
+        use tokio_uring::buf::IoBuf;
+        // A buffer is read into from the start, so only its spare capacity is
+        // handed over.
+        let len = buf.len();
+        let (res, slice) = self.stream.read(buf.slice(len..)).await;
+        (res, slice.into_inner())
+    }
+
+    async fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        self.stream.write_all(bytes).await.0
+    }
+}
@@ -0,0 +1,146 @@
+use crate::redis_resp::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::SystemTime;
+
+/// How many samples are kept per event, at most one per second.
+const HISTORY_LEN: usize = 160;
+
+/// Latency spikes of one kind, such as "command" or "fork".
+struct LatencyEvent {
+    /// (unix time in seconds, latency in milliseconds), oldest first. Spikes
+    /// within the same second are merged into the worst one.
+    samples: VecDeque<(u64, u64)>,
+    /// The worst latency seen since the event was last reset.
+    max: u64,
+}
+
+/// Records operations that took at least latency-monitor-threshold
+/// milliseconds, for LATENCY.
+#[derive(Default)]
+pub struct LatencyMonitor {
+    events: BTreeMap<String, LatencyEvent>,
+}
+
+impl LatencyMonitor {
+    pub fn add(&mut self, event: &str, latency: u64) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let event = self
+            .events
+            .entry(event.to_string())
+            .or_insert_with(|| LatencyEvent {
+                samples: VecDeque::new(),
+                max: 0,
+            });
+        event.max = event.max.max(latency);
+        match event.samples.back_mut() {
+            Some((time, worst)) if *time == now => *worst = (*worst).max(latency),
+            _ => {
+                if event.samples.len() == HISTORY_LEN {
+                    event.samples.pop_front();
+                }
+                event.samples.push_back((now, latency));
+            }
+        }
+    }
+
+    /// LATENCY RESET: forgets the given events, or all of them if none are
+    /// given. Returns how many were reset.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        if events.is_empty() {
+            let count = self.events.len();
+            self.events.clear();
+            return count;
+        }
+        events
+            .iter()
+            .filter(|event| self.events.remove(event.as_str()).is_some())
+            .count()
+    }
+
+    /// LATENCY LATEST: the name, time and latency of the latest spike, and
+    /// the worst latency, of every event.
+    pub fn latest(&self) -> Value {
+        Value::Array(
+            self.events
+                .iter()
+                .filter_map(|(name, event)| {
+                    let (time, latency) = event.samples.back()?;
+                    Some(Value::Array(vec![
+                        Value::bulk(name.clone()),
+                        Value::Integer(*time as i64),
+                        Value::Integer(*latency as i64),
+                        Value::Integer(event.max as i64),
+                    ]))
+                })
+                .collect(),
+        )
+    }
+
+    /// LATENCY HISTORY event: the time and latency of every sample kept.
+    pub fn history(&self, event: &str) -> Value {
+        let samples = match self.events.get(event) {
+            Some(event) => &event.samples,
+            None => return Value::Array(Vec::new()),
+        };
+        Value::Array(
+            samples
+                .iter()
+                .map(|(time, latency)| {
+                    Value::Array(vec![
+                        Value::Integer(*time as i64),
+                        Value::Integer(*latency as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// LATENCY DOCTOR: a human readable analysis of the events recorded,
+    /// with advice on what may cause them.
+    pub fn doctor(&self, threshold: u64) -> String {
+        if threshold == 0 {
+            return "Latency monitoring is disabled. Use CONFIG SET latency-monitor-threshold <milliseconds> to enable it.\n".to_string();
+        }
+        if self.events.is_empty() {
+            return "No latency spike was observed during the lifetime of this instance.\n"
+                .to_string();
+        }
+        let mut report = String::from("Latency spikes were observed in this instance:\n\n");
+        for (i, (name, event)) in self.events.iter().enumerate() {
+            let latencies = event.samples.iter().map(|(_, latency)| *latency);
+            let count = event.samples.len() as u64;
+            let avg = latencies.clone().sum::<u64>() / count;
+            let mean_dev = latencies.map(|latency| latency.abs_diff(avg)).sum::<u64>() / count;
+            let period = match (event.samples.front(), event.samples.back()) {
+                (Some((first, _)), Some((last, _))) if count > 1 => {
+                    format!("{:.1}", (last - first) as f64 / (count - 1) as f64)
+                }
+                _ => "0".to_string(),
+            };
+            report.push_str(&format!(
+                "{}. {}: {} latency spikes (average {}ms, mean deviation {}ms, period {} sec). Worst all time event {}ms.\n",
+                i + 1,
+                name,
+                count,
+                avg,
+                mean_dev,
+                period,
+                event.max
+            ));
+        }
+        report.push_str("\nSome advice:\n\n");
+        for name in self.events.keys() {
+            let advice = match name.as_str() {
+                "command" => "- Check SLOWLOG GET for the commands that were slow, and avoid commands with O(N) complexity on large keys, such as KEYS.\n",
+                "fork" => "- Snapshots copy the whole dataset before saving it. A smaller dataset, or fewer save points, makes them faster.\n",
+                "expire-cycle" => "- Many keys expired at the same time. Spreading out expiry times keeps each expire cycle short.\n",
+                _ => continue,
+            };
+            report.push_str(advice);
+        }
+        report
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{bail, Context, Result};
+use std::fs::OpenOptions;
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
+
+/// Redis log levels, from the most verbose. Each one maps to a tracing level:
+/// commands are traced at debug, connections and replication handshake steps
+/// at verbose, and server lifecycle events at notice.
+#[derive(Copy, Clone, PartialEq)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+    Nothing,
+}
+
+impl LogLevel {
+    fn level(self) -> LevelFilter {
+        match self {
+            LogLevel::Debug => LevelFilter::TRACE,
+            LogLevel::Verbose => LevelFilter::DEBUG,
+            LogLevel::Notice => LevelFilter::INFO,
+            LogLevel::Warning => LevelFilter::WARN,
+            LogLevel::Nothing => LevelFilter::OFF,
+        }
+    }
+
+    /// Only the server's own events go below notice, so debug doesn't fill
+    /// up with tokio internals.
+    fn filter(self) -> Targets {
+        Targets::new()
+            .with_target(env!("CARGO_CRATE_NAME"), self.level())
+            .with_default(self.level().min(LevelFilter::INFO))
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(level: &str) -> Result<Self> {
+        match level {
+            "debug" => Ok(LogLevel::Debug),
+            "verbose" => Ok(LogLevel::Verbose),
+            "notice" => Ok(LogLevel::Notice),
+            "warning" => Ok(LogLevel::Warning),
+            "nothing" => Ok(LogLevel::Nothing),
+            _ => bail!("Invalid loglevel {}", level),
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Verbose => write!(f, "verbose"),
+            LogLevel::Notice => write!(f, "notice"),
+            LogLevel::Warning => write!(f, "warning"),
+            LogLevel::Nothing => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Lets CONFIG SET loglevel change the level once logging is set up.
+static RELOAD: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
+
+/// Sets up logging at `level`. Like in Redis, an empty `logfile` logs to
+/// stdout, anything else is a file that log lines are appended to.
+pub fn init(level: LogLevel, logfile: &str) -> Result<()> {
+    let (filter, handle) = reload::Layer::new(level.filter());
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = if logfile.is_empty() {
+        fmt.with_writer(BoxMakeWriter::new(std::io::stdout))
+    } else {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(logfile)
+            .with_context(|| format!("Can't open the log file {}", logfile))?;
+        fmt.with_ansi(false)
+            .with_writer(BoxMakeWriter::new(Mutex::new(file)))
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .try_init()
+        .context("Logging is already set up")?;
+    let _ = RELOAD.set(handle);
+    Ok(())
+}
+
+pub fn set_level(level: LogLevel) {
+    if let Some(handle) = RELOAD.get() {
+        let _ = handle.modify(|filter| *filter = level.filter());
+    }
+}
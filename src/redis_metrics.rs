@@ -0,0 +1,184 @@
+use crate::redis_server::Redis;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+/// Upper bounds, in microseconds, of the buckets command latencies are
+/// counted in. Anything slower only shows up in the +Inf bucket.
+pub const LATENCY_BUCKETS_USEC: [u64; 14] = [
+    10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 100000, 250000, 1000000,
+];
+
+/// A metrics page in the Prometheus text format. Every metric is added with
+/// its HELP and TYPE lines, followed by one or more samples.
+#[derive(Default)]
+pub struct Metrics {
+    out: String,
+}
+
+impl Metrics {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: impl ToString) -> &mut Self {
+        self.labeled(name, "counter", help, [(String::new(), value)])
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl ToString) -> &mut Self {
+        self.labeled(name, "gauge", help, [(String::new(), value)])
+    }
+
+    /// A metric with one sample per set of labels, given as `key="value"`
+    /// pairs separated by commas. Metrics without samples are left out.
+    pub fn labeled<V: ToString>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (String, V)>,
+    ) -> &mut Self {
+        let mut samples = samples.into_iter().peekable();
+        if samples.peek().is_none() {
+            return self;
+        }
+        self.header(name, kind, help);
+        for (labels, value) in samples {
+            let _ = writeln!(
+                self.out,
+                "{}{} {}",
+                name,
+                braces(&labels),
+                value.to_string()
+            );
+        }
+        self
+    }
+
+    /// A histogram of durations with one series per set of labels. Each
+    /// series has the count in every bucket of LATENCY_BUCKETS_USEC, the
+    /// count and the sum of all durations in microseconds.
+    pub fn histogram<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        series: impl IntoIterator<Item = (String, &'a [u64], u64, u64)>,
+    ) -> &mut Self {
+        let mut series = series.into_iter().peekable();
+        if series.peek().is_none() {
+            return self;
+        }
+        self.header(name, "histogram", help);
+        for (labels, buckets, count, usec) in series {
+            let prefix = match labels.is_empty() {
+                true => String::new(),
+                false => format!("{},", labels),
+            };
+            let mut cumulative = 0;
+            for (bound, n) in LATENCY_BUCKETS_USEC.iter().zip(buckets) {
+                cumulative += n;
+                let _ = writeln!(
+                    self.out,
+                    "{}_bucket{{{}le=\"{}\"}} {}",
+                    name,
+                    prefix,
+                    *bound as f64 / 1e6,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                self.out,
+                "{}_bucket{{{}le=\"+Inf\"}} {}",
+                name, prefix, count
+            );
+            let _ = writeln!(
+                self.out,
+                "{}_sum{} {}",
+                name,
+                braces(&labels),
+                usec as f64 / 1e6
+            );
+            let _ = writeln!(self.out, "{}_count{} {}", name, braces(&labels), count);
+        }
+        self
+    }
+
+    pub fn render(self) -> String {
+        self.out
+    }
+}
+
+fn braces(labels: &str) -> String {
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels),
+    }
+}
+
+/// Escapes a label value as the text format asks for.
+pub fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves GET /metrics over HTTP to whoever connects to `listener`, for as
+/// long as the server runs.
+pub async fn serve(listener: Arc<TcpListener>, redis_server: Redis) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = redis_server.halted() => return,
+        };
+        if let Ok((stream, peer)) = accepted {
+            let redis_server = redis_server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &redis_server).await {
+                    debug!("Error serving metrics to {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Answers a single request and closes the connection. Only the request line
+/// matters, so headers and any body are read but ignored.
+async fn respond(mut stream: TcpStream, redis_server: &Redis) -> std::io::Result<()> {
+    let mut req = Vec::new();
+    let read_head = async {
+        let mut buf = [0; 1024];
+        while !req.windows(4).any(|window| window == b"\r\n\r\n") && req.len() < 8192 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            req.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    match tokio::time::timeout(Duration::from_secs(5), read_head).await {
+        Ok(res) => res?,
+        // Whoever connected never sent a complete request.
+        Err(_) => return Ok(()),
+    }
+    let req = String::from_utf8_lossy(&req);
+    let mut request_line = req.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", redis_server.metrics().await),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
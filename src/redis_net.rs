@@ -0,0 +1,509 @@
+use crate::redis_commands::Command;
+use crate::redis_evict::EvictionPolicy;
+use crate::redis_io::{self, Connection, IoBackend, TlsAuthClients, TlsConnection};
+use crate::redis_metrics;
+use crate::redis_resp::{self, Value};
+use crate::redis_server::{Redis, RedisCliArgs, Role};
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// A server that runs on the caller's tokio runtime, so it can be embedded in
+/// another program or started by tests.
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+/// Configures a server before it starts. Anything not set keeps the default
+/// Redis starts with.
+#[derive(Default)]
+pub struct ServerBuilder {
+    args: RedisCliArgs,
+}
+
+impl From<RedisCliArgs> for ServerBuilder {
+    fn from(args: RedisCliArgs) -> Self {
+        ServerBuilder { args }
+    }
+}
+
+impl ServerBuilder {
+    /// The port to listen on, or 0 for one picked by the OS.
+    pub fn port(mut self, port: u16) -> Self {
+        self.args.port = port.to_string();
+        self
+    }
+
+    /// The addresses to listen on, separated by spaces, as in the bind
+    /// config.
+    pub fn bind(mut self, addrs: &str) -> Self {
+        self.args.bind = addrs.split_whitespace().map(str::to_string).collect();
+        self
+    }
+
+    pub fn protected_mode(mut self, protected_mode: bool) -> Self {
+        self.args.protected_mode = protected_mode;
+        self
+    }
+
+    /// The directory the RDB and AOF files are kept in.
+    pub fn dir(mut self, dir: impl Into<String>) -> Self {
+        self.args.dir = Some(dir.into());
+        self
+    }
+
+    pub fn dbfilename(mut self, file_name: impl Into<String>) -> Self {
+        self.args.file_name = Some(file_name.into());
+        self
+    }
+
+    /// Snapshotting rules, or "" to never save on a schedule.
+    pub fn save(mut self, save: impl Into<String>) -> Self {
+        self.args.save = save.into();
+        self
+    }
+
+    pub fn appendonly(mut self, appendonly: bool) -> Self {
+        self.args.appendonly = appendonly;
+        self
+    }
+
+    /// Starts as a replica of `host:port`.
+    pub fn replicaof(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.args.master_host = Some(host.into());
+        self.args.master_port = Some(port.to_string());
+        self.args.role = Role::Replica;
+        self
+    }
+
+    /// The address a replica announces to its master, in place of the one
+    /// the master sees it connect from and the port it listens on. An empty
+    /// ip or a port of 0 leaves that part as it is.
+    pub fn replica_announce(mut self, ip: impl Into<String>, port: u16) -> Self {
+        self.args.replica_announce_ip = ip.into();
+        self.args.replica_announce_port = port;
+        self
+    }
+
+    pub fn maxmemory(mut self, bytes: u64) -> Self {
+        self.args.maxmemory = bytes;
+        self
+    }
+
+    pub fn maxmemory_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.args.maxmemory_policy = policy;
+        self
+    }
+
+    /// The port metrics are served on over HTTP, or 0 to not serve them.
+    pub fn metrics_port(mut self, port: u16) -> Self {
+        self.args.metrics_port = port;
+        self
+    }
+
+    /// How client connections are served. A server using io_uring has to be
+    /// spawned from within `tokio_uring::start`.
+    pub fn io_backend(mut self, backend: IoBackend) -> Self {
+        self.args.io_backend = backend;
+        self
+    }
+
+    /// The port TLS clients connect to, or 0 for one picked by the OS. TLS
+    /// isn't served unless it's set, and needs the files from `tls_files`.
+    pub fn tls_port(mut self, port: u16) -> Self {
+        self.args.tls_port = Some(port);
+        self
+    }
+
+    /// The PEM files TLS is set up with: this server's certificate and key,
+    /// and the CA certificates clients and masters are checked against.
+    pub fn tls_files(
+        mut self,
+        cert_file: impl Into<String>,
+        key_file: impl Into<String>,
+        ca_cert_file: impl Into<String>,
+    ) -> Self {
+        self.args.tls_files = redis_io::TlsFiles {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            ca_cert_file: ca_cert_file.into(),
+        };
+        self
+    }
+
+    pub fn tls_auth_clients(mut self, auth_clients: TlsAuthClients) -> Self {
+        self.args.tls_auth_clients = auth_clients;
+        self
+    }
+
+    /// Connects to the master over TLS when replicating.
+    pub fn tls_replication(mut self, tls_replication: bool) -> Self {
+        self.args.tls_replication = tls_replication;
+        self
+    }
+
+    /// Binds the listening sockets, loads the dataset and starts accepting
+    /// clients. Fails if an address that isn't optional can't be bound.
+    pub async fn spawn(mut self) -> Result<ServerHandle> {
+        if cfg!(not(target_os = "linux")) && self.args.io_backend == IoBackend::IoUring {
+            bail!("The io-uring backend is only available on Linux");
+        }
+        let port = self
+            .args
+            .port
+            .parse::<u16>()
+            .with_context(|| format!("Invalid port {}", self.args.port))?;
+        let mut listeners = listen(&self.args.bind, port)
+            .await?
+            .into_iter()
+            .map(|listener| (listener, None))
+            .collect::<Vec<_>>();
+        let local_addr = listeners[0].0.local_addr()?;
+        // With port 0, the server has to know which port it ended up on, e.g.
+        // to announce it to its master.
+        self.args.port = local_addr.port().to_string();
+        let mut tls_addr = None;
+        if let Some(tls_port) = self.args.tls_port {
+            let acceptor = self.args.tls_files.acceptor(self.args.tls_auth_clients)?;
+            let tls_listeners = listen(&self.args.bind, tls_port).await?;
+            let addr = tls_listeners[0].local_addr()?;
+            self.args.tls_port = Some(addr.port());
+            tls_addr = Some(addr);
+            listeners.extend(
+                tls_listeners
+                    .into_iter()
+                    .map(|listener| (listener, Some(acceptor.clone()))),
+            );
+        }
+        let metrics_listeners = match self.args.metrics_port {
+            0 => Vec::new(),
+            metrics_port => listen(&self.args.bind, metrics_port).await?,
+        };
+        let io_backend = self.args.io_backend;
+        let redis_server = Redis::new(self.args).await;
+        if let Some(listener) = metrics_listeners.first() {
+            info!("Serving metrics on {}", listener.local_addr()?);
+        }
+        for listener in metrics_listeners {
+            tokio::spawn(redis_metrics::serve(listener, redis_server.clone()));
+        }
+        info!("Ready to accept connections on port {}", local_addr.port());
+        if let Some(addr) = tls_addr {
+            info!("Ready to accept TLS connections on port {}", addr.port());
+        }
+        let listeners = Arc::new(std::sync::Mutex::new(listeners));
+        // A SHUTDOWN from a client ends the accept loops, and the sockets
+        // are closed too so connecting fails rather than hanging.
+        tokio::spawn({
+            let redis_server = redis_server.clone();
+            let listeners = Arc::clone(&listeners);
+            async move {
+                redis_server.halted().await;
+                listeners.lock().unwrap().clear();
+            }
+        });
+        let handle = ServerHandle {
+            io_backend,
+            redis_server,
+            local_addr,
+            tls_addr,
+            listeners,
+            accept_loops: std::sync::Mutex::new(Vec::new()),
+        };
+        handle.start_accepting();
+        Ok(handle)
+    }
+}
+
+/// The sockets a server listens on. TLS listeners come with the acceptor
+/// their clients' handshakes go through.
+type Listeners = Vec<(Arc<TcpListener>, Option<TlsAcceptor>)>;
+
+/// A running server. It keeps running when the handle is dropped, until
+/// it's shut down with `shutdown` or a SHUTDOWN command.
+pub struct ServerHandle {
+    io_backend: IoBackend,
+    redis_server: Redis,
+    local_addr: SocketAddr,
+    tls_addr: Option<SocketAddr>,
+    /// Emptied once the server is shut down, which closes the sockets.
+    listeners: Arc<std::sync::Mutex<Listeners>>,
+    accept_loops: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ServerHandle {
+    /// The address clients can connect to, the first one bound if there are
+    /// several.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address TLS clients can connect to, if TLS is served.
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls_addr
+    }
+
+    /// Shuts the server down like SHUTDOWN does. No new clients are accepted
+    /// meanwhile, and if it fails the server keeps running.
+    pub async fn shutdown(&self) -> Result<()> {
+        let accept_loops = std::mem::take(&mut *self.accept_loops.lock().unwrap());
+        for accept_loop in &accept_loops {
+            accept_loop.abort();
+        }
+        for accept_loop in accept_loops {
+            let _ = accept_loop.await;
+        }
+        let res = self.redis_server.shutdown(None).await;
+        match res {
+            Ok(()) => self.listeners.lock().unwrap().clear(),
+            Err(_) => self.start_accepting(),
+        }
+        res
+    }
+
+    /// Resolves once the server has been shut down, by `shutdown` or by a
+    /// client.
+    pub async fn stopped(&self) {
+        self.redis_server.halted().await
+    }
+
+    fn start_accepting(&self) {
+        let mut accept_loops = self.accept_loops.lock().unwrap();
+        for (listener, tls) in self.listeners.lock().unwrap().iter() {
+            let accept = accept(
+                Arc::clone(listener),
+                self.redis_server.clone(),
+                self.io_backend,
+                tls.clone(),
+            );
+            accept_loops.push(match self.io_backend {
+                IoBackend::Tokio => tokio::spawn(accept),
+                // Connections served through io_uring can't leave the
+                // runtime's thread, and neither can what spawns them.
+                IoBackend::IoUring => tokio::task::spawn_local(accept),
+            });
+        }
+    }
+}
+
+/// Binds every address in `bind` to `port`, failing if one that isn't
+/// optional can't be bound. With port 0, the port picked for the first
+/// address is used for the others.
+pub(crate) async fn listen(bind: &[String], mut port: u16) -> Result<Vec<Arc<TcpListener>>> {
+    let mut listeners = Vec::new();
+    for addr in bind {
+        let addr = addr.clone();
+        // Like in Redis, an address prefixed with "-" is skipped if it isn't
+        // available instead of failing startup.
+        let (optional, addr) = match addr.strip_prefix('-') {
+            Some(addr) => (true, addr.to_string()),
+            None => (false, addr),
+        };
+        // "*" and "::*" stand for every IPv4 and every IPv6 interface.
+        let host = match addr.as_str() {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            addr => addr,
+        };
+        let listener = match TcpListener::bind((host, port)).await {
+            Ok(listener) => listener,
+            Err(e) if optional => {
+                warn!("Skipping bind address {}: {}", addr, e);
+                continue;
+            }
+            Err(e) => bail!("Could not bind to {}:{}: {}", addr, port, e),
+        };
+        if port == 0 {
+            port = listener.local_addr()?.port();
+        }
+        listeners.push(Arc::new(listener));
+    }
+    if listeners.is_empty() {
+        bail!("Could not bind to any of {}", bind.join(" "));
+    }
+    Ok(listeners)
+}
+
+async fn accept(
+    listener: Arc<TcpListener>,
+    redis_server: Redis,
+    io_backend: IoBackend,
+    tls: Option<TlsAcceptor>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = redis_server.halted() => return,
+        };
+        if let Ok((stream, peer)) = accepted {
+            let redis_server = redis_server.clone();
+            match (io_backend, tls.clone()) {
+                // TLS clients are served through tokio whatever the backend,
+                // as rustls does its own buffering.
+                (_, Some(acceptor)) => {
+                    tokio::spawn(serve_client(
+                        stream,
+                        peer,
+                        redis_server,
+                        move |stream| async move {
+                            TlsConnection::accept(&acceptor, stream, peer).await
+                        },
+                    ));
+                }
+                (IoBackend::Tokio, None) => {
+                    tokio::spawn(serve_client(stream, peer, redis_server, |stream| {
+                        std::future::ready(Ok(stream))
+                    }));
+                }
+                #[cfg(target_os = "linux")]
+                (IoBackend::IoUring, None) => {
+                    tokio::task::spawn_local(serve_client(
+                        stream,
+                        peer,
+                        redis_server,
+                        move |stream| {
+                            std::future::ready(crate::redis_io::UringStream::from_tcp(stream, peer))
+                        },
+                    ));
+                }
+                // Refused by ServerBuilder::spawn.
+                #[cfg(not(target_os = "linux"))]
+                (IoBackend::IoUring, None) => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Serves a newly accepted client until it disconnects, unless it's turned
+/// away by protected mode or maxclients. `into_conn` hands the socket over to
+/// the backend, after any TLS handshake.
+async fn serve_client<C, F>(
+    stream: TcpStream,
+    peer: SocketAddr,
+    mut redis_server: Redis,
+    into_conn: impl FnOnce(TcpStream) -> F,
+) where
+    C: Connection,
+    F: Future<Output = std::io::Result<C>>,
+{
+    // Options are set on the socket before it's handed to the backend.
+    let keepalive = redis_server.tcp_keepalive().await;
+    let keepalive = set_keepalive(&stream, keepalive);
+    // Replies are already coalesced into one write per batch of requests, so
+    // there's nothing for Nagle's algorithm to gain by delaying them.
+    let _ = stream.set_nodelay(true);
+    let conn = match into_conn(stream).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!("Error setting up connection from {}: {}", peer, e);
+            return;
+        }
+    };
+    if let Some(err) = redis_server.protected_mode_error(peer).await {
+        let _ = conn.send(err.serialize()).await;
+        return;
+    }
+    let kill = match redis_server.client_connected().await {
+        Ok(kill) => kill,
+        Err(err) => {
+            warn!("Rejected client {}: max number of clients reached", peer);
+            let _ = conn.send(err.serialize()).await;
+            return;
+        }
+    };
+    // Everything logged on behalf of this client carries its id and address.
+    let span = info_span!("client", id = redis_server.client_id(), %peer);
+    async {
+        debug!("Accepted client");
+        if let Err(e) = keepalive {
+            warn!("Error setting TCP keepalive: {:?}", e);
+        }
+        handle_stream(conn, redis_server.clone(), kill).await;
+        redis_server.client_disconnected().await;
+        debug!("Client closed connection");
+    }
+    .instrument(span)
+    .await
+}
+
+/// Turns on TCP keepalive for a client connection, probing every `interval`
+/// seconds once it has been idle for as long, or leaves it off if `interval`
+/// is 0.
+fn set_keepalive(stream: &TcpStream, interval: u64) -> std::io::Result<()> {
+    if interval == 0 {
+        return Ok(());
+    }
+    // Like Redis, the connection is dropped after 3 unanswered probes sent a
+    // third of the interval apart.
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(interval))
+        .with_interval(Duration::from_secs((interval / 3).max(1)))
+        .with_retries(3);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Replies are written out once this much of them is held back, rather
+/// than only at the end of a batch of pipelined requests.
+const MAX_HELD_REPLIES: usize = 64 * 1024;
+
+async fn handle_stream<C: Connection>(conn: C, mut redis_server: Redis, kill: Arc<Notify>) {
+    // Requests may arrive split across reads, so bytes are buffered until a
+    // complete request can be parsed.
+    let mut req = BytesMut::new();
+    // Replies to every request parsed out of a read go out in a single write.
+    let mut out = Vec::new();
+    loop {
+        req.reserve(4096);
+        // A client closed for being idle is closed while waiting for its
+        // next request.
+        let (res, buf) = tokio::select! {
+            read = conn.recv(std::mem::take(&mut req)) => read,
+            _ = kill.notified() => break,
+        };
+        req = buf;
+        match res {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        loop {
+            let args = match redis_resp::parse_request(&mut req) {
+                Ok(Some((args, _))) => args,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Closing client after protocol error: {}", e);
+                    Value::error(format!("ERR {}", e)).serialize_into(&mut out);
+                    let _ = redis_io::flush(&conn, &mut out).await;
+                    return;
+                }
+            };
+            match Command::from_args(&args) {
+                Ok(Some(command)) => {
+                    if !redis_server.execute(&args, command, &conn, &mut out).await {
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => Value::error(e.to_string()).serialize_into(&mut out),
+            }
+            if out.len() >= MAX_HELD_REPLIES && !redis_server.flush_replies(&conn, &mut out).await {
+                return;
+            }
+        }
+        if !redis_server.flush_replies(&conn, &mut out).await {
+            break;
+        }
+    }
+}
@@ -0,0 +1,1455 @@
+use crate::redis_cluster::CLUSTER_SLOTS;
+use crate::redis_commands::{Command, ExpireCondition, RestorePolicy, SetSlot};
+use crate::redis_resp::Value;
+use crate::redis_stream::{NewId, StreamId, Trim};
+use bytes::Bytes;
+use std::{iter::Peekable, slice::Iter, str::FromStr, time::SystemTime};
+use thiserror::Error;
+
+/// The arguments of a request, following the command name.
+type Args<'a> = Peekable<Iter<'a, Bytes>>;
+
+/// Turns the arguments that follow a command's name into a Command.
+type Parser = fn(&mut Args) -> Result<Command, ParseError>;
+
+/// What a command does, as COMMAND INFO reports it. Replication, AOF logging
+/// and maxmemory decide how to treat a command from these.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Flag {
+    /// Modifies the dataset, so it is logged to the AOF and propagated.
+    Write,
+    /// Only reads keys.
+    ReadOnly,
+    /// May grow the dataset, so it is refused once maxmemory is reached.
+    DenyOom,
+    /// Administrative, rather than about the data.
+    Admin,
+    /// Runs in constant or logarithmic time.
+    Fast,
+    /// Can't be called from a function.
+    NoScript,
+}
+
+impl Flag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::DenyOom => "denyoom",
+            Flag::Admin => "admin",
+            Flag::Fast => "fast",
+            Flag::NoScript => "noscript",
+        }
+    }
+}
+
+pub struct CommandSpec {
+    /// The lowercase name. Subcommands are named after their container, as
+    /// in "config|get".
+    pub name: &'static str,
+    /// How many arguments the command takes, counting its name and, for
+    /// subcommands, the container's. A negative arity is a minimum.
+    pub arity: i64,
+    pub flags: &'static [Flag],
+    /// The first key, the last one and the step between them, as positions
+    /// in the arguments. A last key of -1 means keys run to the end. Commands
+    /// whose keys move around, such as SINTERCARD, report 0s, and only
+    /// `Command::keys` knows where their keys are.
+    pub keys: (i64, i64, i64),
+    /// Parses the arguments. Container commands such as CONFIG parse their
+    /// subcommand instead, and only use it when none is given.
+    parse: Option<Parser>,
+    pub subcommands: &'static [CommandSpec],
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+const fn cmd(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [Flag],
+    keys: (i64, i64, i64),
+    parse: Parser,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        keys,
+        parse: Some(parse),
+        subcommands: &[],
+    }
+}
+
+const fn container(
+    name: &'static str,
+    arity: i64,
+    parse: Option<Parser>,
+    subcommands: &'static [CommandSpec],
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags: &[],
+        keys: NO_KEYS,
+        parse,
+        subcommands,
+    }
+}
+
+use Flag::*;
+
+/// Every command the server knows about. The most common ones come first,
+/// as they are looked up for every request.
+pub const COMMANDS: &[CommandSpec] = &[
+    cmd("get", 2, &[ReadOnly, Fast], ONE_KEY, parse_get),
+    cmd("set", -3, &[Write, DenyOom], ONE_KEY, parse_set),
+    cmd("ping", -1, &[Fast], NO_KEYS, parse_ping),
+    cmd("echo", 2, &[Fast], NO_KEYS, parse_echo),
+    cmd("del", -2, &[Write], ALL_KEYS, parse_del),
+    cmd("unlink", -2, &[Write, Fast], ALL_KEYS, parse_unlink),
+    cmd("touch", -2, &[ReadOnly, Fast], ALL_KEYS, parse_touch),
+    cmd("type", 2, &[ReadOnly, Fast], ONE_KEY, parse_type),
+    cmd("keys", 2, &[ReadOnly], NO_KEYS, parse_keys),
+    cmd("expire", -3, &[Write, Fast], ONE_KEY, parse_expire),
+    cmd("pexpire", -3, &[Write, Fast], ONE_KEY, parse_pexpire),
+    cmd("expireat", -3, &[Write, Fast], ONE_KEY, parse_expireat),
+    cmd("pexpireat", -3, &[Write, Fast], ONE_KEY, parse_pexpireat),
+    cmd(
+        "expiretime",
+        2,
+        &[ReadOnly, Fast],
+        ONE_KEY,
+        parse_expiretime,
+    ),
+    cmd(
+        "pexpiretime",
+        2,
+        &[ReadOnly, Fast],
+        ONE_KEY,
+        parse_pexpiretime,
+    ),
+    cmd("rpush", -3, &[Write, DenyOom, Fast], ONE_KEY, parse_rpush),
+    cmd("lrange", 4, &[ReadOnly], ONE_KEY, parse_lrange),
+    cmd("sadd", -3, &[Write, DenyOom, Fast], ONE_KEY, parse_sadd),
+    cmd("srem", -3, &[Write, Fast], ONE_KEY, parse_srem),
+    cmd("spop", -2, &[Write, Fast], ONE_KEY, parse_spop),
+    cmd("smembers", 2, &[ReadOnly], ONE_KEY, parse_smembers),
+    cmd("srandmember", -2, &[ReadOnly], ONE_KEY, parse_srandmember),
+    cmd(
+        "smismember",
+        -3,
+        &[ReadOnly, Fast],
+        ONE_KEY,
+        parse_smismember,
+    ),
+    cmd("sintercard", -3, &[ReadOnly], NO_KEYS, parse_sintercard),
+    cmd("hset", -4, &[Write, DenyOom, Fast], ONE_KEY, parse_hset),
+    cmd("hgetall", 2, &[ReadOnly], ONE_KEY, parse_hgetall),
+    cmd("hrandfield", -2, &[ReadOnly], ONE_KEY, parse_hrandfield),
+    cmd("zadd", -4, &[Write, DenyOom, Fast], ONE_KEY, parse_zadd),
+    cmd("zrange", -4, &[ReadOnly], ONE_KEY, parse_zrange),
+    cmd("zrandmember", -2, &[ReadOnly], ONE_KEY, parse_zrandmember),
+    cmd("sort", -2, &[Write, DenyOom], ONE_KEY, parse_sort),
+    cmd("xadd", -5, &[Write, DenyOom, Fast], ONE_KEY, parse_xadd),
+    cmd("xlen", 2, &[ReadOnly, Fast], ONE_KEY, parse_xlen),
+    cmd("xrange", -4, &[ReadOnly], ONE_KEY, parse_xrange),
+    cmd("xreadgroup", -7, &[Write], NO_KEYS, parse_xreadgroup),
+    cmd("xack", -4, &[Write, Fast], ONE_KEY, parse_xack),
+    cmd("dump", 2, &[ReadOnly], ONE_KEY, parse_dump),
+    cmd("restore", -4, &[Write, DenyOom], ONE_KEY, parse_restore),
+    cmd("migrate", -6, &[Write], (3, 3, 1), parse_migrate),
+    cmd("fcall", -3, &[NoScript], NO_KEYS, parse_fcall),
+    cmd(
+        "fcall_ro",
+        -3,
+        &[ReadOnly, NoScript],
+        NO_KEYS,
+        parse_fcall_ro,
+    ),
+    cmd("info", -1, &[], NO_KEYS, parse_info),
+    cmd("save", 1, &[Admin, NoScript], NO_KEYS, |_| {
+        Ok(Command::Save)
+    }),
+    cmd("bgsave", -1, &[Admin, NoScript], NO_KEYS, parse_bgsave),
+    cmd("bgrewriteaof", 1, &[Admin, NoScript], NO_KEYS, |_| {
+        Ok(Command::BgRewriteAof)
+    }),
+    cmd("shutdown", -1, &[Admin, NoScript], NO_KEYS, parse_shutdown),
+    cmd("replconf", -1, &[Admin, NoScript], NO_KEYS, parse_replconf),
+    cmd("psync", -3, &[Admin, NoScript], NO_KEYS, parse_psync),
+    cmd("replicaof", 3, &[Admin, NoScript], NO_KEYS, parse_replicaof),
+    cmd("slaveof", 3, &[Admin, NoScript], NO_KEYS, parse_replicaof),
+    cmd("wait", 3, &[NoScript], NO_KEYS, parse_wait),
+    cmd("asking", 1, &[Fast], NO_KEYS, |_| Ok(Command::Asking)),
+    cmd("failover", -1, &[Admin, NoScript], NO_KEYS, parse_failover),
+    cmd("reset", 1, &[Fast, NoScript], NO_KEYS, |_| {
+        Ok(Command::Reset)
+    }),
+    container(
+        "function",
+        -2,
+        None,
+        &[
+            cmd(
+                "function|load",
+                -3,
+                &[Write, DenyOom, NoScript],
+                NO_KEYS,
+                parse_function_load,
+            ),
+            cmd("function|delete", 3, &[Write, NoScript], NO_KEYS, |args| {
+                Ok(Command::FunctionDelete(next_string(args)?))
+            }),
+            cmd(
+                "function|flush",
+                -2,
+                &[Write, NoScript],
+                NO_KEYS,
+                parse_function_flush,
+            ),
+            cmd(
+                "function|list",
+                -2,
+                &[NoScript],
+                NO_KEYS,
+                parse_function_list,
+            ),
+            cmd("function|dump", 2, &[NoScript], NO_KEYS, |_| {
+                Ok(Command::FunctionDump)
+            }),
+            cmd("function|kill", 2, &[NoScript], NO_KEYS, |_| {
+                Ok(Command::FunctionKill)
+            }),
+            cmd(
+                "function|restore",
+                -3,
+                &[Write, DenyOom, NoScript],
+                NO_KEYS,
+                parse_function_restore,
+            ),
+        ],
+    ),
+    container(
+        "client",
+        -2,
+        None,
+        &[
+            cmd(
+                "client|pause",
+                -3,
+                &[Admin, NoScript],
+                NO_KEYS,
+                parse_client_pause,
+            ),
+            cmd("client|unpause", 2, &[Admin, NoScript], NO_KEYS, |_| {
+                Ok(Command::ClientUnpause)
+            }),
+            cmd("client|id", 2, &[Fast, NoScript], NO_KEYS, |_| {
+                Ok(Command::ClientId)
+            }),
+            cmd(
+                "client|setname",
+                3,
+                &[Fast, NoScript],
+                NO_KEYS,
+                parse_client_setname,
+            ),
+            cmd("client|getname", 2, &[Fast, NoScript], NO_KEYS, |_| {
+                Ok(Command::ClientGetName)
+            }),
+        ],
+    ),
+    container(
+        "config",
+        -2,
+        None,
+        &[
+            cmd(
+                "config|get",
+                -3,
+                &[Admin, NoScript],
+                NO_KEYS,
+                parse_config_get,
+            ),
+            cmd(
+                "config|set",
+                -4,
+                &[Admin, NoScript],
+                NO_KEYS,
+                parse_config_set,
+            ),
+            cmd("config|resetstat", 2, &[Admin, NoScript], NO_KEYS, |_| {
+                Ok(Command::ConfigResetStat)
+            }),
+        ],
+    ),
+    container(
+        "cluster",
+        -2,
+        None,
+        &[
+            cmd("cluster|info", 2, &[], NO_KEYS, |_| {
+                Ok(Command::ClusterInfo)
+            }),
+            cmd("cluster|myid", 2, &[], NO_KEYS, |_| {
+                Ok(Command::ClusterMyId)
+            }),
+            cmd("cluster|nodes", 2, &[], NO_KEYS, |_| {
+                Ok(Command::ClusterNodes)
+            }),
+            cmd("cluster|slots", 2, &[], NO_KEYS, |_| {
+                Ok(Command::ClusterSlots)
+            }),
+            cmd("cluster|shards", 2, &[], NO_KEYS, |_| {
+                Ok(Command::ClusterShards)
+            }),
+            cmd("cluster|meet", -4, &[Admin], NO_KEYS, parse_cluster_meet),
+            cmd("cluster|hello", 3, &[Admin], NO_KEYS, parse_cluster_hello),
+            cmd(
+                "cluster|setslot",
+                -4,
+                &[Admin],
+                NO_KEYS,
+                parse_cluster_setslot,
+            ),
+        ],
+    ),
+    container(
+        "object",
+        -2,
+        None,
+        &[
+            cmd("object|encoding", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::ObjectEncoding(next_arg(args)?))
+            }),
+            cmd("object|idletime", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::ObjectIdleTime(next_arg(args)?))
+            }),
+            cmd("object|refcount", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::ObjectRefCount(next_arg(args)?))
+            }),
+            cmd("object|freq", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::ObjectFreq(next_arg(args)?))
+            }),
+        ],
+    ),
+    container(
+        "xgroup",
+        -2,
+        None,
+        &[
+            cmd(
+                "xgroup|create",
+                -5,
+                &[Write, DenyOom],
+                (2, 2, 1),
+                parse_xgroup_create,
+            ),
+            cmd(
+                "xgroup|createconsumer",
+                5,
+                &[Write, DenyOom],
+                (2, 2, 1),
+                |args| {
+                    let (key, group) = (next_arg(args)?, next_arg(args)?);
+                    Ok(Command::XGroupCreateConsumer(key, group, next_arg(args)?))
+                },
+            ),
+            cmd("xgroup|delconsumer", 5, &[Write], (2, 2, 1), |args| {
+                let (key, group) = (next_arg(args)?, next_arg(args)?);
+                Ok(Command::XGroupDelConsumer(key, group, next_arg(args)?))
+            }),
+            cmd("xgroup|destroy", 4, &[Write], (2, 2, 1), |args| {
+                Ok(Command::XGroupDestroy(next_arg(args)?, next_arg(args)?))
+            }),
+        ],
+    ),
+    container(
+        "xinfo",
+        -2,
+        None,
+        &[
+            cmd("xinfo|stream", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::XInfoStream(next_arg(args)?))
+            }),
+            cmd("xinfo|groups", 3, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::XInfoGroups(next_arg(args)?))
+            }),
+            cmd("xinfo|consumers", 4, &[ReadOnly], (2, 2, 1), |args| {
+                Ok(Command::XInfoConsumers(next_arg(args)?, next_arg(args)?))
+            }),
+        ],
+    ),
+    container(
+        "memory",
+        -2,
+        None,
+        &[
+            cmd(
+                "memory|usage",
+                -3,
+                &[ReadOnly],
+                (2, 2, 1),
+                parse_memory_usage,
+            ),
+            cmd("memory|stats", 2, &[], NO_KEYS, |_| {
+                Ok(Command::MemoryStats)
+            }),
+            cmd("memory|doctor", 2, &[], NO_KEYS, |_| {
+                Ok(Command::MemoryDoctor)
+            }),
+        ],
+    ),
+    container(
+        "slowlog",
+        -2,
+        None,
+        &[
+            cmd("slowlog|get", -2, &[Admin], NO_KEYS, parse_slowlog_get),
+            cmd("slowlog|len", 2, &[Admin], NO_KEYS, |_| {
+                Ok(Command::SlowLogLen)
+            }),
+            cmd("slowlog|reset", 2, &[Admin], NO_KEYS, |_| {
+                Ok(Command::SlowLogReset)
+            }),
+        ],
+    ),
+    container(
+        "latency",
+        -2,
+        None,
+        &[
+            cmd("latency|latest", 2, &[Admin, NoScript], NO_KEYS, |_| {
+                Ok(Command::LatencyLatest)
+            }),
+            cmd("latency|history", 3, &[Admin, NoScript], NO_KEYS, |args| {
+                Ok(Command::LatencyHistory(next_string(args)?))
+            }),
+            cmd("latency|reset", -2, &[Admin, NoScript], NO_KEYS, |args| {
+                Ok(Command::LatencyReset(remaining_strings(args)?))
+            }),
+            cmd("latency|doctor", 2, &[Admin, NoScript], NO_KEYS, |_| {
+                Ok(Command::LatencyDoctor)
+            }),
+        ],
+    ),
+    container(
+        "debug",
+        -2,
+        None,
+        &[
+            cmd(
+                "debug|sleep",
+                3,
+                &[Admin, NoScript],
+                NO_KEYS,
+                parse_debug_sleep,
+            ),
+            cmd("debug|object", 3, &[Admin, NoScript], NO_KEYS, |args| {
+                Ok(Command::DebugObject(next_arg(args)?))
+            }),
+            cmd(
+                "debug|set-active-expire",
+                3,
+                &[Admin, NoScript],
+                NO_KEYS,
+                |args| Ok(Command::DebugSetActiveExpire(next_string(args)? != "0")),
+            ),
+            cmd(
+                "debug|change-repl-id",
+                2,
+                &[Admin, NoScript],
+                NO_KEYS,
+                |_| Ok(Command::DebugChangeReplId),
+            ),
+        ],
+    ),
+    // COMMAND on its own describes every command.
+    container(
+        "command",
+        -1,
+        Some(parse_command),
+        &[
+            cmd("command|count", 2, &[], NO_KEYS, |_| {
+                Ok(Command::CommandCount)
+            }),
+            cmd("command|list", 2, &[], NO_KEYS, |_| {
+                Ok(Command::CommandList)
+            }),
+            cmd("command|info", -2, &[], NO_KEYS, |args| {
+                Ok(Command::CommandInfo(remaining_strings(args)?))
+            }),
+        ],
+    ),
+];
+
+/// Finds a command by name, ignoring case. Subcommands are found by their
+/// full name, such as "config|get".
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    match name.split_once('|') {
+        Some((container, subcommand)) => find(COMMANDS, container)?.subcommand(subcommand),
+        None => find(COMMANDS, name),
+    }
+}
+
+/// The entry of the command a request invokes, going by the names it starts
+/// with. Commands are rewritten as they are parsed, EXPIRE into PEXPIREAT
+/// for one, so this is what per-command stats are kept under.
+pub fn invoked(req: &[Bytes]) -> Option<&'static CommandSpec> {
+    let spec = find(COMMANDS, &String::from_utf8_lossy(req.first()?))?;
+    match req.get(1) {
+        Some(subcommand) if !spec.subcommands.is_empty() => {
+            spec.subcommand(&String::from_utf8_lossy(subcommand))
+        }
+        _ => Some(spec),
+    }
+}
+
+fn find(specs: &'static [CommandSpec], name: &str) -> Option<&'static CommandSpec> {
+    specs
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+impl CommandSpec {
+    pub fn has(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    fn subcommand(&self, name: &str) -> Option<&'static CommandSpec> {
+        self.subcommands.iter().find(|spec| {
+            spec.name
+                .split_once('|')
+                .is_some_and(|(_, subcommand)| subcommand.eq_ignore_ascii_case(name))
+        })
+    }
+
+    fn arity_matches(&self, argc: usize) -> bool {
+        match self.arity >= 0 {
+            true => argc as i64 == self.arity,
+            false => argc as i64 >= -self.arity,
+        }
+    }
+
+    /// The command's entry in the COMMAND and COMMAND INFO replies. There are
+    /// no ACL categories, tips or key specs to report.
+    pub fn info(&self) -> Value {
+        let (first_key, last_key, step) = self.keys;
+        Value::Array(vec![
+            Value::bulk(self.name),
+            Value::Integer(self.arity),
+            Value::Array(
+                self.flags
+                    .iter()
+                    .map(|flag| Value::SimpleString(flag.as_str().to_string()))
+                    .collect(),
+            ),
+            Value::Integer(first_key),
+            Value::Integer(last_key),
+            Value::Integer(step),
+            Value::Array(vec![]),
+            Value::Array(vec![]),
+            Value::Array(vec![]),
+            Value::Array(self.subcommands.iter().map(CommandSpec::info).collect()),
+        ])
+    }
+}
+
+/// Why a request couldn't be turned into a command. The message is the
+/// error reply sent back for it.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR unknown subcommand '{1}'. Try {0} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(&'static str),
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR value is not a valid float")]
+    NotAFloat,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(&'static str),
+    #[error("ERR numkeys should be greater than 0")]
+    NoKeys,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR Invalid or out of range slot")]
+    InvalidSlot,
+    #[error("ERR timeout is not an integer or out of range")]
+    InvalidTimeout,
+    #[error("ERR Client names cannot contain spaces, newlines or special characters.")]
+    InvalidClientName,
+    #[error("ERR Number of keys can't be negative")]
+    NegativeNumKeys,
+    #[error("ERR Number of keys can't be greater than number of args")]
+    TooManyKeys,
+    #[error("ERR Invalid stream ID specified as stream command argument")]
+    InvalidStreamId,
+    #[error(
+        "ERR Unbalanced '{0}' list of streams: for each stream key an ID or '>' must be specified."
+    )]
+    UnbalancedStreams(&'static str),
+}
+
+/// Parses a request's arguments into a command. An empty request, such as a
+/// blank inline line, gives None.
+pub fn parse(req: &[Bytes]) -> Result<Option<Command>, ParseError> {
+    let mut args = req.iter().peekable();
+    // Only the error message needs the arguments as text, and a name that
+    // isn't text can't be a command anyway.
+    let name = match args.next() {
+        Some(name) => String::from_utf8_lossy(name),
+        None => return Ok(None),
+    };
+    let mut spec = find(COMMANDS, &name).ok_or_else(|| {
+        let preview = req[1..]
+            .iter()
+            .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+            .collect::<String>();
+        ParseError::UnknownCommand(name.to_string(), preview)
+    })?;
+    if !spec.subcommands.is_empty() && args.peek().is_some() {
+        let subcommand = next_string(&mut args)?;
+        spec = spec
+            .subcommand(&subcommand)
+            .ok_or_else(|| ParseError::UnknownSubcommand(name.to_uppercase(), subcommand))?;
+    }
+    let parse = match spec.parse {
+        Some(parse) if spec.arity_matches(req.len()) => parse,
+        _ => return Err(ParseError::WrongArity(spec.name)),
+    };
+    let command = parse(&mut args)?;
+    // Every argument has to be understood.
+    if args.next().is_some() {
+        return Err(ParseError::Syntax);
+    }
+    Ok(Some(command))
+}
+
+fn parse_command(_: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::CommandInfo(vec![]))
+}
+
+/// PING [message]. The message is accepted but PONG is always the reply.
+fn parse_ping(args: &mut Args) -> Result<Command, ParseError> {
+    let _ = args.next();
+    Ok(Command::Ping)
+}
+
+/// BGSAVE [SCHEDULE]. A save is always started straight away.
+fn parse_bgsave(args: &mut Args) -> Result<Command, ParseError> {
+    match optional_string(args)? {
+        Some(arg) if !arg.eq_ignore_ascii_case("SCHEDULE") => Err(ParseError::Syntax),
+        _ => Ok(Command::BgSave),
+    }
+}
+
+fn parse_get(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Get(next_arg(args)?))
+}
+
+fn parse_set(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let value = next_arg(args)?;
+    let mut exp: Option<SystemTime> = None;
+    let mut keep_ttl = false;
+    // The last of PX, PXAT and KEEPTTL wins.
+    while let Some(next_str) = args.peek().map(|arg| as_str(arg)).transpose()? {
+        if next_str.eq_ignore_ascii_case("PX") || next_str.eq_ignore_ascii_case("PXAT") {
+            let _ = next_string(args)?;
+            let ms = next_int::<i64>(args)?;
+            if ms <= 0 {
+                return Err(ParseError::InvalidExpireTime("set"));
+            }
+            let base = match next_str.eq_ignore_ascii_case("PX") {
+                true => SystemTime::now(),
+                false => SystemTime::UNIX_EPOCH,
+            };
+            exp = Some(
+                base.checked_add(std::time::Duration::from_millis(ms as u64))
+                    .ok_or(ParseError::InvalidExpireTime("set"))?,
+            );
+            keep_ttl = false;
+        } else if next_str.eq_ignore_ascii_case("KEEPTTL") {
+            let _ = next_string(args)?;
+            exp = None;
+            keep_ttl = true;
+        } else {
+            break;
+        }
+    }
+    Ok(Command::Set(key, value, exp, keep_ttl))
+}
+
+fn parse_echo(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Echo(next_arg(args)?))
+}
+
+fn parse_del(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Del(remaining_args(args)))
+}
+
+fn parse_unlink(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Unlink(remaining_args(args)))
+}
+
+fn parse_touch(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Touch(remaining_args(args)))
+}
+
+fn parse_type(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Type(next_arg(args)?))
+}
+
+fn parse_keys(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Keys(next_arg(args)?))
+}
+
+fn parse_expire(args: &mut Args) -> Result<Command, ParseError> {
+    parse_expiry(args, "expire")
+}
+
+fn parse_pexpire(args: &mut Args) -> Result<Command, ParseError> {
+    parse_expiry(args, "pexpire")
+}
+
+fn parse_expireat(args: &mut Args) -> Result<Command, ParseError> {
+    parse_expiry(args, "expireat")
+}
+
+fn parse_pexpireat(args: &mut Args) -> Result<Command, ParseError> {
+    parse_expiry(args, "pexpireat")
+}
+
+/// EXPIRE and its variants: the ones starting with P take milliseconds, and
+/// the ones ending with AT a unix time.
+fn parse_expiry(args: &mut Args, name: &'static str) -> Result<Command, ParseError> {
+    let millis = name.starts_with('p');
+    let absolute = name.ends_with("at");
+    let key = next_arg(args)?;
+    let time = next_int::<i64>(args)?;
+    let mut conditions = Vec::new();
+    for flag in remaining_strings(args)? {
+        let condition = match flag.to_ascii_uppercase().as_str() {
+            "NX" => ExpireCondition::Nx,
+            "XX" => ExpireCondition::Xx,
+            "GT" => ExpireCondition::Gt,
+            "LT" => ExpireCondition::Lt,
+            _ => return Err(ParseError::Syntax),
+        };
+        if !conditions.contains(&condition) {
+            conditions.push(condition);
+        }
+    }
+    // Negative times are allowed, and expire the key straight away.
+    let base = match absolute {
+        true => SystemTime::UNIX_EPOCH,
+        false => SystemTime::now(),
+    };
+    let offset = std::time::Duration::from_millis(time.unsigned_abs().saturating_mul(if millis {
+        1
+    } else {
+        1000
+    }));
+    let exp = match time < 0 {
+        true => base.checked_sub(offset).unwrap_or(SystemTime::UNIX_EPOCH),
+        false => base
+            .checked_add(offset)
+            .ok_or(ParseError::InvalidExpireTime(name))?,
+    };
+    Ok(Command::PExpireAt(key, exp, conditions))
+}
+
+fn parse_expiretime(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::ExpireTime(next_arg(args)?, false))
+}
+
+fn parse_pexpiretime(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::ExpireTime(next_arg(args)?, true))
+}
+
+fn parse_rpush(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    Ok(Command::RPush(key, remaining_args(args)))
+}
+
+fn parse_lrange(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let start = next_int(args)?;
+    let stop = next_int(args)?;
+    Ok(Command::LRange(key, start, stop))
+}
+
+fn parse_sadd(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    Ok(Command::SAdd(key, remaining_args(args)))
+}
+
+fn parse_srem(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    Ok(Command::SRem(key, remaining_args(args)))
+}
+
+fn parse_spop(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let count = optional_int(args)?;
+    Ok(Command::SPop(key, count))
+}
+
+fn parse_smembers(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::SMembers(next_arg(args)?))
+}
+
+fn parse_srandmember(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let count = optional_int(args)?;
+    Ok(Command::SRandMember(key, count))
+}
+
+fn parse_smismember(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    Ok(Command::SMIsMember(key, remaining_args(args)))
+}
+
+fn parse_sintercard(args: &mut Args) -> Result<Command, ParseError> {
+    let numkeys = next_int::<usize>(args)?;
+    if numkeys == 0 {
+        return Err(ParseError::NoKeys);
+    }
+    let keys = (0..numkeys)
+        .map(|_| next_arg(args))
+        .collect::<Result<_, _>>()?;
+    let mut limit = 0;
+    if let Some(arg) = optional_string(args)? {
+        if !arg.eq_ignore_ascii_case("LIMIT") {
+            return Err(ParseError::Syntax);
+        }
+        limit = next_int(args)?;
+    }
+    Ok(Command::SInterCard(keys, limit))
+}
+
+fn parse_hset(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let args = remaining_args(args);
+    if args.len() % 2 == 1 {
+        return Err(ParseError::WrongArity("hset"));
+    }
+    let fields = args
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::HSet(key, fields))
+}
+
+fn parse_hgetall(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::HGetAll(next_arg(args)?))
+}
+
+fn parse_hrandfield(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let count = optional_int(args)?;
+    let with_values = optional_flag(args, "WITHVALUES")?;
+    Ok(Command::HRandField(key, count, with_values))
+}
+
+fn parse_zadd(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let args = remaining_args(args);
+    if args.len() % 2 == 1 {
+        return Err(ParseError::Syntax);
+    }
+    let members = args
+        .chunks(2)
+        .map(
+            |pair| match std::str::from_utf8(&pair[0]).map(str::parse::<f64>) {
+                Ok(Ok(score)) if !score.is_nan() => Ok((score, pair[1].clone())),
+                _ => Err(ParseError::NotAFloat),
+            },
+        )
+        .collect::<Result<_, _>>()?;
+    Ok(Command::ZAdd(key, members))
+}
+
+fn parse_zrange(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let start = next_int(args)?;
+    let stop = next_int(args)?;
+    let with_scores = optional_flag(args, "WITHSCORES")?;
+    Ok(Command::ZRange(key, start, stop, with_scores))
+}
+
+fn parse_zrandmember(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let count = optional_int(args)?;
+    let with_scores = optional_flag(args, "WITHSCORES")?;
+    Ok(Command::ZRandMember(key, count, with_scores))
+}
+
+fn parse_sort(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let mut by = None;
+    let mut limit = None;
+    let mut get = Vec::new();
+    let mut desc = false;
+    let mut alpha = false;
+    let mut store = None;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "BY" => by = Some(next_arg(args)?),
+            "LIMIT" => limit = Some((next_int(args)?, next_int(args)?)),
+            "GET" => get.push(next_arg(args)?),
+            "ASC" => desc = false,
+            "DESC" => desc = true,
+            "ALPHA" => alpha = true,
+            "STORE" => store = Some(next_arg(args)?),
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::Sort {
+        key,
+        by,
+        limit,
+        get,
+        desc,
+        alpha,
+        store,
+    })
+}
+
+/// XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] id
+/// field value [field value ...]. LIMIT only bounds approximate trimming,
+/// and trimming is always exact here, so it's accepted and ignored.
+fn parse_xadd(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let mut no_mkstream = false;
+    let mut trim = None;
+    let id = loop {
+        let arg = next_string(args)?;
+        match arg.to_uppercase().as_str() {
+            "NOMKSTREAM" => no_mkstream = true,
+            option @ ("MAXLEN" | "MINID") => {
+                if matches!(args.peek().map(|arg| arg.as_ref()), Some(b"=" | b"~")) {
+                    let _ = args.next();
+                }
+                trim = Some(match option {
+                    "MAXLEN" => Trim::MaxLen(next_int(args)?),
+                    _ => Trim::MinId(
+                        StreamId::parse(&next_string(args)?, 0)
+                            .ok_or(ParseError::InvalidStreamId)?,
+                    ),
+                });
+                if args
+                    .peek()
+                    .is_some_and(|arg| arg.eq_ignore_ascii_case(b"LIMIT"))
+                {
+                    let _ = args.next();
+                    let _ = next_int::<u64>(args)?;
+                }
+            }
+            _ => break parse_new_stream_id(&arg)?,
+        }
+    };
+    let fields = remaining_args(args);
+    if fields.is_empty() || fields.len() % 2 == 1 {
+        return Err(ParseError::WrongArity("xadd"));
+    }
+    let fields = fields
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::XAdd {
+        key,
+        id,
+        fields,
+        no_mkstream,
+        trim,
+    })
+}
+
+/// The ID XADD is given: `*`, `ms-*`, or an explicit one where a missing
+/// sequence number is 0.
+fn parse_new_stream_id(id: &str) -> Result<NewId, ParseError> {
+    if id == "*" {
+        return Ok(NewId::Auto);
+    }
+    if let Some(ms) = id.strip_suffix("-*") {
+        return ms
+            .parse()
+            .map(NewId::AutoSeq)
+            .map_err(|_| ParseError::InvalidStreamId);
+    }
+    StreamId::parse(id, 0)
+        .map(NewId::Explicit)
+        .ok_or(ParseError::InvalidStreamId)
+}
+
+fn parse_xlen(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::XLen(next_arg(args)?))
+}
+
+/// XRANGE key start end [COUNT count].
+fn parse_xrange(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let start = parse_range_bound(&next_string(args)?, 0, StreamId::next)?;
+    let end = parse_range_bound(&next_string(args)?, u64::MAX, StreamId::prev)?;
+    let count = match optional_string(args)? {
+        Some(arg) if arg.eq_ignore_ascii_case("COUNT") => {
+            Some(next_int::<i64>(args)?.max(0) as usize)
+        }
+        Some(_) => return Err(ParseError::Syntax),
+        None => None,
+    };
+    Ok(Command::XRange(key, start.zip(end), count))
+}
+
+/// A bound of XRANGE: `-` or `+` for the smallest or greatest ID, an ID
+/// where a missing sequence number is `seq`, or one of those after `(` to
+/// leave it out of the range. An exclusive bound past the last possible ID
+/// leaves nothing in the range, which is None.
+fn parse_range_bound(
+    bound: &str,
+    seq: u64,
+    exclude: fn(StreamId) -> Option<StreamId>,
+) -> Result<Option<StreamId>, ParseError> {
+    let (bound, exclusive) = match bound.strip_prefix('(') {
+        Some(bound) => (bound, true),
+        None => (bound, false),
+    };
+    let id = match bound {
+        "-" => StreamId::MIN,
+        "+" => StreamId::MAX,
+        _ => StreamId::parse(bound, seq).ok_or(ParseError::InvalidStreamId)?,
+    };
+    Ok(match exclusive {
+        true => exclude(id),
+        false => Some(id),
+    })
+}
+
+/// XGROUP CREATE key group id|$ [MKSTREAM] [ENTRIESREAD entries-read].
+fn parse_xgroup_create(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let group = next_arg(args)?;
+    let start = match next_string(args)?.as_str() {
+        "$" => None,
+        id => Some(StreamId::parse(id, 0).ok_or(ParseError::InvalidStreamId)?),
+    };
+    let mut mkstream = false;
+    let mut entries_read = None;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "MKSTREAM" => mkstream = true,
+            "ENTRIESREAD" => entries_read = Some(next_int(args)?),
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::XGroupCreate {
+        key,
+        group,
+        start,
+        mkstream,
+        entries_read,
+    })
+}
+
+/// XREADGROUP GROUP group consumer [COUNT count] [NOACK] STREAMS key
+/// [key ...] id [id ...]. Reads never block, so BLOCK isn't accepted, and a
+/// count that isn't positive reads every entry.
+fn parse_xreadgroup(args: &mut Args) -> Result<Command, ParseError> {
+    if !next_string(args)?.eq_ignore_ascii_case("GROUP") {
+        return Err(ParseError::Syntax);
+    }
+    let group = next_arg(args)?;
+    let consumer = next_arg(args)?;
+    let mut count = None;
+    let mut no_ack = false;
+    loop {
+        match next_string(args)?.to_uppercase().as_str() {
+            "COUNT" => count = Some(next_int::<i64>(args)?).filter(|count| *count > 0),
+            "NOACK" => no_ack = true,
+            "STREAMS" => break,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    let rest = remaining_args(args);
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err(ParseError::UnbalancedStreams("xreadgroup"));
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let streams = keys
+        .iter()
+        .zip(ids)
+        .map(|(key, id)| match id.as_ref() {
+            b">" => Ok((key.clone(), None)),
+            id => std::str::from_utf8(id)
+                .ok()
+                .and_then(|id| StreamId::parse(id, 0))
+                .map(|id| (key.clone(), Some(id)))
+                .ok_or(ParseError::InvalidStreamId),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Command::XReadGroup {
+        group,
+        consumer,
+        count: count.map(|count| count as usize),
+        no_ack,
+        streams,
+    })
+}
+
+/// XACK key group id [id ...].
+fn parse_xack(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let group = next_arg(args)?;
+    let ids = remaining_strings(args)?
+        .iter()
+        .map(|id| StreamId::parse(id, 0).ok_or(ParseError::InvalidStreamId))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::XAck(key, group, ids))
+}
+
+fn parse_dump(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Dump(next_arg(args)?))
+}
+
+fn parse_restore(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let ttl = next_int(args)?;
+    let payload = next_bytes(args)?.to_vec();
+    let mut replace = false;
+    let mut absttl = false;
+    for arg in remaining_strings(args)? {
+        match arg.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::Restore(key, ttl, payload, replace, absttl))
+}
+
+fn parse_migrate(args: &mut Args) -> Result<Command, ParseError> {
+    let host = next_string(args)?;
+    let port = next_string(args)?;
+    let key = next_arg(args)?;
+    let db = next_int(args)?;
+    let timeout = next_int(args)?;
+    let mut keys = vec![key];
+    let mut copy = false;
+    let mut replace = false;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "KEYS" => keys = remaining_args(args),
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::Migrate {
+        host,
+        port,
+        keys,
+        db,
+        timeout,
+        copy,
+        replace,
+    })
+}
+
+fn parse_info(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::Info(remaining_strings(args)?))
+}
+
+fn parse_shutdown(args: &mut Args) -> Result<Command, ParseError> {
+    let mut save = None;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "SAVE" => save = Some(true),
+            "NOSAVE" => save = Some(false),
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::Shutdown(save))
+}
+
+fn parse_replconf(args: &mut Args) -> Result<Command, ParseError> {
+    let mut options = Vec::new();
+    while let Some(key) = optional_string(args)? {
+        let val = next_string(args)?;
+        options.push((key.to_lowercase(), val));
+    }
+    Ok(Command::ReplConf(options))
+}
+
+fn parse_psync(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_string(args)?;
+    let val = next_string(args)?;
+    Ok(Command::Psync(key, val))
+}
+
+fn parse_replicaof(args: &mut Args) -> Result<Command, ParseError> {
+    let host = next_string(args)?;
+    let port = next_string(args)?;
+    if host.eq_ignore_ascii_case("NO") && port.eq_ignore_ascii_case("ONE") {
+        Ok(Command::ReplicaOf(None))
+    } else {
+        Ok(Command::ReplicaOf(Some((host, port))))
+    }
+}
+
+fn parse_wait(args: &mut Args) -> Result<Command, ParseError> {
+    let numreplicas = next_int(args)?;
+    let timeout = next_int(args)?;
+    Ok(Command::Wait(numreplicas, timeout))
+}
+
+fn parse_failover(args: &mut Args) -> Result<Command, ParseError> {
+    let mut to = None;
+    let mut timeout = 0;
+    let mut abort = false;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "TO" => to = Some((next_string(args)?, next_string(args)?)),
+            "TIMEOUT" => timeout = next_int(args)?,
+            "ABORT" => abort = true,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::Failover { to, timeout, abort })
+}
+
+fn parse_client_pause(args: &mut Args) -> Result<Command, ParseError> {
+    let timeout = next_int(args).map_err(|_| ParseError::InvalidTimeout)?;
+    let write_only = match optional_string(args)?.map(|mode| mode.to_uppercase()) {
+        None => false,
+        Some(mode) if mode == "ALL" => false,
+        Some(mode) if mode == "WRITE" => true,
+        Some(_) => return Err(ParseError::Syntax),
+    };
+    if args.peek().is_some() {
+        return Err(ParseError::Syntax);
+    }
+    Ok(Command::ClientPause(timeout, write_only))
+}
+
+/// An empty name clears the connection's name.
+fn parse_client_setname(args: &mut Args) -> Result<Command, ParseError> {
+    let name = next_string(args)?;
+    if !name.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(ParseError::InvalidClientName);
+    }
+    Ok(Command::ClientSetName(name))
+}
+
+fn parse_function_load(args: &mut Args) -> Result<Command, ParseError> {
+    let mut code = next_arg(args)?;
+    let replace = code.eq_ignore_ascii_case(b"REPLACE") && args.peek().is_some();
+    if replace {
+        code = next_arg(args)?;
+    }
+    Ok(Command::FunctionLoad(code, replace))
+}
+
+/// FUNCTION FLUSH [ASYNC|SYNC]. Libraries are always freed straight away.
+fn parse_function_flush(args: &mut Args) -> Result<Command, ParseError> {
+    match optional_string(args)?.map(|mode| mode.to_uppercase()) {
+        None => Ok(Command::FunctionFlush),
+        Some(mode) if mode == "ASYNC" || mode == "SYNC" => Ok(Command::FunctionFlush),
+        Some(_) => Err(ParseError::Syntax),
+    }
+}
+
+fn parse_function_list(args: &mut Args) -> Result<Command, ParseError> {
+    let mut pattern = None;
+    let mut with_code = false;
+    while let Some(arg) = optional_string(args)? {
+        match arg.to_uppercase().as_str() {
+            "LIBRARYNAME" => pattern = Some(next_string(args)?),
+            "WITHCODE" => with_code = true,
+            _ => return Err(ParseError::Syntax),
+        }
+    }
+    Ok(Command::FunctionList { pattern, with_code })
+}
+
+fn parse_function_restore(args: &mut Args) -> Result<Command, ParseError> {
+    let payload = next_bytes(args)?.to_vec();
+    let policy = match optional_string(args)?.map(|policy| policy.to_uppercase()) {
+        None => RestorePolicy::Append,
+        Some(policy) if policy == "APPEND" => RestorePolicy::Append,
+        Some(policy) if policy == "REPLACE" => RestorePolicy::Replace,
+        Some(policy) if policy == "FLUSH" => RestorePolicy::Flush,
+        Some(_) => return Err(ParseError::Syntax),
+    };
+    Ok(Command::FunctionRestore(payload, policy))
+}
+
+fn parse_fcall(args: &mut Args) -> Result<Command, ParseError> {
+    parse_function_call(args, false)
+}
+
+fn parse_fcall_ro(args: &mut Args) -> Result<Command, ParseError> {
+    parse_function_call(args, true)
+}
+
+/// FCALL and FCALL_RO: the function's name, then numkeys keys followed by
+/// the rest of the arguments.
+fn parse_function_call(args: &mut Args, read_only: bool) -> Result<Command, ParseError> {
+    let function = next_string(args)?;
+    let numkeys = next_int::<i64>(args)?;
+    if numkeys < 0 {
+        return Err(ParseError::NegativeNumKeys);
+    }
+    let mut rest = remaining_args(args);
+    if numkeys as usize > rest.len() {
+        return Err(ParseError::TooManyKeys);
+    }
+    let args = rest.split_off(numkeys as usize);
+    Ok(Command::FCall {
+        function,
+        keys: rest,
+        args,
+        read_only,
+    })
+}
+
+fn parse_config_get(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::ConfigGet(remaining_strings(args)?))
+}
+
+fn parse_config_set(args: &mut Args) -> Result<Command, ParseError> {
+    let mut params = Vec::new();
+    while let Some(key) = optional_string(args)? {
+        let val = next_string(args).map_err(|_| ParseError::WrongArity("config|set"))?;
+        params.push((key.to_lowercase(), val));
+    }
+    Ok(Command::ConfigSet(params))
+}
+
+fn parse_cluster_meet(args: &mut Args) -> Result<Command, ParseError> {
+    let host = next_string(args)?;
+    let port = next_string(args)?;
+    Ok(Command::ClusterMeet(host, port))
+}
+
+fn parse_cluster_hello(args: &mut Args) -> Result<Command, ParseError> {
+    Ok(Command::ClusterHello(next_string(args)?))
+}
+
+fn parse_cluster_setslot(args: &mut Args) -> Result<Command, ParseError> {
+    let slot = optional_int::<u16>(args)
+        .ok()
+        .flatten()
+        .filter(|slot| (*slot as usize) < CLUSTER_SLOTS)
+        .ok_or(ParseError::InvalidSlot)?;
+    let action = next_string(args)?.to_uppercase();
+    let action = match action.as_str() {
+        "MIGRATING" => SetSlot::Migrating(next_string(args)?),
+        "IMPORTING" => SetSlot::Importing(next_string(args)?),
+        "NODE" => SetSlot::Node(next_string(args)?),
+        "STABLE" => SetSlot::Stable,
+        _ => return Err(ParseError::Syntax),
+    };
+    if args.next().is_some() {
+        return Err(ParseError::Syntax);
+    }
+    Ok(Command::ClusterSetSlot(slot, action))
+}
+
+fn parse_memory_usage(args: &mut Args) -> Result<Command, ParseError> {
+    let key = next_arg(args)?;
+    let mut samples = 5;
+    if let Some(arg) = optional_string(args)? {
+        if !arg.eq_ignore_ascii_case("SAMPLES") {
+            return Err(ParseError::Syntax);
+        }
+        samples = next_int(args)?;
+    }
+    Ok(Command::MemoryUsage(key, samples))
+}
+
+fn parse_slowlog_get(args: &mut Args) -> Result<Command, ParseError> {
+    let count = optional_int::<i64>(args)?.unwrap_or(10);
+    // A negative count asks for every entry.
+    let count = usize::try_from(count).ok();
+    Ok(Command::SlowLogGet(count))
+}
+
+fn parse_debug_sleep(args: &mut Args) -> Result<Command, ParseError> {
+    let seconds = next_string(args)?;
+    match seconds.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() => Ok(Command::DebugSleep(seconds)),
+        _ => Err(ParseError::NotAFloat),
+    }
+}
+
+/// An argument that has to be text, such as an option or a host. Keys and
+/// values are kept as the bytes they were sent as instead, see `next_arg`.
+fn as_str(arg: &Bytes) -> Result<&str, ParseError> {
+    std::str::from_utf8(arg).map_err(|_| ParseError::Syntax)
+}
+
+/// Consumes the rest of the command's arguments, for variadic commands.
+fn remaining_strings(args: &mut Args) -> Result<Vec<String>, ParseError> {
+    args.map(|arg| as_str(arg).map(str::to_string)).collect()
+}
+
+/// Consumes the rest of the command's arguments as they were sent, for
+/// commands taking any number of keys or members.
+fn remaining_args(args: &mut Args) -> Vec<Bytes> {
+    args.map(|arg| Bytes::copy_from_slice(arg)).collect()
+}
+
+fn next_bytes<'a>(args: &mut Args<'a>) -> Result<&'a Bytes, ParseError> {
+    args.next().ok_or(ParseError::Syntax)
+}
+
+/// The next argument as it was sent, such as a key or a member. It's copied
+/// out of the request, so the dataset doesn't keep the connection's read
+/// buffer around.
+fn next_arg(args: &mut Args) -> Result<Bytes, ParseError> {
+    next_bytes(args).map(|arg| Bytes::copy_from_slice(arg))
+}
+
+/// The next argument, which the command requires.
+fn next_string(args: &mut Args) -> Result<String, ParseError> {
+    optional_string(args)?.ok_or(ParseError::Syntax)
+}
+
+/// The next argument, if the command was given one.
+fn optional_string(args: &mut Args) -> Result<Option<String>, ParseError> {
+    args.next()
+        .map(|arg| as_str(arg).map(str::to_string))
+        .transpose()
+}
+
+fn next_int<T: FromStr>(args: &mut Args) -> Result<T, ParseError> {
+    optional_int(args)?.ok_or(ParseError::Syntax)
+}
+
+fn optional_int<T: FromStr>(args: &mut Args) -> Result<Option<T>, ParseError> {
+    match args.next() {
+        Some(arg) => std::str::from_utf8(arg)
+            .ok()
+            .and_then(|arg| arg.parse::<T>().ok())
+            .map(Some)
+            .ok_or(ParseError::NotAnInteger),
+        None => Ok(None),
+    }
+}
+
+/// Consumes a trailing flag such as WITHSCORES, if the command was given
+/// one. Any other argument in its place is a syntax error.
+fn optional_flag(args: &mut Args, flag: &str) -> Result<bool, ParseError> {
+    match args.next() {
+        Some(arg) if arg.eq_ignore_ascii_case(flag.as_bytes()) => Ok(true),
+        Some(_) => Err(ParseError::Syntax),
+        None => Ok(false),
+    }
+}
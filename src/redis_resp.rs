@@ -0,0 +1,362 @@
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use std::ops::Range;
+
+/// A RESP value. Every reply sent to a client (and every command sent to a
+/// master or replica) is built from this type and serialized in one place.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    BulkString(String),
+    /// A bulk string that isn't valid UTF-8, such as a DUMP payload.
+    Bytes(Vec<u8>),
+    Integer(i64),
+    Error(String),
+    Nil,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    pub fn ok() -> Self {
+        Value::SimpleString("OK".to_string())
+    }
+
+    pub fn bulk(str: impl Into<String>) -> Self {
+        Value::BulkString(str.into())
+    }
+
+    /// A bulk string of any bytes, such as a key or a value. It's kept as a
+    /// BulkString when the bytes are text, the way replies are parsed, so the
+    /// two compare equal.
+    pub fn bulk_bytes(bytes: impl AsRef<[u8]>) -> Self {
+        match std::str::from_utf8(bytes.as_ref()) {
+            Ok(str) => Value::BulkString(str.to_string()),
+            Err(_) => Value::Bytes(bytes.as_ref().to_vec()),
+        }
+    }
+
+    /// An array of bulk strings of any bytes, such as a command with keys
+    /// among its arguments.
+    pub fn bytes_array<T: AsRef<[u8]>>(items: impl IntoIterator<Item = T>) -> Self {
+        Value::Array(items.into_iter().map(Value::bulk_bytes).collect())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Value::Error(msg.into())
+    }
+
+    pub fn bulk_array<T: Into<String>>(items: impl IntoIterator<Item = T>) -> Self {
+        Value::Array(items.into_iter().map(Value::bulk).collect())
+    }
+
+    /// Serializes the value as RESP2. Maps have no RESP2 representation, so
+    /// they are flattened into an array of alternating keys and values, the
+    /// same way Redis answers RESP2 clients.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.serialize_into(&mut out);
+        out
+    }
+
+    /// Appends the value's RESP encoding to `out`.
+    pub fn serialize_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::SimpleString(str) => out.extend_from_slice(format!("+{}\r\n", str).as_bytes()),
+            Value::BulkString(str) => {
+                out.extend_from_slice(format!("${}\r\n{}\r\n", str.len(), str).as_bytes())
+            }
+            Value::Bytes(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Integer(num) => out.extend_from_slice(format!(":{}\r\n", num).as_bytes()),
+            Value::Error(msg) => out.extend_from_slice(format!("-{}\r\n", msg).as_bytes()),
+            Value::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Value::Array(arr) => {
+                out.extend_from_slice(format!("*{}\r\n", arr.len()).as_bytes());
+                for item in arr {
+                    item.serialize_into(out);
+                }
+            }
+            Value::Map(map) => {
+                out.extend_from_slice(format!("*{}\r\n", map.len() * 2).as_bytes());
+                for (key, val) in map {
+                    key.serialize_into(out);
+                    val.serialize_into(out);
+                }
+            }
+        }
+    }
+
+    pub fn deserialize(data: &[u8]) -> Option<Self> {
+        Self::deserialize_all(data).pop()
+    }
+
+    /// Parses every complete top-level value in `data`, e.g. a batch of
+    /// pipelined commands or a whole AOF.
+    pub fn deserialize_all(mut data: &[u8]) -> Vec<Self> {
+        let mut values = Vec::new();
+        while let Ok(Some((value, consumed))) = Self::parse(data) {
+            values.push(value);
+            data = &data[consumed..];
+        }
+        values
+    }
+
+    /// Parses the first value in `data`. Returns the value along with the
+    /// number of bytes it took up, or None if `data` doesn't hold a complete
+    /// value yet. Lines that don't start with a RESP type byte are treated as
+    /// inline commands, split on whitespace.
+    pub fn parse(data: &[u8]) -> Result<Option<(Value, usize)>> {
+        let line_end = match data.windows(2).position(|window| window == b"\r\n") {
+            Some(line_end) => line_end,
+            None => return Ok(None),
+        };
+        let line = &data[..line_end];
+        let mut consumed = line_end + 2;
+        let first_byte = match line.first() {
+            Some(first_byte) => *first_byte,
+            None => return Ok(Some((Value::Array(vec![]), consumed))),
+        };
+        let rest = String::from_utf8_lossy(&line[1..]);
+        let value = match first_byte {
+            b'+' => Value::SimpleString(rest.to_string()),
+            b'-' => Value::Error(rest.to_string()),
+            b':' => Value::Integer(
+                rest.parse::<i64>()
+                    .context("Protocol error: invalid integer")?,
+            ),
+            b'_' => Value::Nil,
+            b'$' => {
+                if rest == "-1" {
+                    return Ok(Some((Value::Nil, consumed)));
+                }
+                let len = rest
+                    .parse::<usize>()
+                    .context("Protocol error: invalid bulk length")?;
+                if data.len() < consumed + len + 2 {
+                    return Ok(None);
+                }
+                let bytes = data[consumed..consumed + len].to_vec();
+                consumed += len + 2;
+                match String::from_utf8(bytes) {
+                    Ok(str) => Value::BulkString(str),
+                    Err(e) => Value::Bytes(e.into_bytes()),
+                }
+            }
+            b'*' | b'%' => {
+                if rest == "-1" {
+                    return Ok(Some((Value::Nil, consumed)));
+                }
+                let len = rest
+                    .parse::<usize>()
+                    .context("Protocol error: invalid multibulk length")?;
+                let len = if first_byte == b'%' { len * 2 } else { len };
+                let mut items = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    match Self::parse(&data[consumed..])? {
+                        Some((item, item_len)) => {
+                            items.push(item);
+                            consumed += item_len;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                if first_byte == b'%' {
+                    let mut items = items.into_iter();
+                    let mut map = Vec::new();
+                    while let (Some(key), Some(val)) = (items.next(), items.next()) {
+                        map.push((key, val));
+                    }
+                    Value::Map(map)
+                } else {
+                    Value::Array(items)
+                }
+            }
+            _ => Value::bulk_array(String::from_utf8_lossy(line).split_whitespace()),
+        };
+        Ok(Some((value, consumed)))
+    }
+}
+
+/// Splits the first request off the front of `buf`. A request is an array of
+/// bulk strings, or else an inline command: a line of arguments separated by
+/// whitespace. Its arguments are returned as views into the bytes it took up
+/// rather than copies, along with those bytes. Returns None if `buf` doesn't
+/// hold a complete request yet.
+pub fn parse_request(buf: &mut BytesMut) -> Result<Option<(Vec<Bytes>, Bytes)>> {
+    let (ranges, len) = match request_ranges(buf)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    let frame = buf.split_to(len).freeze();
+    let args = ranges.into_iter().map(|range| frame.slice(range)).collect();
+    Ok(Some((args, frame)))
+}
+
+/// Where each argument of the first request in `data` is, and how many bytes
+/// the request takes up.
+fn request_ranges(data: &[u8]) -> Result<Option<(Vec<Range<usize>>, usize)>> {
+    let (line, mut consumed) = match next_line(data, 0) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        for arg in line.split(u8::is_ascii_whitespace) {
+            if !arg.is_empty() {
+                ranges.push(pos..pos + arg.len());
+            }
+            pos += arg.len() + 1;
+        }
+        return Ok(Some((ranges, consumed)));
+    }
+    let count = parse_len(&line[1..]).context("Protocol error: invalid multibulk length")?;
+    // Like in Redis, *0 and *-1 are empty requests.
+    let count = usize::try_from(count).unwrap_or(0);
+    let mut ranges = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let (line, start) = match next_line(data, consumed) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        match line.first() {
+            Some(b'$') => {}
+            Some(&byte) => bail!("Protocol error: expected '$', got '{}'", byte as char),
+            None => bail!("Protocol error: expected '$', got an empty line"),
+        }
+        let len = parse_len(&line[1..])
+            .ok()
+            .and_then(|len| usize::try_from(len).ok())
+            .context("Protocol error: invalid bulk length")?;
+        if data.len() < start + len + 2 {
+            return Ok(None);
+        }
+        ranges.push(start..start + len);
+        consumed = start + len + 2;
+    }
+    Ok(Some((ranges, consumed)))
+}
+
+/// The line starting at `start`, without its CRLF, and where the next one
+/// starts.
+fn next_line(data: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let line_end = start
+        + data[start..]
+            .windows(2)
+            .position(|window| window == b"\r\n")?;
+    Some((&data[start..line_end], line_end + 2))
+}
+
+fn parse_len(digits: &[u8]) -> Result<i64> {
+    Ok(std::str::from_utf8(digits)?.parse::<i64>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serializes `value`, parses it back, and checks the whole encoding was
+    /// consumed.
+    fn roundtrip(value: &Value) -> Value {
+        let bytes = value.serialize();
+        let (parsed, consumed) = Value::parse(&bytes).unwrap().unwrap();
+        assert_eq!(consumed, bytes.len(), "{:?}", value);
+        parsed
+    }
+
+    fn samples() -> Vec<Value> {
+        vec![
+            Value::SimpleString("OK".into()),
+            Value::BulkString("hello world".into()),
+            Value::BulkString(String::new()),
+            Value::Bytes(vec![0xff, 0x00, b'\r', b'\n', 0xfe]),
+            Value::Integer(-42),
+            Value::Integer(i64::MAX),
+            Value::Error("ERR something went wrong".into()),
+            Value::Nil,
+            Value::Array(vec![]),
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Array(vec![Value::bulk("nested"), Value::Nil]),
+                Value::Bytes(vec![0x80]),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn values_survive_a_roundtrip() {
+        for value in samples() {
+            assert_eq!(roundtrip(&value), value);
+        }
+    }
+
+    #[test]
+    fn maps_are_flattened_into_arrays() {
+        let map = Value::Map(vec![
+            (Value::bulk("a"), Value::Integer(1)),
+            (Value::bulk("b"), Value::Array(vec![Value::bulk("c")])),
+        ]);
+        assert_eq!(
+            roundtrip(&map),
+            Value::Array(vec![
+                Value::bulk("a"),
+                Value::Integer(1),
+                Value::bulk("b"),
+                Value::Array(vec![Value::bulk("c")]),
+            ])
+        );
+        // A RESP3 map is parsed back into pairs.
+        let (parsed, _) = Value::parse(b"%1\r\n+a\r\n:1\r\n").unwrap().unwrap();
+        assert_eq!(
+            parsed,
+            Value::Map(vec![(Value::SimpleString("a".into()), Value::Integer(1))])
+        );
+    }
+
+    #[test]
+    fn partial_input_is_not_a_value_yet() {
+        for value in samples() {
+            let bytes = value.serialize();
+            for len in 0..bytes.len() {
+                assert!(
+                    Value::parse(&bytes[..len]).unwrap().is_none(),
+                    "{:?} parsed from {} of {} bytes",
+                    value,
+                    len,
+                    bytes.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pipelined_values_are_parsed_in_order() {
+        let values = samples();
+        let mut bytes = Vec::new();
+        for value in &values {
+            value.serialize_into(&mut bytes);
+        }
+        assert_eq!(Value::deserialize_all(&bytes), values);
+    }
+
+    #[test]
+    fn requests_split_into_binary_arguments() {
+        let req = Value::Array(vec![
+            Value::bulk("SET"),
+            Value::Bytes(vec![0xff, b'\r', b'\n']),
+            Value::bulk("v"),
+        ]);
+        let mut buf = BytesMut::from(&req.serialize()[..]);
+        buf.extend_from_slice(b"PING\r\n*1\r\n$4\r\nPI");
+        let (args, _) = parse_request(&mut buf).unwrap().unwrap();
+        assert_eq!(args, [&b"SET"[..], &[0xff, b'\r', b'\n'], b"v"]);
+        let (args, _) = parse_request(&mut buf).unwrap().unwrap();
+        assert_eq!(args, [&b"PING"[..]]);
+        assert!(parse_request(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"*1\r\n$4\r\nPI");
+    }
+}
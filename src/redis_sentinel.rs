@@ -0,0 +1,719 @@
+use crate::redis_cli::Connection;
+use crate::redis_evict::random_index;
+use crate::redis_net::listen;
+use crate::redis_resp::{self, Value};
+use crate::redis_server::random_id;
+use anyhow::{bail, Result};
+use bytes::BytesMut;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+pub struct SentinelOptions {
+    pub port: u16,
+    /// The addresses to listen on, as in the bind config.
+    pub bind: Vec<String>,
+    /// The name clients ask for the master by.
+    pub master_name: String,
+    pub master_host: String,
+    pub master_port: u16,
+    /// How many sentinels have to agree the master is down before it's
+    /// failed over.
+    pub quorum: usize,
+    /// The other sentinels monitoring the master, as HOST:PORT.
+    pub peers: Vec<String>,
+    /// How long the master can go without replying before it's considered
+    /// down.
+    pub down_after: Duration,
+    /// How long to wait before trying again after a failover attempt, or
+    /// after voting for another sentinel's.
+    pub failover_timeout: Duration,
+}
+
+impl Default for SentinelOptions {
+    /// The same defaults as redis-sentinel and its sample config.
+    fn default() -> Self {
+        SentinelOptions {
+            port: 26379,
+            bind: vec!["*".to_string(), "-::*".to_string()],
+            master_name: "mymaster".to_string(),
+            master_host: "127.0.0.1".to_string(),
+            master_port: 6379,
+            quorum: 2,
+            peers: Vec::new(),
+            down_after: Duration::from_secs(30),
+            failover_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+/// A running sentinel. It stops monitoring and serving clients once it's
+/// dropped.
+pub struct Sentinel {
+    local_addr: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Sentinel {
+    /// Binds the listening sockets and starts monitoring the master.
+    pub async fn spawn(opts: SentinelOptions) -> Result<Self> {
+        if opts.quorum == 0 {
+            bail!("Quorum must be 1 or greater");
+        }
+        let listeners = listen(&opts.bind, opts.port).await?;
+        let local_addr = listeners[0].local_addr()?;
+        let monitor = Monitor {
+            run_id: Arc::new(random_id()),
+            state: Arc::new(Mutex::new(State {
+                master: Node {
+                    host: opts.master_host.clone(),
+                    port: opts.master_port,
+                },
+                replicas: BTreeSet::new(),
+                last_reply: Instant::now(),
+                s_down: false,
+                o_down: false,
+                current_epoch: 0,
+                leader: None,
+                failover_start: None,
+            })),
+            events: broadcast::channel(64).0,
+            opts: Arc::new(opts),
+        };
+        info!(
+            "Sentinel {} monitoring master {} {}",
+            monitor.run_id,
+            monitor.opts.master_name,
+            monitor.state.lock().await.master
+        );
+        let mut tasks = vec![tokio::spawn(monitor.clone().run())];
+        for listener in listeners {
+            tasks.push(tokio::spawn(accept(listener, monitor.clone())));
+        }
+        info!(
+            "Sentinel ready to accept connections on port {}",
+            local_addr.port()
+        );
+        Ok(Sentinel { local_addr, tasks })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A Redis instance, as the master or one of its replicas.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Node {
+    host: String,
+    port: u16,
+}
+
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.host, self.port)
+    }
+}
+
+impl Node {
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// What the sentinel knows about the master it monitors.
+struct State {
+    master: Node,
+    /// Every replica listed in the master's INFO, along with masters demoted
+    /// by a failover, which are pointed at the new master once they're back.
+    replicas: BTreeSet<Node>,
+    /// When the master last replied to PING.
+    last_reply: Instant,
+    /// Subjectively down: this sentinel couldn't reach the master for
+    /// down-after.
+    s_down: bool,
+    /// Objectively down: a quorum of sentinels couldn't.
+    o_down: bool,
+    current_epoch: u64,
+    /// The sentinel voted for to lead a failover, and the epoch of the vote.
+    leader: Option<(u64, String)>,
+    /// When this sentinel last tried a failover or voted for another's.
+    failover_start: Option<Instant>,
+}
+
+/// Published to clients subscribed to `channel`, such as +switch-master.
+#[derive(Clone)]
+struct Event {
+    channel: &'static str,
+    message: String,
+}
+
+#[derive(Clone)]
+struct Monitor {
+    opts: Arc<SentinelOptions>,
+    run_id: Arc<String>,
+    state: Arc<Mutex<State>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Monitor {
+    /// Checks on the master every second, or every down-after if that's
+    /// shorter, which also bounds how long a request may take.
+    fn period(&self) -> Duration {
+        self.opts.down_after.min(Duration::from_secs(1))
+    }
+
+    async fn run(self) {
+        let mut interval = tokio::time::interval(self.period());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let master = self.state.lock().await.master.clone();
+            self.ping_master(&master).await;
+            let s_down = self.state.lock().await.s_down;
+            if self.check_replicas(&master, s_down).await || !s_down {
+                continue;
+            }
+            if self.check_quorum(&master).await {
+                self.try_failover(&master).await;
+            }
+        }
+    }
+
+    /// Publishes an event and logs it the way Redis does.
+    fn event(&self, channel: &'static str, message: String) {
+        warn!("{} {}", channel, message);
+        let _ = self.events.send(Event { channel, message });
+    }
+
+    fn describe(&self, master: &Node) -> String {
+        format!("master {} {}", self.opts.master_name, master)
+    }
+
+    /// PINGs the master, and while it replies, learns its replicas from INFO
+    /// replication. It's down once it hasn't replied for down-after.
+    async fn ping_master(&self, master: &Node) {
+        let timeout = self.period();
+        // As in Redis, a master that is loading its dataset or has lost its
+        // own master still counts as reachable.
+        let reachable = match call(&master.addr(), &["PING"], timeout).await {
+            Some(Value::SimpleString(_)) => true,
+            Some(Value::Error(e)) => e.starts_with("LOADING") || e.starts_with("MASTERDOWN"),
+            _ => false,
+        };
+        let info = match reachable {
+            true => call(&master.addr(), &["INFO", "replication"], timeout).await,
+            false => None,
+        };
+        let mut state = self.state.lock().await;
+        if reachable {
+            state.last_reply = Instant::now();
+            if state.s_down {
+                state.s_down = false;
+                self.event("-sdown", self.describe(master));
+            }
+            if state.o_down {
+                state.o_down = false;
+                self.event("-odown", self.describe(master));
+            }
+        } else if !state.s_down && state.last_reply.elapsed() >= self.opts.down_after {
+            state.s_down = true;
+            self.event("+sdown", self.describe(master));
+        }
+        if let Some(Value::BulkString(info)) = info {
+            state.replicas.extend(replicas_in(&info));
+        }
+    }
+
+    /// Checks what every known replica follows. While the master is down, one
+    /// that became a master was promoted by another sentinel's failover, and
+    /// is switched to, which returns true. Otherwise replicas following
+    /// anything but the master, such as a demoted master that came back, are
+    /// pointed at it.
+    async fn check_replicas(&self, master: &Node, master_down: bool) -> bool {
+        let replicas = self.state.lock().await.replicas.clone();
+        for replica in replicas {
+            let info = match call(&replica.addr(), &["INFO", "replication"], self.period()).await {
+                Some(Value::BulkString(info)) => info,
+                _ => continue,
+            };
+            let fields = info_fields(&info);
+            let following = match fields.get("role") {
+                Some(&"slave") => fields
+                    .get("master_host")
+                    .zip(fields.get("master_port").and_then(|port| port.parse().ok()))
+                    .map(|(host, port)| Node {
+                        host: host.to_string(),
+                        port,
+                    }),
+                _ => None,
+            };
+            match following {
+                None if master_down => {
+                    self.switch_master(replica).await;
+                    return true;
+                }
+                Some(following) if following == *master => {}
+                _ if master_down => {}
+                _ => {
+                    self.event(
+                        "+convert-to-slave",
+                        format!("slave {} @ {}", replica, self.describe(master)),
+                    );
+                    let port = master.port.to_string();
+                    let args = ["REPLICAOF", &master.host, &port];
+                    call(&replica.addr(), &args, self.period()).await;
+                }
+            }
+        }
+        false
+    }
+
+    /// Asks the peers whether they consider the master down too. Returns true
+    /// once a quorum of sentinels, this one included, do.
+    async fn check_quorum(&self, master: &Node) -> bool {
+        let port = master.port.to_string();
+        let epoch = self.state.lock().await.current_epoch.to_string();
+        let args = [
+            "SENTINEL",
+            "IS-MASTER-DOWN-BY-ADDR",
+            &master.host,
+            &port,
+            &epoch,
+            "*",
+        ];
+        let mut down = 1;
+        for peer in &self.opts.peers {
+            if let Some(Value::Array(reply)) = call(peer, &args, self.period()).await {
+                if reply.first() == Some(&Value::Integer(1)) {
+                    down += 1;
+                }
+            }
+        }
+        let o_down = down >= self.opts.quorum;
+        let mut state = self.state.lock().await;
+        if o_down && !state.o_down {
+            self.event(
+                "+odown",
+                format!(
+                    "{} #quorum {}/{}",
+                    self.describe(master),
+                    down,
+                    self.opts.quorum
+                ),
+            );
+        } else if !o_down && state.o_down {
+            self.event("-odown", self.describe(master));
+        }
+        state.o_down = o_down;
+        o_down
+    }
+
+    /// Asks the peers to elect this sentinel to fail the master over in a
+    /// new epoch, and does it if a majority of all sentinels, and no fewer
+    /// than the quorum, vote for it. Attempts are spread out by a random
+    /// delay, so sentinels noticing the master is down at the same time
+    /// don't keep splitting the vote.
+    async fn try_failover(&self, master: &Node) {
+        let jitter = random_index(self.period().as_millis() as usize).unwrap_or(0);
+        tokio::time::sleep(Duration::from_millis(jitter as u64)).await;
+        let epoch = {
+            let mut state = self.state.lock().await;
+            if state
+                .failover_start
+                .is_some_and(|start| start.elapsed() < self.opts.failover_timeout)
+            {
+                return;
+            }
+            state.current_epoch += 1;
+            state.leader = Some((state.current_epoch, self.run_id.to_string()));
+            state.failover_start = Some(Instant::now());
+            state.current_epoch
+        };
+        self.event("+new-epoch", epoch.to_string());
+        self.event("+try-failover", self.describe(master));
+        let port = master.port.to_string();
+        let epoch_arg = epoch.to_string();
+        let args = [
+            "SENTINEL",
+            "IS-MASTER-DOWN-BY-ADDR",
+            &master.host,
+            &port,
+            &epoch_arg,
+            &self.run_id,
+        ];
+        let mut votes = 1;
+        for peer in &self.opts.peers {
+            if let Some(Value::Array(reply)) = call(peer, &args, self.period()).await {
+                if let [_, Value::BulkString(leader), Value::Integer(leader_epoch)] =
+                    reply.as_slice()
+                {
+                    if *leader == *self.run_id && *leader_epoch as u64 == epoch {
+                        votes += 1;
+                    }
+                }
+            }
+        }
+        let sentinels = self.opts.peers.len() + 1;
+        let needed = (sentinels / 2 + 1).max(self.opts.quorum);
+        if votes < needed {
+            self.event(
+                "-failover-abort-not-elected",
+                format!("{} #votes {}/{}", self.describe(master), votes, needed),
+            );
+            return;
+        }
+        self.event(
+            "+elected-leader",
+            format!("{} #votes {}/{}", self.describe(master), votes, needed),
+        );
+        self.failover(master).await;
+    }
+
+    /// Promotes the replica that got furthest in the replication stream
+    /// with REPLICAOF NO ONE, and points the others at it.
+    async fn failover(&self, master: &Node) {
+        let replicas = self.state.lock().await.replicas.clone();
+        let mut best: Option<(u64, &Node)> = None;
+        for replica in &replicas {
+            let info = match call(&replica.addr(), &["INFO", "replication"], self.period()).await {
+                Some(Value::BulkString(info)) => info,
+                _ => continue,
+            };
+            let fields = info_fields(&info);
+            if fields.get("role") != Some(&"slave") {
+                continue;
+            }
+            let offset = fields
+                .get("master_repl_offset")
+                .and_then(|offset| offset.parse().ok())
+                .unwrap_or(0);
+            if best.is_none_or(|(best_offset, _)| offset > best_offset) {
+                best = Some((offset, replica));
+            }
+        }
+        let promoted = match best {
+            Some((_, promoted)) => promoted.clone(),
+            None => {
+                self.event("-failover-abort-no-good-slave", self.describe(master));
+                return;
+            }
+        };
+        self.event(
+            "+selected-slave",
+            format!("slave {} @ {}", promoted, self.describe(master)),
+        );
+        match call(&promoted.addr(), &["REPLICAOF", "NO", "ONE"], self.period()).await {
+            Some(Value::SimpleString(_)) => {}
+            reply => {
+                debug!("Unexpected reply to REPLICAOF NO ONE: {:?}", reply);
+                self.event("-failover-abort-slave-timeout", self.describe(master));
+                return;
+            }
+        }
+        self.event(
+            "+promoted-slave",
+            format!("slave {} @ {}", promoted, self.describe(master)),
+        );
+        let port = promoted.port.to_string();
+        for replica in replicas.iter().filter(|replica| **replica != promoted) {
+            let args = ["REPLICAOF", &promoted.host, &port];
+            call(&replica.addr(), &args, self.period()).await;
+        }
+        self.switch_master(promoted).await;
+    }
+
+    /// Monitors `promoted` from now on. The previous master is kept as a
+    /// replica, to be pointed at it once it's back.
+    async fn switch_master(&self, promoted: Node) {
+        let mut state = self.state.lock().await;
+        let old = std::mem::replace(&mut state.master, promoted.clone());
+        state.replicas.remove(&promoted);
+        state.replicas.insert(old.clone());
+        state.last_reply = Instant::now();
+        state.s_down = false;
+        state.o_down = false;
+        self.event(
+            "+switch-master",
+            format!("{} {} {}", self.opts.master_name, old, promoted),
+        );
+    }
+
+    /// SENTINEL IS-MASTER-DOWN-BY-ADDR: whether this sentinel considers the
+    /// master at `host:port` down. Along with a run id rather than "*", it
+    /// also asks for a vote to lead the failover in `epoch`, which goes to
+    /// the first sentinel to ask in each epoch.
+    async fn is_master_down(&self, host: &str, port: u16, epoch: u64, run_id: &str) -> Value {
+        let mut state = self.state.lock().await;
+        let down = state.master.host == host && state.master.port == port && state.s_down;
+        if run_id == "*" {
+            return Value::Array(vec![
+                Value::Integer(down as i64),
+                Value::bulk("*"),
+                Value::Integer(0),
+            ]);
+        }
+        state.current_epoch = state.current_epoch.max(epoch);
+        if state
+            .leader
+            .as_ref()
+            .is_none_or(|(leader_epoch, _)| *leader_epoch < epoch)
+        {
+            info!("Voting for {} in epoch {}", run_id, epoch);
+            state.leader = Some((epoch, run_id.to_string()));
+            // Having voted for another sentinel, this one gives it time to
+            // finish before trying a failover of its own.
+            if run_id != *self.run_id {
+                state.failover_start = Some(Instant::now());
+            }
+        }
+        let (leader_epoch, leader) = state.leader.clone().unwrap_or_default();
+        Value::Array(vec![
+            Value::Integer(down as i64),
+            Value::bulk(leader),
+            Value::Integer(leader_epoch as i64),
+        ])
+    }
+
+    /// SENTINEL MASTER: the state of the monitored master.
+    async fn master(&self) -> Value {
+        let state = self.state.lock().await;
+        let mut flags = vec!["master"];
+        if state.s_down {
+            flags.push("s_down");
+        }
+        if state.o_down {
+            flags.push("o_down");
+        }
+        let field = |name: &str, val: String| (Value::bulk(name), Value::bulk(val));
+        Value::Map(vec![
+            field("name", self.opts.master_name.clone()),
+            field("ip", state.master.host.clone()),
+            field("port", state.master.port.to_string()),
+            field("flags", flags.join(",")),
+            field(
+                "last-ok-ping-reply",
+                state.last_reply.elapsed().as_millis().to_string(),
+            ),
+            field("num-slaves", state.replicas.len().to_string()),
+            field("num-other-sentinels", self.opts.peers.len().to_string()),
+            field("quorum", self.opts.quorum.to_string()),
+            field(
+                "down-after-milliseconds",
+                self.opts.down_after.as_millis().to_string(),
+            ),
+            field(
+                "failover-timeout",
+                self.opts.failover_timeout.as_millis().to_string(),
+            ),
+            field("config-epoch", state.current_epoch.to_string()),
+        ])
+    }
+
+    /// Replies to a request from a client or another sentinel. SUBSCRIBE
+    /// replies once per channel, so there can be several replies.
+    async fn handle(&self, args: &[String], subscription: &mut Subscription) -> Vec<Value> {
+        let name = args[0].to_lowercase();
+        let wrong_arity = || {
+            Value::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ))
+        };
+        let reply = match name.as_str() {
+            "ping" => Value::SimpleString("PONG".to_string()),
+            "subscribe" if args.len() < 2 => wrong_arity(),
+            "subscribe" => {
+                if subscription.rx.is_none() {
+                    subscription.rx = Some(self.events.subscribe());
+                }
+                return args[1..]
+                    .iter()
+                    .map(|channel| {
+                        subscription.channels.insert(channel.clone());
+                        Value::Array(vec![
+                            Value::bulk("subscribe"),
+                            Value::bulk(channel),
+                            Value::Integer(subscription.channels.len() as i64),
+                        ])
+                    })
+                    .collect();
+            }
+            "sentinel" if args.len() < 2 => wrong_arity(),
+            "sentinel" => self.sentinel(&args[1..]).await,
+            _ => Value::error(format!("ERR unknown command '{}'", args[0])),
+        };
+        vec![reply]
+    }
+
+    async fn sentinel(&self, args: &[String]) -> Value {
+        let is_master = |name: &String| *name == self.opts.master_name;
+        match (args[0].to_lowercase().as_str(), &args[1..]) {
+            ("myid", []) => Value::bulk(self.run_id.as_str()),
+            ("master", [name]) if is_master(name) => self.master().await,
+            ("master", [_]) => Value::error("ERR No such master with that name"),
+            ("get-master-addr-by-name", [name]) if is_master(name) => {
+                let master = self.state.lock().await.master.clone();
+                Value::bulk_array([master.host, master.port.to_string()])
+            }
+            ("get-master-addr-by-name", [_]) => Value::Nil,
+            ("is-master-down-by-addr", [host, port, epoch, run_id]) => {
+                match (port.parse(), epoch.parse()) {
+                    (Ok(port), Ok(epoch)) => self.is_master_down(host, port, epoch, run_id).await,
+                    _ => Value::error("ERR value is not an integer or out of range"),
+                }
+            }
+            ("myid" | "master" | "get-master-addr-by-name" | "is-master-down-by-addr", _) => {
+                Value::error(format!(
+                    "ERR wrong number of arguments for 'sentinel|{}' command",
+                    args[0].to_lowercase()
+                ))
+            }
+            _ => Value::error(format!(
+                "ERR unknown subcommand '{}'. Try SENTINEL HELP.",
+                args[0]
+            )),
+        }
+    }
+}
+
+/// The channels a client subscribed to, and the events it receives once it
+/// has subscribed to any.
+#[derive(Default)]
+struct Subscription {
+    channels: BTreeSet<String>,
+    rx: Option<broadcast::Receiver<Event>>,
+}
+
+impl Subscription {
+    /// The next event published on one of the channels. Never resolves
+    /// without a subscription.
+    async fn next(&mut self) -> Event {
+        let rx = match self.rx.as_mut() {
+            Some(rx) => rx,
+            None => return std::future::pending().await,
+        };
+        loop {
+            match rx.recv().await {
+                Ok(event) if self.channels.contains(event.channel) => return event,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+async fn accept(listener: Arc<TcpListener>, monitor: Monitor) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_client(stream, monitor.clone()));
+        }
+    }
+}
+
+/// Serves a client or another sentinel until it disconnects. Once it has
+/// subscribed to a channel, events are pushed to it as they happen.
+async fn serve_client(mut stream: TcpStream, monitor: Monitor) {
+    let mut req = BytesMut::new();
+    let mut subscription = Subscription::default();
+    loop {
+        let mut out = Vec::new();
+        tokio::select! {
+            read = stream.read_buf(&mut req) => match read {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            },
+            event = subscription.next() => {
+                Value::Array(vec![
+                    Value::bulk("message"),
+                    Value::bulk(event.channel),
+                    Value::bulk(event.message),
+                ])
+                .serialize_into(&mut out);
+            }
+        }
+        loop {
+            let args = match redis_resp::parse_request(&mut req) {
+                Ok(Some((args, _))) => args,
+                Ok(None) => break,
+                Err(e) => {
+                    Value::error(format!("ERR {}", e)).serialize_into(&mut out);
+                    let _ = stream.write_all(&out).await;
+                    return;
+                }
+            };
+            if args.is_empty() {
+                continue;
+            }
+            let args = args
+                .iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect::<Vec<_>>();
+            for reply in monitor.handle(&args, &mut subscription).await {
+                reply.serialize_into(&mut out);
+            }
+        }
+        if !out.is_empty() && stream.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends one command on a new connection and waits for the reply, for no
+/// longer than `timeout`.
+async fn call(addr: &str, args: &[&str], timeout: Duration) -> Option<Value> {
+    let request = async {
+        let mut conn = Connection::connect(addr).await?;
+        conn.call(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+            .await
+    };
+    match tokio::time::timeout(timeout, request).await {
+        Ok(Ok(reply)) => Some(reply),
+        Ok(Err(e)) => {
+            debug!("Error sending {:?} to {}: {:#}", args, addr, e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// The `field:value` lines of an INFO reply.
+fn info_fields(info: &str) -> HashMap<&str, &str> {
+    info.lines()
+        .filter_map(|line| line.trim_end().split_once(':'))
+        .collect()
+}
+
+/// The replicas a master lists in INFO replication, as
+/// `slave0:ip=...,port=...,state=...`.
+fn replicas_in(info: &str) -> Vec<Node> {
+    info_fields(info)
+        .into_iter()
+        .filter(|(field, _)| field.starts_with("slave"))
+        .filter_map(|(_, replica)| {
+            let attrs = replica
+                .split(',')
+                .filter_map(|attr| attr.split_once('='))
+                .collect::<HashMap<_, _>>();
+            let port = attrs.get("port")?.parse().ok().filter(|port| *port != 0)?;
+            Some(Node {
+                host: attrs.get("ip")?.to_string(),
+                port,
+            })
+        })
+        .collect()
+}
@@ -1,13 +1,38 @@
-use crate::redis_commands::Command;
-use crate::redis_db::RedisDB;
+use crate::redis_aof::{FsyncPolicy, RedisAof};
+use crate::redis_cluster::{Cluster, Route};
+use crate::redis_commands::{Command, ExpireCondition};
+use crate::redis_config::{
+    self, parse_save_rules, ClientClass, OutputBufferLimit, OutputBufferLimits,
+};
+use crate::redis_db::{Dataset, RedisDB};
+use crate::redis_evict::{
+    entry_size, human_bytes, overhead, used_memory, EvictionPolicy, KeyAccess,
+};
+use crate::redis_functions::{Functions, Library, Running};
+use crate::redis_info::{CallOutcome, InfoRegistry, InfoSection, Stats};
+use crate::redis_io::{self, Connection, IoBackend, Link, TlsAuthClients, TlsFiles};
+use crate::redis_latency::LatencyMonitor;
+use crate::redis_log::{self, LogLevel};
+use crate::redis_metrics::{label_value, Metrics};
+use crate::redis_registry::{self, CommandSpec, Flag};
+use crate::redis_resp::{self, Value};
+use crate::redis_slowlog::SlowLog;
+use crate::redis_storage::{Entry, Shard, ShardedStorage, Storage};
+use crate::redis_stream::{self, NewId, Stream, StreamId};
+use crate::redis_value::{
+    index_range, random_sample, sample_count_in_range, sorted_zset, RedisValue, WRONGTYPE,
+};
 use anyhow::Context;
-use std::collections::HashMap;
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::broadcast::*;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify, RwLock};
+use tracing::{debug, info, trace, warn};
 
 #[derive(Copy, Clone)]
 pub enum Role {
@@ -25,347 +50,4255 @@ impl std::fmt::Display for Role {
 }
 
 pub struct Redis {
-    db: Arc<Mutex<HashMap<String, String>>>,
-    exp: Arc<Mutex<HashMap<String, SystemTime>>>,
+    /// The keyspace, split into shards that are locked separately.
+    store: Arc<ShardedStorage>,
+    stats: Arc<Mutex<Stats>>,
+    slowlog: Arc<Mutex<SlowLog>>,
+    latency: Arc<Mutex<LatencyMonitor>>,
+    /// Cleared by DEBUG SET-ACTIVE-EXPIRE 0 to leave expired keys to be
+    /// removed lazily.
+    active_expire: Arc<AtomicBool>,
+    /// Every connected client, keyed by client id.
+    clients: Arc<Mutex<HashMap<u64, Client>>>,
     config: Arc<Mutex<HashMap<String, String>>>,
-    role: Role,
+    rdb_status: Arc<Mutex<RdbStatus>>,
+    aof: Arc<Mutex<Option<RedisAof>>>,
+    replicas: Arc<Mutex<Vec<Replica>>>,
+    ack_notify: Arc<Notify>,
+    /// Wakes up commands paused by a FAILOVER or CLIENT PAUSE once it ends.
+    pause_notify: Arc<Notify>,
+    client_pause: Arc<Mutex<Option<ClientPause>>>,
+    /// Parsed from the `client-output-buffer-limit` config.
+    output_buffer_limits: Arc<Mutex<OutputBufferLimits>>,
+    /// Replicas waiting for the next full sync, if one is about to start.
+    pending_syncs: Arc<Mutex<Option<PendingSyncs>>>,
+    /// The hash slot table, when running in cluster mode.
+    cluster: Arc<Mutex<Option<Cluster>>>,
+    /// Libraries loaded with FUNCTION LOAD. The lock is never held while a
+    /// function runs.
+    functions: Arc<Mutex<Functions>>,
+    /// Function calls in progress, for FUNCTION KILL.
+    running_functions: Arc<Mutex<Vec<Arc<Running>>>>,
+    /// Taken shared by every client command that reads or writes keys, and
+    /// exclusively by a function call for as long as it runs, so no other
+    /// client sees or changes the keyspace between the commands it calls.
+    keyspace_lock: Arc<RwLock<()>>,
     port: String,
-    replid: Option<String>,
-    repl_offset: Option<usize>,
+    repl_status: Arc<Mutex<ReplStatus>>,
+    /// Keys this connection expired lazily or evicted, waiting to be logged
+    /// and propagated as DELs.
+    expired: Vec<Bytes>,
+    /// The id of the client this connection serves, once registered.
+    client_id: Option<u64>,
+    connection: ConnectionState,
+    /// Set once the server is shut down, which ends its background tasks and
+    /// accept loops.
+    halted: Arc<watch::Sender<bool>>,
+}
+
+/// The most keys the active expire cycle removes per run.
+const ACTIVE_EXPIRE_LIMIT: usize = 200;
+
+/// How many propagated writes may be queued for a replica before it is
+/// considered too slow and disconnected.
+const REPLICA_QUEUE_LEN: usize = 10_000;
+
+/// The reply to a client refused by protected mode, before its connection is
+/// closed.
+const PROTECTED_MODE_DENIED: &str = "DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside.";
+
+static NEXT_REPLICA_ID: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connected client, as tracked for the idle timeout.
+struct Client {
+    last_interaction: Instant,
+    /// Whether a command of the client is running, e.g. blocked in WAIT or
+    /// serving a replica after PSYNC. Such clients never time out.
+    in_command: bool,
+    /// Notified to close the connection.
+    kill: Arc<Notify>,
+}
+
+/// What a client has set up on its connection. Each connection's task has
+/// its own, and RESET puts it back the way it was when the client connected.
+#[derive(Default)]
+struct ConnectionState {
+    /// Set with CLIENT SETNAME.
+    name: Option<String>,
+    /// What the client announced with REPLCONF, if it's a replica.
+    replconf: ReplicaAttributes,
+    /// Set by ASKING, for the next command only.
+    asking: bool,
+}
+
+/// What a replica tells its master about itself with REPLCONF before asking
+/// for a sync.
+#[derive(Clone, Default)]
+struct ReplicaAttributes {
+    /// From REPLCONF ip-address. Without it, the replica is reached at the
+    /// address it connected from.
+    ip_address: Option<String>,
+    /// From REPLCONF listening-port.
+    listening_port: Option<String>,
+    /// From REPLCONF capa, such as eof and psync2.
+    capabilities: Vec<String>,
+}
+
+/// Commands held back by CLIENT PAUSE.
+#[derive(Copy, Clone)]
+struct ClientPause {
+    /// Only writes are paused, rather than every command.
+    write_only: bool,
+    until: Instant,
+}
+
+/// A connected replica, fed through a bounded queue that its connection task
+/// drains.
+struct Replica {
+    id: u64,
+    addr: Option<SocketAddr>,
+    attributes: ReplicaAttributes,
+    state: ReplicaState,
+    /// The offset the replica last acknowledged with REPLCONF ACK.
+    ack_offset: usize,
+    last_ack: Instant,
+    tx: mpsc::Sender<Vec<u8>>,
+    /// Bytes queued for the replica that haven't been written yet, which
+    /// count against its output buffer limits.
+    queued: Arc<AtomicUsize>,
+    over_soft_limit_since: Option<Instant>,
+}
+
+impl Replica {
+    /// The address the replica can be reached at: the one it announced, or
+    /// else the one it connected from.
+    fn ip(&self) -> Option<String> {
+        self.attributes
+            .ip_address
+            .clone()
+            .or_else(|| self.addr.map(|addr| addr.ip().to_string()))
+    }
+
+    fn port(&self) -> Option<&str> {
+        self.attributes.listening_port.as_deref()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum ReplicaState {
+    /// Waiting for a snapshot to be taken.
+    WaitBgsave,
+    /// Receiving the snapshot.
+    SendBulk,
+    Online,
+}
+
+impl std::fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaState::WaitBgsave => write!(f, "wait_bgsave"),
+            ReplicaState::SendBulk => write!(f, "send_bulk"),
+            ReplicaState::Online => write!(f, "online"),
+        }
+    }
+}
+
+/// A snapshot handed to a replica for a full resync, along with its entry
+/// in the registry.
+struct FullSync {
+    id: u64,
+    rx: mpsc::Receiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+    replid: String,
+    offset: usize,
+    rdb: Arc<Vec<u8>>,
+}
+
+/// Replicas waiting for a full sync, with their address and what they
+/// announced.
+type PendingSyncs = Vec<(
+    Option<SocketAddr>,
+    ReplicaAttributes,
+    oneshot::Sender<FullSync>,
+)>;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FailoverState {
+    NoFailover,
+    /// Writes are paused until the target replica catches up.
+    WaitingForSync,
+    /// The target replica is being promoted.
+    FailoverInProgress,
+}
+
+impl std::fmt::Display for FailoverState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailoverState::NoFailover => write!(f, "no-failover"),
+            FailoverState::WaitingForSync => write!(f, "waiting-for-sync"),
+            FailoverState::FailoverInProgress => write!(f, "failover-in-progress"),
+        }
+    }
+}
+
+/// The role of this instance, along with the replication ID and offset. On
+/// a replica these are the master's, as of the last command applied from its
+/// stream.
+struct ReplStatus {
+    role: Role,
     master_host: Option<String>,
     master_port: Option<String>,
+    /// The task following the master's stream, while connected to one.
+    master_link: Option<tokio::task::JoinHandle<()>>,
+    replid: Option<String>,
+    offset: usize,
+    failover: FailoverState,
+}
+
+/// Generates a random 40 character hex ID, as used for replication IDs and
+/// cluster node IDs.
+pub fn random_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let mut id = String::new();
+    while id.len() < 40 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(id.len());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(40);
+    id
+}
+
+struct RdbStatus {
+    last_save_time: SystemTime,
+    last_bgsave_try: SystemTime,
+    bgsave_in_progress: bool,
+    last_bgsave_ok: bool,
+    changes_since_last_save: u64,
+}
+
+impl Clone for Redis {
+    fn clone(&self) -> Self {
+        Redis {
+            store: Arc::clone(&self.store),
+            stats: Arc::clone(&self.stats),
+            slowlog: Arc::clone(&self.slowlog),
+            latency: Arc::clone(&self.latency),
+            active_expire: Arc::clone(&self.active_expire),
+            clients: Arc::clone(&self.clients),
+            config: Arc::clone(&self.config),
+            rdb_status: Arc::clone(&self.rdb_status),
+            aof: Arc::clone(&self.aof),
+            replicas: Arc::clone(&self.replicas),
+            ack_notify: Arc::clone(&self.ack_notify),
+            pause_notify: Arc::clone(&self.pause_notify),
+            client_pause: Arc::clone(&self.client_pause),
+            output_buffer_limits: Arc::clone(&self.output_buffer_limits),
+            pending_syncs: Arc::clone(&self.pending_syncs),
+            cluster: Arc::clone(&self.cluster),
+            functions: Arc::clone(&self.functions),
+            running_functions: Arc::clone(&self.running_functions),
+            keyspace_lock: Arc::clone(&self.keyspace_lock),
+            repl_status: Arc::clone(&self.repl_status),
+            port: self.port.clone(),
+            expired: Vec::new(),
+            client_id: self.client_id,
+            connection: ConnectionState::default(),
+            halted: Arc::clone(&self.halted),
+        }
+    }
 }
 
 pub struct RedisCliArgs {
     pub dir: Option<String>,
     pub file_name: Option<String>,
     pub port: String,
+    pub bind: Vec<String>,
+    pub protected_mode: bool,
+    pub maxclients: u64,
+    pub timeout: u64,
+    pub tcp_keepalive: u64,
     pub master_host: Option<String>,
     pub master_port: Option<String>,
     pub role: Role,
+    pub save: String,
+    pub rdbchecksum: bool,
+    pub appendonly: bool,
+    pub appendfsync: FsyncPolicy,
+    pub appendfilename: String,
+    pub aof_use_rdb_preamble: bool,
+    pub repl_diskless_sync: bool,
+    pub repl_diskless_sync_delay: u64,
+    pub replica_announce_ip: String,
+    pub replica_announce_port: u16,
+    pub cluster: Option<Cluster>,
+    pub cluster_announce_ip: String,
+    pub cluster_config_file: String,
+    pub maxmemory: u64,
+    pub maxmemory_policy: EvictionPolicy,
+    pub loglevel: LogLevel,
+    pub logfile: String,
+    pub metrics_port: u16,
+    pub io_backend: IoBackend,
+    /// The port TLS clients connect to, None when TLS isn't served.
+    pub tls_port: Option<u16>,
+    pub tls_files: TlsFiles,
+    pub tls_auth_clients: TlsAuthClients,
+    pub tls_replication: bool,
+}
+
+impl Default for RedisCliArgs {
+    /// What Redis starts with when given no options.
+    fn default() -> Self {
+        RedisCliArgs {
+            dir: None,
+            file_name: None,
+            port: "6379".to_string(),
+            bind: vec!["127.0.0.1".to_string(), "-::1".to_string()],
+            protected_mode: true,
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            master_host: None,
+            master_port: None,
+            role: Role::Primary,
+            save: "3600 1 300 100 60 10000".to_string(),
+            rdbchecksum: true,
+            appendonly: false,
+            appendfsync: FsyncPolicy::EverySec,
+            appendfilename: "appendonly.aof".to_string(),
+            aof_use_rdb_preamble: true,
+            repl_diskless_sync: true,
+            repl_diskless_sync_delay: 0,
+            replica_announce_ip: String::new(),
+            replica_announce_port: 0,
+            cluster: None,
+            cluster_announce_ip: String::new(),
+            cluster_config_file: "nodes.conf".to_string(),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            loglevel: LogLevel::Notice,
+            logfile: String::new(),
+            metrics_port: 0,
+            io_backend: IoBackend::Tokio,
+            tls_port: None,
+            tls_files: TlsFiles::default(),
+            tls_auth_clients: TlsAuthClients::Yes,
+            tls_replication: false,
+        }
+    }
 }
 
 impl Redis {
     pub async fn new(cli_args: RedisCliArgs) -> Self {
+        let cluster_enabled = cli_args.cluster.is_some();
         let mut instance = Redis {
-            db: Arc::new(Mutex::new(HashMap::new())),
-            exp: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(ShardedStorage::default()),
+            stats: Arc::new(Mutex::new(Stats::new(random_id()))),
+            slowlog: Arc::new(Mutex::new(SlowLog::default())),
+            latency: Arc::new(Mutex::new(LatencyMonitor::default())),
+            active_expire: Arc::new(AtomicBool::new(true)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(HashMap::new())),
-            repl_offset: Some(0),
+            rdb_status: Arc::new(Mutex::new(RdbStatus {
+                last_save_time: SystemTime::now(),
+                last_bgsave_try: SystemTime::UNIX_EPOCH,
+                bgsave_in_progress: false,
+                last_bgsave_ok: true,
+                changes_since_last_save: 0,
+            })),
+            aof: Arc::new(Mutex::new(None)),
+            replicas: Arc::new(Mutex::new(Vec::new())),
+            ack_notify: Arc::new(Notify::new()),
+            pause_notify: Arc::new(Notify::new()),
+            client_pause: Arc::new(Mutex::new(None)),
+            output_buffer_limits: Arc::new(Mutex::new(OutputBufferLimits::from_config(""))),
+            pending_syncs: Arc::new(Mutex::new(None)),
+            cluster: Arc::new(Mutex::new(cli_args.cluster)),
+            functions: Arc::new(Mutex::new(Functions::default())),
+            running_functions: Arc::new(Mutex::new(Vec::new())),
+            keyspace_lock: Arc::new(RwLock::new(())),
+            repl_status: Arc::new(Mutex::new(ReplStatus {
+                role: cli_args.role,
+                master_host: cli_args.master_host,
+                master_port: cli_args.master_port,
+                master_link: None,
+                replid: match cli_args.role {
+                    Role::Primary => Some(random_id()),
+                    Role::Replica => None,
+                },
+                offset: 0,
+                failover: FailoverState::NoFailover,
+            })),
             port: cli_args.port,
-            replid: match cli_args.role {
-                Role::Primary => Some("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string()),
-                Role::Replica => None,
-            },
-            role: cli_args.role,
-            master_host: cli_args.master_host,
-            master_port: cli_args.master_port,
-        };
-        if let Some(dir) = cli_args.dir {
-            if let Some(file_name) = cli_args.file_name {
-                let mut config = instance.config.lock().await;
-                config.insert("dir".to_string(), dir.clone());
-                config.insert("file_name".to_string(), file_name.clone());
-                let mut redis_db = RedisDB::new(dir, file_name);
-                match redis_db.read_rdb() {
-                    Ok((kivals, exp_map)) => {
-                        let mut db = instance.db.lock().await;
-                        let mut exp = instance.exp.lock().await;
-                        for (key, value) in kivals {
-                            match exp_map.get(&key) {
-                                Some(exp_time) => {
-                                    println!(
-                                        "key: {:?}, val: {:?}, exp_time: {:?}, cuurent_time: {:?}",
-                                        key,
-                                        value,
-                                        exp_time,
-                                        SystemTime::now()
-                                    );
-                                    if exp_time > &SystemTime::now() {
-                                        db.insert(key.clone(), value);
-                                        exp.insert(key.clone(), *exp_time);
-                                    }
-                                }
-                                None => {
-                                    db.insert(key.clone(), value);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("Error reading RDB file: {:?}", e);
-                    }
+            expired: Vec::new(),
+            client_id: None,
+            connection: ConnectionState::default(),
+            halted: Arc::new(watch::channel(false).0),
+        };
+        let dir = cli_args.dir.unwrap_or_else(|| ".".to_string());
+        let file_name = cli_args.file_name.unwrap_or_else(|| "dump.rdb".to_string());
+        {
+            let mut config = instance.config.lock().await;
+            config.extend(
+                redis_config::PARAMS
+                    .iter()
+                    .map(|param| (param.name.to_string(), param.default.to_string())),
+            );
+            config.insert("port".to_string(), instance.port.clone());
+            config.insert("bind".to_string(), cli_args.bind.join(" "));
+            let protected_mode = if cli_args.protected_mode { "yes" } else { "no" };
+            config.insert("protected-mode".to_string(), protected_mode.to_string());
+            config.insert("maxclients".to_string(), cli_args.maxclients.to_string());
+            config.insert("timeout".to_string(), cli_args.timeout.to_string());
+            config.insert(
+                "tcp-keepalive".to_string(),
+                cli_args.tcp_keepalive.to_string(),
+            );
+            config.insert("dir".to_string(), dir.clone());
+            config.insert("dbfilename".to_string(), file_name.clone());
+            config.insert("save".to_string(), cli_args.save);
+            let rdbchecksum = if cli_args.rdbchecksum { "yes" } else { "no" };
+            config.insert("rdbchecksum".to_string(), rdbchecksum.to_string());
+            let repl_diskless_sync = if cli_args.repl_diskless_sync {
+                "yes"
+            } else {
+                "no"
+            };
+            config.insert(
+                "repl-diskless-sync".to_string(),
+                repl_diskless_sync.to_string(),
+            );
+            config.insert(
+                "repl-diskless-sync-delay".to_string(),
+                cli_args.repl_diskless_sync_delay.to_string(),
+            );
+            config.insert(
+                "replica-announce-ip".to_string(),
+                cli_args.replica_announce_ip,
+            );
+            config.insert(
+                "replica-announce-port".to_string(),
+                cli_args.replica_announce_port.to_string(),
+            );
+            config.insert("maxmemory".to_string(), cli_args.maxmemory.to_string());
+            config.insert(
+                "maxmemory-policy".to_string(),
+                cli_args.maxmemory_policy.to_string(),
+            );
+            config.insert(
+                "cluster-announce-ip".to_string(),
+                cli_args.cluster_announce_ip,
+            );
+            config.insert(
+                "cluster-config-file".to_string(),
+                cli_args.cluster_config_file,
+            );
+            config.insert("loglevel".to_string(), cli_args.loglevel.to_string());
+            config.insert("logfile".to_string(), cli_args.logfile);
+            config.insert(
+                "metrics-port".to_string(),
+                cli_args.metrics_port.to_string(),
+            );
+            config.insert("io-backend".to_string(), cli_args.io_backend.to_string());
+            config.insert(
+                "tls-port".to_string(),
+                cli_args.tls_port.unwrap_or(0).to_string(),
+            );
+            config.insert("tls-cert-file".to_string(), cli_args.tls_files.cert_file);
+            config.insert("tls-key-file".to_string(), cli_args.tls_files.key_file);
+            config.insert(
+                "tls-ca-cert-file".to_string(),
+                cli_args.tls_files.ca_cert_file,
+            );
+            config.insert(
+                "tls-auth-clients".to_string(),
+                cli_args.tls_auth_clients.to_string(),
+            );
+            let tls_replication = if cli_args.tls_replication {
+                "yes"
+            } else {
+                "no"
+            };
+            config.insert("tls-replication".to_string(), tls_replication.to_string());
+            let cluster_enabled = if cluster_enabled { "yes" } else { "no" };
+            config.insert("cluster-enabled".to_string(), cluster_enabled.to_string());
+        }
+        {
+            // A node table saved by a previous run takes precedence over the
+            // slots given on the command line.
+            let mut cluster = instance.cluster.lock().await;
+            if let Some(cluster) = cluster.as_mut() {
+                let path = format!(
+                    "{}/{}",
+                    dir,
+                    instance.config.lock().await["cluster-config-file"]
+                );
+                match Cluster::load(&path, cluster.host()) {
+                    Ok(Some(loaded)) => *cluster = loaded,
+                    Ok(None) => {}
+                    Err(e) => warn!("Error loading cluster config: {:?}", e),
+                }
+                instance.save_cluster(cluster).await;
+                for addr in cluster.handshakes() {
+                    tokio::spawn(instance.clone().say_hello(addr));
                 }
+            }
+        }
+        {
+            let mut config = instance.config.lock().await;
+            let appendonly = if cli_args.appendonly { "yes" } else { "no" };
+            config.insert("appendonly".to_string(), appendonly.to_string());
+            config.insert("appendfsync".to_string(), cli_args.appendfsync.to_string());
+            config.insert(
+                "appendfilename".to_string(),
+                cli_args.appendfilename.clone(),
+            );
+            let aof_use_rdb_preamble = if cli_args.aof_use_rdb_preamble {
+                "yes"
+            } else {
+                "no"
             };
-        };
-        match &instance.role {
-            Role::Primary => {}
-            Role::Replica => instance.handshake_with_master().await,
+            config.insert(
+                "aof-use-rdb-preamble".to_string(),
+                aof_use_rdb_preamble.to_string(),
+            );
+        }
+        let aof_path = format!("{}/{}", dir, cli_args.appendfilename);
+        if cli_args.appendonly && std::path::Path::new(&aof_path).exists() {
+            instance.load_aof(&dir, &cli_args.appendfilename).await;
+        } else {
+            instance.load_rdb().await;
+        }
+        if cli_args.appendonly {
+            instance
+                .open_aof(dir, cli_args.appendfilename, cli_args.appendfsync)
+                .await;
+        }
+        instance.rdb_status.lock().await.changes_since_last_save = 0;
+        if let Role::Replica = instance.role().await {
+            instance.connect_to_master().await;
         }
+        tokio::spawn(instance.clone().cron());
         instance
     }
 
-    pub fn clone(&self) -> Self {
-        let clone = Redis {
-            db: Arc::clone(&self.db),
-            exp: Arc::clone(&self.exp),
-            config: Arc::clone(&self.config),
-            role: self.role.clone(),
-            repl_offset: self.repl_offset.clone(),
-            replid: self.replid.clone(),
-            master_host: self.master_host.clone(),
-            master_port: self.master_port.clone(),
-            port: self.port.clone(),
-        };
-        clone
+    async fn load_rdb(&mut self) {
+        let mut redis_db = self.redis_db().await;
+        match redis_db.read_rdb() {
+            Ok(dataset) => self.load_dataset(dataset).await,
+            Err(e) => {
+                warn!("Error reading RDB file: {:?}", e);
+            }
+        }
     }
 
-    async fn get(&mut self, key: &str) -> Option<String> {
-        let mut exp = self.exp.lock().await;
-        let mut db = self.db.lock().await;
-        if let Some(exp) = exp.get(key).cloned() {
-            if exp < std::time::SystemTime::now() {
-                db.remove(key);
+    async fn load_dataset(&mut self, (kivals, exp_map, libraries): Dataset) {
+        for code in libraries {
+            let loaded = match Library::load(code).await {
+                Ok(library) => self.functions.lock().await.add(library, true),
+                Err(resp) => Err(resp),
+            };
+            if let Err(Value::Error(e)) = loaded {
+                warn!("Error loading function library: {}", e);
             }
         }
-
-        if let None = db.get(key) {
-            exp.remove(key);
+        for (key, value) in kivals {
+            self.store.write(&key, |shard| match exp_map.get(&key) {
+                Some(exp_time) => {
+                    if exp_time > &SystemTime::now() {
+                        shard.insert(key.clone(), value);
+                        shard.set_expiry(&key, Some(*exp_time));
+                    }
+                }
+                None => {
+                    shard.insert(key.clone(), value);
+                }
+            });
         }
-        return db.get(key).cloned();
     }
 
-    async fn set(&mut self, key: String, value: String, exp: &Option<SystemTime>) {
-        let mut db = self.db.lock().await;
-        db.insert(key.clone(), value);
-        if let Some(exp) = exp {
-            self.exp.lock().await.insert(key, exp.clone());
+    /// Rebuilds the dataset by replaying every command in the AOF. Runs before
+    /// the AOF is opened for appending, so nothing is logged twice.
+    async fn load_aof(&mut self, dir: &str, file_name: &str) {
+        let aof = match RedisAof::open(dir.to_string(), file_name.to_string(), FsyncPolicy::No) {
+            Ok(aof) => aof,
+            Err(e) => {
+                warn!("Error opening AOF file: {:?}", e);
+                return;
+            }
+        };
+        match aof.read(self.verify_rdb_checksum().await) {
+            Ok(contents) => {
+                if let Some(dataset) = contents.preamble {
+                    self.load_dataset(dataset).await;
+                }
+                for command in contents.commands {
+                    self.apply(&command).await;
+                }
+            }
+            Err(e) => {
+                warn!("Error reading AOF file: {:?}", e);
+            }
         }
     }
 
-    async fn handshake_with_master(&mut self) {
-        if let None = &self.master_port {
-            println!("master port is not set. This instance must be the master, so will not init handshake");
-            return;
-        }
-        let master_port = self.master_port.clone().unwrap();
-        if let None = &self.master_host {
-            println!("master host is not set, This instance must be the master, so will not init handshake. But since master_port is set to {}, there may be some issue", master_port);
-            return;
+    /// Opens the AOF for appending. If the dataset was loaded from an RDB
+    /// because no AOF existed yet, its contents are logged first so the AOF
+    /// alone is enough to restore it.
+    async fn open_aof(&mut self, dir: String, file_name: String, fsync: FsyncPolicy) {
+        let path = format!("{}/{}", dir, file_name);
+        let is_new = !std::path::Path::new(&path).exists();
+        let mut aof = match RedisAof::open(dir, file_name, fsync) {
+            Ok(aof) => aof,
+            Err(e) => {
+                warn!("Error opening AOF file: {:?}", e);
+                return;
+            }
+        };
+        if is_new {
+            let ((kivals, exp_map, libraries), _) = self.snapshot().await;
+            for code in libraries {
+                if let Err(e) = aof.append(&Command::FunctionLoad(code, false)) {
+                    warn!("Error writing AOF file: {:?}", e);
+                }
+            }
+            for (key, val) in kivals {
+                let exp = exp_map.get(&key).cloned();
+                for command in RedisAof::commands_for(key, val, exp) {
+                    if let Err(e) = aof.append(&command) {
+                        warn!("Error writing AOF file: {:?}", e);
+                    }
+                }
+            }
         }
-        let master_host = self.master_host.clone().unwrap();
-        let stream = TcpStream::connect(format!("{}:{}", master_host, master_port)).await;
-        if let Err(e) = stream {
-            println!("error while connecting to master for handshake:{}", e);
-            return;
+        *self.aof.lock().await = Some(aof);
+    }
+
+    /// Applies a write command to the dataset and returns the reply for it.
+    /// Used for client writes as well as AOF replay.
+    async fn apply(&mut self, command: &Command) -> Value {
+        // Replicas leave expiring keys to their master, which sends a DEL.
+        let primary = matches!(self.role().await, Role::Primary);
+        // Evicted keys are queued up here too, but only before a command is
+        // applied, so whatever it adds was lazily expired.
+        let queued = self.expired.len();
+        let mut expired = primary.then_some(&mut self.expired);
+        let resp = match command {
+            Command::Del(keys) | Command::Unlink(keys) => {
+                let mut deleted = Vec::new();
+                for key in keys {
+                    let val = self.store.write(key, |shard| {
+                        shard.remove_if_expired(key, expired.as_deref_mut());
+                        shard.remove(key)
+                    });
+                    deleted.extend(val);
+                }
+                let count = deleted.len() as i64;
+                if matches!(command, Command::Unlink(_)) {
+                    lazy_free(deleted);
+                }
+                Value::Integer(count)
+            }
+            // The library is loaded before the libraries are locked, so
+            // FCALL isn't held up while its code runs.
+            Command::FunctionLoad(code, replace) => {
+                let added = match Library::load(code.clone()).await {
+                    Ok(library) => self.functions.lock().await.add(library, *replace),
+                    Err(resp) => Err(resp),
+                };
+                match added {
+                    Ok(name) => Value::bulk(name),
+                    Err(resp) => resp,
+                }
+            }
+            Command::FunctionDelete(name) => match self.functions.lock().await.delete(name) {
+                true => Value::ok(),
+                false => Value::error("ERR Library not found"),
+            },
+            Command::FunctionFlush => {
+                self.functions.lock().await.flush();
+                Value::ok()
+            }
+            Command::FunctionRestore(payload, policy) => {
+                match RedisDB::restore_functions(payload) {
+                    Ok(codes) => match Library::load_all(codes).await {
+                        Ok(libraries) => {
+                            match self.functions.lock().await.restore(libraries, *policy) {
+                                Ok(()) => Value::ok(),
+                                Err(resp) => resp,
+                            }
+                        }
+                        Err(resp) => resp,
+                    },
+                    Err(_) => Value::error("ERR payload version or checksum are wrong"),
+                }
+            }
+            Command::XReadGroup {
+                group,
+                consumer,
+                count,
+                no_ack,
+                streams,
+            } => read_groups(
+                &*self.store,
+                group,
+                consumer,
+                streams,
+                *count,
+                *no_ack,
+                expired,
+            ),
+            _ => match command.keys().first() {
+                Some(key) => self
+                    .store
+                    .write(key, |shard| apply_to_shard(shard, command, expired)),
+                None => return Value::Nil,
+            },
+        };
+        let lazily_expired = self.expired.len() - queued;
+        if lazily_expired > 0 {
+            self.stats.lock().await.expired_keys += lazily_expired as u64;
         }
-        let stream = stream.unwrap();
-        let ping = Command::Ping;
-        let msg = ping.serialize();
-        write(&stream, msg.as_bytes()).await;
-        let mut buf = [0; 512];
-        if let Err(e) = stream.readable().await {
-            println!(
-                "error while waiting for stream to be readable after sending handshake(PING): {}",
-                e
-            );
-            return;
+        if !matches!(resp, Value::Error(_)) {
+            self.rdb_status.lock().await.changes_since_last_save += 1;
         }
-        loop {
-            match stream.try_read(&mut buf) {
-                Ok(_) => break,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        continue;
-                    }
-                    println!(
-                        "Error while reading handshake(PING) response from master: {}",
-                        e
-                    );
-                    return;
+        resp
+    }
+
+    /// Rewrites the AOF on a background task. Returns false if AOF is off or a
+    /// rewrite is already running.
+    async fn bgrewriteaof(&self) -> bool {
+        let mut aof = self.aof.lock().await;
+        let temp_path = match aof.as_mut().and_then(|aof| aof.start_rewrite()) {
+            Some(temp_path) => temp_path,
+            None => return false,
+        };
+        // The AOF stays locked while the dataset is copied, so every write is
+        // either in the snapshot or in the rewrite buffer, never neither.
+        let ((kivals, exp_map, libraries), _) = self.fork_snapshot().await;
+        drop(aof);
+        let use_rdb_preamble = self
+            .config
+            .lock()
+            .await
+            .get("aof-use-rdb-preamble")
+            .is_some_and(|val| val == "yes");
+        let aof = Arc::clone(&self.aof);
+        tokio::spawn(async move {
+            let path = temp_path.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                RedisAof::write_rewrite(&path, kivals, &exp_map, libraries, use_rdb_preamble)
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+            if let Some(aof) = aof.lock().await.as_mut() {
+                match aof.finish_rewrite(&temp_path, res) {
+                    Ok(()) => info!("Background AOF rewrite finished successfully"),
+                    Err(e) => warn!("Error during AOF rewrite: {:?}", e),
                 }
             }
+        });
+        true
+    }
+
+    /// Moves `keys` to another instance by sending a RESTORE for each of
+    /// them. Returns the reply for the client along with the keys the target
+    /// accepted, which the caller deletes unless COPY was given.
+    async fn migrate(
+        &mut self,
+        host: &str,
+        port: &str,
+        keys: &[Bytes],
+        db: u64,
+        timeout: u64,
+        replace: bool,
+    ) -> (Value, Vec<Bytes>) {
+        let mut restores = Vec::new();
+        for key in keys {
+            let restore = self.store.read(key, |shard| {
+                let val = shard.get(key)?;
+                // A TTL of 0 means no expiry, so one that is about to run out
+                // is rounded up.
+                let ttl = shard.expiry(key).map_or(0, |at| {
+                    at.duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .as_millis()
+                        .max(1) as u64
+                });
+                Some(Command::Restore(
+                    key.clone(),
+                    ttl,
+                    RedisDB::dump_value(val),
+                    replace,
+                    false,
+                ))
+            });
+            restores.extend(restore);
         }
-        let pong = String::from_utf8_lossy(&buf).trim().to_string();
-        if pong.eq("$4\r\nPONG\r\n") {
-            println!("Pong did not match: {}", pong);
+        if restores.is_empty() {
+            return (Value::SimpleString("NOKEY".to_string()), vec![]);
         }
-        let replconf1 = Command::ReplConf("listening-port".to_string(), self.port.clone());
-        let msg = replconf1.serialize();
-        write(&stream, msg.as_bytes()).await;
-        println!("sent listening port");
-        if let Err(e) = stream.readable().await {
-            println!(
-                "error while waiting for stream to become readable after sending handshake(REPLCONF 1): {}",
-                e
-            );
-            return;
+        let timeout = Duration::from_millis(timeout.max(1));
+        let addr = format!("{}:{}", host, port);
+        let mut target = match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(target)) => target,
+            _ => {
+                return (
+                    Value::error("IOERR error or timeout connecting to the client"),
+                    vec![],
+                )
+            }
+        };
+        // In cluster mode the target is importing the keys' slot, so each
+        // RESTORE is preceded by an ASKING.
+        let asking = self.cluster.lock().await.is_some();
+        let mut req = Vec::new();
+        if db != 0 {
+            req.extend(Value::bulk_array(["SELECT".to_string(), db.to_string()]).serialize());
         }
-        loop {
-            match stream.try_read(&mut buf) {
-                Ok(_) => break,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        continue;
-                    }
-                    println!(
-                        "Error while reading handshake(REPLCONF 1) response from master: {}",
-                        e
-                    );
-                    return;
-                }
+        for restore in &restores {
+            if asking {
+                req.extend(Command::Asking.serialize());
             }
+            req.extend(restore.serialize());
         }
-        let replconf2 = Command::ReplConf("capa".to_string(), "psync2".to_string());
-        let msg = replconf2.serialize();
-        write(&stream, msg.as_bytes()).await;
-        if let Err(e) = stream.readable().await {
-            println!(
-                "error while waiting for stream to become readable after sending handshake(REPLCONF 2): {}",
-                e
-            );
-            return;
+        let expected = restores.len() * (1 + asking as usize) + (db != 0) as usize;
+        let replies = tokio::time::timeout(timeout, async {
+            target.write_all(&req).await?;
+            read_values(&mut target, expected).await
+        })
+        .await;
+        let mut replies = match replies {
+            Ok(Ok(replies)) => replies,
+            _ => {
+                return (
+                    Value::error("IOERR error or timeout reading to target instance"),
+                    vec![],
+                )
+            }
+        };
+        if db != 0 {
+            if let Value::Error(e) = replies.remove(0) {
+                let e = format!("ERR Target instance replied with error: {}", e);
+                return (Value::error(e), vec![]);
+            }
         }
-        loop {
-            match stream.try_read(&mut buf) {
-                Ok(_) => break,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        continue;
-                    }
-                    println!(
-                        "error while reading handshake(REPLCONF 2) response from master: {}",
-                        e
-                    );
-                    return;
-                }
+        if asking {
+            replies = replies.into_iter().skip(1).step_by(2).collect();
+        }
+        let mut errors = Vec::new();
+        let mut moved = Vec::new();
+        for (restore, reply) in restores.into_iter().zip(replies) {
+            match (restore, reply) {
+                (_, Value::Error(e)) => errors.push(e),
+                (Command::Restore(key, ..), _) => moved.push(key),
+                _ => {}
             }
         }
-        let psync = Command::Psync("?".to_string(), "-1".to_string());
-        let msg = psync.serialize();
-        write(&stream, msg.as_bytes()).await;
+        let resp = match errors.first() {
+            Some(e) => Value::error(format!("ERR Target instance replied with error: {}", e)),
+            None => Value::ok(),
+        };
+        (resp, moved)
     }
 
-    pub async fn execute(
-        &mut self,
-        command: Command,
-        stream: &TcpStream,
-        tx: Arc<Sender<Command>>,
-    ) {
-        let mut replicate = false;
-        let resp = match &command {
-            Command::Echo(echo) => format!("${}\r\n{}\r\n", echo.len(), echo),
-            Command::Ping => format!("$4\r\nPONG\r\n"),
-            Command::Get(key) => {
-                if let Some(value) = self.get(key).await {
-                    format!("${}\r\n{}\r\n", value.len(), value)
-                } else {
-                    format!("$-1\r\n")
-                }
-            }
-            Command::Set(key, val, exp) => {
-                self.set(key.to_string(), val.to_string(), exp).await;
-                replicate = true;
-                format!("+OK\r\n")
-            }
-            Command::ConfigGet(key) => {
-                if let Some(value) = self.config.lock().await.get(key) {
-                    format!(
-                        "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                        key.len(),
-                        key,
-                        value.len(),
-                        value
-                    )
-                } else {
-                    format!("$-1\r\n")
+    /// Looks up a key, hiding it if it has expired. Reads don't hold the AOF
+    /// lock, so they leave deleting it to the next write of the key or the
+    /// active expire cycle, which can log the DEL in order.
+    async fn get(&self, key: &[u8]) -> Option<RedisValue> {
+        self.store.read(key, |shard| shard.get(key).cloned())
+    }
+
+    /// Looks up a key for a read command, counting it as a keyspace hit or
+    /// miss.
+    async fn lookup_read(&self, key: &[u8]) -> Option<RedisValue> {
+        let value = self.get(key).await;
+        let mut stats = self.stats.lock().await;
+        match value {
+            Some(_) => stats.keyspace_hits += 1,
+            None => stats.keyspace_misses += 1,
+        }
+        value
+    }
+
+    /// Runs a read-only command against the value stored at `key`.
+    async fn read_value(&mut self, command: &Command) -> Value {
+        let key = match command {
+            Command::Get(key)
+            | Command::Type(key)
+            | Command::LRange(key, ..)
+            | Command::SMembers(key)
+            | Command::SRandMember(key, _)
+            | Command::SMIsMember(key, _)
+            | Command::HRandField(key, ..)
+            | Command::ZRandMember(key, ..)
+            | Command::HGetAll(key)
+            | Command::ZRange(key, ..)
+            | Command::XLen(key)
+            | Command::XRange(key, ..)
+            | Command::XInfoStream(key)
+            | Command::XInfoGroups(key)
+            | Command::XInfoConsumers(key, _)
+            | Command::Dump(key) => key,
+            _ => return Value::Nil,
+        };
+        let sample = match command {
+            Command::SRandMember(_, count) => Some((*count, false)),
+            Command::HRandField(_, count, with_values) => Some((*count, *with_values)),
+            Command::ZRandMember(_, count, with_scores) => Some((*count, *with_scores)),
+            _ => None,
+        };
+        if let Some((Some(count), pairs)) = sample {
+            if !sample_count_in_range(count, pairs) {
+                return Value::error("ERR value is out of range");
+            }
+        }
+        let value = self.lookup_read(key).await;
+        match (command, value) {
+            (Command::Dump(_), Some(value)) => Value::Bytes(RedisDB::dump_value(&value)),
+            (Command::Type(_), value) => Value::SimpleString(
+                value
+                    .map(|value| value.type_name())
+                    .unwrap_or("none")
+                    .to_string(),
+            ),
+            (Command::Get(_), None)
+            | (Command::Dump(_), None)
+            | (Command::SRandMember(_, None), None)
+            | (Command::HRandField(_, None, _), None)
+            | (Command::ZRandMember(_, None, _), None) => Value::Nil,
+            (Command::SMIsMember(_, members), None) => {
+                Value::Array(vec![Value::Integer(0); members.len()])
+            }
+            (Command::XLen(_), None) => Value::Integer(0),
+            (Command::XInfoStream(_), None)
+            | (Command::XInfoGroups(_), None)
+            | (Command::XInfoConsumers(..), None) => Value::error("ERR no such key"),
+            (_, None) => Value::Array(vec![]),
+            (Command::Get(_), Some(RedisValue::String(value))) => Value::Bytes(value.to_vec()),
+            (Command::LRange(_, start, stop), Some(RedisValue::List(list))) => {
+                match index_range(*start, *stop, list.len()) {
+                    Some((start, stop)) => {
+                        Value::bytes_array(list.into_iter().skip(start).take(stop - start + 1))
+                    }
+                    None => Value::Array(vec![]),
                 }
             }
-            Command::Keys(_pattern) => {
-                let key_count = self.db.lock().await.keys().count();
-                let res = self.db.lock().await.keys().fold(String::new(), |acc, key| {
-                    format!("{}${}\r\n{}\r\n", acc, key.len(), key)
-                });
-                format!("*{}\r\n{}", key_count, res)
+            (Command::SMembers(_), Some(RedisValue::Set(set))) => Value::bytes_array(set),
+            (Command::SMIsMember(_, members), Some(RedisValue::Set(set))) => Value::Array(
+                members
+                    .iter()
+                    .map(|member| Value::Integer(set.contains(member) as i64))
+                    .collect(),
+            ),
+            // Without a count a single item is returned on its own.
+            (Command::SRandMember(_, count), Some(RedisValue::Set(set))) => {
+                let members = random_sample(set.into_iter().collect(), count.unwrap_or(1));
+                match count {
+                    Some(_) => Value::bytes_array(members),
+                    None => members
+                        .into_iter()
+                        .next()
+                        .map_or(Value::Nil, Value::bulk_bytes),
+                }
             }
-            Command::Info(section) => {
-                if section == "all" || section == "replication" || section == "REPLICATION" {
-                    let info = format!("# Replication \r\nrole:{}\r\n", self.role);
-                    let info = if let Some(master_replid) = &self.replid {
-                        format!("{}master_replid:{}\r\n", info, master_replid)
-                    } else {
-                        info
-                    };
-                    let info = if let Some(master_repl_offset) = &self.repl_offset {
-                        format!("{}master_repl_offset:{}\r\n", info, master_repl_offset)
-                    } else {
-                        info
-                    };
-                    format!("${}\r\n{}\r\n", info.len(), info)
-                } else {
-                    format!("$-1\r\n")
-                }
-            }
-            Command::ReplConf(_, _) => format!("+OK\r\n"),
-            Command::Psync(_repl_id, _offset) => match self.role {
-                Role::Primary => {
-                    let master_repl_offset = self.repl_offset.clone().unwrap();
-                    let master_replid = self.replid.clone().unwrap();
-                    let resp = format!("+FULLRESYNC {} {}\r\n", master_replid, master_repl_offset);
-                    write(&stream, resp.as_bytes()).await;
-                    self.send_emtpy_rdb(&stream).await;
-                    let rx = tx.subscribe();
-                    self.init_replication(rx, &stream).await;
-                    "".to_string()
-                }
-                Role::Replica => format!("$-1\r\n"),
+            (Command::HRandField(_, count, with_values), Some(RedisValue::Hash(hash))) => {
+                let fields = random_sample(hash.into_iter().collect(), count.unwrap_or(1));
+                match count {
+                    Some(_) => Value::bytes_array(fields.into_iter().flat_map(|(field, val)| {
+                        std::iter::once(field).chain(with_values.then_some(val))
+                    })),
+                    None => fields
+                        .into_iter()
+                        .next()
+                        .map_or(Value::Nil, |(field, _)| Value::bulk_bytes(field)),
+                }
+            }
+            (Command::ZRandMember(_, count, with_scores), Some(RedisValue::ZSet(zset))) => {
+                let members = random_sample(zset.into_iter().collect(), count.unwrap_or(1));
+                match count {
+                    Some(_) => {
+                        Value::bytes_array(members.into_iter().flat_map(|(member, score)| {
+                            std::iter::once(member)
+                                .chain(with_scores.then(|| Bytes::from(score.to_string())))
+                        }))
+                    }
+                    None => members
+                        .into_iter()
+                        .next()
+                        .map_or(Value::Nil, |(member, _)| Value::bulk_bytes(member)),
+                }
+            }
+            (Command::HGetAll(_), Some(RedisValue::Hash(hash))) => Value::Map(
+                hash.into_iter()
+                    .map(|(field, val)| (Value::bulk_bytes(field), Value::bulk_bytes(val)))
+                    .collect(),
+            ),
+            (Command::ZRange(_, start, stop, with_scores), Some(RedisValue::ZSet(zset))) => {
+                let members = sorted_zset(&zset);
+                let (start, stop) = match index_range(*start, *stop, members.len()) {
+                    Some(range) => range,
+                    None => return Value::Array(vec![]),
+                };
+                let mut resp = Vec::new();
+                for (member, score) in &members[start..=stop] {
+                    resp.push(Value::bulk_bytes(member));
+                    if *with_scores {
+                        resp.push(Value::BulkString(score.to_string()));
+                    }
+                }
+                Value::Array(resp)
+            }
+            (Command::XLen(_), Some(RedisValue::Stream(stream))) => {
+                Value::Integer(stream.entries.len() as i64)
+            }
+            (Command::XRange(_, range, count), Some(RedisValue::Stream(stream))) => match range {
+                Some((start, end)) => Value::Array(
+                    stream
+                        .range(*start, *end, *count)
+                        .map(|(id, fields)| redis_stream::entry_reply(id, fields))
+                        .collect(),
+                ),
+                None => Value::Array(vec![]),
             },
+            (Command::XInfoStream(_), Some(RedisValue::Stream(stream))) => stream.info(),
+            (Command::XInfoGroups(_), Some(RedisValue::Stream(stream))) => stream.groups_info(),
+            (Command::XInfoConsumers(key, group), Some(RedisValue::Stream(stream))) => stream
+                .consumers_info(group)
+                .unwrap_or_else(|| no_group(key, group)),
+            _ => Value::error(WRONGTYPE),
+        }
+    }
+
+    async fn redis_db(&self) -> RedisDB {
+        let config = self.config.lock().await;
+        let mut redis_db = RedisDB::new(
+            config
+                .get("dir")
+                .cloned()
+                .unwrap_or_else(|| ".".to_string()),
+            config
+                .get("dbfilename")
+                .cloned()
+                .unwrap_or_else(|| "dump.rdb".to_string()),
+        );
+        redis_db.set_verify_checksum(config.get("rdbchecksum").map(String::as_str) != Some("no"));
+        redis_db
+    }
+
+    async fn verify_rdb_checksum(&self) -> bool {
+        self.config
+            .lock()
+            .await
+            .get("rdbchecksum")
+            .map(String::as_str)
+            != Some("no")
+    }
+
+    /// Copies the dataset along with the number of changes it contains since
+    /// the last save, so a successful snapshot can subtract exactly those.
+    async fn snapshot(&self) -> (Dataset, u64) {
+        let dirty = self.rdb_status.lock().await.changes_since_last_save;
+        let (db, exp) = self.store.read_all(|shards| {
+            let mut db = HashMap::new();
+            let mut exp = HashMap::new();
+            for shard in shards {
+                db.extend(
+                    shard
+                        .db
+                        .iter()
+                        .map(|(key, entry)| (key.clone(), entry.value.clone())),
+                );
+                exp.extend(shard.volatile().map(|(key, at)| (key.clone(), at)));
+            }
+            (db, exp)
+        });
+        let libraries = self.functions.lock().await.codes();
+        ((db, exp, libraries), dirty)
+    }
+
+    /// Takes a snapshot for a background save, AOF rewrite or full resync,
+    /// where Redis would fork, and records how long it took as a "fork"
+    /// latency event.
+    async fn fork_snapshot(&self) -> (Dataset, u64) {
+        let start = Instant::now();
+        let snapshot = self.snapshot().await;
+        self.record_latency("fork", start.elapsed()).await;
+        snapshot
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let ((kivals, exp_map, libraries), dirty) = self.snapshot().await;
+        self.redis_db()
+            .await
+            .write_rdb(&kivals, &exp_map, &libraries)?;
+        let mut rdb_status = self.rdb_status.lock().await;
+        rdb_status.last_save_time = SystemTime::now();
+        rdb_status.changes_since_last_save =
+            rdb_status.changes_since_last_save.saturating_sub(dirty);
+        Ok(())
+    }
+
+    /// Flushes the AOF and, if `save` asks for it or save points are
+    /// configured, saves a final snapshot, then halts the server: clients are
+    /// disconnected and background tasks end. The AOF lock is held until
+    /// then, so no write can slip in behind the snapshot. Returns the error,
+    /// leaving the server running, if any of it fails.
+    pub async fn shutdown(&self, save: Option<bool>) -> anyhow::Result<()> {
+        info!("User requested shutdown...");
+        let mut aof = self.aof.lock().await;
+        if let Some(aof) = aof.as_mut() {
+            aof.fsync()?;
+        }
+        let save = match save {
+            Some(save) => save,
+            None => self
+                .config
+                .lock()
+                .await
+                .get("save")
+                .and_then(|rules| parse_save_rules(rules))
+                .is_some_and(|rules| !rules.is_empty()),
         };
-        if !resp.eq("") {
-            write(&stream, resp.as_bytes()).await;
+        if save {
+            info!("Saving the final RDB snapshot before exiting");
+            self.save().await?;
+        }
+        info!("Redis is now ready to exit, bye bye...");
+        self.halted.send_replace(true);
+        if let Some(master_link) = self.repl_status.lock().await.master_link.take() {
+            master_link.abort();
         }
-        if replicate {
-            let _ = tx.send(command);
+        // Dropping the replicas' queues ends the tasks serving them.
+        self.replicas.lock().await.clear();
+        for client in self.clients.lock().await.values() {
+            client.kill.notify_one();
         }
+        Ok(())
     }
 
-    async fn init_replication(&self, mut rx: Receiver<Command>, stream: &TcpStream) {
-        loop {
-            match rx.recv().await {
-                Ok(cmd) => {
-                    let cmd_str = cmd.serialize();
-                    write(&stream, cmd_str.as_bytes()).await;
+    /// Resolves once the server has been shut down.
+    pub async fn halted(&self) {
+        let mut halted = self.halted.subscribe();
+        while !*halted.borrow_and_update() {
+            if halted.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Starts a snapshot on a background task. Returns false if another
+    /// background save is still running.
+    async fn bgsave(&self) -> bool {
+        {
+            let mut rdb_status = self.rdb_status.lock().await;
+            if rdb_status.bgsave_in_progress {
+                return false;
+            }
+            rdb_status.bgsave_in_progress = true;
+            rdb_status.last_bgsave_try = SystemTime::now();
+        }
+        let ((kivals, exp_map, libraries), dirty) = self.fork_snapshot().await;
+        let redis_db = self.redis_db().await;
+        let rdb_status = Arc::clone(&self.rdb_status);
+        info!("Background saving started");
+        tokio::spawn(async move {
+            let res = tokio::task::spawn_blocking(move || {
+                redis_db.write_rdb(&kivals, &exp_map, &libraries)
+            })
+            .await;
+            let mut rdb_status = rdb_status.lock().await;
+            rdb_status.bgsave_in_progress = false;
+            match res {
+                Ok(Ok(())) => {
+                    info!("Background saving terminated with success");
+                    rdb_status.last_save_time = SystemTime::now();
+                    rdb_status.last_bgsave_ok = true;
+                    rdb_status.changes_since_last_save =
+                        rdb_status.changes_since_last_save.saturating_sub(dirty);
+                }
+                Ok(Err(e)) => {
+                    warn!("Error during background save: {:?}", e);
+                    rdb_status.last_bgsave_ok = false;
                 }
-                Err(error::RecvError::Closed) => {
-                    break;
+                Err(e) => {
+                    warn!("Background save task failed: {:?}", e);
+                    rdb_status.last_bgsave_ok = false;
                 }
-                Err(_) => {}
             }
-        }
+        });
+        true
     }
 
-    async fn send_emtpy_rdb(&mut self, stream: &TcpStream) {
-        let decode_bytes = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2")
-            .context("Error while decoding hex").unwrap();
-        match &self.role {
-            Role::Primary => {
-                write(&stream, format!("${}\r\n", decode_bytes.len()).as_bytes()).await;
-                write(&stream, &decode_bytes).await;
+    /// Periodic housekeeping, run on its own task for the lifetime of the
+    /// server.
+    async fn cron(self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        let mut last_ping = Instant::now();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.halted() => return,
+            }
+            self.check_save_rules().await;
+            let (ping_period, timeout) = {
+                let config = self.config.lock().await;
+                let get = |key: &str, default: u64| {
+                    config
+                        .get(key)
+                        .and_then(|val| val.parse::<u64>().ok())
+                        .unwrap_or(default)
+                };
+                (get("repl-ping-replica-period", 10), get("repl-timeout", 60))
+            };
+            if last_ping.elapsed() >= Duration::from_secs(ping_period) {
+                last_ping = Instant::now();
+                self.ping_replicas().await;
+            }
+            self.drop_timed_out_replicas(Duration::from_secs(timeout))
+                .await;
+            let start = Instant::now();
+            self.active_expire_cycle().await;
+            self.record_latency("expire-cycle", start.elapsed()).await;
+            self.close_idle_clients().await;
+            self.stats.lock().await.sample_ops();
+            if let Some(aof) = self.aof.lock().await.as_mut() {
+                if let Err(e) = aof.fsync_if_due() {
+                    warn!("Error syncing AOF file: {:?}", e);
+                }
             }
-            Role::Replica => {}
         }
     }
-}
 
-async fn write(stream: &TcpStream, bytes: &[u8]) {
-    let mut offset = 0;
-    loop {
-        stream.writable().await.unwrap();
-        if let Ok(n) = stream.try_write(&bytes) {
-            offset += n;
-            if offset >= bytes.len() {
-                break;
+    /// Removes keys whose expiry has passed even if nobody asks for them,
+    /// logging and propagating a DEL for each. Only primaries expire keys.
+    async fn active_expire_cycle(&self) {
+        if !self.active_expire.load(Ordering::Relaxed) || self.writes_paused().await {
+            return;
+        }
+        if let Role::Replica = self.role().await {
+            return;
+        }
+        let mut aof = self.aof.lock().await;
+        let now = SystemTime::now();
+        let expired = self.store.write_all(|shards| {
+            let mut expired = Vec::new();
+            for shard in shards.iter_mut() {
+                let keys = shard.due(now, ACTIVE_EXPIRE_LIMIT - expired.len());
+                for key in &keys {
+                    shard.remove(key);
+                }
+                expired.extend(keys);
+            }
+            expired
+        });
+        if expired.is_empty() {
+            return;
+        }
+        self.rdb_status.lock().await.changes_since_last_save += expired.len() as u64;
+        self.stats.lock().await.expired_keys += expired.len() as u64;
+        for key in expired {
+            let del = Command::Del(vec![key]);
+            if let Some(aof) = aof.as_mut() {
+                if let Err(e) = aof.append(&del) {
+                    warn!("Error writing AOF file: {:?}", e);
+                }
             }
+            self.propagate(&del).await;
+        }
+    }
+
+    /// Triggers a BGSAVE once any `save <seconds> <changes>` rule is met. After
+    /// a failed background save, retries are held back for a few seconds.
+    async fn check_save_rules(&self) {
+        let rules = match self.config.lock().await.get("save") {
+            Some(rules) => parse_save_rules(rules).unwrap_or_default(),
+            None => return,
+        };
+        let should_save = {
+            let rdb_status = self.rdb_status.lock().await;
+            let since_save = rdb_status.last_save_time.elapsed().unwrap_or_default();
+            let since_try = rdb_status.last_bgsave_try.elapsed().unwrap_or_default();
+            !rdb_status.bgsave_in_progress
+                && (rdb_status.last_bgsave_ok || since_try >= Duration::from_secs(5))
+                && rules.iter().any(|(seconds, changes)| {
+                    rdb_status.changes_since_last_save >= *changes
+                        && since_save >= Duration::from_secs(*seconds)
+                })
+        };
+        if should_save {
+            self.bgsave().await;
         }
     }
+
+    async fn role(&self) -> Role {
+        self.repl_status.lock().await.role
+    }
+
+    async fn has_replid(&self) -> bool {
+        self.repl_status.lock().await.replid.is_some()
+    }
+
+    /// In cluster mode, the error redirecting a command whose keys this node
+    /// doesn't serve. MIGRATE is never redirected, as it moves whichever
+    /// keys of a migrating slot are still here.
+    async fn route(&self, command: &Command, asking: bool) -> Option<Value> {
+        let cluster = self.cluster.lock().await;
+        let migrate = matches!(command, Command::Migrate { .. });
+        let exists = |key: &[u8]| migrate || self.store.read(key, |shard| shard.get(key).is_some());
+        let resp = match cluster.as_ref()?.route(command.keys(), asking, exists) {
+            Route::Local => return None,
+            Route::CrossSlot => {
+                Value::error("CROSSSLOT Keys in request don't hash to the same slot")
+            }
+            Route::Moved(slot, addr) => Value::error(format!("MOVED {} {}", slot, addr)),
+            Route::Ask(slot, addr) => Value::error(format!("ASK {} {}", slot, addr)),
+            Route::TryAgain => {
+                Value::error("TRYAGAIN Multiple keys request during rehashing of slot")
+            }
+            Route::Unassigned(slot) => {
+                Value::error(format!("CLUSTERDOWN Hash slot {} not served", slot))
+            }
+        };
+        Some(resp)
+    }
+
+    async fn cluster_command(&self, command: &Command) -> Value {
+        let mut cluster = self.cluster.lock().await;
+        let cluster = match cluster.as_mut() {
+            Some(cluster) => cluster,
+            None => return Value::error("ERR This instance has cluster support disabled"),
+        };
+        match command {
+            Command::ClusterInfo => Value::bulk(cluster.info()),
+            Command::ClusterMyId => Value::bulk(cluster.myid()),
+            Command::ClusterNodes => Value::bulk(cluster.describe()),
+            Command::ClusterSlots => cluster.slots_reply(),
+            Command::ClusterShards => cluster.shards_reply(),
+            Command::ClusterMeet(host, port) => {
+                if port.parse::<u16>().is_err() {
+                    return Value::error(format!(
+                        "ERR Invalid node address specified: {}:{}",
+                        host, port
+                    ));
+                }
+                cluster.meet(host, port);
+                self.save_cluster(cluster).await;
+                tokio::spawn(self.clone().say_hello(format!("{}:{}", host, port)));
+                Value::ok()
+            }
+            Command::ClusterHello(description) => {
+                let added = cluster.merge(description);
+                self.save_cluster(cluster).await;
+                for node in added {
+                    tokio::spawn(self.clone().say_hello(node.addr()));
+                }
+                Value::bulk(cluster.describe())
+            }
+            Command::ClusterSetSlot(slot, action) => match cluster.set_slot(*slot, action) {
+                Ok(()) => {
+                    self.save_cluster(cluster).await;
+                    Value::ok()
+                }
+                Err(e) => e,
+            },
+            _ => Value::Nil,
+        }
+    }
+
+    /// Sends this node's description to the node at `addr` and merges the one
+    /// it replies with. Nodes learned about this way are greeted in turn, so
+    /// meeting a single node of a cluster is enough to join all of it.
+    async fn say_hello(self, addr: String) {
+        let mut pending = vec![addr];
+        while let Some(addr) = pending.pop() {
+            pending.extend(self.greet(&addr).await);
+        }
+    }
+
+    /// Exchanges descriptions with a single node. Returns the addresses of
+    /// the nodes this one didn't know about.
+    async fn greet(&self, addr: &str) -> Vec<String> {
+        let hello = {
+            let cluster = self.cluster.lock().await;
+            match cluster.as_ref() {
+                Some(cluster) => Command::ClusterHello(cluster.describe()),
+                None => return vec![],
+            }
+        };
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(&hello.serialize()).await?;
+            read_values(&mut stream, 1).await
+        })
+        .await;
+        let description = match reply {
+            Ok(Ok(mut replies)) => match replies.remove(0) {
+                Value::BulkString(description) => description,
+                reply => {
+                    warn!("Unexpected reply from cluster node {}: {:?}", addr, reply);
+                    return vec![];
+                }
+            },
+            _ => {
+                warn!("Error meeting cluster node {}", addr);
+                return vec![];
+            }
+        };
+        let mut cluster = self.cluster.lock().await;
+        match cluster.as_mut() {
+            Some(cluster) => {
+                let added = cluster.merge(&description);
+                self.save_cluster(cluster).await;
+                added.iter().map(|node| node.addr()).collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Persists the node table to `cluster-config-file`.
+    async fn save_cluster(&self, cluster: &Cluster) {
+        let path = {
+            let config = self.config.lock().await;
+            format!(
+                "{}/{}",
+                config.get("dir").map(String::as_str).unwrap_or("."),
+                config
+                    .get("cluster-config-file")
+                    .map(String::as_str)
+                    .unwrap_or("nodes.conf")
+            )
+        };
+        if let Err(e) = cluster.save(&path) {
+            warn!("Error saving cluster config: {:?}", e);
+        }
+    }
+
+    /// Replicas reject writes from clients, unless replica-read-only is off.
+    /// Writes from the master are applied without going through `execute`.
+    async fn is_read_only(&self) -> bool {
+        if let Role::Primary = self.role().await {
+            return false;
+        }
+        self.config
+            .lock()
+            .await
+            .get("replica-read-only")
+            .map(String::as_str)
+            != Some("no")
+    }
+
+    /// Starts following the configured master on a background task.
+    async fn connect_to_master(&self) {
+        let mut instance = self.clone();
+        let master_link = tokio::spawn(async move {
+            match instance.handshake_with_master().await {
+                Ok(stream) => instance.follow_master(stream).await,
+                Err(e) => warn!("Replication handshake failed: {:#}", e),
+            }
+        });
+        self.repl_status.lock().await.master_link = Some(master_link);
+    }
+
+    /// Records what a replica announces about itself, which it is registered
+    /// with once it asks for a sync. ACK and GETACK only mean something on a
+    /// replication link, and are ignored here.
+    fn replconf(&mut self, options: &[(String, String)]) -> Value {
+        let attributes = &mut self.connection.replconf;
+        for (key, val) in options {
+            match key.as_str() {
+                "listening-port" => match val.parse::<u16>() {
+                    Ok(port) => attributes.listening_port = Some(port.to_string()),
+                    Err(_) => return Value::error("ERR value is not an integer or out of range"),
+                },
+                "ip-address" => {
+                    // Matches NET_HOST_STR_LEN, which Redis limits it to.
+                    if val.len() >= 46 {
+                        return Value::error(format!(
+                            "ERR REPLCONF ip-address provided by replica instance is too long: {} bytes",
+                            val.len()
+                        ));
+                    }
+                    attributes.ip_address = Some(val.clone());
+                }
+                "capa" => {
+                    if !attributes.capabilities.contains(val) {
+                        attributes.capabilities.push(val.clone());
+                    }
+                }
+                "ack" | "getack" => {}
+                _ => return Value::error(format!("ERR Unrecognized REPLCONF option: {}", key)),
+            }
+        }
+        Value::ok()
+    }
+
+    /// Switches to replicating `host:port`, or back to a primary when `master`
+    /// is None. Replicas attached to this instance are dropped when it is
+    /// demoted, as they now have to sync with the new master's history.
+    async fn replicaof(&self, master: Option<(String, String)>) -> Value {
+        let mut repl_status = self.repl_status.lock().await;
+        if let Some((host, port)) = &master {
+            if matches!(repl_status.role, Role::Replica)
+                && repl_status.master_host.as_ref() == Some(host)
+                && repl_status.master_port.as_ref() == Some(port)
+            {
+                return Value::SimpleString("OK Already connected to specified master".to_string());
+            }
+        }
+        if let Some(master_link) = repl_status.master_link.take() {
+            master_link.abort();
+        }
+        match master {
+            Some((host, port)) => {
+                repl_status.role = Role::Replica;
+                repl_status.master_host = Some(host);
+                repl_status.master_port = Some(port);
+                drop(repl_status);
+                self.replicas.lock().await.clear();
+                self.connect_to_master().await;
+            }
+            None => {
+                if let Role::Replica = repl_status.role {
+                    repl_status.role = Role::Primary;
+                    repl_status.master_host = None;
+                    repl_status.master_port = None;
+                    repl_status.replid = Some(random_id());
+                }
+            }
+        }
+        Value::ok()
+    }
+
+    /// Runs the replication handshake up to PSYNC. Returns the connection to
+    /// the master, on which the FULLRESYNC reply and the stream follow, or
+    /// the step the master didn't go along with.
+    async fn handshake_with_master(&mut self) -> anyhow::Result<Box<dyn Link>> {
+        let (master_host, master_port) = {
+            let repl_status = self.repl_status.lock().await;
+            match (&repl_status.master_host, &repl_status.master_port) {
+                (Some(host), Some(port)) => (host.clone(), port.clone()),
+                _ => anyhow::bail!("Master host and port are not set"),
+            }
+        };
+        info!("Connecting to MASTER {}:{}", master_host, master_port);
+        let stream = TcpStream::connect(format!("{}:{}", master_host, master_port))
+            .await
+            .context("Error condition on socket for SYNC")?;
+        let mut stream: Box<dyn Link> = match self.replication_tls().await {
+            Some(tls_files) => Box::new(
+                tls_files
+                    .connect(&master_host, stream)
+                    .await
+                    .context("Error connecting to MASTER over TLS")?,
+            ),
+            None => Box::new(stream),
+        };
+        let mut buf = BytesMut::new();
+        let pong = handshake_call(&mut stream, &mut buf, &Command::Ping)
+            .await
+            .context("Error reading reply to PING from master")?;
+        if pong != Value::SimpleString("PONG".to_string()) {
+            anyhow::bail!("Unexpected reply to PING from master: {:?}", pong);
+        }
+        debug!("Master replied to PING, replication can continue...");
+        // The master is told the address to reach this replica at, which is
+        // the announced one when it's behind NAT or port forwarding.
+        let (announce_ip, announce_port) = {
+            let config = self.config.lock().await;
+            (
+                config
+                    .get("replica-announce-ip")
+                    .cloned()
+                    .unwrap_or_default(),
+                config
+                    .get("replica-announce-port")
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+        };
+        let port = match announce_port.as_str() {
+            "" | "0" => self.port.clone(),
+            _ => announce_port,
+        };
+        debug!("Sending REPLCONF listening-port {}", port);
+        let mut options = vec![("listening-port".to_string(), port)];
+        if !announce_ip.is_empty() {
+            debug!("Sending REPLCONF ip-address {}", announce_ip);
+            options.push(("ip-address".to_string(), announce_ip));
+        }
+        let reply = handshake_call(&mut stream, &mut buf, &Command::ReplConf(options))
+            .await
+            .context("Error reading reply to REPLCONF listening-port from master")?;
+        expect_ok(reply).context("Master refused REPLCONF listening-port")?;
+        let capa = Command::ReplConf(vec![("capa".to_string(), "psync2".to_string())]);
+        let reply = handshake_call(&mut stream, &mut buf, &capa)
+            .await
+            .context("Error reading reply to REPLCONF capa from master")?;
+        expect_ok(reply).context("Master refused REPLCONF capa")?;
+        info!("Trying a partial resynchronization (request ?:-1)");
+        let psync = Command::Psync("?".to_string(), "-1".to_string());
+        stream
+            .write_all(&psync.serialize())
+            .await
+            .context("Error sending PSYNC to master")?;
+        Ok(stream)
+    }
+
+    /// The files to connect to the master over TLS with, or None when
+    /// tls-replication is off.
+    async fn replication_tls(&self) -> Option<TlsFiles> {
+        let config = self.config.lock().await;
+        if config["tls-replication"] != "yes" {
+            return None;
+        }
+        Some(TlsFiles {
+            cert_file: config["tls-cert-file"].clone(),
+            key_file: config["tls-key-file"].clone(),
+            ca_cert_file: config["tls-ca-cert-file"].clone(),
+        })
+    }
+
+    /// Reads the master's FULLRESYNC reply and RDB payload, then applies every
+    /// command it propagates for as long as the connection stays up. The
+    /// offset counts the bytes of the stream processed so far.
+    async fn follow_master(mut self, stream: Box<dyn Link>) {
+        // Acks are written while a read is pending.
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut buf = BytesMut::new();
+        if let Err(e) = self.read_fullresync(&mut reader, &mut buf).await {
+            warn!("Error during full resync with master: {:#}", e);
+            return;
+        }
+        info!("MASTER <-> REPLICA sync: Finished with success");
+        // Our own replicas followed the previous history and have to resync.
+        self.replicas.lock().await.clear();
+        // Besides answering GETACK, the offset is acknowledged every second
+        // so the master always has a recent view of it.
+        let mut ack_interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            loop {
+                let (args, frame) = match redis_resp::parse_request(&mut buf) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Error parsing replication stream: {:?}", e);
+                        return;
+                    }
+                };
+                let aof_lock = Arc::clone(&self.aof);
+                let mut aof = aof_lock.lock().await;
+                let command = match Command::from_args(&args) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        warn!("Error parsing replication stream: {}", e);
+                        None
+                    }
+                };
+                if let Some(command) = command {
+                    if let Command::ReplConf(options) = &command {
+                        // The GETACK itself isn't included in the offset.
+                        if options.iter().any(|(key, _)| key == "getack") {
+                            self.send_ack(&mut writer).await;
+                        }
+                    } else if command.is_write()
+                        && !matches!(self.apply(&command).await, Value::Error(_))
+                    {
+                        if let Some(aof) = aof.as_mut() {
+                            if let Err(e) = aof.append(&command) {
+                                warn!("Error writing AOF file: {:?}", e);
+                            }
+                        }
+                    }
+                }
+                // Replicas of this replica get the master's stream byte for
+                // byte, so offsets line up across the whole chain.
+                self.forward(frame.to_vec()).await;
+            }
+            tokio::select! {
+                res = read_more(&mut reader, &mut buf) => {
+                    if let Err(e) = res {
+                        warn!("Error reading from master: {}", e);
+                        break;
+                    }
+                }
+                _ = ack_interval.tick() => self.send_ack(&mut writer).await,
+            }
+        }
+        warn!("Connection with master lost");
+    }
+
+    async fn send_ack(&self, stream: &mut (impl AsyncWrite + Unpin)) {
+        let offset = self.repl_status.lock().await.offset;
+        let ack = Command::ReplConf(vec![("ACK".to_string(), offset.to_string())]);
+        let _ = stream.write_all(&ack.serialize()).await;
+    }
+
+    /// Consumes `+FULLRESYNC <replid> <offset>` and the RDB payload sent after
+    /// it from the front of the stream, leaving anything past them in `buf`.
+    async fn read_fullresync(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+        buf: &mut BytesMut,
+    ) -> anyhow::Result<()> {
+        let reply = loop {
+            if let Some((value, consumed)) = Value::parse(buf)? {
+                buf.advance(consumed);
+                break value;
+            }
+            read_more(stream, buf).await?;
+        };
+        let (replid, offset) = match &reply {
+            Value::SimpleString(reply) => {
+                let mut parts = reply.split_whitespace();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+                        (replid.to_string(), offset.parse::<usize>()?)
+                    }
+                    _ => anyhow::bail!("Unexpected PSYNC reply {:?}", reply),
+                }
+            }
+            _ => anyhow::bail!("Unexpected PSYNC reply {:?}", reply),
+        };
+        // The payload is framed like a bulk string, minus the trailing CRLF.
+        let (header_len, rdb_len) = loop {
+            if let Some(line_end) = buf.windows(2).position(|window| window == b"\r\n") {
+                let header = String::from_utf8_lossy(&buf[..line_end]);
+                let rdb_len = header
+                    .strip_prefix('$')
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid RDB header {:?}", header))?;
+                break (line_end + 2, rdb_len);
+            }
+            read_more(stream, buf).await?;
+        };
+        while buf.len() < header_len + rdb_len {
+            read_more(stream, buf).await?;
+        }
+        info!(
+            "MASTER <-> REPLICA sync: receiving {} bytes from master to memory",
+            rdb_len
+        );
+        let mut redis_db = self.redis_db().await;
+        let (dataset, _) = redis_db
+            .parse_rdb(&buf[header_len..header_len + rdb_len])
+            .context("Error parsing the RDB payload from master")?;
+        buf.advance(header_len + rdb_len);
+        // The master's dataset replaces whatever this instance held, keys
+        // and function libraries alike.
+        info!("MASTER <-> REPLICA sync: Flushing old data");
+        self.store.write_all(|shards| {
+            for shard in shards.iter_mut() {
+                **shard = Shard::default();
+            }
+        });
+        self.functions.lock().await.flush();
+        info!("MASTER <-> REPLICA sync: Loading DB in memory");
+        self.load_dataset(dataset).await;
+        {
+            let mut repl_status = self.repl_status.lock().await;
+            repl_status.replid = Some(replid);
+            repl_status.offset = offset;
+        }
+        // An AOF holds none of the loaded keys, so it's rewritten from them.
+        self.bgrewriteaof().await;
+        Ok(())
+    }
+
+    /// Runs a command parsed from the request `args` and appends the reply to
+    /// `out`, recording its timing for INFO commandstats. Returns false when
+    /// the connection should be closed: after SHUTDOWN, or once the replica
+    /// it was handed over to is gone.
+    pub async fn execute<C: Connection>(
+        &mut self,
+        args: &[Bytes],
+        command: Command,
+        conn: &C,
+        out: &mut Vec<u8>,
+    ) -> bool {
+        let name = redis_registry::invoked(args).map_or(command.name(), |spec| spec.name);
+        if command.may_block() || self.is_paused(&command).await {
+            let _ = redis_io::flush(conn, out).await;
+        }
+        // Pauses are waited out before the keyspace is locked, so a paused
+        // client doesn't hold up function calls too. A function call locks
+        // the keyspace itself.
+        self.wait_until_unpaused(&command).await;
+        let keyspace_lock = Arc::clone(&self.keyspace_lock);
+        let uses_keys = match command {
+            Command::FCall { .. } => false,
+            Command::Migrate { .. } => true,
+            _ => command.is_write() || command.is_read() || !command.keys().is_empty(),
+        };
+        let _keyspace = match uses_keys {
+            true => Some(keyspace_lock.read().await),
+            false => None,
+        };
+        self.set_in_command(true).await;
+        let start = Instant::now();
+        let dispatched = self.dispatch(&command, conn).await;
+        let duration = start.elapsed();
+        self.set_in_command(false).await;
+        if dispatched.is_ok() {
+            self.log_if_slow(args, duration, conn).await;
+            self.record_latency("command", duration).await;
+        }
+        trace!(command = name, ?duration, "Executed command");
+        let (resp, outcome) = match dispatched {
+            Ok(Some(resp)) if matches!(resp, Value::Error(_)) => (resp, CallOutcome::Failed),
+            Ok(Some(resp)) => (resp, CallOutcome::Ok),
+            Err(resp) => (resp, CallOutcome::Rejected),
+            // The connection was shut down or handed over to a replica.
+            Ok(None) => return false,
+        };
+        self.stats
+            .lock()
+            .await
+            .record(name.to_string(), duration, outcome);
+        resp.serialize_into(out);
+        true
+    }
+
+    /// Records a latency spike of `event` if it took at least
+    /// latency-monitor-threshold milliseconds. A threshold of 0 disables the
+    /// latency monitor.
+    async fn record_latency(&self, event: &str, duration: Duration) {
+        let threshold = self.latency_threshold().await;
+        let latency = duration.as_millis() as u64;
+        if threshold > 0 && latency >= threshold {
+            self.latency.lock().await.add(event, latency);
+        }
+    }
+
+    async fn latency_threshold(&self) -> u64 {
+        self.config
+            .lock()
+            .await
+            .get("latency-monitor-threshold")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(0)
+    }
+
+    /// Adds the request to the slow log if it ran for longer than
+    /// slowlog-log-slower-than microseconds. A negative threshold disables
+    /// the slow log. The arguments are logged as the client sent them, not
+    /// as the command was rewritten, e.g. EXPIRE to PEXPIREAT.
+    async fn log_if_slow<C: Connection>(&self, args: &[Bytes], duration: Duration, conn: &C) {
+        let (slower_than, max_len) = {
+            let config = self.config.lock().await;
+            (
+                config
+                    .get("slowlog-log-slower-than")
+                    .and_then(|val| val.parse::<i64>().ok())
+                    .unwrap_or(10000),
+                config
+                    .get("slowlog-max-len")
+                    .and_then(|val| val.parse::<usize>().ok())
+                    .unwrap_or(128),
+            )
+        };
+        let slower_than = match u128::try_from(slower_than) {
+            Ok(slower_than) => slower_than,
+            Err(_) => return,
+        };
+        if duration.as_micros() < slower_than {
+            return;
+        }
+        let client_addr = conn
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let args = args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+        let client_name = self.connection.name.clone().unwrap_or_default();
+        self.slowlog
+            .lock()
+            .await
+            .push(args, duration, client_addr, client_name, max_len);
+    }
+
+    /// Runs a command and returns the reply for it. Commands refused before
+    /// they run are replied to with an Err.
+    async fn dispatch<C: Connection>(
+        &mut self,
+        command: &Command,
+        conn: &C,
+    ) -> Result<Option<Value>, Value> {
+        let asking = std::mem::take(&mut self.connection.asking);
+        if let Some(resp) = self.route(command, asking).await {
+            return Err(resp);
+        }
+        let is_write = command.is_write() || matches!(command, Command::Migrate { .. });
+        self.wait_until_unpaused(command).await;
+        if is_write && self.is_read_only().await {
+            return Err(Value::error(
+                "READONLY You can't write against a read only replica.",
+            ));
+        }
+        // The command logged to the AOF and sent to replicas, if any.
+        let mut propagate: Option<Command> = None;
+        // Writes hold the AOF lock until they are logged, which keeps them
+        // ordered with respect to an AOF rewrite taking its snapshot. Reads
+        // don't take it, and only hide expired keys instead of deleting them,
+        // so they can run alongside each other.
+        let aof_lock = Arc::clone(&self.aof);
+        let mut aof = match command.is_write() {
+            true => Some(aof_lock.lock().await),
+            false => None,
+        };
+        if command.may_grow() && !self.free_memory().await {
+            self.log_and_propagate(aof.as_deref_mut(), None).await;
+            return Err(Value::error(
+                "OOM command not allowed when used memory > 'maxmemory'.",
+            ));
+        }
+        let resp = match command {
+            Command::Echo(echo) => Value::bulk_bytes(echo),
+            Command::Ping => Value::SimpleString("PONG".to_string()),
+            Command::Get(_)
+            | Command::Type(_)
+            | Command::LRange(..)
+            | Command::SMembers(_)
+            | Command::SRandMember(..)
+            | Command::SMIsMember(..)
+            | Command::HRandField(..)
+            | Command::ZRandMember(..)
+            | Command::HGetAll(_)
+            | Command::ZRange(..)
+            | Command::XLen(_)
+            | Command::XRange(..)
+            | Command::XInfoStream(_)
+            | Command::XInfoGroups(_)
+            | Command::XInfoConsumers(..)
+            | Command::Dump(_) => self.read_value(command).await,
+            Command::SInterCard(keys, limit) => self.sintercard(keys, *limit).await,
+            Command::Set(..)
+            | Command::Del(_)
+            | Command::Unlink(_)
+            | Command::Restore(..)
+            | Command::RPush(..)
+            | Command::SAdd(..)
+            | Command::SRem(..)
+            | Command::HSet(..)
+            | Command::ZAdd(..)
+            | Command::XGroupCreate { .. }
+            | Command::XGroupCreateConsumer(..)
+            | Command::XGroupDelConsumer(..)
+            | Command::XGroupDestroy(..)
+            | Command::XAck(..)
+            | Command::FunctionLoad(..)
+            | Command::FunctionDelete(_)
+            | Command::FunctionFlush
+            | Command::FunctionRestore(..) => {
+                let resp = self.apply(command).await;
+                if !matches!(resp, Value::Error(_)) {
+                    propagate = Some(command.clone());
+                }
+                resp
+            }
+            // The condition has already been checked, so replicas get a plain
+            // PEXPIREAT, or a DEL if the key expired straight away.
+            Command::PExpireAt(key, at, _) => {
+                let resp = self.apply(command).await;
+                if resp == Value::Integer(1) {
+                    propagate = Some(match *at <= SystemTime::now() {
+                        true => Command::Del(vec![key.clone()]),
+                        false => Command::PExpireAt(key.clone(), *at, vec![]),
+                    });
+                }
+                resp
+            }
+            Command::ExpireTime(key, millis) => self.expire_time(key, *millis).await,
+            // Replicas read the same entries from the same state, so the read
+            // is propagated as it is, unless it didn't find anything.
+            Command::XReadGroup { .. } => {
+                let resp = self.apply(command).await;
+                if !matches!(resp, Value::Error(_) | Value::Nil) {
+                    propagate = Some(command.clone());
+                }
+                resp
+            }
+            // The ID picked is propagated, so replicas add the entry under the
+            // same one rather than their own clock's.
+            Command::XAdd {
+                key,
+                fields,
+                no_mkstream,
+                trim,
+                ..
+            } => {
+                let resp = self.apply(command).await;
+                if let Value::BulkString(id) = &resp {
+                    propagate = StreamId::parse(id, 0).map(|id| Command::XAdd {
+                        key: key.clone(),
+                        id: NewId::Explicit(id),
+                        fields: fields.clone(),
+                        no_mkstream: *no_mkstream,
+                        trim: *trim,
+                    });
+                }
+                resp
+            }
+            Command::Touch(keys) => self.count_existing(keys).await,
+            // The members popped are propagated as an SREM, since replicas
+            // would pop different ones.
+            Command::SPop(key, _) => {
+                let resp = self.apply(command).await;
+                let member = |member: &Value| match member {
+                    Value::BulkString(member) => Some(Bytes::from(member.clone())),
+                    Value::Bytes(member) => Some(Bytes::from(member.clone())),
+                    _ => None,
+                };
+                let popped: Vec<Bytes> = match &resp {
+                    Value::Array(members) => members.iter().filter_map(member).collect(),
+                    resp => member(resp).into_iter().collect(),
+                };
+                if !popped.is_empty() {
+                    propagate = Some(Command::SRem(key.clone(), popped));
+                }
+                resp
+            }
+            Command::Sort { store, .. } => match (self.sort(command).await, store) {
+                (Err(resp), _) => resp,
+                (Ok(items), None) => Value::Array(
+                    items
+                        .into_iter()
+                        .map(|item| item.map_or(Value::Nil, Value::bulk_bytes))
+                        .collect(),
+                ),
+                // The sorted list is propagated rather than the SORT itself,
+                // so replicas don't have to repeat the lookups.
+                (Ok(items), Some(dest)) => {
+                    let len = items.len();
+                    let store = match len {
+                        0 => Command::Del(vec![dest.clone()]),
+                        _ => {
+                            let list = items.into_iter().map(Option::unwrap_or_default).collect();
+                            let payload = RedisDB::dump_value(&RedisValue::List(list));
+                            Command::Restore(dest.clone(), 0, payload, true, false)
+                        }
+                    };
+                    self.apply(&store).await;
+                    propagate = Some(store);
+                    Value::Integer(len as i64)
+                }
+            },
+            Command::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout,
+                copy,
+                replace,
+            } => {
+                let (resp, moved) = self
+                    .migrate(host, port, keys, *db, *timeout, *replace)
+                    .await;
+                if !copy && !moved.is_empty() {
+                    aof = Some(aof_lock.lock().await);
+                    let del = Command::Del(moved);
+                    self.apply(&del).await;
+                    propagate = Some(del);
+                }
+                resp
+            }
+            Command::ConfigGet(patterns) => {
+                let config = self.config.lock().await;
+                Value::Map(
+                    redis_config::matching(patterns)
+                        .filter_map(|param| {
+                            let value = config.get(param.name)?;
+                            Some((Value::bulk(param.name), Value::bulk(value)))
+                        })
+                        .collect(),
+                )
+            }
+            Command::ConfigSet(params) => self.config_set(params).await,
+            Command::ConfigResetStat => {
+                self.stats.lock().await.reset();
+                Value::ok()
+            }
+            Command::Keys(_pattern) => Value::bytes_array(self.store.read_all(|shards| {
+                shards
+                    .iter()
+                    .flat_map(|shard| shard.db.keys().cloned())
+                    .collect::<Vec<_>>()
+            })),
+            Command::Info(sections) => Value::BulkString(self.info(sections).await),
+            Command::Save => match self.save().await {
+                Ok(()) => Value::ok(),
+                Err(e) => {
+                    warn!("Error while saving: {:?}", e);
+                    Value::error("ERR Error saving DB on disk")
+                }
+            },
+            Command::Shutdown(save) => match self.shutdown(*save).await {
+                // The connection is closed without a reply.
+                Ok(()) => return Ok(None),
+                Err(e) => {
+                    warn!("Error trying to shut down: {:?}", e);
+                    Value::error("ERR Errors trying to SHUTDOWN. Check logs.")
+                }
+            },
+            Command::SlowLogGet(count) => self.slowlog.lock().await.get(*count),
+            Command::LatencyLatest => self.latency.lock().await.latest(),
+            Command::LatencyHistory(event) => self.latency.lock().await.history(event),
+            Command::LatencyReset(events) => {
+                Value::Integer(self.latency.lock().await.reset(events) as i64)
+            }
+            Command::LatencyDoctor => {
+                let threshold = self.latency_threshold().await;
+                Value::BulkString(self.latency.lock().await.doctor(threshold))
+            }
+            Command::SlowLogLen => Value::Integer(self.slowlog.lock().await.len() as i64),
+            Command::SlowLogReset => {
+                self.slowlog.lock().await.reset();
+                Value::ok()
+            }
+            Command::BgRewriteAof => {
+                if self.aof.lock().await.is_none() {
+                    Value::error("ERR Append only file is disabled")
+                } else if self.bgrewriteaof().await {
+                    Value::SimpleString(
+                        "Background append only file rewriting started".to_string(),
+                    )
+                } else {
+                    Value::error("ERR Background append only file rewriting already in progress")
+                }
+            }
+            Command::BgSave => {
+                if self.bgsave().await {
+                    Value::SimpleString("Background saving started".to_string())
+                } else {
+                    Value::error("ERR Background save already in progress")
+                }
+            }
+            Command::ReplConf(options) => self.replconf(options),
+            Command::ReplicaOf(master) => self.replicaof(master.clone()).await,
+            Command::Wait(numreplicas, timeout) => match self.role().await {
+                Role::Primary => Value::Integer(self.wait(*numreplicas, *timeout).await as i64),
+                Role::Replica => {
+                    Value::error("ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.")
+                }
+            },
+            Command::ClusterInfo
+            | Command::ClusterMyId
+            | Command::ClusterNodes
+            | Command::ClusterSlots
+            | Command::ClusterShards
+            | Command::ClusterMeet(..)
+            | Command::ClusterHello(_)
+            | Command::ClusterSetSlot(..) => self.cluster_command(command).await,
+            Command::Asking => match self.cluster.lock().await.is_some() {
+                true => {
+                    self.connection.asking = true;
+                    Value::ok()
+                }
+                false => Value::error("ERR This instance has cluster support disabled"),
+            },
+            Command::ObjectEncoding(key) => match self.get(key).await {
+                Some(val) => Value::bulk(val.encoding()),
+                None => Value::Nil,
+            },
+            Command::ObjectIdleTime(key) => self.object_idletime(key).await,
+            Command::ObjectRefCount(key) => match self.get(key).await {
+                Some(val) => Value::Integer(val.refcount()),
+                None => Value::Nil,
+            },
+            Command::ObjectFreq(key) => self.object_freq(key).await,
+            Command::MemoryUsage(key, samples) => match self.get(key).await {
+                Some(val) => Value::Integer(entry_size(key, &val, *samples) as i64),
+                None => Value::Nil,
+            },
+            Command::MemoryStats => self.memory_stats().await,
+            Command::MemoryDoctor => self.memory_doctor().await,
+            // Unlike in Redis, only the calling client waits, while others
+            // keep being served.
+            Command::DebugSleep(seconds) => {
+                if let Ok(duration) = Duration::try_from_secs_f64(*seconds) {
+                    tokio::time::sleep(duration).await;
+                }
+                Value::ok()
+            }
+            Command::DebugObject(key) => self.debug_object(key).await,
+            Command::DebugSetActiveExpire(enabled) => {
+                self.active_expire.store(*enabled, Ordering::Relaxed);
+                Value::ok()
+            }
+            Command::DebugChangeReplId => {
+                self.repl_status.lock().await.replid = Some(random_id());
+                Value::ok()
+            }
+            Command::ClientPause(timeout, write_only) => {
+                self.client_pause(*timeout, *write_only).await
+            }
+            Command::ClientUnpause => self.client_unpause().await,
+            Command::ClientId => Value::Integer(self.client_id.unwrap_or(0) as i64),
+            Command::ClientSetName(name) => {
+                self.connection.name = (!name.is_empty()).then(|| name.clone());
+                Value::ok()
+            }
+            Command::ClientGetName => match &self.connection.name {
+                Some(name) => Value::bulk(name),
+                None => Value::Nil,
+            },
+            Command::Reset => {
+                self.connection = ConnectionState::default();
+                Value::SimpleString("RESET".to_string())
+            }
+            Command::FunctionList { pattern, with_code } => self
+                .functions
+                .lock()
+                .await
+                .list(pattern.as_deref(), *with_code),
+            Command::FunctionDump => {
+                Value::Bytes(RedisDB::dump_functions(&self.functions.lock().await.codes()))
+            }
+            Command::FunctionKill => self.function_kill().await,
+            Command::FCall {
+                function,
+                keys,
+                args,
+                read_only,
+            } => self.fcall(function, keys, args, *read_only, conn).await,
+            Command::CommandCount => Value::Integer(redis_registry::COMMANDS.len() as i64),
+            Command::CommandList => Value::bulk_array(
+                redis_registry::COMMANDS.iter().map(|spec| spec.name),
+            ),
+            Command::CommandInfo(names) if names.is_empty() => Value::Array(
+                redis_registry::COMMANDS
+                    .iter()
+                    .map(CommandSpec::info)
+                    .collect(),
+            ),
+            Command::CommandInfo(names) => Value::Array(
+                names
+                    .iter()
+                    .map(|name| redis_registry::lookup(name).map_or(Value::Nil, CommandSpec::info))
+                    .collect(),
+            ),
+            Command::Failover { to, timeout, abort } => match abort {
+                true => self.abort_failover().await,
+                false => self.failover(to.clone(), *timeout).await,
+            },
+            // A replica can serve replicas of its own once it has synced with
+            // its master, passing the master's stream along to them.
+            Command::Psync(_repl_id, _offset) => match self.has_replid().await {
+                true => {
+                    let addr = conn.peer_addr().ok();
+                    let attributes = self.connection.replconf.clone();
+                    info!(
+                        "Replica {}:{} asks for synchronization (capa: {}), starting a full resync",
+                        attributes
+                            .ip_address
+                            .clone()
+                            .or_else(|| addr.map(|addr| addr.ip().to_string()))
+                            .unwrap_or_default(),
+                        attributes.listening_port.as_deref().unwrap_or("0"),
+                        attributes.capabilities.join(" ")
+                    );
+                    let full_sync = match self.full_sync(addr, attributes).await {
+                        Some(full_sync) => full_sync,
+                        None => return Ok(None),
+                    };
+                    let id = full_sync.id;
+                    self.set_replica_state(id, ReplicaState::SendBulk).await;
+                    match self.send_full_sync(conn, &full_sync).await {
+                        Ok(()) => {
+                            info!("Synchronization with replica succeeded");
+                            self.set_replica_state(id, ReplicaState::Online).await;
+                            self.serve_replica(id, full_sync.rx, &full_sync.queued, conn)
+                                .await;
+                        }
+                        Err(e) => warn!("Error sending the RDB to replica: {}", e),
+                    }
+                    info!("Connection with replica lost");
+                    self.replicas.lock().await.retain(|replica| replica.id != id);
+                    return Ok(None);
+                }
+                false => Value::error("NOMASTERLINK Can't SYNC while not connected with my master"),
+            },
+        };
+        if command.is_write() || command.is_read() {
+            self.touch(&command.keys()).await;
+        }
+        if !self.expired.is_empty() && aof.is_none() {
+            aof = Some(aof_lock.lock().await);
+        }
+        self.log_and_propagate(aof.as_deref_mut(), propagate).await;
+        Ok(Some(resp))
+    }
+
+    /// FCALL and FCALL_RO. The function runs on a blocking task, and the
+    /// commands it calls are sent back here to run one at a time, each
+    /// logged and propagated on its own like any other command. The keyspace
+    /// stays locked until it returns, which makes the call atomic.
+    async fn fcall<C: Connection>(
+        &mut self,
+        function: &str,
+        keys: &[Bytes],
+        args: &[Bytes],
+        read_only: bool,
+        conn: &C,
+    ) -> Value {
+        let library = match self.functions.lock().await.find(function) {
+            Some(library) => library,
+            None => return Value::error("ERR Function not found"),
+        };
+        let no_writes = library.is_read_only(function);
+        if read_only && !no_writes {
+            return Value::error(
+                "ERR Can not execute a script with write flag using *_ro command.",
+            );
+        }
+        if !no_writes && self.is_read_only().await {
+            return Value::error("READONLY You can't write against a read only replica.");
+        }
+        let keyspace_lock = Arc::clone(&self.keyspace_lock);
+        let _keyspace = keyspace_lock.write().await;
+        let (calls_tx, mut calls_rx) = mpsc::unbounded_channel();
+        let (function, keys, args) = (function.to_string(), keys.to_vec(), args.to_vec());
+        let running = Arc::new(Running::default());
+        self.running_functions
+            .lock()
+            .await
+            .push(Arc::clone(&running));
+        let call_running = Arc::clone(&running);
+        let mut task = tokio::task::spawn_blocking(move || {
+            library.call(&function, &keys, &args, calls_tx, call_running)
+        });
+        let resp = loop {
+            tokio::select! {
+                Some((args, reply_tx)) = calls_rx.recv() => {
+                    let resp = self
+                        .script_call(&args, no_writes || read_only, &running, conn)
+                        .await;
+                    let _ = reply_tx.send(resp);
+                }
+                res = &mut task => {
+                    break res.unwrap_or_else(|e| Value::error(format!("ERR {}", e)));
+                }
+            }
+        };
+        self.running_functions
+            .lock()
+            .await
+            .retain(|other| !Arc::ptr_eq(other, &running));
+        resp
+    }
+
+    /// FUNCTION KILL: stops every function call in progress that hasn't
+    /// written anything. Like in Redis, those that have can't be stopped.
+    async fn function_kill(&self) -> Value {
+        let running = self.running_functions.lock().await;
+        if running.is_empty() {
+            return Value::error("NOTBUSY No scripts in execution right now.");
+        }
+        let killed = running.iter().filter(|call| call.kill()).count();
+        if killed == 0 {
+            return Value::error("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.");
+        }
+        Value::ok()
+    }
+
+    /// Runs a command a function called through redis.call or redis.pcall.
+    async fn script_call<C: Connection>(
+        &mut self,
+        args: &[Bytes],
+        read_only: bool,
+        running: &Running,
+        conn: &C,
+    ) -> Value {
+        let command = match Command::from_args(args) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Value::error("ERR Unknown Redis command called from script"),
+            Err(e) => return Value::error(e.to_string()),
+        };
+        if command.has(Flag::NoScript) {
+            return Value::error("ERR This Redis command is not allowed from script");
+        }
+        if read_only && command.is_write() {
+            return Value::error("ERR Write commands are not allowed from read-only scripts.");
+        }
+        if command.is_write() {
+            if let Err(resp) = running.start_write() {
+                return resp;
+            }
+        }
+        match Box::pin(self.dispatch(&command, conn)).await {
+            Ok(Some(resp)) | Err(resp) => resp,
+            Ok(None) => Value::Nil,
+        }
+    }
+
+    /// CONFIG SET: validates every parameter before changing any of them, then
+    /// applies the ones that take effect beyond the config table.
+    async fn config_set(&mut self, params: &[(String, String)]) -> Value {
+        let mut values = Vec::new();
+        for (name, value) in params {
+            let param = match redis_config::lookup(name) {
+                Some(param) => param,
+                None => {
+                    return Value::error(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ))
+                }
+            };
+            match param.validate(value) {
+                Ok(value) => values.push((param.name, value)),
+                Err(e) => {
+                    return Value::error(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                }
+            }
+        }
+        for (name, mut value) in values {
+            let mut config = self.config.lock().await;
+            // Classes left out of client-output-buffer-limit keep their
+            // limits.
+            if name == "client-output-buffer-limit" {
+                let current = config.get(name).map_or("", String::as_str);
+                value =
+                    OutputBufferLimits::from_config(&format!("{} {}", current, value)).to_string();
+            }
+            let old = config.insert(name.to_string(), value.clone());
+            drop(config);
+            if old.as_ref() != Some(&value) {
+                self.apply_config(name, &value).await;
+            }
+        }
+        Value::ok()
+    }
+
+    /// Puts a changed parameter into effect, for those that aren't simply
+    /// read from the config table when needed.
+    async fn apply_config(&mut self, name: &str, value: &str) {
+        match name {
+            "client-output-buffer-limit" => {
+                *self.output_buffer_limits.lock().await = OutputBufferLimits::from_config(value);
+            }
+            "appendfsync" => {
+                if let (Some(aof), Ok(fsync)) =
+                    (self.aof.lock().await.as_mut(), value.parse::<FsyncPolicy>())
+                {
+                    aof.set_fsync(fsync);
+                }
+            }
+            "appendonly" if value == "yes" => {
+                let (dir, file_name, fsync) = {
+                    let config = self.config.lock().await;
+                    (
+                        config["dir"].clone(),
+                        config["appendfilename"].clone(),
+                        config["appendfsync"]
+                            .parse()
+                            .unwrap_or(FsyncPolicy::EverySec),
+                    )
+                };
+                let exists = std::path::Path::new(&format!("{}/{}", dir, file_name)).exists();
+                self.open_aof(dir, file_name, fsync).await;
+                // A file left over from an earlier run doesn't hold the
+                // current dataset, so it is rewritten from scratch.
+                if exists {
+                    self.bgrewriteaof().await;
+                }
+            }
+            "appendonly" => *self.aof.lock().await = None,
+            "loglevel" => {
+                if let Ok(level) = value.parse::<LogLevel>() {
+                    redis_log::set_level(level);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Builds the INFO reply out of the sections asked for.
+    async fn info(&self, sections: &[String]) -> String {
+        let mut info = InfoRegistry::new(sections);
+        if info.wants("server") {
+            info.add(self.info_server().await);
+        }
+        if info.wants("clients") {
+            let maxclients = self.maxclients().await;
+            let stats = self.stats.lock().await;
+            let mut section = InfoSection::new("Clients");
+            section
+                .field("connected_clients", stats.connected_clients)
+                .field("maxclients", maxclients)
+                .field("rejected_connections", stats.rejected_connections);
+            info.add(section);
+        }
+        if info.wants("memory") {
+            info.add(self.info_memory().await);
+        }
+        if info.wants("persistence") {
+            info.add(self.info_persistence().await);
+        }
+        if info.wants("stats") {
+            let stats = self.stats.lock().await;
+            let mut section = InfoSection::new("Stats");
+            section
+                .field(
+                    "total_connections_received",
+                    stats.total_connections_received,
+                )
+                .field("total_commands_processed", stats.total_commands_processed)
+                .field("instantaneous_ops_per_sec", stats.ops_per_sec())
+                .field("expired_keys", stats.expired_keys)
+                .field("evicted_keys", stats.evicted_keys)
+                .field("keyspace_hits", stats.keyspace_hits)
+                .field("keyspace_misses", stats.keyspace_misses)
+                .field(
+                    "client_output_buffer_limit_disconnections",
+                    stats.client_output_buffer_limit_disconnections,
+                );
+            info.add(section);
+        }
+        if info.wants("replication") {
+            info.add(self.info_replication().await);
+        }
+        if info.wants("cluster") {
+            let mut section = InfoSection::new("Cluster");
+            section.field("cluster_enabled", self.cluster.lock().await.is_some() as u8);
+            info.add(section);
+        }
+        if info.wants("commandstats") {
+            info.add(self.stats.lock().await.commandstats());
+        }
+        if info.wants("latencystats") {
+            info.add(self.stats.lock().await.latencystats());
+        }
+        if info.wants("keyspace") {
+            info.add(self.info_keyspace().await);
+        }
+        info.render()
+    }
+
+    async fn info_server(&self) -> InfoSection {
+        let mode = match self.cluster.lock().await.is_some() {
+            true => "cluster",
+            false => "standalone",
+        };
+        let stats = self.stats.lock().await;
+        let uptime = stats.started.elapsed().as_secs();
+        let mut section = InfoSection::new("Server");
+        section
+            .field("redis_version", env!("CARGO_PKG_VERSION"))
+            .field("redis_mode", mode)
+            .field("os", std::env::consts::OS)
+            .field("arch_bits", usize::BITS)
+            .field("process_id", std::process::id())
+            .field("run_id", &stats.run_id)
+            .field("tcp_port", &self.port)
+            .field("uptime_in_seconds", uptime)
+            .field("uptime_in_days", uptime / 86400);
+        drop(stats);
+        let bind = self.config.lock().await["bind"]
+            .split_whitespace()
+            .map(|addr| format!("bind={},", addr))
+            .collect::<String>();
+        section.field("listener0", format!("name=tcp,{}port={}", bind, self.port));
+        section
+    }
+
+    async fn info_memory(&self) -> InfoSection {
+        let used_memory = self.used_memory();
+        let maxmemory = self.maxmemory().await;
+        let mut section = InfoSection::new("Memory");
+        section
+            .field("used_memory", used_memory)
+            .field("used_memory_human", human_bytes(used_memory))
+            .field("maxmemory", maxmemory)
+            .field("maxmemory_human", human_bytes(maxmemory))
+            .field("maxmemory_policy", self.eviction_policy().await);
+        section
+    }
+
+    async fn info_persistence(&self) -> InfoSection {
+        let mut section = InfoSection::new("Persistence");
+        {
+            let rdb_status = self.rdb_status.lock().await;
+            let last_save_time = rdb_status
+                .last_save_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            section
+                .field(
+                    "rdb_changes_since_last_save",
+                    rdb_status.changes_since_last_save,
+                )
+                .field(
+                    "rdb_bgsave_in_progress",
+                    rdb_status.bgsave_in_progress as u8,
+                )
+                .field("rdb_last_save_time", last_save_time)
+                .field(
+                    "rdb_last_bgsave_status",
+                    if rdb_status.last_bgsave_ok {
+                        "ok"
+                    } else {
+                        "err"
+                    },
+                );
+        }
+        match self.aof.lock().await.as_ref() {
+            Some(aof) => section
+                .field("aof_enabled", 1)
+                .field("aof_rewrite_in_progress", aof.rewrite_in_progress() as u8)
+                .field(
+                    "aof_last_bgrewrite_status",
+                    if aof.last_rewrite_ok() { "ok" } else { "err" },
+                ),
+            None => section.field("aof_enabled", 0),
+        };
+        section
+    }
+
+    async fn info_replication(&self) -> InfoSection {
+        let repl_status = self.repl_status.lock().await;
+        let mut section = InfoSection::new("Replication");
+        section
+            .field("role", &repl_status.role)
+            .field("master_failover_state", repl_status.failover);
+        if let (Some(host), Some(port)) = (&repl_status.master_host, &repl_status.master_port) {
+            section
+                .field("master_host", host)
+                .field("master_port", port);
+        }
+        let replicas = self.replicas.lock().await;
+        section.field("connected_slaves", replicas.len());
+        for (n, replica) in replicas.iter().enumerate() {
+            section.field(
+                format!("slave{}", n),
+                format!(
+                    "ip={},port={},state={},offset={},lag={}",
+                    replica.ip().unwrap_or_default(),
+                    replica.port().unwrap_or("0"),
+                    replica.state,
+                    replica.ack_offset,
+                    replica.last_ack.elapsed().as_secs()
+                ),
+            );
+        }
+        if let Some(master_replid) = &repl_status.replid {
+            section.field("master_replid", master_replid);
+        }
+        section.field("master_repl_offset", repl_status.offset);
+        section
+    }
+
+    /// Renders the page served on --metrics-port.
+    pub async fn metrics(&self) -> String {
+        let mut metrics = Metrics::default();
+        self.stats.lock().await.metrics(&mut metrics);
+        let (keys, expires) = self.store.read_all(|shards| {
+            let keys = shards.iter().map(|shard| shard.db.len()).sum::<usize>();
+            let expires = shards
+                .iter()
+                .map(|shard| shard.volatile().count())
+                .sum::<usize>();
+            (keys, expires)
+        });
+        metrics
+            .gauge("redis_keys", "Keys in the dataset.", keys)
+            .gauge("redis_expiring_keys", "Keys with an expiry.", expires)
+            .gauge(
+                "redis_used_memory_bytes",
+                "Memory taken by the dataset.",
+                self.used_memory(),
+            )
+            .gauge(
+                "redis_maxmemory_bytes",
+                "Memory limit for the dataset, 0 for none.",
+                self.maxmemory().await,
+            );
+        let repl_status = self.repl_status.lock().await;
+        let replicas = self.replicas.lock().await;
+        let replica_label = |replica: &Replica| {
+            let ip = replica.ip().unwrap_or_default();
+            let port = replica.port().unwrap_or("0");
+            format!("replica=\"{}\"", label_value(&format!("{}:{}", ip, port)))
+        };
+        metrics
+            .gauge(
+                "redis_replica",
+                "1 if this instance is a replica, 0 if it is a primary.",
+                matches!(repl_status.role, Role::Replica) as u8,
+            )
+            .gauge(
+                "redis_master_repl_offset",
+                "Bytes of replication stream produced or processed.",
+                repl_status.offset,
+            )
+            .gauge(
+                "redis_connected_replicas",
+                "Replicas attached to this instance.",
+                replicas.len(),
+            )
+            .labeled(
+                "redis_replica_lag_seconds",
+                "gauge",
+                "Seconds since each replica last acknowledged its offset.",
+                replicas
+                    .iter()
+                    .map(|replica| (replica_label(replica), replica.last_ack.elapsed().as_secs())),
+            )
+            .labeled(
+                "redis_replica_lag_bytes",
+                "gauge",
+                "Bytes of replication stream each replica has yet to acknowledge.",
+                replicas.iter().map(|replica| {
+                    (
+                        replica_label(replica),
+                        repl_status.offset.saturating_sub(replica.ack_offset),
+                    )
+                }),
+            );
+        metrics.render()
+    }
+
+    /// Keys, keys with an expiry, and their average TTL in milliseconds.
+    /// Only db0 exists, and it is left out while empty, like in Redis.
+    async fn info_keyspace(&self) -> InfoSection {
+        let now = SystemTime::now();
+        let (keys, ttls) = self.store.read_all(|shards| {
+            let keys = shards.iter().map(|shard| shard.db.len()).sum::<usize>();
+            let ttls = shards
+                .iter()
+                .flat_map(|shard| shard.volatile())
+                .map(|(_, at)| at.duration_since(now).unwrap_or_default().as_millis())
+                .collect::<Vec<_>>();
+            (keys, ttls)
+        });
+        let mut section = InfoSection::new("Keyspace");
+        if keys > 0 {
+            let avg_ttl = match ttls.len() {
+                0 => 0,
+                len => ttls.iter().sum::<u128>() / len as u128,
+            };
+            section.field(
+                "db0",
+                format!("keys={},expires={},avg_ttl={}", keys, ttls.len(), avg_ttl),
+            );
+        }
+        section
+    }
+
+    /// Logs and propagates a DEL for every key this connection expired or
+    /// evicted, followed by `command`.
+    async fn log_and_propagate(
+        &mut self,
+        mut aof: Option<&mut Option<RedisAof>>,
+        command: Option<Command>,
+    ) {
+        let expired = std::mem::take(&mut self.expired);
+        let dels = expired.into_iter().map(|key| Command::Del(vec![key]));
+        for command in dels.chain(command) {
+            if let Some(Some(aof)) = aof.as_deref_mut() {
+                if let Err(e) = aof.append(&command) {
+                    warn!("Error writing AOF file: {:?}", e);
+                }
+            }
+            self.propagate(&command).await;
+        }
+    }
+
+    /// Records an access to those of `keys` that exist.
+    async fn touch(&self, keys: &[&[u8]]) {
+        let (log_factor, decay_time) = self.lfu_config().await;
+        let now = Instant::now();
+        for key in keys {
+            self.store.write(key, |shard| {
+                if let Some(entry) = shard.db.get_mut(*key) {
+                    entry.access.hit(now, log_factor, decay_time);
+                }
+            });
+        }
+    }
+
+    /// How `key` has been accessed, or None if it doesn't exist.
+    fn key_access(&self, key: &[u8]) -> Option<KeyAccess> {
+        self.store.read(key, |shard| match shard.is_expired(key) {
+            true => None,
+            false => shard.db.get(key).map(|entry| entry.access),
+        })
+    }
+
+    /// The lfu-log-factor and lfu-decay-time settings.
+    async fn lfu_config(&self) -> (u64, u64) {
+        let config = self.config.lock().await;
+        let get = |key: &str, default: u64| {
+            config
+                .get(key)
+                .and_then(|val| val.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        (get("lfu-log-factor", 10), get("lfu-decay-time", 1))
+    }
+
+    /// The approximate memory taken by the whole dataset.
+    fn used_memory(&self) -> u64 {
+        self.store
+            .read_all(|shards| shards.iter().map(|shard| used_memory(&shard.db)).sum())
+    }
+
+    /// MEMORY STATS: how the memory taken by the dataset breaks down.
+    async fn memory_stats(&self) -> Value {
+        let (total, overhead, keys) = self.store.read_all(|shards| {
+            let total = shards
+                .iter()
+                .map(|shard| used_memory(&shard.db))
+                .sum::<u64>();
+            let overhead = shards.iter().map(|shard| overhead(&shard.db)).sum::<u64>();
+            let keys = shards
+                .iter()
+                .map(|shard| shard.db.len() as u64)
+                .sum::<u64>();
+            (total, overhead, keys)
+        });
+        let dataset = total - overhead;
+        let maxmemory = self.maxmemory().await;
+        let int = |num: u64| Value::Integer(num as i64);
+        let mut stats = vec![
+            ("total.allocated", int(total)),
+            ("overhead.total", int(overhead)),
+            ("keys.count", int(keys)),
+            (
+                "keys.bytes-per-key",
+                int(total.checked_div(keys).unwrap_or(0)),
+            ),
+            ("dataset.bytes", int(dataset)),
+            (
+                "dataset.percentage",
+                Value::BulkString(format!(
+                    "{:.2}",
+                    (dataset * 100) as f64 / total.max(1) as f64
+                )),
+            ),
+            ("maxmemory", int(maxmemory)),
+        ];
+        if maxmemory != 0 {
+            stats.push((
+                "maxmemory.percentage",
+                Value::BulkString(format!("{:.2}", (total * 100) as f64 / maxmemory as f64)),
+            ));
+        }
+        Value::Map(
+            stats
+                .into_iter()
+                .map(|(name, val)| (Value::bulk(name), val))
+                .collect(),
+        )
+    }
+
+    /// MEMORY DOCTOR: a human readable report on memory problems.
+    async fn memory_doctor(&self) -> Value {
+        let used = self.used_memory();
+        let maxmemory = self.maxmemory().await;
+        let policy = self
+            .config
+            .lock()
+            .await
+            .get("maxmemory-policy")
+            .cloned()
+            .unwrap_or_default();
+        if used == 0 {
+            return Value::bulk("Hi Sam, this instance is empty, my issues detector can't be used in these conditions. Please, fill it with some data and ask me again.");
+        }
+        let mut issues = Vec::new();
+        if maxmemory != 0 && used * 10 >= maxmemory * 9 {
+            issues.push(format!(
+                " * High memory usage: the dataset takes {} out of the {} allowed by maxmemory, so writes will {} soon.",
+                human_bytes(used),
+                human_bytes(maxmemory),
+                if policy == "noeviction" { "be refused" } else { "evict keys" }
+            ));
+        }
+        let evicted_keys = self.stats.lock().await.evicted_keys;
+        if evicted_keys != 0 {
+            issues.push(format!(
+                " * Evictions: {} keys were evicted to stay under maxmemory. Consider raising it if those keys are needed.",
+                evicted_keys
+            ));
+        }
+        match issues.is_empty() {
+            true => Value::bulk("Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs on this base."),
+            false => Value::BulkString(format!(
+                "Sam, I detected a few issues in this Redis instance memory implants:\n\n{}\n",
+                issues.join("\n\n")
+            )),
+        }
+    }
+
+    async fn maxmemory(&self) -> u64 {
+        self.config
+            .lock()
+            .await
+            .get("maxmemory")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(0)
+    }
+
+    async fn maxclients(&self) -> u64 {
+        self.config
+            .lock()
+            .await
+            .get("maxclients")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(10000)
+    }
+
+    async fn eviction_policy(&self) -> EvictionPolicy {
+        self.config
+            .lock()
+            .await
+            .get("maxmemory-policy")
+            .and_then(|val| val.parse::<EvictionPolicy>().ok())
+            .unwrap_or(EvictionPolicy::NoEviction)
+    }
+
+    /// OBJECT IDLETIME: seconds since the key was last read or written. Not
+    /// available under an LFU policy, like in Redis.
+    async fn object_idletime(&mut self, key: &[u8]) -> Value {
+        if self.eviction_policy().await.is_lfu() {
+            return Value::error("ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.");
+        }
+        match self.key_access(key) {
+            Some(access) => Value::Integer(access.last.elapsed().as_secs() as i64),
+            None => Value::Nil,
+        }
+    }
+
+    /// SORT: the elements of a list, set or sorted set, or the values the
+    /// GET patterns look up for them, in sorted order. Missing values are
+    /// None.
+    async fn sort(&mut self, command: &Command) -> Result<Vec<Option<Bytes>>, Value> {
+        let (key, by, limit, get, desc, alpha) = match command {
+            Command::Sort {
+                key,
+                by,
+                limit,
+                get,
+                desc,
+                alpha,
+                ..
+            } => (key, by, limit, get, *desc, *alpha),
+            _ => return Ok(Vec::new()),
+        };
+        let elements = match self.get(key).await {
+            None => Vec::new(),
+            Some(RedisValue::List(list)) => list.into_iter().collect(),
+            Some(RedisValue::Set(set)) => set.into_iter().collect(),
+            Some(RedisValue::ZSet(zset)) => sorted_zset(&zset)
+                .into_iter()
+                .map(|(member, _)| member.clone())
+                .collect(),
+            Some(_) => return Err(Value::error(WRONGTYPE)),
+        };
+        // A BY pattern without "*" can't depend on the element, which skips
+        // sorting altogether.
+        let mut elements = match by {
+            Some(by) if !by.contains(&b'*') => elements,
+            _ => {
+                let mut weighted = Vec::new();
+                for element in elements {
+                    let weight = match by {
+                        Some(by) => self.sort_lookup(by, &element).await,
+                        None => Some(element.clone()),
+                    };
+                    weighted.push((weight, element));
+                }
+                match alpha {
+                    true => {
+                        weighted.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+                        weighted.into_iter().map(|(_, element)| element).collect()
+                    }
+                    // Elements without a weight sort as 0.
+                    false => {
+                        let mut scored = weighted
+                            .into_iter()
+                            .map(|(weight, element)| match weight {
+                                Some(weight) => {
+                                    let weight = std::str::from_utf8(&weight).ok()?;
+                                    Some((weight.trim().parse::<f64>().ok()?, element))
+                                }
+                                None => Some((0.0, element)),
+                            })
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or_else(|| {
+                                Value::error(
+                                    "ERR One or more scores can't be converted into double",
+                                )
+                            })?;
+                        scored.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+                        scored.into_iter().map(|(_, element)| element).collect()
+                    }
+                }
+            }
+        };
+        if desc {
+            elements.reverse();
+        }
+        if let Some((offset, count)) = limit {
+            let offset = (*offset).max(0) as usize;
+            let count = usize::try_from(*count).unwrap_or(usize::MAX);
+            elements = elements.into_iter().skip(offset).take(count).collect();
+        }
+        if get.is_empty() {
+            return Ok(elements.into_iter().map(Some).collect());
+        }
+        let mut items = Vec::new();
+        for element in &elements {
+            for pattern in get {
+                items.push(self.sort_lookup(pattern, element).await);
+            }
+        }
+        Ok(items)
+    }
+
+    /// Looks up a BY or GET pattern of SORT for `element`: the first "*" is
+    /// replaced with it to get a key name, and a "->field" suffix reads a
+    /// hash field instead of a string. "#" stands for the element itself.
+    async fn sort_lookup(&mut self, pattern: &[u8], element: &[u8]) -> Option<Bytes> {
+        if pattern == b"#" {
+            return Some(Bytes::copy_from_slice(element));
+        }
+        let star = pattern.iter().position(|b| *b == b'*')?;
+        let (key, field) = match pattern[star + 1..].windows(2).position(|w| w == b"->") {
+            Some(arrow) if star + 1 + arrow + 2 < pattern.len() => {
+                let arrow = star + 1 + arrow;
+                (&pattern[..arrow], Some(&pattern[arrow + 2..]))
+            }
+            _ => (pattern, None),
+        };
+        let key = [&key[..star], element, &key[star + 1..]].concat();
+        match (self.get(&key).await?, field) {
+            (RedisValue::String(val), None) => Some(val),
+            (RedisValue::Hash(hash), Some(field)) => hash.get(field).cloned(),
+            _ => None,
+        }
+    }
+
+    /// SINTERCARD: the size of the intersection of the sets. Stops counting
+    /// once `limit` is reached, unless it is 0.
+    async fn sintercard(&mut self, keys: &[Bytes], limit: usize) -> Value {
+        let mut sets = Vec::new();
+        for key in keys {
+            match self.lookup_read(key).await {
+                Some(RedisValue::Set(set)) => sets.push(set),
+                Some(_) => return Value::error(WRONGTYPE),
+                None => return Value::Integer(0),
+            }
+        }
+        // Only the members of the smallest set can be in all of them.
+        sets.sort_by_key(HashSet::len);
+        let (smallest, rest) = match sets.split_first() {
+            Some(split) => split,
+            None => return Value::Integer(0),
+        };
+        let common = smallest
+            .iter()
+            .filter(|member| rest.iter().all(|set| set.contains(*member)));
+        let count = match limit {
+            0 => common.count(),
+            limit => common.take(limit).count(),
+        };
+        Value::Integer(count as i64)
+    }
+
+    /// TOUCH: how many of the keys exist. Their access time is updated like
+    /// for any other read.
+    async fn count_existing(&self, keys: &[Bytes]) -> Value {
+        let count = keys
+            .iter()
+            .filter(|key| self.store.read(key, |shard| shard.get(key).is_some()))
+            .count();
+        Value::Integer(count as i64)
+    }
+
+    /// EXPIRETIME and PEXPIRETIME: the unix time the key expires at, -1 if
+    /// it doesn't expire or -2 if it doesn't exist.
+    async fn expire_time(&mut self, key: &[u8], millis: bool) -> Value {
+        let at = self.store.read(key, |shard| {
+            shard.get(key)?;
+            Some(shard.expiry(key))
+        });
+        let at = match at {
+            Some(Some(at)) => at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64,
+            Some(None) => return Value::Integer(-1),
+            None => return Value::Integer(-2),
+        };
+        match millis {
+            true => Value::Integer(at),
+            false => Value::Integer((at + 500) / 1000),
+        }
+    }
+
+    /// DEBUG OBJECT: low level details of how the key's value is stored.
+    async fn debug_object(&mut self, key: &[u8]) -> Value {
+        let val = match self.get(key).await {
+            Some(val) => val,
+            None => return Value::error("ERR no such key"),
+        };
+        let addr = self.store.read(key, |shard| match shard.db.get(key) {
+            Some(entry) => &entry.value as *const RedisValue as usize,
+            None => 0,
+        });
+        let idle = match self.key_access(key) {
+            Some(access) => access.last.elapsed().as_secs(),
+            None => 0,
+        };
+        // The LRU clock Redis stores in every object: seconds since the epoch,
+        // wrapping around at 24 bits.
+        let lru = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .saturating_sub(idle)
+            & ((1 << 24) - 1);
+        Value::SimpleString(format!(
+            "Value at:{:#x} refcount:{} encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}",
+            addr,
+            val.refcount(),
+            val.encoding(),
+            RedisDB::serialized_length(&val),
+            lru,
+            idle
+        ))
+    }
+
+    /// OBJECT FREQ: the key's LFU counter, only tracked under an LFU policy.
+    async fn object_freq(&mut self, key: &[u8]) -> Value {
+        if !self.eviction_policy().await.is_lfu() {
+            return Value::error("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.");
+        }
+        let (_, decay_time) = self.lfu_config().await;
+        match self.key_access(key) {
+            Some(access) => Value::Integer(access.freq(decay_time) as i64),
+            None => Value::Nil,
+        }
+    }
+
+    /// Evicts keys according to maxmemory-policy until the dataset fits in
+    /// maxmemory again. Returns false if it still doesn't, in which case
+    /// writes that would grow it are refused. Replicas leave eviction to
+    /// their master.
+    async fn free_memory(&mut self) -> bool {
+        let (maxmemory, policy) = {
+            let config = self.config.lock().await;
+            let maxmemory = config
+                .get("maxmemory")
+                .and_then(|val| val.parse::<u64>().ok())
+                .unwrap_or(0);
+            let policy = config
+                .get("maxmemory-policy")
+                .and_then(|val| val.parse::<EvictionPolicy>().ok())
+                .unwrap_or(EvictionPolicy::NoEviction);
+            (maxmemory, policy)
+        };
+        if maxmemory == 0 {
+            return true;
+        }
+        if let Role::Replica = self.role().await {
+            return true;
+        }
+        let (_, decay_time) = self.lfu_config().await;
+        let expired = &mut self.expired;
+        let (used, evicted) = self.store.write_all(|shards| {
+            let mut used = shards
+                .iter()
+                .map(|shard| used_memory(&shard.db))
+                .sum::<u64>();
+            let mut evicted = 0;
+            while used > maxmemory {
+                let (i, key) = match policy.pick(shards, decay_time) {
+                    Some(picked) => picked,
+                    None => break,
+                };
+                if let Some(val) = shards[i].remove(&key) {
+                    used -= entry_size(&key, &val, 0);
+                }
+                expired.push(key);
+                evicted += 1;
+            }
+            (used, evicted)
+        });
+        if evicted > 0 {
+            self.stats.lock().await.evicted_keys += evicted;
+            self.rdb_status.lock().await.changes_since_last_save += evicted;
+        }
+        used <= maxmemory
+    }
+
+    /// Called by the accept loop when a client connects.
+    /// The error a client connecting from `peer` is refused with if the
+    /// server runs in protected mode, which keeps an instance listening on
+    /// every interface by default from being open to the network. It applies
+    /// while protected-mode is enabled and no bind address was configured,
+    /// to clients not connecting over the loopback interface. Passwords
+    /// aren't supported, so none is ever set.
+    pub async fn protected_mode_error(&self, peer: SocketAddr) -> Option<Value> {
+        let config = self.config.lock().await;
+        let default_bind = redis_config::lookup("bind").map(|param| param.default);
+        if config["protected-mode"] != "yes" || Some(config["bind"].as_str()) != default_bind {
+            return None;
+        }
+        let loopback = match peer.ip() {
+            IpAddr::V4(ip) => ip.is_loopback(),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip.is_loopback(),
+                None => ip.is_loopback(),
+            },
+        };
+        match loopback {
+            true => None,
+            false => Some(Value::error(PROTECTED_MODE_DENIED)),
+        }
+    }
+
+    /// Registers a new client connection and returns what is notified to
+    /// close it, or the error it is refused with if maxclients clients are
+    /// already connected.
+    pub async fn client_connected(&mut self) -> Result<Arc<Notify>, Value> {
+        let maxclients = self.maxclients().await;
+        {
+            let mut stats = self.stats.lock().await;
+            if stats.connected_clients >= maxclients {
+                stats.rejected_connections += 1;
+                return Err(Value::error("ERR max number of clients reached"));
+            }
+            stats.connected_clients += 1;
+            stats.total_connections_received += 1;
+        }
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let kill = Arc::new(Notify::new());
+        self.clients.lock().await.insert(
+            id,
+            Client {
+                last_interaction: Instant::now(),
+                in_command: false,
+                kill: Arc::clone(&kill),
+            },
+        );
+        self.client_id = Some(id);
+        Ok(kill)
+    }
+
+    pub fn client_id(&self) -> Option<u64> {
+        self.client_id
+    }
+
+    pub async fn client_disconnected(&self) {
+        if let Some(id) = self.client_id {
+            self.clients.lock().await.remove(&id);
+        }
+        self.stats.lock().await.connected_clients -= 1;
+    }
+
+    /// Records that this connection's client started or finished running a
+    /// command.
+    async fn set_in_command(&self, in_command: bool) {
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = self.client_id.and_then(|id| clients.get_mut(&id)) {
+            client.in_command = in_command;
+            client.last_interaction = Instant::now();
+        }
+    }
+
+    /// Closes the connections of clients that have been idle for longer
+    /// than the `timeout` config. Clients running a command, such as
+    /// replicas and clients blocked in WAIT, are left alone.
+    async fn close_idle_clients(&self) {
+        let timeout = self
+            .config
+            .lock()
+            .await
+            .get("timeout")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(0);
+        if timeout == 0 {
+            return;
+        }
+        let timeout = Duration::from_secs(timeout);
+        for (id, client) in self.clients.lock().await.iter() {
+            if !client.in_command && client.last_interaction.elapsed() > timeout {
+                debug!("Closing idle client {}", id);
+                client.kill.notify_one();
+            }
+        }
+    }
+
+    /// The interval of TCP keepalive probes sent to clients, from the
+    /// `tcp-keepalive` config. 0 disables them.
+    pub async fn tcp_keepalive(&self) -> u64 {
+        self.config
+            .lock()
+            .await
+            .get("tcp-keepalive")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(300)
+    }
+
+    /// Adds a replica to the registry. Returns its id and the receiving end
+    /// of its queue of propagated writes.
+    async fn register_replica(
+        &self,
+        addr: Option<SocketAddr>,
+        attributes: ReplicaAttributes,
+    ) -> (u64, mpsc::Receiver<Vec<u8>>, Arc<AtomicUsize>) {
+        let (tx, rx) = mpsc::channel(REPLICA_QUEUE_LEN);
+        let id = NEXT_REPLICA_ID.fetch_add(1, Ordering::Relaxed);
+        let queued = Arc::new(AtomicUsize::new(0));
+        self.replicas.lock().await.push(Replica {
+            id,
+            addr,
+            attributes,
+            state: ReplicaState::WaitBgsave,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+            tx,
+            queued: Arc::clone(&queued),
+            over_soft_limit_since: None,
+        });
+        (id, rx, queued)
+    }
+
+    /// Queues a write for every connected replica. Replicas only pass their
+    /// master's stream along, see `forward`.
+    async fn propagate(&self, command: &Command) {
+        if let Role::Replica = self.role().await {
+            return;
+        }
+        self.forward(command.serialize()).await;
+    }
+
+    /// Adds `bytes` to the replication stream. A replica whose queue is full
+    /// or over its output buffer limits has fallen too far behind to catch
+    /// up, so it is dropped and has to resync, rather than holding up writes.
+    async fn forward(&self, bytes: Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.repl_status.lock().await.offset += bytes.len();
+        let limit = self.output_buffer_limit(ClientClass::Replica).await;
+        let mut disconnected = 0;
+        self.replicas.lock().await.retain_mut(|replica| {
+            let queued = replica.queued.load(Ordering::Relaxed) + bytes.len();
+            if limit.is_exceeded(queued, &mut replica.over_soft_limit_since) {
+                warn!(
+                    "Replica {:?} scheduled to be closed ASAP for overcoming of output buffer limits",
+                    replica.addr
+                );
+                disconnected += 1;
+                return false;
+            }
+            match replica.tx.try_send(bytes.clone()) {
+                Ok(()) => {
+                    replica.queued.fetch_add(bytes.len(), Ordering::Relaxed);
+                    true
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!(
+                        "Replica {:?} is too far behind, disconnecting",
+                        replica.addr
+                    );
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+        if disconnected > 0 {
+            self.stats
+                .lock()
+                .await
+                .client_output_buffer_limit_disconnections += disconnected;
+        }
+    }
+
+    async fn output_buffer_limit(&self, class: ClientClass) -> OutputBufferLimit {
+        self.output_buffer_limits.lock().await.get(class)
+    }
+
+    /// Writes out the replies held back in `out`. Returns false if the
+    /// client has to be disconnected, because the write failed or the
+    /// replies are over the output buffer limits of normal clients. Replies
+    /// count against the soft limit for as long as the write takes.
+    pub async fn flush_replies<C: Connection>(&self, conn: &C, out: &mut Vec<u8>) -> bool {
+        if out.is_empty() {
+            return true;
+        }
+        let limit = self.output_buffer_limit(ClientClass::Normal).await;
+        let mut over_soft_limit_since = None;
+        let flushed = if limit.is_exceeded(out.len(), &mut over_soft_limit_since) {
+            false
+        } else if over_soft_limit_since.is_some() {
+            let soft_seconds = Duration::from_secs(limit.soft_seconds);
+            tokio::time::timeout(soft_seconds, redis_io::flush(conn, out))
+                .await
+                .is_ok_and(|res| res.is_ok())
+        } else {
+            return redis_io::flush(conn, out).await.is_ok();
+        };
+        if !flushed {
+            warn!("Client closed for overcoming of output buffer limits");
+            self.stats
+                .lock()
+                .await
+                .client_output_buffer_limit_disconnections += 1;
+        }
+        flushed
+    }
+
+    /// Forwards queued writes to a replica until either end goes away, and
+    /// records the offsets it acknowledges in the meantime.
+    async fn serve_replica<C: Connection>(
+        &self,
+        id: u64,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        queued: &AtomicUsize,
+        conn: &C,
+    ) {
+        let mut buf = BytesMut::new();
+        'serve: loop {
+            buf.reserve(512);
+            // The read stays pending while writes go out, rather than being
+            // dropped and started again, which would lose whatever io_uring
+            // had already read into it.
+            let read = conn.recv(std::mem::take(&mut buf));
+            tokio::pin!(read);
+            let (res, read_buf) = loop {
+                tokio::select! {
+                    bytes = rx.recv() => {
+                        let bytes = match bytes {
+                            Some(bytes) => bytes,
+                            None => break 'serve,
+                        };
+                        let len = bytes.len();
+                        if let Err(e) = conn.send(bytes).await {
+                            warn!("Error writing to replica: {}", e);
+                            break 'serve;
+                        }
+                        queued.fetch_sub(len, Ordering::Relaxed);
+                    }
+                    read = &mut read => break read,
+                }
+            };
+            buf = read_buf;
+            match res {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            while let Ok(Some((args, _))) = redis_resp::parse_request(&mut buf) {
+                if let Ok(Some(Command::ReplConf(options))) = Command::from_args(&args) {
+                    if let Some((_, offset)) = options.iter().find(|(key, _)| key == "ack") {
+                        self.record_ack(id, offset.parse().unwrap_or(0)).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn record_ack(&self, id: u64, offset: usize) {
+        let mut replicas = self.replicas.lock().await;
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+        self.ack_notify.notify_waiters();
+    }
+
+    async fn set_replica_state(&self, id: u64, state: ReplicaState) {
+        let mut replicas = self.replicas.lock().await;
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.state = state;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Sends a PING down the replication stream, so replicas can tell the
+    /// link is alive even when there are no writes.
+    async fn ping_replicas(&self) {
+        if self.replicas.lock().await.is_empty() {
+            return;
+        }
+        let _aof = self.aof.lock().await;
+        self.propagate(&Command::Ping).await;
+    }
+
+    /// Disconnects online replicas that haven't acknowledged anything within
+    /// `repl-timeout`.
+    async fn drop_timed_out_replicas(&self, timeout: Duration) {
+        self.replicas.lock().await.retain(|replica| {
+            let timed_out = matches!(replica.state, ReplicaState::Online)
+                && replica.last_ack.elapsed() > timeout;
+            if timed_out {
+                warn!("Disconnecting timed out replica {:?}", replica.addr);
+            }
+            !timed_out
+        });
+    }
+
+    /// Asks every replica to acknowledge its offset.
+    async fn request_acks(&self) {
+        let _aof = self.aof.lock().await;
+        self.propagate(&Command::ReplConf(vec![(
+            "GETACK".to_string(),
+            "*".to_string(),
+        )]))
+        .await;
+    }
+
+    async fn count_acked(&self, offset: usize) -> usize {
+        self.replicas
+            .lock()
+            .await
+            .iter()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Blocks until `numreplicas` replicas have acknowledged every write made
+    /// so far, or the timeout (in milliseconds, 0 for none) runs out. Returns
+    /// how many did.
+    async fn wait(&self, numreplicas: usize, timeout: u64) -> usize {
+        let offset = self.repl_status.lock().await.offset;
+        let deadline = match timeout {
+            0 => None,
+            timeout => Some(tokio::time::Instant::now() + Duration::from_millis(timeout)),
+        };
+        let mut requested = false;
+        loop {
+            let notified = self.ack_notify.notified();
+            let acked = self.count_acked(offset).await;
+            if acked >= numreplicas {
+                return acked;
+            }
+            if !requested {
+                self.request_acks().await;
+                requested = true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                        return self.count_acked(offset).await;
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Starts handing the primary role over to `to`, or to the most up to
+    /// date replica, on a background task. Writes are paused until it
+    /// completes or is aborted.
+    async fn failover(&self, to: Option<(String, String)>, timeout: u64) -> Value {
+        let mut repl_status = self.repl_status.lock().await;
+        if let Role::Replica = repl_status.role {
+            return Value::error("ERR FAILOVER is not valid when server is a replica.");
+        }
+        if repl_status.failover != FailoverState::NoFailover {
+            return Value::error("ERR FAILOVER already in progress.");
+        }
+        let target = {
+            let replicas = self.replicas.lock().await;
+            let mut candidates = replicas
+                .iter()
+                .filter(|replica| matches!(replica.state, ReplicaState::Online))
+                .filter_map(|replica| {
+                    let host = replica.ip()?;
+                    let port = replica.port()?.to_string();
+                    Some((host, port, replica.ack_offset))
+                });
+            match &to {
+                Some((host, port)) => {
+                    match candidates
+                        .find(|(ip, listening_port, _)| ip == host && listening_port == port)
+                    {
+                        Some(target) => target,
+                        None => {
+                            return Value::error(
+                                "ERR FAILOVER target HOST and PORT is not a replica.",
+                            )
+                        }
+                    }
+                }
+                None => match candidates.max_by_key(|(_, _, ack_offset)| *ack_offset) {
+                    Some(target) => target,
+                    None => return Value::error("ERR FAILOVER requires connected replicas."),
+                },
+            }
+        };
+        repl_status.failover = FailoverState::WaitingForSync;
+        drop(repl_status);
+        tokio::spawn(self.clone().run_failover(target.0, target.1, timeout));
+        Value::ok()
+    }
+
+    async fn abort_failover(&self) -> Value {
+        if self.failover_state().await == FailoverState::NoFailover {
+            return Value::error("ERR No failover in progress.");
+        }
+        self.end_failover().await;
+        Value::ok()
+    }
+
+    /// Resumes paused writes.
+    async fn end_failover(&self) {
+        self.repl_status.lock().await.failover = FailoverState::NoFailover;
+        self.pause_notify.notify_waiters();
+    }
+
+    async fn failover_state(&self) -> FailoverState {
+        self.repl_status.lock().await.failover
+    }
+
+    /// Holds a command back for as long as it's paused.
+    async fn wait_until_unpaused(&self, command: &Command) {
+        loop {
+            let notified = self.pause_notify.notified();
+            if !self.is_paused(command).await {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Whether `command` has to wait, because it's a write and a FAILOVER is
+    /// waiting for its target, or because of CLIENT PAUSE.
+    async fn is_paused(&self, command: &Command) -> bool {
+        let is_write = command.is_write() || matches!(command, Command::Migrate { .. });
+        if is_write && self.failover_state().await != FailoverState::NoFailover {
+            return true;
+        }
+        match *self.client_pause.lock().await {
+            Some(pause) if pause.until > Instant::now() => is_write || !pause.write_only,
+            _ => false,
+        }
+    }
+
+    /// Whether keys are kept from expiring, which would write to the
+    /// dataset.
+    async fn writes_paused(&self) -> bool {
+        if self.failover_state().await != FailoverState::NoFailover {
+            return true;
+        }
+        (*self.client_pause.lock().await).is_some_and(|pause| pause.until > Instant::now())
+    }
+
+    /// CLIENT PAUSE: holds back commands from clients for `timeout`
+    /// milliseconds. A pause already in effect is only ever extended, and
+    /// turned from pausing writes into pausing everything.
+    async fn client_pause(&self, timeout: u64, write_only: bool) -> Value {
+        let until = Instant::now() + Duration::from_millis(timeout);
+        {
+            let mut client_pause = self.client_pause.lock().await;
+            let pause = match *client_pause {
+                Some(pause) if pause.until > Instant::now() => ClientPause {
+                    write_only: pause.write_only && write_only,
+                    until: pause.until.max(until),
+                },
+                _ => ClientPause { write_only, until },
+            };
+            *client_pause = Some(pause);
+        }
+        // Nobody else notices the pause running out.
+        let redis = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(until.into()).await;
+            redis.pause_notify.notify_waiters();
+        });
+        Value::ok()
+    }
+
+    async fn client_unpause(&self) -> Value {
+        *self.client_pause.lock().await = None;
+        self.pause_notify.notify_waiters();
+        Value::ok()
+    }
+
+    /// Waits for the target to acknowledge every write, tells it to take
+    /// over with REPLICAOF NO ONE and then becomes its replica. Gives up,
+    /// resuming writes, if the timeout (in milliseconds, 0 for none) runs out
+    /// first or the failover is aborted.
+    async fn run_failover(self, host: String, port: String, timeout: u64) {
+        let deadline = match timeout {
+            0 => None,
+            timeout => Some(Instant::now() + Duration::from_millis(timeout)),
+        };
+        let offset = self.repl_status.lock().await.offset;
+        self.request_acks().await;
+        loop {
+            if self.failover_state().await != FailoverState::WaitingForSync {
+                return;
+            }
+            let acked = self.replicas.lock().await.iter().any(|replica| {
+                replica.ip().as_ref() == Some(&host)
+                    && replica.port() == Some(port.as_str())
+                    && replica.ack_offset >= offset
+            });
+            if acked {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                warn!("FAILOVER to {}:{} timed out", host, port);
+                self.end_failover().await;
+                return;
+            }
+            let notified = self.ack_notify.notified();
+            let _ = tokio::time::timeout(Duration::from_millis(100), notified).await;
+        }
+        self.repl_status.lock().await.failover = FailoverState::FailoverInProgress;
+        let promoted = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut target = TcpStream::connect(format!("{}:{}", host, port)).await?;
+            target
+                .write_all(&Command::ReplicaOf(None).serialize())
+                .await?;
+            read_values(&mut target, 1).await
+        })
+        .await;
+        match promoted {
+            Ok(Ok(replies)) if !matches!(replies[0], Value::Error(_)) => {
+                self.replicaof(Some((host, port))).await;
+            }
+            _ => warn!("FAILOVER to {}:{} failed to promote the target", host, port),
+        }
+        self.end_failover().await;
+    }
+
+    /// Waits for a snapshot to sync a new replica from. Replicas asking for
+    /// one within `repl-diskless-sync-delay` seconds of each other share it.
+    async fn full_sync(
+        &self,
+        addr: Option<SocketAddr>,
+        attributes: ReplicaAttributes,
+    ) -> Option<FullSync> {
+        let (tx, rx) = oneshot::channel();
+        let start = {
+            let mut pending_syncs = self.pending_syncs.lock().await;
+            let start = pending_syncs.is_none();
+            pending_syncs
+                .get_or_insert_with(Vec::new)
+                .push((addr, attributes, tx));
+            start
+        };
+        if start {
+            tokio::spawn(self.clone().run_full_sync());
+        }
+        rx.await.ok()
+    }
+
+    /// Takes a snapshot for every replica waiting on one. Diskless syncs
+    /// serialize it straight to memory, while disk-backed ones save it to the
+    /// RDB file first and send that.
+    async fn run_full_sync(self) {
+        let (diskless, delay) = {
+            let config = self.config.lock().await;
+            let diskless = config.get("repl-diskless-sync").map(String::as_str) != Some("no");
+            let delay = config
+                .get("repl-diskless-sync-delay")
+                .and_then(|delay| delay.parse::<u64>().ok())
+                .unwrap_or(0);
+            (diskless, delay)
+        };
+        if diskless && delay > 0 {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+        }
+        // Registering under the AOF lock, which writes hold until they are
+        // propagated, means every write is either in the snapshot (and so the
+        // offset) or queued for the replicas, but never both.
+        let aof = self.aof.lock().await;
+        let waiters = self.pending_syncs.lock().await.take().unwrap_or_default();
+        let mut replicas = Vec::new();
+        for (addr, attributes, tx) in waiters {
+            let (id, rx, queued) = self.register_replica(addr, attributes).await;
+            replicas.push((id, rx, queued, tx));
+        }
+        let ((kivals, exp_map, libraries), _) = self.fork_snapshot().await;
+        let (replid, offset) = {
+            let repl_status = self.repl_status.lock().await;
+            (
+                repl_status.replid.clone().unwrap_or_default(),
+                repl_status.offset,
+            )
+        };
+        drop(aof);
+        let redis_db = self.redis_db().await;
+        let rdb = tokio::task::spawn_blocking(move || {
+            if diskless {
+                Ok(RedisDB::serialize_rdb(&kivals, &exp_map, &libraries))
+            } else {
+                redis_db.write_rdb(&kivals, &exp_map, &libraries)?;
+                redis_db.get_rbd_bytes()
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+        let rdb = match rdb {
+            Ok(rdb) => Arc::new(rdb),
+            Err(e) => {
+                warn!("Error preparing RDB for replicas: {:?}", e);
+                let ids = replicas.iter().map(|(id, ..)| *id).collect::<Vec<_>>();
+                self.replicas
+                    .lock()
+                    .await
+                    .retain(|replica| !ids.contains(&replica.id));
+                return;
+            }
+        };
+        for (id, rx, queued, tx) in replicas {
+            let _ = tx.send(FullSync {
+                id,
+                rx,
+                queued,
+                replid: replid.clone(),
+                offset,
+                rdb: Arc::clone(&rdb),
+            });
+        }
+    }
+
+    /// Sends the FULLRESYNC reply followed by the RDB payload, framed like a
+    /// bulk string without the trailing CRLF.
+    async fn send_full_sync<C: Connection>(
+        &self,
+        conn: &C,
+        full_sync: &FullSync,
+    ) -> io::Result<()> {
+        let resp = Value::SimpleString(format!(
+            "FULLRESYNC {} {}",
+            full_sync.replid, full_sync.offset
+        ));
+        let mut bytes = resp.serialize();
+        bytes.extend_from_slice(format!("${}\r\n", full_sync.rdb.len()).as_bytes());
+        bytes.extend_from_slice(&full_sync.rdb);
+        conn.send(bytes).await
+    }
+}
+
+/// Appends whatever is available on `stream` to `buf`, failing on EOF.
+async fn read_more(stream: &mut (impl AsyncRead + Unpin), buf: &mut BytesMut) -> io::Result<()> {
+    buf.reserve(4096);
+    if stream.read_buf(buf).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Sends a command of the replication handshake and reads the master's
+/// reply to it.
+async fn handshake_call(
+    stream: &mut Box<dyn Link>,
+    buf: &mut BytesMut,
+    command: &Command,
+) -> anyhow::Result<Value> {
+    stream.write_all(&command.serialize()).await?;
+    loop {
+        if let Some((value, consumed)) = Value::parse(buf)? {
+            buf.advance(consumed);
+            return Ok(value);
+        }
+        read_more(stream, buf).await?;
+    }
+}
+
+/// Fails unless `reply` is +OK, with the error the master sent if it did.
+fn expect_ok(reply: Value) -> anyhow::Result<()> {
+    match reply {
+        Value::SimpleString(ok) if ok == "OK" => Ok(()),
+        Value::Error(e) => anyhow::bail!("{}", e),
+        reply => anyhow::bail!("Unexpected reply {:?}", reply),
+    }
+}
+
+/// Reads `count` complete RESP values from `stream`.
+async fn read_values(stream: &mut TcpStream, count: usize) -> io::Result<Vec<Value>> {
+    let mut values = Vec::with_capacity(count);
+    let mut buf = BytesMut::new();
+    while values.len() < count {
+        match Value::parse(&buf) {
+            Ok(Some((value, consumed))) => {
+                values.push(value);
+                buf.advance(consumed);
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+        read_more(stream, &mut buf).await?;
+    }
+    Ok(values)
+}
+
+/// Applies a single key write command to the shard holding its key.
+fn apply_to_shard(shard: &mut Shard, command: &Command, expired: Option<&mut Vec<Bytes>>) -> Value {
+    match command {
+        // Any expiry the key had is replaced by the new one, unless KEEPTTL
+        // asks to keep it. One that already passed goes with the old value.
+        Command::Set(key, val, exp, keep_ttl) => {
+            shard.remove_if_expired(key, expired);
+            shard.insert(key.clone(), RedisValue::String(val.clone()));
+            if !keep_ttl {
+                shard.set_expiry(key, *exp);
+            }
+            Value::ok()
+        }
+        Command::Restore(key, ttl, payload, replace, absttl) => {
+            shard.remove_if_expired(key, expired);
+            if shard.db.contains_key(key) && !replace {
+                return Value::error("BUSYKEY Target key name already exists.");
+            }
+            let val = match RedisDB::restore_value(payload) {
+                Ok(val) => val,
+                Err(_) => return Value::error("ERR DUMP payload version or checksum are wrong"),
+            };
+            let at = match (ttl, absttl) {
+                (0, _) => None,
+                (ttl, true) => SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(*ttl)),
+                (ttl, false) => SystemTime::now().checked_add(Duration::from_millis(*ttl)),
+            };
+            shard.remove(key);
+            // A key restored with an expiry that already passed is gone
+            // straight away.
+            if !matches!(at, Some(at) if at <= SystemTime::now()) {
+                shard.insert(key.clone(), val);
+                shard.set_expiry(key, at);
+            }
+            Value::ok()
+        }
+        Command::PExpireAt(key, at, conditions) => {
+            if let Some(err) = ExpireCondition::conflict(conditions) {
+                return Value::error(err);
+            }
+            shard.remove_if_expired(key, expired);
+            if !shard.db.contains_key(key) {
+                return Value::Integer(0);
+            }
+            // A key without an expiry counts as never expiring.
+            let current = shard.expiry(key);
+            let allowed = conditions.iter().all(|condition| match condition {
+                ExpireCondition::Nx => current.is_none(),
+                ExpireCondition::Xx => current.is_some(),
+                ExpireCondition::Gt => current.is_some_and(|current| *at > current),
+                ExpireCondition::Lt => match current {
+                    Some(current) => *at < current,
+                    None => true,
+                },
+            });
+            if !allowed {
+                return Value::Integer(0);
+            }
+            if *at <= SystemTime::now() {
+                shard.remove(key);
+            } else {
+                shard.set_expiry(key, Some(*at));
+            }
+            Value::Integer(1)
+        }
+        Command::RPush(key, items) => {
+            shard.remove_if_expired(key, expired);
+            let entry = shard
+                .db
+                .entry(key.clone())
+                .or_insert_with(|| Entry::new(RedisValue::List(VecDeque::new())));
+            match &mut entry.value {
+                RedisValue::List(list) => {
+                    list.extend(items.iter().cloned());
+                    Value::Integer(list.len() as i64)
+                }
+                _ => Value::error(WRONGTYPE),
+            }
+        }
+        Command::SAdd(key, members) => {
+            shard.remove_if_expired(key, expired);
+            let entry = shard
+                .db
+                .entry(key.clone())
+                .or_insert_with(|| Entry::new(RedisValue::Set(HashSet::new())));
+            match &mut entry.value {
+                RedisValue::Set(set) => {
+                    let added = members
+                        .iter()
+                        .filter(|member| set.insert(Bytes::clone(member)))
+                        .count();
+                    Value::Integer(added as i64)
+                }
+                _ => Value::error(WRONGTYPE),
+            }
+        }
+        Command::XAdd {
+            key,
+            id,
+            fields,
+            no_mkstream,
+            trim,
+        } => {
+            shard.remove_if_expired(key, expired);
+            let created = !shard.db.contains_key(key);
+            if created && *no_mkstream {
+                return Value::Nil;
+            }
+            let entry = shard
+                .db
+                .entry(key.clone())
+                .or_insert_with(|| Entry::new(RedisValue::Stream(Stream::default())));
+            let stream = match &mut entry.value {
+                RedisValue::Stream(stream) => stream,
+                _ => return Value::error(WRONGTYPE),
+            };
+            match stream.add(*id, fields.clone()) {
+                Ok(id) => {
+                    if let Some(trim) = trim {
+                        stream.trim(*trim);
+                    }
+                    Value::bulk(id.to_string())
+                }
+                // Streams may be empty, so only one made for this entry is
+                // dropped again.
+                Err(err) => {
+                    if created {
+                        shard.remove(key);
+                    }
+                    Value::error(err)
+                }
+            }
+        }
+        Command::XGroupCreate {
+            key,
+            group,
+            start,
+            mkstream,
+            entries_read,
+        } => {
+            shard.remove_if_expired(key, expired);
+            if *mkstream && !shard.db.contains_key(key) {
+                shard.insert(key.clone(), RedisValue::Stream(Stream::default()));
+            }
+            match stream_mut(shard, key) {
+                Ok(stream) => match stream.create_group(group.clone(), *start, *entries_read) {
+                    true => Value::ok(),
+                    false => Value::error("BUSYGROUP Consumer Group name already exists"),
+                },
+                Err(resp) => resp,
+            }
+        }
+        Command::XGroupCreateConsumer(key, group, consumer) => {
+            shard.remove_if_expired(key, expired);
+            match stream_mut(shard, key) {
+                Ok(stream) => match stream.create_consumer(group, consumer) {
+                    Some(created) => Value::Integer(created as i64),
+                    None => no_group(key, group),
+                },
+                Err(resp) => resp,
+            }
+        }
+        Command::XGroupDelConsumer(key, group, consumer) => {
+            shard.remove_if_expired(key, expired);
+            match stream_mut(shard, key) {
+                Ok(stream) => match stream.delete_consumer(group, consumer) {
+                    Some(pending) => Value::Integer(pending as i64),
+                    None => no_group(key, group),
+                },
+                Err(resp) => resp,
+            }
+        }
+        Command::XGroupDestroy(key, group) => {
+            shard.remove_if_expired(key, expired);
+            match stream_mut(shard, key) {
+                Ok(stream) => Value::Integer(stream.groups.remove(group).is_some() as i64),
+                Err(resp) => resp,
+            }
+        }
+        Command::XAck(key, group, ids) => {
+            shard.remove_if_expired(key, expired);
+            match shard.db.get_mut(key).map(|entry| &mut entry.value) {
+                Some(RedisValue::Stream(stream)) => Value::Integer(stream.ack(group, ids) as i64),
+                Some(_) => Value::error(WRONGTYPE),
+                None => Value::Integer(0),
+            }
+        }
+        Command::SRem(key, members) => {
+            shard.remove_if_expired(key, expired);
+            let removed = match shard.db.get_mut(key).map(|entry| &mut entry.value) {
+                Some(RedisValue::Set(set)) => {
+                    members.iter().filter(|member| set.remove(*member)).count()
+                }
+                Some(_) => return Value::error(WRONGTYPE),
+                None => 0,
+            };
+            shard.remove_if_empty(key);
+            Value::Integer(removed as i64)
+        }
+        Command::SPop(key, count) => {
+            shard.remove_if_expired(key, expired);
+            if count.is_some_and(|count| count < 0) {
+                return Value::error("ERR value is out of range, must be positive");
+            }
+            let popped = match shard.db.get_mut(key).map(|entry| &mut entry.value) {
+                Some(RedisValue::Set(set)) => {
+                    let members = set.iter().cloned().collect();
+                    let popped = random_sample(members, count.unwrap_or(1));
+                    for member in &popped {
+                        set.remove(member);
+                    }
+                    popped
+                }
+                Some(_) => return Value::error(WRONGTYPE),
+                None => Vec::new(),
+            };
+            shard.remove_if_empty(key);
+            match count {
+                Some(_) => Value::bytes_array(popped),
+                None => popped
+                    .into_iter()
+                    .next()
+                    .map_or(Value::Nil, Value::bulk_bytes),
+            }
+        }
+        Command::HSet(key, fields) => {
+            shard.remove_if_expired(key, expired);
+            let entry = shard
+                .db
+                .entry(key.clone())
+                .or_insert_with(|| Entry::new(RedisValue::Hash(HashMap::new())));
+            match &mut entry.value {
+                RedisValue::Hash(hash) => {
+                    let added = fields
+                        .iter()
+                        .filter(|(field, val)| hash.insert(field.clone(), val.clone()).is_none())
+                        .count();
+                    Value::Integer(added as i64)
+                }
+                _ => Value::error(WRONGTYPE),
+            }
+        }
+        Command::ZAdd(key, members) => {
+            shard.remove_if_expired(key, expired);
+            let entry = shard
+                .db
+                .entry(key.clone())
+                .or_insert_with(|| Entry::new(RedisValue::ZSet(HashMap::new())));
+            match &mut entry.value {
+                RedisValue::ZSet(zset) => {
+                    let added = members
+                        .iter()
+                        .filter(|(score, member)| zset.insert(member.clone(), *score).is_none())
+                        .count();
+                    Value::Integer(added as i64)
+                }
+                _ => Value::error(WRONGTYPE),
+            }
+        }
+        _ => Value::Nil,
+    }
+}
+
+/// The stream at `key`, for the XGROUP subcommands, which need it to exist.
+fn stream_mut<'a>(shard: &'a mut Shard, key: &[u8]) -> Result<&'a mut Stream, Value> {
+    match shard.db.get_mut(key).map(|entry| &mut entry.value) {
+        Some(RedisValue::Stream(stream)) => Ok(stream),
+        Some(_) => Err(Value::error(WRONGTYPE)),
+        None => Err(Value::error(
+            "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+             want to use the MKSTREAM option to create an empty stream automatically.",
+        )),
+    }
+}
+
+fn no_group(key: &[u8], group: &[u8]) -> Value {
+    Value::error(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        String::from_utf8_lossy(group),
+        String::from_utf8_lossy(key)
+    ))
+}
+
+/// XREADGROUP: reads from each of `streams` for `consumer` of `group`. Every
+/// stream is checked before any is read from, so a missing group doesn't
+/// leave the others read. Streams with nothing new to read are left out of
+/// the reply, which is nil if that's all of them.
+fn read_groups(
+    store: &impl Storage,
+    group: &Bytes,
+    consumer: &Bytes,
+    streams: &[(Bytes, Option<StreamId>)],
+    count: Option<usize>,
+    no_ack: bool,
+    mut expired: Option<&mut Vec<Bytes>>,
+) -> Value {
+    for (key, _) in streams {
+        let found = store.write(key, |shard| {
+            shard.remove_if_expired(key, expired.as_deref_mut());
+            match shard.get(key) {
+                Some(RedisValue::Stream(stream)) => Ok(stream.groups.contains_key(group)),
+                Some(_) => Err(Value::error(WRONGTYPE)),
+                None => Ok(false),
+            }
+        });
+        match found {
+            Ok(true) => {}
+            Ok(false) => {
+                return Value::error(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP \
+                     option",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(group)
+                ))
+            }
+            Err(resp) => return resp,
+        }
+    }
+    let mut reply = Vec::new();
+    for (key, after) in streams {
+        let entries = store.write(key, |shard| {
+            match shard.db.get_mut(key).map(|entry| &mut entry.value) {
+                Some(RedisValue::Stream(stream)) => {
+                    stream.read_group(group, consumer, *after, count, no_ack)
+                }
+                _ => None,
+            }
+        });
+        match entries {
+            Some(entries) if after.is_some() || !entries.is_empty() => {
+                reply.push(Value::Array(vec![
+                    Value::bulk_bytes(key),
+                    Value::Array(entries),
+                ]))
+            }
+            _ => {}
+        }
+    }
+    match reply.is_empty() {
+        true => Value::Nil,
+        false => Value::Array(reply),
+    }
+}
+
+/// UNLINK drops values with more elements than this on a background task.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Drops the values UNLINK removed. Freeing a huge collection can take a
+/// while, so big ones are dropped off the event loop.
+fn lazy_free(values: Vec<RedisValue>) {
+    let big = values
+        .into_iter()
+        .filter(|val| val.element_count() > LAZYFREE_THRESHOLD)
+        .collect::<Vec<_>>();
+    if !big.is_empty() {
+        tokio::task::spawn_blocking(move || drop(big));
+    }
 }
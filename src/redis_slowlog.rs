@@ -0,0 +1,109 @@
+use crate::redis_resp::Value;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// At most this many arguments of a command are kept, the last one standing
+/// in for the rest.
+const MAX_ARGS: usize = 32;
+
+/// Arguments longer than this many bytes are truncated.
+const MAX_ARG_LEN: usize = 128;
+
+/// A command that took longer than slowlog-log-slower-than to run.
+struct SlowLogEntry {
+    id: u64,
+    time: SystemTime,
+    duration: Duration,
+    args: Vec<String>,
+    client_addr: String,
+    client_name: String,
+}
+
+/// The most recent slow commands, newest first, for SLOWLOG.
+#[derive(Default)]
+pub struct SlowLog {
+    next_id: u64,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+impl SlowLog {
+    /// Records a slow command, dropping the oldest entries beyond `max_len`.
+    pub fn push(
+        &mut self,
+        args: Vec<String>,
+        duration: Duration,
+        client_addr: String,
+        client_name: String,
+        max_len: usize,
+    ) {
+        self.entries.push_front(SlowLogEntry {
+            id: self.next_id,
+            time: SystemTime::now(),
+            duration,
+            args: Self::trim_args(args),
+            client_addr,
+            client_name,
+        });
+        self.next_id += 1;
+        self.entries.truncate(max_len);
+    }
+
+    /// Shortens long argument lists and long arguments the way Redis does,
+    /// so a huge command doesn't take up as much memory in the log.
+    fn trim_args(mut args: Vec<String>) -> Vec<String> {
+        if args.len() > MAX_ARGS {
+            let more = args.len() - MAX_ARGS + 1;
+            args.truncate(MAX_ARGS - 1);
+            args.push(format!("... ({} more arguments)", more));
+        }
+        args.into_iter()
+            .map(|arg| match arg.len() > MAX_ARG_LEN {
+                true => {
+                    let mut end = MAX_ARG_LEN;
+                    while !arg.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+                }
+                false => arg,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    /// SLOWLOG GET: the `count` newest entries, or all of them if None.
+    pub fn get(&self, count: Option<usize>) -> Value {
+        let count = count.unwrap_or(self.entries.len());
+        Value::Array(
+            self.entries
+                .iter()
+                .take(count)
+                .map(|entry| {
+                    let time = entry
+                        .time
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default();
+                    Value::Array(vec![
+                        Value::Integer(entry.id as i64),
+                        Value::Integer(time.as_secs() as i64),
+                        Value::Integer(entry.duration.as_micros() as i64),
+                        Value::bulk_array(entry.args.iter().cloned()),
+                        Value::bulk(entry.client_addr.clone()),
+                        Value::bulk(entry.client_name.clone()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
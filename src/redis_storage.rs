@@ -0,0 +1,226 @@
+use crate::redis_evict::KeyAccess;
+use crate::redis_value::RedisValue;
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Instant, SystemTime};
+
+/// How many shards the keyspace is split into.
+const SHARDS: usize = 64;
+
+/// A value, along with what is tracked about the key holding it.
+pub struct Entry {
+    pub value: RedisValue,
+    /// How the key has been read or written, for the LRU and LFU eviction
+    /// policies.
+    pub access: KeyAccess,
+    /// When the key expires, if it does. Only changed through the shard,
+    /// which keeps its expiry index in step.
+    exp: Option<SystemTime>,
+}
+
+impl Entry {
+    pub fn new(value: RedisValue) -> Self {
+        Entry {
+            value,
+            access: KeyAccess::new(Instant::now()),
+            exp: None,
+        }
+    }
+
+    pub fn exp(&self) -> Option<SystemTime> {
+        self.exp
+    }
+}
+
+/// The keys that hash to the same shard, along with an index of the ones
+/// that expire.
+#[derive(Default)]
+pub struct Shard {
+    pub db: HashMap<Bytes, Entry>,
+    /// The keys with an expiry, soonest first, so the ones that are due can
+    /// be found without going through every key.
+    expires: BTreeSet<(SystemTime, Bytes)>,
+}
+
+impl Shard {
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        self.expiry(key)
+            .is_some_and(|exp_time| exp_time < SystemTime::now())
+    }
+
+    /// When `key` expires, or None if it doesn't exist or never expires.
+    pub fn expiry(&self, key: &[u8]) -> Option<SystemTime> {
+        self.db.get(key).and_then(|entry| entry.exp)
+    }
+
+    /// Sets or clears the expiry of `key`. Does nothing if it doesn't exist.
+    pub fn set_expiry(&mut self, key: &[u8], exp: Option<SystemTime>) {
+        let entry = match self.db.get_mut(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Some(old) = entry.exp {
+            self.expires.remove(&(old, Bytes::copy_from_slice(key)));
+        }
+        if let Some(exp) = exp {
+            self.expires.insert((exp, Bytes::copy_from_slice(key)));
+        }
+        entry.exp = exp;
+    }
+
+    /// The keys with an expiry and when they expire, soonest first.
+    pub fn volatile(&self) -> impl Iterator<Item = (&Bytes, SystemTime)> {
+        self.expires.iter().map(|(exp, key)| (key, *exp))
+    }
+
+    /// Up to `limit` of the keys whose expiry is before `now`.
+    pub fn due(&self, now: SystemTime, limit: usize) -> Vec<Bytes> {
+        self.volatile()
+            .take_while(|(_, exp)| *exp < now)
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// The value stored at `key`, hiding it once it has expired.
+    pub fn get(&self, key: &[u8]) -> Option<&RedisValue> {
+        match self.is_expired(key) {
+            true => None,
+            false => self.db.get(key).map(|entry| &entry.value),
+        }
+    }
+
+    /// Removes `key` along with its expiry.
+    pub fn remove(&mut self, key: &[u8]) -> Option<RedisValue> {
+        let entry = self.db.remove(key)?;
+        if let Some(exp) = entry.exp {
+            self.expires.remove(&(exp, Bytes::copy_from_slice(key)));
+        }
+        Some(entry.value)
+    }
+
+    /// Stores `value` at `key`, replacing whatever was there but keeping its
+    /// expiry, unless that already passed.
+    pub fn insert(&mut self, key: Bytes, value: RedisValue) {
+        let exp = self.expiry(&key).filter(|exp| *exp >= SystemTime::now());
+        self.db.insert(
+            key,
+            Entry {
+                exp,
+                ..Entry::new(value)
+            },
+        );
+    }
+
+    /// Lazily expires `key`: drops it once its expiry has passed, and records
+    /// it in `expired` so the deletion can be propagated. Does nothing
+    /// without `expired`, which is how replicas call it.
+    pub fn remove_if_expired(&mut self, key: &[u8], expired: Option<&mut Vec<Bytes>>) {
+        let expired = match expired {
+            Some(expired) => expired,
+            None => return,
+        };
+        if self.is_expired(key) && self.remove(key).is_some() {
+            expired.push(Bytes::copy_from_slice(key));
+        }
+    }
+
+    /// Deletes a collection once its last element was removed, since Redis
+    /// never keeps empty ones.
+    pub fn remove_if_empty(&mut self, key: &[u8]) {
+        if self
+            .db
+            .get(key)
+            .is_some_and(|entry| entry.value.element_count() == 0)
+        {
+            self.remove(key);
+        }
+    }
+}
+
+/// Where the keyspace is kept. Access goes through the shard holding a key,
+/// so commands on keys in different shards don't wait on each other, and
+/// reads of the same shard run side by side.
+///
+/// Shard locks are never held across an await: they are taken last, after
+/// any other lock a command needs, and released before it goes on.
+pub trait Storage: Send + Sync {
+    /// Runs `f` on the shard holding `key`, with other readers allowed in.
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Shard) -> R) -> R;
+
+    /// Runs `f` on the shard holding `key`, with no one else allowed in.
+    fn write<R>(&self, key: &[u8], f: impl FnOnce(&mut Shard) -> R) -> R;
+
+    /// Runs `f` on every shard at once, for a consistent view of the whole
+    /// keyspace such as a snapshot.
+    fn read_all<R>(&self, f: impl FnOnce(&[&Shard]) -> R) -> R;
+
+    /// Runs `f` on every shard at once, with no one else allowed in.
+    fn write_all<R>(&self, f: impl FnOnce(&mut [&mut Shard]) -> R) -> R;
+}
+
+/// The keyspace split into a fixed number of shards by key hash, each behind
+/// its own lock.
+pub struct ShardedStorage {
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl Default for ShardedStorage {
+    fn default() -> Self {
+        ShardedStorage {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl ShardedStorage {
+    fn shard(&self, key: &[u8]) -> &RwLock<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+// A command that panicked half way through poisons the lock of its shard.
+// The shard is still usable, so the poisoning is ignored rather than making
+// every later command on it panic too.
+impl Storage for ShardedStorage {
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Shard) -> R) -> R {
+        f(&self
+            .shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn write<R>(&self, key: &[u8], f: impl FnOnce(&mut Shard) -> R) -> R {
+        f(&mut self
+            .shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn read_all<R>(&self, f: impl FnOnce(&[&Shard]) -> R) -> R {
+        // Always locked in the same order, so two callers can't deadlock.
+        let guards = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner))
+            .collect::<Vec<_>>();
+        f(&guards.iter().map(|guard| &**guard).collect::<Vec<_>>())
+    }
+
+    fn write_all<R>(&self, f: impl FnOnce(&mut [&mut Shard]) -> R) -> R {
+        let mut guards = self
+            .shards
+            .iter()
+            .map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner))
+            .collect::<Vec<_>>();
+        f(&mut guards
+            .iter_mut()
+            .map(|guard| &mut **guard)
+            .collect::<Vec<_>>())
+    }
+}
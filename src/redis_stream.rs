@@ -0,0 +1,507 @@
+use crate::redis_resp::Value;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::SystemTime;
+
+/// The ID of a stream entry: the unix time in milliseconds it was added at,
+/// and a sequence number telling apart the entries of the same millisecond.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `ms-seq`, or a bare `ms` which takes `seq` as its sequence
+    /// number, the way XRANGE reads its bounds.
+    pub fn parse(id: &str, seq: u64) -> Option<StreamId> {
+        let (ms, seq) = match id.split_once('-') {
+            Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+            None => (id.parse().ok()?, seq),
+        };
+        Some(StreamId { ms, seq })
+    }
+
+    /// The ID as RDB files store it: both halves as big endian integers.
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        ((self.ms as u128) << 64 | self.seq as u128).to_be_bytes()
+    }
+
+    pub fn from_be_bytes(raw: [u8; 16]) -> StreamId {
+        let id = u128::from_be_bytes(raw);
+        StreamId {
+            ms: (id >> 64) as u64,
+            seq: id as u64,
+        }
+    }
+
+    /// The ID right after this one, for a range starting after it.
+    pub fn next(self) -> Option<StreamId> {
+        let id = u128::from_be_bytes(self.to_be_bytes()).checked_add(1)?;
+        Some(StreamId::from_be_bytes(id.to_be_bytes()))
+    }
+
+    /// The ID right before this one, for a range ending before it.
+    pub fn prev(self) -> Option<StreamId> {
+        let id = u128::from_be_bytes(self.to_be_bytes()).checked_sub(1)?;
+        Some(StreamId::from_be_bytes(id.to_be_bytes()))
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// How XADD picks the ID of the entry it adds.
+#[derive(Clone, Copy)]
+pub enum NewId {
+    /// `*`: the current time, or the last ID's time if the clock went back.
+    Auto,
+    /// `ms-*`: the given time, with the next free sequence number in it.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// How XADD trims the stream after adding to it. Trimming is always exact,
+/// which is also what an approximate `~` trim may do.
+#[derive(Clone, Copy)]
+pub enum Trim {
+    /// MAXLEN: keeps the newest entries, at most this many.
+    MaxLen(usize),
+    /// MINID: drops the entries with a smaller ID.
+    MinId(StreamId),
+}
+
+/// Fields and values, in the order they were added with.
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// An entry delivered to a consumer of a group, and not acknowledged yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    /// When the entry was last delivered, in unix milliseconds.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+/// A reader of a consumer group, known from the first time it read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Consumer {
+    /// When it last tried to read, in unix milliseconds.
+    pub seen_time: u64,
+    /// When it last got entries, if it ever did.
+    pub active_time: Option<u64>,
+}
+
+/// Readers sharing the entries of a stream, each entry going to one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerGroup {
+    /// The ID of the last entry delivered to the group.
+    pub last_id: StreamId,
+    /// How many of the stream's entries the group has read, or None when
+    /// that can't be told, such as for a group starting at an arbitrary ID.
+    pub entries_read: Option<u64>,
+    /// The entries delivered and not acknowledged yet.
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<Bytes, Consumer>,
+}
+
+impl ConsumerGroup {
+    /// The consumer called `name`, added if it isn't known yet.
+    fn consumer(&mut self, name: &Bytes, now: u64) -> &mut Consumer {
+        self.consumers.entry(name.clone()).or_insert(Consumer {
+            seen_time: now,
+            active_time: None,
+        })
+    }
+
+    /// How many entries are pending for the consumer called `name`.
+    pub fn pending_for(&self, name: &[u8]) -> usize {
+        self.pending
+            .values()
+            .filter(|pending| pending.consumer == name)
+            .count()
+    }
+}
+
+/// An append only log of entries, each holding fields and values, ordered
+/// by ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    /// The ID of the last entry added. New ones have to go after it, even
+    /// once it's gone.
+    pub last_id: StreamId,
+    /// The greatest ID of an entry that was deleted, as RDB files keep it.
+    pub max_deleted_id: StreamId,
+    /// How many entries were ever added.
+    pub entries_added: u64,
+    pub groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+impl Stream {
+    /// Adds an entry and returns its ID, or the error reply for an ID that
+    /// doesn't go after the last one.
+    pub fn add(&mut self, id: NewId, fields: Fields) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto => {
+                let now = now_ms();
+                match now > last.ms {
+                    true => StreamId { ms: now, seq: 0 },
+                    false => next_in_ms(last).ok_or(EXHAUSTED)?,
+                }
+            }
+            NewId::AutoSeq(ms) if ms > last.ms => StreamId { ms, seq: 0 },
+            NewId::AutoSeq(ms) if ms == last.ms => next_in_ms(last).ok_or(EXHAUSTED)?,
+            NewId::AutoSeq(_) => return Err(NOT_GREATER),
+            NewId::Explicit(id) => id,
+        };
+        if id == StreamId::MIN {
+            return Err("ERR The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= last {
+            return Err(NOT_GREATER);
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+        Ok(id)
+    }
+
+    /// Drops the oldest entries as `trim` asks.
+    pub fn trim(&mut self, trim: Trim) {
+        while let Some((&id, _)) = self.entries.first_key_value() {
+            let keep = match trim {
+                Trim::MaxLen(max_len) => self.entries.len() <= max_len,
+                Trim::MinId(min_id) => id >= min_id,
+            };
+            if keep {
+                break;
+            }
+            self.entries.remove(&id);
+            self.max_deleted_id = self.max_deleted_id.max(id);
+        }
+    }
+
+    /// The ID of the first entry, or 0-0 when there are none.
+    pub fn first_id(&self) -> StreamId {
+        self.entries.keys().next().copied().unwrap_or(StreamId::MIN)
+    }
+
+    /// Whether an entry from `start` on was deleted while the ones around it
+    /// were kept, so counting entries from there on is off.
+    fn has_tombstones(&self, start: StreamId) -> bool {
+        !self.entries.is_empty()
+            && self.max_deleted_id != StreamId::MIN
+            && start <= self.max_deleted_id
+    }
+
+    /// How many entries were added up to and including `id`, when that can
+    /// be worked out from what's left of the stream.
+    fn entries_up_to(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.entries.is_empty() && id <= self.last_id || id == self.last_id {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first_id = self.first_id();
+        let len = self.entries.len() as u64;
+        match self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first_id {
+            true if id < first_id => Some(self.entries_added - len),
+            true if id == first_id => Some(self.entries_added - len + 1),
+            _ => None,
+        }
+    }
+
+    /// How many entries are left for `group` to read, when that can be told.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(read) if !self.has_tombstones(group.last_id) => read,
+            _ => self.entries_up_to(group.last_id)?,
+        };
+        Some(self.entries_added.saturating_sub(entries_read))
+    }
+
+    /// XGROUP CREATE: adds a group that reads the entries after `start`, or
+    /// after the last one when None. Returns false if it already exists.
+    pub fn create_group(
+        &mut self,
+        name: Bytes,
+        start: Option<StreamId>,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let (last_id, entries_read) = match start {
+            Some(id) => (id, entries_read),
+            None => (self.last_id, entries_read.or(Some(self.entries_added))),
+        };
+        self.groups.insert(
+            name,
+            ConsumerGroup {
+                last_id,
+                entries_read,
+                pending: BTreeMap::new(),
+                consumers: BTreeMap::new(),
+            },
+        );
+        true
+    }
+
+    /// XGROUP CREATECONSUMER. Returns whether the consumer is new, or None
+    /// without such a group.
+    pub fn create_consumer(&mut self, group: &[u8], consumer: &Bytes) -> Option<bool> {
+        let group = self.groups.get_mut(group)?;
+        let created = !group.consumers.contains_key(consumer);
+        group.consumer(consumer, now_ms());
+        Some(created)
+    }
+
+    /// XGROUP DELCONSUMER. Returns how many entries were pending for the
+    /// consumer, or None without such a group.
+    pub fn delete_consumer(&mut self, group: &[u8], consumer: &[u8]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        let pending = group.pending_for(consumer);
+        group
+            .pending
+            .retain(|_, pending| pending.consumer != consumer);
+        group.consumers.remove(consumer);
+        Some(pending)
+    }
+
+    /// XREADGROUP on this stream for `consumer` of `group`. With no `after`
+    /// it's up to `count` entries never delivered to the group, which are
+    /// then pending unless `no_ack`. Otherwise it's the consumer's pending
+    /// entries after that ID, where the ones since deleted have no fields.
+    /// Returns the entries as XREADGROUP replies with them, or None without
+    /// such a group.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        after: Option<StreamId>,
+        count: Option<usize>,
+        no_ack: bool,
+    ) -> Option<Vec<Value>> {
+        let (name, mut cg) = self.groups.remove_entry(group)?;
+        let now = now_ms();
+        let count = count.unwrap_or(usize::MAX);
+        let mut reply = Vec::new();
+        match after {
+            None => {
+                let ids = cg
+                    .last_id
+                    .next()
+                    .map(|start| self.entries.range(start..))
+                    .into_iter()
+                    .flatten()
+                    .take(count)
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                for id in ids {
+                    cg.entries_read = match cg.entries_read {
+                        Some(read) if !self.has_tombstones(id) => Some(read + 1),
+                        _ => self.entries_up_to(id),
+                    };
+                    cg.last_id = id;
+                    if !no_ack {
+                        cg.pending.insert(
+                            id,
+                            PendingEntry {
+                                consumer: consumer.clone(),
+                                delivery_time: now,
+                                delivery_count: 1,
+                            },
+                        );
+                    }
+                    reply.push(entry_reply(&id, &self.entries[&id]));
+                }
+            }
+            Some(after) => {
+                let pending = cg
+                    .pending
+                    .iter_mut()
+                    .filter(|(id, pending)| **id > after && pending.consumer == consumer)
+                    .take(count);
+                for (id, pending) in pending {
+                    pending.delivery_time = now;
+                    pending.delivery_count += 1;
+                    reply.push(match self.entries.get(id) {
+                        Some(fields) => entry_reply(id, fields),
+                        None => Value::Array(vec![Value::bulk(id.to_string()), Value::Nil]),
+                    });
+                }
+            }
+        }
+        let consumer = cg.consumer(consumer, now);
+        consumer.seen_time = now;
+        if !reply.is_empty() {
+            consumer.active_time = Some(now);
+        }
+        self.groups.insert(name, cg);
+        Some(reply)
+    }
+
+    /// XACK: drops the entries from the group's pending ones, and returns
+    /// how many were pending.
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> usize {
+        match self.groups.get_mut(group) {
+            Some(group) => ids
+                .iter()
+                .filter(|id| group.pending.remove(id).is_some())
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// XINFO STREAM: the stream's length and IDs, and its first and last
+    /// entries.
+    pub fn info(&self) -> Value {
+        let nodes = self.entries.len().div_ceil(100) as i64;
+        let entry = |entry: Option<(&StreamId, &Fields)>| {
+            entry.map_or(Value::Nil, |(id, fields)| entry_reply(id, fields))
+        };
+        info_map([
+            ("length", Value::Integer(self.entries.len() as i64)),
+            ("radix-tree-keys", Value::Integer(nodes)),
+            ("radix-tree-nodes", Value::Integer(nodes)),
+            ("last-generated-id", Value::bulk(self.last_id.to_string())),
+            (
+                "max-deleted-entry-id",
+                Value::bulk(self.max_deleted_id.to_string()),
+            ),
+            ("entries-added", Value::Integer(self.entries_added as i64)),
+            (
+                "recorded-first-entry-id",
+                Value::bulk(self.first_id().to_string()),
+            ),
+            ("groups", Value::Integer(self.groups.len() as i64)),
+            ("first-entry", entry(self.entries.first_key_value())),
+            ("last-entry", entry(self.entries.last_key_value())),
+        ])
+    }
+
+    /// XINFO GROUPS: each group's progress through the stream.
+    pub fn groups_info(&self) -> Value {
+        let optional = |num: Option<u64>| num.map_or(Value::Nil, |num| Value::Integer(num as i64));
+        Value::Array(
+            self.groups
+                .iter()
+                .map(|(name, group)| {
+                    info_map([
+                        ("name", Value::bulk_bytes(name)),
+                        ("consumers", Value::Integer(group.consumers.len() as i64)),
+                        ("pending", Value::Integer(group.pending.len() as i64)),
+                        ("last-delivered-id", Value::bulk(group.last_id.to_string())),
+                        ("entries-read", optional(group.entries_read)),
+                        ("lag", optional(self.lag(group))),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// XINFO CONSUMERS: what each consumer of `group` has pending, and how
+    /// long ago it last read. None without such a group.
+    pub fn consumers_info(&self, group: &[u8]) -> Option<Value> {
+        let group = self.groups.get(group)?;
+        let now = now_ms();
+        let since = |time: u64| now.saturating_sub(time) as i64;
+        Some(Value::Array(
+            group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    info_map([
+                        ("name", Value::bulk_bytes(name)),
+                        ("pending", Value::Integer(group.pending_for(name) as i64)),
+                        ("idle", Value::Integer(since(consumer.seen_time))),
+                        (
+                            "inactive",
+                            Value::Integer(consumer.active_time.map_or(-1, since)),
+                        ),
+                    ])
+                })
+                .collect(),
+        ))
+    }
+
+    /// Up to `count` entries with IDs from `start` to `end`, both included.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        let entries = match start <= end {
+            true => Some(self.entries.range(start..=end)),
+            false => None,
+        };
+        entries
+            .into_iter()
+            .flatten()
+            .take(count.unwrap_or(usize::MAX))
+    }
+}
+
+const NOT_GREATER: &str =
+    "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+
+const EXHAUSTED: &str =
+    "ERR The stream has exhausted the last possible ID, unable to add more items";
+
+/// The current unix time in milliseconds, which stream IDs and consumer
+/// times are based on.
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn info_map<const N: usize>(fields: [(&str, Value); N]) -> Value {
+    Value::Map(
+        fields
+            .into_iter()
+            .map(|(name, value)| (Value::bulk(name), value))
+            .collect(),
+    )
+}
+
+fn next_in_ms(id: StreamId) -> Option<StreamId> {
+    Some(StreamId {
+        ms: id.ms,
+        seq: id.seq.checked_add(1)?,
+    })
+}
+
+/// An entry as XRANGE replies with it: its ID, then its fields and values.
+pub fn entry_reply(id: &StreamId, fields: &Fields) -> Value {
+    Value::Array(vec![
+        Value::bulk(id.to_string()),
+        Value::Array(
+            fields
+                .iter()
+                .flat_map(|(field, val)| [Value::bulk_bytes(field), Value::bulk_bytes(val)])
+                .collect(),
+        ),
+    ])
+}
@@ -0,0 +1,127 @@
+use crate::redis_cli::Connection;
+use crate::redis_resp::Value;
+use crate::{Server, ServerBuilder, ServerHandle};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static NEXT_TEST_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A server for tests, listening on a free loopback port with its own
+/// temporary directory, which is removed when it's dropped. It never saves
+/// on a schedule, so only SAVE and friends write files.
+pub struct TestServer {
+    handle: ServerHandle,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|builder| builder).await
+    }
+
+    /// Starts a server with the settings above, changed by `configure`.
+    pub async fn start_with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-rs-test-{}-{}",
+            std::process::id(),
+            NEXT_TEST_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).expect("can't create test server directory");
+        let builder = Server::builder()
+            .port(0)
+            .bind("127.0.0.1")
+            .dir(dir.to_string_lossy())
+            .save("");
+        let handle = configure(builder)
+            .spawn()
+            .await
+            .expect("test server didn't start");
+        TestServer { handle, dir }
+    }
+
+    /// Starts a primary and a replica of it, and waits for the replica to
+    /// finish its initial sync.
+    pub async fn start_pair() -> (Self, Self) {
+        let primary = Self::start().await;
+        let replica = primary.start_replica().await;
+        (primary, replica)
+    }
+
+    /// Starts a replica of this server, and waits for it to finish its
+    /// initial sync.
+    pub async fn start_replica(&self) -> Self {
+        let port = self.addr().port();
+        let replica = Self::start_with(|builder| builder.replicaof("127.0.0.1", port)).await;
+        self.wait_until(
+            &["INFO", "replication"],
+            |reply| matches!(reply, Value::BulkString(info) if info.contains("state=online")),
+        )
+        .await;
+        replica
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.handle.local_addr()
+    }
+
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.handle.tls_addr()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Shuts the server down. Dropping a TestServer doesn't, so this is how
+    /// a test stops one before starting another on the same directory.
+    pub async fn shutdown(&self) {
+        self.handle
+            .shutdown()
+            .await
+            .expect("test server didn't shut down");
+        self.handle.stopped().await;
+    }
+
+    pub async fn connect(&self) -> Connection {
+        Connection::connect(&self.addr().to_string())
+            .await
+            .expect("can't connect to test server")
+    }
+
+    /// Sends one command on a new connection and returns the reply.
+    pub async fn call(&self, args: &[&str]) -> Value {
+        let args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        self.connect()
+            .await
+            .call(args)
+            .await
+            .expect("test server didn't reply")
+    }
+
+    /// Sends the command every few milliseconds until the reply passes
+    /// `check`, and returns that reply. Panics after 5 seconds.
+    pub async fn wait_until(&self, args: &[&str], check: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let reply = self.call(args).await;
+            if check(&reply) {
+                return reply;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "gave up waiting on {:?}, last reply {:?}",
+                args,
+                reply
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
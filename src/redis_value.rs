@@ -0,0 +1,283 @@
+use crate::redis_evict::random_index;
+use crate::redis_stream::{Stream, StreamId};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A value stored under a key. Strings and the elements of collections are
+/// binary safe, and kept as the exact bytes they were set to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedisValue {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    ZSet(HashMap<Bytes, f64>),
+    Stream(Stream),
+}
+
+impl RedisValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Set(_) => "set",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::ZSet(_) => "zset",
+            RedisValue::Stream(_) => "stream",
+        }
+    }
+
+    /// The number of references Redis would count to the value. Like in
+    /// Redis, small integers count as shared objects.
+    pub fn refcount(&self) -> i64 {
+        match self {
+            RedisValue::String(val)
+                if as_int(val).is_some_and(|num| (0..10_000).contains(&num)) =>
+            {
+                i32::MAX as i64
+            }
+            _ => 1,
+        }
+    }
+
+    /// The encoding Redis would store the value with. Small collections are
+    /// packed into a single allocation until they grow past these limits.
+    pub fn encoding(&self) -> &'static str {
+        const MAX_ENTRIES: usize = 128;
+        const MAX_VALUE: usize = 64;
+        const MAX_INTSET_ENTRIES: usize = 512;
+        let small = |len: usize, mut items: Box<dyn Iterator<Item = &Bytes> + '_>| {
+            len <= MAX_ENTRIES && items.all(|item| item.len() <= MAX_VALUE)
+        };
+        match self {
+            RedisValue::String(val) if val.len() <= 20 && as_int(val).is_some() => "int",
+            RedisValue::String(val) if val.len() <= 44 => "embstr",
+            RedisValue::String(_) => "raw",
+            RedisValue::List(list) if small(list.len(), Box::new(list.iter())) => "listpack",
+            RedisValue::List(_) => "quicklist",
+            RedisValue::Set(set)
+                if set.len() <= MAX_INTSET_ENTRIES
+                    && set.iter().all(|member| as_int(member).is_some()) =>
+            {
+                "intset"
+            }
+            RedisValue::Set(set) if small(set.len(), Box::new(set.iter())) => "listpack",
+            RedisValue::Set(_) => "hashtable",
+            RedisValue::Hash(hash)
+                if small(hash.len(), Box::new(hash.iter().flat_map(|(k, v)| [k, v]))) =>
+            {
+                "listpack"
+            }
+            RedisValue::Hash(_) => "hashtable",
+            RedisValue::ZSet(zset) if small(zset.len(), Box::new(zset.keys())) => "listpack",
+            RedisValue::ZSet(_) => "skiplist",
+            RedisValue::Stream(_) => "stream",
+        }
+    }
+
+    /// Approximately how many bytes the value takes up, counting a small
+    /// fixed overhead for every element of a collection. Only the first
+    /// `samples` elements are measured and the rest are assumed to be of the
+    /// same size, 0 measures them all.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        const ELEMENT_OVERHEAD: usize = 16;
+        let sizes: Box<dyn Iterator<Item = usize>> = match self {
+            RedisValue::String(val) => return val.len(),
+            RedisValue::List(list) => Box::new(list.iter().map(Bytes::len)),
+            RedisValue::Set(set) => Box::new(set.iter().map(Bytes::len)),
+            RedisValue::Hash(hash) => {
+                Box::new(hash.iter().map(|(field, val)| field.len() + val.len()))
+            }
+            RedisValue::ZSet(zset) => Box::new(
+                zset.keys()
+                    .map(|member| member.len() + std::mem::size_of::<f64>()),
+            ),
+            RedisValue::Stream(stream) => Box::new(stream.entries.values().map(|fields| {
+                std::mem::size_of::<StreamId>()
+                    + fields
+                        .iter()
+                        .map(|(field, val)| field.len() + val.len())
+                        .sum::<usize>()
+            })),
+        };
+        let len = self.element_count();
+        let samples = match samples {
+            0 => len,
+            samples => samples.min(len),
+        };
+        if samples == 0 {
+            return 0;
+        }
+        let sampled = sizes
+            .take(samples)
+            .map(|size| size + ELEMENT_OVERHEAD)
+            .sum::<usize>();
+        sampled * len / samples
+    }
+
+    /// The number of elements in a collection, or 1 for a string.
+    pub fn element_count(&self) -> usize {
+        match self {
+            RedisValue::String(_) => 1,
+            RedisValue::List(list) => list.len(),
+            RedisValue::Set(set) => set.len(),
+            RedisValue::Hash(hash) => hash.len(),
+            RedisValue::ZSet(zset) => zset.len(),
+            RedisValue::Stream(stream) => stream.entries.len(),
+        }
+    }
+}
+
+/// The integer a string value holds, if it holds one.
+pub fn as_int(val: &[u8]) -> Option<i64> {
+    std::str::from_utf8(val).ok()?.parse::<i64>().ok()
+}
+
+/// The most items a negative count may ask SRANDMEMBER, HRANDFIELD or
+/// ZRANDMEMBER for. Replies are built in memory before they are sent, so
+/// larger ones are refused rather than left to exhaust it.
+pub const MAX_RANDOM_SAMPLE: u64 = 1 << 20;
+
+/// Checks a count given to SRANDMEMBER, HRANDFIELD or ZRANDMEMBER. `pairs`
+/// is set when every pick is replied with its value or score, which doubles
+/// the reply.
+pub fn sample_count_in_range(count: i64, pairs: bool) -> bool {
+    let items = count
+        .unsigned_abs()
+        .saturating_mul(if pairs { 2 } else { 1 });
+    count >= 0 || items <= MAX_RANDOM_SAMPLE
+}
+
+/// Picks `count` random items the way SRANDMEMBER, HRANDFIELD and
+/// ZRANDMEMBER do: a positive count picks up to that many distinct items,
+/// while a negative one picks exactly that many, possibly repeating some.
+pub fn random_sample<T: Clone>(mut items: Vec<T>, count: i64) -> Vec<T> {
+    if count < 0 {
+        return (0..count.unsigned_abs())
+            .filter_map(|_| random_index(items.len()).map(|i| items[i].clone()))
+            .collect();
+    }
+    // A partial Fisher-Yates shuffle, moving each pick to the front.
+    let count = (count as usize).min(items.len());
+    for i in 0..count {
+        if let Some(j) = random_index(items.len() - i) {
+            items.swap(i, i + j);
+        }
+    }
+    items.truncate(count);
+    items
+}
+
+/// Sorted set members ordered by score, ties broken by member.
+pub fn sorted_zset(zset: &HashMap<Bytes, f64>) -> Vec<(&Bytes, f64)> {
+    let mut members = zset
+        .iter()
+        .map(|(member, score)| (member, *score))
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    members
+}
+
+/// Resolves Redis style start/stop indexes (negative ones count from the
+/// end) against a collection of `len` items. Returns None when the range is
+/// empty.
+pub fn index_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Matches `text` against a Redis glob pattern: `*` and `?` wildcards,
+/// `[abc]`, `[^abc]` and `[a-z]` classes, and `\` escapes. Like in Redis,
+/// both are matched byte by byte, so keys needn't be text.
+pub fn glob_match(pattern: impl AsRef<[u8]>, text: impl AsRef<[u8]>, nocase: bool) -> bool {
+    let fold = |c: &u8| match nocase {
+        true => c.to_ascii_lowercase(),
+        false => *c,
+    };
+    let pattern = pattern.as_ref().iter().map(fold).collect::<Vec<_>>();
+    let text = text.as_ref().iter().map(fold).collect::<Vec<_>>();
+    glob_match_bytes(&pattern, &text)
+}
+
+fn glob_match_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (t..=text.len()).any(|t| glob_match_bytes(&pattern[p + 1..], &text[t..]));
+            }
+            b'?' => {
+                if t == text.len() {
+                    return false;
+                }
+                t += 1;
+            }
+            b'[' => {
+                let c = match text.get(t) {
+                    Some(c) => *c,
+                    None => return false,
+                };
+                p += 1;
+                let negate = pattern.get(p) == Some(&b'^');
+                if negate {
+                    p += 1;
+                }
+                let mut matched = false;
+                while p < pattern.len() && pattern[p] != b']' {
+                    if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= pattern[p] == c;
+                    } else if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() {
+                        let (start, end) = (pattern[p], pattern[p + 2]);
+                        let (start, end) = (start.min(end), start.max(end));
+                        matched |= (start..=end).contains(&c);
+                        p += 2;
+                    } else {
+                        matched |= pattern[p] == c;
+                    }
+                    p += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                t += 1;
+            }
+            c => {
+                let c = match c == b'\\' && p + 1 < pattern.len() {
+                    true => {
+                        p += 1;
+                        pattern[p]
+                    }
+                    false => c,
+                };
+                if text.get(t) != Some(&c) {
+                    return false;
+                }
+                t += 1;
+            }
+        }
+        p += 1;
+    }
+    t == text.len()
+}
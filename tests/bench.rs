@@ -0,0 +1,23 @@
+//! Runs the benchmark against an in-process server.
+
+use redis_starter_rust::redis_bench::{self, BenchOptions, Workload};
+use redis_starter_rust::redis_testing::TestServer;
+
+#[tokio::test]
+async fn bench_sends_every_request_and_reports_latencies() {
+    let server = TestServer::start().await;
+    let addr = server.addr().to_string();
+    let opts = BenchOptions {
+        clients: 4,
+        requests: 1001,
+        pipeline: 8,
+        keyspace: 100,
+        ..BenchOptions::default()
+    };
+    for workload in [Workload::Set, Workload::Get] {
+        let report = redis_bench::bench(&addr, workload, &opts).await.unwrap();
+        assert_eq!(report.latencies.len(), 1001);
+        assert!(report.percentile(50.0) <= report.percentile(99.0));
+    }
+    server.shutdown().await;
+}
@@ -0,0 +1,69 @@
+//! Drives the binary's --cli mode against an in-process server.
+
+use redis_starter_rust::redis_testing::TestServer;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const BIN: &str = env!("CARGO_BIN_EXE_redis-starter-rust");
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cli_prints_replies_like_redis_cli() {
+    let server = TestServer::start().await;
+    let addr = server.addr().to_string();
+
+    let output = tokio::task::spawn_blocking(move || {
+        let mut child = Command::new(BIN)
+            .args(["--cli", &addr])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(
+                b"RPUSH l a \"b c\" \"\\x00\\n\"\nLRANGE l 0 -1\nGET missing\nGET\nSET \"open\n",
+            )
+            .unwrap();
+        child.wait_with_output().unwrap()
+    })
+    .await
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "(integer) 3\n\
+         1) \"a\"\n\
+         2) \"b c\"\n\
+         3) \"\\x00\\n\"\n\
+         (nil)\n\
+         (error) ERR wrong number of arguments for 'get' command\n\
+         Invalid argument(s)\n"
+    );
+
+    server.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cli_passes_the_command_after_the_address_through() {
+    let server = TestServer::start().await;
+    server.call(&["RPUSH", "l", "a", "b", "c"]).await;
+    let addr = server.addr().to_string();
+
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new(BIN)
+            .args(["--cli", &addr, "LRANGE", "l", "-2", "-1"])
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1) \"b\"\n2) \"c\"\n"
+    );
+
+    server.shutdown().await;
+}
@@ -0,0 +1,119 @@
+//! Runs the server binary in its own process, for the tests that need
+//! settings only its command line takes, and talks to it over plain RESP.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BIN: &str = env!("CARGO_BIN_EXE_redis-starter-rust");
+
+/// A server running in its own process with its own temporary directory,
+/// killed and cleaned up when dropped. It never saves on a schedule.
+pub struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Server {
+    /// Starts a server with `args` added to its command line.
+    pub fn start_with(args: &[&str]) -> Server {
+        // Ask the OS for a free port, then hand it to the server.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dir = std::env::temp_dir().join(format!("redis-rs-test-{}", port));
+        std::fs::create_dir_all(&dir).unwrap();
+        let child = Command::new(BIN)
+            .args(["--port", &port.to_string()])
+            .args(["--dir", dir.to_str().unwrap(), "--save", ""])
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, port, dir };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < deadline, "server didn't start");
+            thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    /// Sends one command on a new connection and returns the raw reply.
+    pub fn call(&self, args: &[&str]) -> String {
+        roundtrip(&mut self.connect(), &request(args))
+    }
+
+    /// Sends SIGTERM and waits for the server to exit.
+    pub fn terminate(&mut self) -> ExitStatus {
+        Command::new("kill")
+            .args(["-TERM", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        self.child.wait().unwrap()
+    }
+
+    /// Repeats a command until `done` accepts its reply, which it returns.
+    pub fn wait_until(&self, args: &[&str], done: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let reply = self.call(args);
+            if done(&reply) {
+                return reply;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "{:?} still answers {:?}",
+                args,
+                reply
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn request(args: &[&str]) -> Vec<u8> {
+    let mut req = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        req.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    req
+}
+
+/// Sends `req` and reads the reply, which is expected to fit in one read.
+pub fn roundtrip(stream: &mut TcpStream, req: &[u8]) -> String {
+    stream.write_all(req).unwrap();
+    let mut buf = [0; 4096];
+    let n = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..n]).to_string()
+}
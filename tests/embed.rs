@@ -0,0 +1,43 @@
+//! Runs the server in-process through the library API.
+
+use redis_starter_rust::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn embedded_server_serves_clients_until_shut_down() {
+    let dir = std::env::temp_dir().join(format!("redis-rs-embed-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let server = Server::builder()
+        .port(0)
+        .bind("127.0.0.1")
+        .dir(dir.to_str().unwrap())
+        .save("")
+        .spawn()
+        .await
+        .unwrap();
+    let addr = server.local_addr();
+    assert_ne!(addr.port(), 0);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut buf = [0; 64];
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+        .await
+        .unwrap();
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"+OK\r\n");
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+        .await
+        .unwrap();
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"$1\r\nv\r\n");
+
+    server.shutdown().await.unwrap();
+    server.stopped().await;
+    // Connected clients are closed and no new ones are accepted.
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    assert!(TcpStream::connect(addr).await.is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
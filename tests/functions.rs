@@ -0,0 +1,240 @@
+//! Loads Lua libraries with FUNCTION LOAD and calls them with FCALL.
+
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+use std::time::Duration;
+
+const LIBRARY: &str = "#!lua name=mylib
+redis.register_function('incr_by', function(keys, args)
+    local current = tonumber(redis.call('GET', keys[1]) or '0')
+    redis.call('SET', keys[1], current + args[1])
+    return current + args[1]
+end)
+redis.register_function{
+    function_name = 'get',
+    callback = function(keys) return redis.call('GET', keys[1]) end,
+    flags = {'no-writes'},
+    description = 'Reads a key',
+}
+redis.register_function('fail', function(keys)
+    return redis.call('RPUSH', keys[1], 'a')
+end)";
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+fn error(reply: Value) -> String {
+    match reply {
+        Value::Error(msg) => msg,
+        reply => panic!("expected an error, got {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn functions_run_commands_and_respect_their_flags() {
+    let server = TestServer::start().await;
+    assert_eq!(
+        server.call(&["FUNCTION", "LOAD", LIBRARY]).await,
+        bulk("mylib")
+    );
+    assert!(error(server.call(&["FUNCTION", "LOAD", LIBRARY]).await).contains("already exists"));
+    assert_eq!(
+        server
+            .call(&["FCALL", "incr_by", "1", "counter", "5"])
+            .await,
+        Value::Integer(5)
+    );
+    assert_eq!(
+        server
+            .call(&["FCALL", "incr_by", "1", "counter", "2"])
+            .await,
+        Value::Integer(7)
+    );
+    assert_eq!(
+        server.call(&["FCALL_RO", "get", "1", "counter"]).await,
+        bulk("7")
+    );
+    assert_eq!(
+        error(
+            server
+                .call(&["FCALL_RO", "incr_by", "1", "counter", "1"])
+                .await
+        ),
+        "ERR Can not execute a script with write flag using *_ro command."
+    );
+    // redis.call raises the error of the command it ran.
+    assert!(error(server.call(&["FCALL", "fail", "1", "counter"]).await).starts_with("WRONGTYPE"));
+    assert_eq!(
+        error(server.call(&["FCALL", "missing", "0"]).await),
+        "ERR Function not found"
+    );
+
+    let list = server
+        .call(&["FUNCTION", "LIST", "LIBRARYNAME", "my*", "WITHCODE"])
+        .await;
+    let libraries = match list {
+        Value::Array(libraries) => libraries,
+        list => panic!("unexpected FUNCTION LIST reply {:?}", list),
+    };
+    assert_eq!(libraries.len(), 1);
+    // Maps are flattened into arrays of alternating keys and values.
+    match &libraries[0] {
+        Value::Array(fields) => {
+            assert_eq!(fields[1], bulk("mylib"));
+            assert_eq!(fields[7], bulk(LIBRARY));
+        }
+        library => panic!("unexpected library entry {:?}", library),
+    }
+
+    assert_eq!(
+        server.call(&["FUNCTION", "DELETE", "mylib"]).await,
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(
+        error(server.call(&["FCALL", "get", "1", "counter"]).await),
+        "ERR Function not found"
+    );
+    assert_eq!(
+        error(server.call(&["FUNCTION", "LOAD", "return 1"]).await),
+        "ERR Missing library metadata"
+    );
+    assert_eq!(
+        error(
+            server
+                .call(&["FUNCTION", "LOAD", "#!lua name=empty\nlocal x = 1"])
+                .await
+        ),
+        "ERR No functions registered"
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn functions_survive_a_restart_and_restore_from_a_dump() {
+    let first = TestServer::start().await;
+    first.call(&["FUNCTION", "LOAD", LIBRARY]).await;
+    let dump = first.call(&["FUNCTION", "DUMP"]).await;
+    assert_eq!(
+        first.call(&["SAVE"]).await,
+        Value::SimpleString("OK".into())
+    );
+    first.shutdown().await;
+
+    let dir = first.dir().to_string_lossy().into_owned();
+    let second = TestServer::start_with(|builder| builder.dir(dir)).await;
+    second.call(&["SET", "k", "1"]).await;
+    assert_eq!(
+        second.call(&["FCALL", "incr_by", "1", "k", "1"]).await,
+        Value::Integer(2)
+    );
+    second.call(&["FUNCTION", "FLUSH"]).await;
+    assert_eq!(
+        second.call(&["FUNCTION", "LIST"]).await,
+        Value::Array(vec![])
+    );
+
+    let payload = match dump {
+        Value::Bytes(payload) => payload,
+        dump => panic!("unexpected FUNCTION DUMP reply {:?}", dump),
+    };
+    let mut conn = second.connect().await;
+    let restore = vec![b"FUNCTION".to_vec(), b"RESTORE".to_vec(), payload];
+    assert_eq!(
+        conn.call(restore).await.unwrap(),
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(second.call(&["FCALL_RO", "get", "1", "k"]).await, bulk("2"));
+    second.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_get_the_libraries_and_the_writes_of_functions() {
+    let (primary, replica) = TestServer::start_pair().await;
+    primary.call(&["FUNCTION", "LOAD", LIBRARY]).await;
+    primary
+        .call(&["FCALL", "incr_by", "1", "counter", "3"])
+        .await;
+    replica
+        .wait_until(&["GET", "counter"], |reply| *reply == bulk("3"))
+        .await;
+    assert_eq!(
+        replica.call(&["FCALL_RO", "get", "1", "counter"]).await,
+        bulk("3")
+    );
+    assert!(error(
+        replica
+            .call(&["FCALL", "incr_by", "1", "counter", "1"])
+            .await
+    )
+    .starts_with("READONLY"));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn function_kill_stops_functions_that_have_not_written() {
+    const BUSY: &str = "#!lua name=busy
+redis.register_function('spin', function() while true do end end)
+redis.register_function('write_then_spin', function(keys, args)
+    redis.call('SET', keys[1], 'partial')
+    for i = 1, tonumber(args[1]) do redis.call('GET', keys[1]) end
+    redis.call('SET', keys[1], 'done')
+    return 'done'
+end)";
+    let server = TestServer::start().await;
+    server.call(&["FUNCTION", "LOAD", BUSY]).await;
+    assert_eq!(
+        error(server.call(&["FUNCTION", "KILL"]).await),
+        "NOTBUSY No scripts in execution right now."
+    );
+
+    let mut spinning = server.connect().await;
+    spinning
+        .send(&Value::bulk_array(["FCALL", "spin", "0"]).serialize())
+        .await
+        .unwrap();
+    server
+        .wait_until(&["FUNCTION", "KILL"], |reply| *reply == Value::ok())
+        .await;
+    assert_eq!(
+        error(spinning.read_reply().await.unwrap()),
+        "ERR Script killed by user with FUNCTION KILL..."
+    );
+    // Nothing is left running once the reply is in.
+    assert_eq!(
+        server.call(&["FUNCTION", "KILL"]).await,
+        Value::Error("NOTBUSY No scripts in execution right now.".into())
+    );
+
+    let mut writing = server.connect().await;
+    writing
+        .send(&Value::bulk_array(["FCALL", "write_then_spin", "1", "k", "100000"]).serialize())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(error(server.call(&["FUNCTION", "KILL"]).await).starts_with("UNKILLABLE"));
+    // Other clients wait for the function to return, so they never see its
+    // first write.
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("done"));
+    assert_eq!(writing.read_reply().await.unwrap(), bulk("done"));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn functions_cannot_load_code_or_reach_other_environments() {
+    const SANDBOX: &str = "#!lua name=sandbox
+redis.register_function('escapes', function()
+    return {
+        type(load), type(loadstring), type(setfenv), type(getfenv),
+        type(string.dump), type(dofile), type(loadfile),
+    }
+end)";
+    let server = TestServer::start().await;
+    server.call(&["FUNCTION", "LOAD", SANDBOX]).await;
+    assert_eq!(
+        server.call(&["FCALL", "escapes", "0"]).await,
+        Value::Array(vec![bulk("nil"); 7])
+    );
+    server.shutdown().await;
+}
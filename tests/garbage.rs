@@ -0,0 +1,345 @@
+//! Feeds the server malformed requests and garbage bytes, and checks that it
+//! answers with errors instead of going down.
+
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+use std::process::Command;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const BIN: &str = env!("CARGO_BIN_EXE_redis-starter-rust");
+
+fn request(args: &[&str]) -> Vec<u8> {
+    let mut req = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        req.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    req
+}
+
+/// Sends `req` and reads the reply, which is expected to fit in one read.
+async fn roundtrip(stream: &mut TcpStream, req: &[u8]) -> String {
+    stream.write_all(req).await.unwrap();
+    let mut buf = [0; 1024];
+    let n = tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buf))
+        .await
+        .expect("no reply")
+        .unwrap();
+    String::from_utf8_lossy(&buf[..n]).to_string()
+}
+
+/// CRC64 with the Jones polynomial, which DUMP payloads end with.
+fn crc64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x95ac9329ac4bc9b5,
+                _ => crc >> 1,
+            };
+        }
+        crc
+    })
+}
+
+/// Wraps an RDB encoded value in a DUMP footer with RDB version 11 and a
+/// valid checksum, so only the value itself is malformed.
+fn dump_payload(body: &[u8]) -> Vec<u8> {
+    let mut payload = body.to_vec();
+    payload.extend_from_slice(&11u16.to_le_bytes());
+    let checksum = crc64(&payload);
+    payload.extend_from_slice(&checksum.to_le_bytes());
+    payload
+}
+
+/// Restores `payload` into `k`, replacing whatever an earlier payload left
+/// there.
+async fn restore(server: &TestServer, payload: Vec<u8>) -> Value {
+    let args = vec![
+        b"RESTORE".to_vec(),
+        b"k".to_vec(),
+        b"0".to_vec(),
+        payload,
+        b"REPLACE".to_vec(),
+    ];
+    server.connect().await.call(args).await.unwrap()
+}
+
+/// A small xorshift generator, so failures can be reproduced from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[tokio::test]
+async fn malformed_commands_get_error_replies() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    let cases: &[(&[&str], &str)] = &[
+        (
+            &["GET"],
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        ),
+        (
+            &["GET", "a", "b"],
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        ),
+        (
+            &["NOSUCHCOMMAND", "a"],
+            "-ERR unknown command 'NOSUCHCOMMAND', with args beginning with: 'a' \r\n",
+        ),
+        (
+            &["CONFIG", "NOPE"],
+            "-ERR unknown subcommand 'NOPE'. Try CONFIG HELP.\r\n",
+        ),
+        (
+            &["LRANGE", "k", "a", "1"],
+            "-ERR value is not an integer or out of range\r\n",
+        ),
+        (
+            &["ZADD", "k", "x", "m"],
+            "-ERR value is not a valid float\r\n",
+        ),
+        (&["SET", "k", "v", "PX"], "-ERR syntax error\r\n"),
+        (
+            &["SET", "k", "v", "PX", "0"],
+            "-ERR invalid expire time in 'set' command\r\n",
+        ),
+        (&["EXPIRE", "k", "10", "SOON"], "-ERR syntax error\r\n"),
+        (&["SINTERCARD", "3", "a"], "-ERR syntax error\r\n"),
+        (
+            &["SRANDMEMBER", "k", "-9223372036854775808"],
+            "-ERR value is out of range\r\n",
+        ),
+        (
+            &["SRANDMEMBER", "k", "-9223372036854775807"],
+            "-ERR value is out of range\r\n",
+        ),
+        (
+            &["HRANDFIELD", "k", "-4611686018427387904", "WITHVALUES"],
+            "-ERR value is out of range\r\n",
+        ),
+        (
+            &["ZRANDMEMBER", "k", "-9223372036854775808"],
+            "-ERR value is out of range\r\n",
+        ),
+    ];
+    for (args, reply) in cases {
+        assert_eq!(
+            roundtrip(&mut stream, &request(args)).await,
+            *reply,
+            "{:?}",
+            args
+        );
+    }
+    // The connection is still usable afterwards.
+    assert_eq!(
+        roundtrip(&mut stream, &request(&["PING"])).await,
+        "+PONG\r\n"
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn protocol_errors_close_the_connection() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    let reply = roundtrip(&mut stream, b"*1\r\n:12\r\n").await;
+    assert!(reply.starts_with("-ERR Protocol error"), "{}", reply);
+    let mut buf = [0; 64];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn garbage_bytes_do_not_take_the_server_down() {
+    let server = TestServer::start().await;
+    let valid = [
+        request(&["SET", "key", "value", "PX", "1000"]),
+        request(&["GET", "key"]),
+        request(&["LRANGE", "list", "0", "-1"]),
+        request(&["ZADD", "zset", "1.5", "member"]),
+        request(&["EXPIRE", "key", "10", "NX"]),
+        request(&["SINTERCARD", "2", "a", "b", "LIMIT", "1"]),
+        request(&["CONFIG", "GET", "maxmemory"]),
+    ];
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..300 {
+        let mut req = match rng.below(3) {
+            // Random bytes, sometimes starting with a RESP type byte.
+            0 => {
+                let mut req = (0..rng.below(64))
+                    .map(|_| rng.next() as u8)
+                    .collect::<Vec<_>>();
+                if !req.is_empty() {
+                    req[0] = b"*$+-:%_"[rng.below(7)];
+                }
+                req
+            }
+            // A valid request cut short or with extra lines.
+            1 => {
+                let mut req = valid[rng.below(valid.len())].clone();
+                req.truncate(rng.below(req.len() + 1));
+                req.extend_from_slice(b"\r\n");
+                req
+            }
+            // A valid request with a few bytes changed.
+            _ => valid[rng.below(valid.len())].clone(),
+        };
+        if req.is_empty() {
+            continue;
+        }
+        for _ in 0..rng.below(4) {
+            let i = rng.below(req.len());
+            req[i] = match rng.below(4) {
+                0 => b'\r',
+                1 => b'-',
+                2 => b'9',
+                _ => rng.next() as u8,
+            };
+        }
+        let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+        let _ = stream.write_all(&req).await;
+        let _ = tokio::time::timeout(Duration::from_millis(5), stream.read(&mut [0; 1024])).await;
+    }
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    assert_eq!(
+        roundtrip(&mut stream, &request(&["PING"])).await,
+        "+PONG\r\n"
+    );
+    assert_eq!(
+        roundtrip(&mut stream, &request(&["SET", "after", "garbage"])).await,
+        "+OK\r\n"
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn restore_rejects_lengths_the_payload_cannot_hold() {
+    let server = TestServer::start().await;
+    let bodies: [&[u8]; 5] = [
+        // A list claiming 2^32 - 1 elements, with none of them present.
+        &[0x01, 0x80, 0xFF, 0xFF, 0xFF, 0xFF],
+        // The same with a 64 bit length.
+        &[0x01, 0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+        // A string whose two LZF compressed bytes claim to decompress to
+        // 4 GiB.
+        &[0x00, 0xC3, 0x02, 0x80, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, b'a'],
+        // Or to 1 TiB.
+        &[0x00, 0xC3, 0x02, 0x81, 0, 0, 1, 0, 0, 0, 0, 0, 0x00, b'a'],
+        // 0x80 and 0x81 are the only lengths with a 10 prefix.
+        &[0x00, 0x82, 0, 0, 0, 3, b'a', b'b', b'c'],
+    ];
+    for body in bodies {
+        let reply = restore(&server, dump_payload(body)).await;
+        assert!(matches!(reply, Value::Error(_)), "{:?}: {:?}", body, reply);
+    }
+    // A 64 bit length that does fit is read like any other.
+    let payload = dump_payload(&[0x00, 0x81, 0, 0, 0, 0, 0, 0, 0, 3, b'a', b'b', b'c']);
+    assert_eq!(
+        restore(&server, payload).await,
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(
+        server.call(&["GET", "k"]).await,
+        Value::BulkString("abc".into())
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn malformed_dump_payloads_are_rejected() {
+    let server = TestServer::start().await;
+    server.call(&["RPUSH", "list", "a", "b", "c"]).await;
+    let dump = match server.call(&["DUMP", "list"]).await {
+        Value::Bytes(dump) => dump,
+        Value::BulkString(dump) => dump.into_bytes(),
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    let footer = dump.len() - 10;
+    let mut newer = dump[..footer].to_vec();
+    newer.extend_from_slice(&u16::MAX.to_le_bytes());
+    let checksum = crc64(&newer);
+    newer.extend_from_slice(&checksum.to_le_bytes());
+    let mut flipped = dump.clone();
+    flipped[1] ^= 0xFF;
+    let mut trailing = dump[..footer].to_vec();
+    trailing.push(0);
+    let payloads = [
+        ("empty", Vec::new()),
+        ("too short", dump[..9].to_vec()),
+        ("truncated", dump[..dump.len() - 1].to_vec()),
+        ("flipped byte", flipped),
+        ("newer version", newer),
+        ("bad value type", dump_payload(&[0x7F, 0x01, b'a'])),
+        ("truncated list", dump_payload(&dump[..footer - 1])),
+        ("trailing bytes", dump_payload(&trailing)),
+        ("bad ziplist", dump_payload(&[10, 0x03, 1, 2, 3])),
+        (
+            "bad intset width",
+            dump_payload(&[11, 0x0A, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0]),
+        ),
+    ];
+    for (name, payload) in payloads {
+        assert!(
+            matches!(restore(&server, payload).await, Value::Error(_)),
+            "{} payload was accepted",
+            name
+        );
+    }
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..200 {
+        let mut body = dump[..footer].to_vec();
+        for _ in 0..1 + rng.below(4) {
+            let i = rng.below(body.len());
+            body[i] = rng.next() as u8;
+        }
+        restore(&server, dump_payload(&body)).await;
+    }
+    // The original payload still restores after all of that.
+    let args = vec![
+        b"RESTORE".to_vec(),
+        b"copy".to_vec(),
+        b"0".to_vec(),
+        dump.clone(),
+    ];
+    assert_eq!(
+        server.connect().await.call(args).await.unwrap(),
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(
+        server.call(&["LRANGE", "copy", "0", "-1"]).await,
+        Value::Array(vec![
+            Value::BulkString("a".into()),
+            Value::BulkString("b".into()),
+            Value::BulkString("c".into()),
+        ])
+    );
+    server.shutdown().await;
+}
+
+#[test]
+fn bad_command_line_flags_print_usage() {
+    for args in [
+        &["--port", "not-a-port"][..],
+        &["--no-such-flag"],
+        &["--maxmemory-policy", "sometimes"],
+        &["--replicaof", "localhost"],
+    ] {
+        let output = Command::new(BIN).args(args).output().unwrap();
+        assert!(!output.status.success(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Usage:"), "{:?}: {}", args, stderr);
+    }
+}
@@ -0,0 +1,48 @@
+//! Serves clients through io_uring, on a tokio-uring runtime.
+#![cfg(target_os = "linux")]
+
+use redis_starter_rust::redis_io::IoBackend;
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+
+#[test]
+fn io_uring_backend_serves_clients_and_replicas() {
+    tokio_uring::start(async {
+        let primary =
+            TestServer::start_with(|builder| builder.io_backend(IoBackend::IoUring)).await;
+        assert_eq!(
+            primary.call(&["CONFIG", "GET", "io-backend"]).await,
+            Value::Array(vec![
+                Value::BulkString("io-backend".into()),
+                Value::BulkString("io-uring".into())
+            ])
+        );
+        // Bigger than a single read, and pipelined.
+        let big = "x".repeat(100_000);
+        let mut conn = primary.connect().await;
+        let mut req = Vec::new();
+        for _ in 0..10 {
+            req.extend(Value::bulk_array(["SET", "big", big.as_str()]).serialize());
+        }
+        req.extend(Value::bulk_array(["GET", "big"]).serialize());
+        conn.send(&req).await.unwrap();
+        for _ in 0..10 {
+            assert_eq!(conn.read_reply().await.unwrap(), Value::ok());
+        }
+        assert_eq!(conn.read_reply().await.unwrap(), Value::BulkString(big));
+
+        let replica = primary.start_replica().await;
+        primary.call(&["SET", "k", "v"]).await;
+        assert_eq!(
+            primary.call(&["WAIT", "1", "5000"]).await,
+            Value::Integer(1)
+        );
+        replica
+            .wait_until(&["GET", "k"], |reply| {
+                *reply == Value::BulkString("v".into())
+            })
+            .await;
+        replica.shutdown().await;
+        primary.shutdown().await;
+    });
+}
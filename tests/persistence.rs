@@ -0,0 +1,200 @@
+//! Writes RDB snapshots and append-only files, and checks a restarted
+//! server loads the dataset back from them.
+
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+fn info_contains(reply: &Value, line: &str) -> bool {
+    matches!(reply, Value::BulkString(info) if info.contains(line))
+}
+
+/// Starts a server on the directory `server` used, once it's shut down.
+async fn restart(server: &TestServer) -> TestServer {
+    let dir = server.dir().to_string_lossy().into_owned();
+    TestServer::start_with(|builder| builder.dir(dir)).await
+}
+
+/// Like `restart`, loading the dataset from the append-only file.
+async fn restart_appendonly(server: &TestServer) -> TestServer {
+    let dir = server.dir().to_string_lossy().into_owned();
+    TestServer::start_with(|builder| builder.dir(dir).appendonly(true)).await
+}
+
+#[tokio::test]
+async fn bgsave_writes_a_snapshot_in_the_background() {
+    let first = TestServer::start().await;
+    first.call(&["SET", "k", "v"]).await;
+    first.call(&["RPUSH", "list", "a", "b"]).await;
+    assert!(info_contains(
+        &first.call(&["INFO", "persistence"]).await,
+        "rdb_changes_since_last_save:2"
+    ));
+    assert_eq!(
+        first.call(&["BGSAVE"]).await,
+        Value::SimpleString("Background saving started".into())
+    );
+    first
+        .wait_until(&["INFO", "persistence"], |reply| {
+            info_contains(reply, "rdb_bgsave_in_progress:0")
+                && info_contains(reply, "rdb_changes_since_last_save:0")
+                && info_contains(reply, "rdb_last_bgsave_status:ok")
+        })
+        .await;
+    // The snapshot was written to a temp file and renamed into place.
+    let files = std::fs::read_dir(first.dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(files, vec!["dump.rdb"]);
+    first.shutdown().await;
+
+    let second = restart(&first).await;
+    assert_eq!(second.call(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(
+        second.call(&["LRANGE", "list", "0", "-1"]).await,
+        Value::Array(vec![bulk("a"), bulk("b")])
+    );
+    second.shutdown().await;
+}
+
+#[tokio::test]
+async fn save_rules_trigger_a_snapshot_once_enough_keys_change() {
+    let first = TestServer::start().await;
+    assert_eq!(
+        first.call(&["CONFIG", "GET", "save"]).await,
+        Value::Array(vec![bulk("save"), bulk("")])
+    );
+    first.call(&["CONFIG", "SET", "save", "1 2"]).await;
+    assert_eq!(
+        first.call(&["CONFIG", "GET", "save"]).await,
+        Value::Array(vec![bulk("save"), bulk("1 2")])
+    );
+    first.call(&["SET", "a", "1"]).await;
+    // One change is below the rule's threshold, however long it waits.
+    tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    assert!(info_contains(
+        &first.call(&["INFO", "persistence"]).await,
+        "rdb_changes_since_last_save:1"
+    ));
+    assert!(!first.dir().join("dump.rdb").exists());
+
+    first.call(&["SET", "b", "2"]).await;
+    first
+        .wait_until(&["INFO", "persistence"], |reply| {
+            info_contains(reply, "rdb_changes_since_last_save:0")
+                && info_contains(reply, "rdb_bgsave_in_progress:0")
+        })
+        .await;
+    first.shutdown().await;
+
+    let second = restart(&first).await;
+    assert_eq!(second.call(&["GET", "a"]).await, bulk("1"));
+    assert_eq!(second.call(&["GET", "b"]).await, bulk("2"));
+    second.shutdown().await;
+}
+
+#[tokio::test]
+async fn appendonly_replays_the_commands_it_logged() {
+    let first = TestServer::start_with(|builder| builder.appendonly(true)).await;
+    first
+        .call(&["CONFIG", "SET", "aof-use-rdb-preamble", "no"])
+        .await;
+    first
+        .call(&["CONFIG", "SET", "appendfsync", "always"])
+        .await;
+    first.call(&["SET", "k", "v"]).await;
+    first.call(&["SET", "n", "1"]).await;
+    first.call(&["SET", "n", "2"]).await;
+    first.call(&["DEL", "k"]).await;
+    first.call(&["SADD", "set", "x"]).await;
+    let aof = std::fs::read(first.dir().join("appendonly.aof")).unwrap();
+    assert!(aof.starts_with(b"*"));
+    assert!(String::from_utf8_lossy(&aof).contains("$3\r\nDEL\r\n$1\r\nk\r\n"));
+    first.shutdown().await;
+
+    let second = restart_appendonly(&first).await;
+    assert_eq!(second.call(&["GET", "k"]).await, Value::Nil);
+    assert_eq!(second.call(&["GET", "n"]).await, bulk("2"));
+    assert_eq!(
+        second.call(&["SMEMBERS", "set"]).await,
+        Value::Array(vec![bulk("x")])
+    );
+    second.shutdown().await;
+}
+
+#[tokio::test]
+async fn bgrewriteaof_compacts_the_log_and_keeps_later_writes() {
+    let off = TestServer::start().await;
+    assert_eq!(
+        off.call(&["BGREWRITEAOF"]).await,
+        Value::Error("ERR Append only file is disabled".into())
+    );
+    off.shutdown().await;
+
+    let first = TestServer::start_with(|builder| builder.appendonly(true)).await;
+    first
+        .call(&["CONFIG", "SET", "aof-use-rdb-preamble", "no"])
+        .await;
+    for n in 0..100 {
+        first.call(&["SET", "k", &n.to_string()]).await;
+    }
+    let aof = first.dir().join("appendonly.aof");
+    let before = std::fs::metadata(&aof).unwrap().len();
+    assert_eq!(
+        first.call(&["BGREWRITEAOF"]).await,
+        Value::SimpleString("Background append only file rewriting started".into())
+    );
+    // Arrives while the rewrite may still be running, and must not be lost
+    // when the rewritten file replaces the old one.
+    first.call(&["SET", "later", "x"]).await;
+    first
+        .wait_until(&["INFO", "persistence"], |reply| {
+            info_contains(reply, "aof_rewrite_in_progress:0")
+                && info_contains(reply, "aof_last_bgrewrite_status:ok")
+        })
+        .await;
+    let after = std::fs::read(&aof).unwrap();
+    assert!((after.len() as u64) < before);
+    assert!(!String::from_utf8_lossy(&after).contains("$2\r\n98\r\n"));
+    first.shutdown().await;
+
+    let second = restart_appendonly(&first).await;
+    assert_eq!(second.call(&["GET", "k"]).await, bulk("99"));
+    assert_eq!(second.call(&["GET", "later"]).await, bulk("x"));
+    second.shutdown().await;
+}
+
+#[tokio::test]
+async fn rdb_preamble_loads_with_the_commands_after_it() {
+    let first = TestServer::start_with(|builder| builder.appendonly(true)).await;
+    first.call(&["RPUSH", "list", "a", "b"]).await;
+    first.call(&["HSET", "hash", "f", "v"]).await;
+    first.call(&["BGREWRITEAOF"]).await;
+    first
+        .wait_until(&["INFO", "persistence"], |reply| {
+            info_contains(reply, "aof_rewrite_in_progress:0")
+        })
+        .await;
+    first.call(&["SET", "after", "1"]).await;
+    first.call(&["DEL", "hash"]).await;
+    let aof = std::fs::read(first.dir().join("appendonly.aof")).unwrap();
+    assert!(aof.starts_with(b"REDIS"));
+    assert!(aof.ends_with(b"*2\r\n$3\r\nDEL\r\n$4\r\nhash\r\n"));
+    first.shutdown().await;
+
+    let second = restart_appendonly(&first).await;
+    assert_eq!(
+        second.call(&["LRANGE", "list", "0", "-1"]).await,
+        Value::Array(vec![bulk("a"), bulk("b")])
+    );
+    assert_eq!(second.call(&["GET", "after"]).await, bulk("1"));
+    assert_eq!(
+        second.call(&["TYPE", "hash"]).await,
+        Value::SimpleString("none".into())
+    );
+    second.shutdown().await;
+}
@@ -0,0 +1,741 @@
+//! Starts a primary and a replica of it, and checks writes reach the replica.
+
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+fn is_error(reply: &Value, prefix: &str) -> bool {
+    matches!(reply, Value::Error(e) if e.starts_with(prefix))
+}
+
+fn info_contains(reply: &Value, line: &str) -> bool {
+    matches!(reply, Value::BulkString(info) if info.contains(line))
+}
+
+/// Goes through the handshake with `primary` the way a replica would, and
+/// returns the link along with the RDB payload of the full resync.
+async fn fake_replica(primary: &TestServer) -> (BufReader<TcpStream>, Vec<u8>) {
+    let mut link = BufReader::new(TcpStream::connect(primary.addr()).await.unwrap());
+    let handshake = [
+        "*1\r\n$4\r\nPING\r\n",
+        "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n",
+        "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n",
+        "*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n",
+    ];
+    let mut line = String::new();
+    for command in handshake {
+        link.write_all(command.as_bytes()).await.unwrap();
+        line.clear();
+        link.read_line(&mut line).await.unwrap();
+    }
+    assert!(line.starts_with("+FULLRESYNC "), "{:?}", line);
+    line.clear();
+    link.read_line(&mut line).await.unwrap();
+    let len = line.trim_end().strip_prefix('$').unwrap().parse().unwrap();
+    let mut rdb = vec![0; len];
+    link.read_exact(&mut rdb).await.unwrap();
+    (link, rdb)
+}
+
+/// Reads the replication stream until it holds `needle`, and returns what
+/// was read.
+async fn read_stream_until(link: &mut BufReader<TcpStream>, needle: &str) -> String {
+    let mut stream = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !String::from_utf8_lossy(&stream).contains(needle) {
+        let mut buf = [0; 512];
+        let n = tokio::time::timeout_at(deadline.into(), link.read(&mut buf))
+            .await
+            .unwrap_or_else(|_| panic!("no {:?} in {:?}", needle, String::from_utf8_lossy(&stream)))
+            .unwrap();
+        assert!(n > 0, "the master closed the link");
+        stream.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&stream).into_owned()
+}
+
+#[tokio::test]
+async fn replica_follows_the_primary() {
+    let (primary, replica) = TestServer::start_pair().await;
+    let role = replica.call(&["INFO", "replication"]).await;
+    assert!(info_contains(&role, "role:slave"));
+
+    primary.call(&["SET", "k", "v"]).await;
+    primary.call(&["RPUSH", "list", "a", "b", "c"]).await;
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    replica
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("v"))
+        .await;
+    replica
+        .wait_until(
+            &["LRANGE", "list", "0", "-1"],
+            |reply| matches!(reply, Value::Array(items) if items.len() == 3),
+        )
+        .await;
+
+    assert!(is_error(
+        &replica.call(&["SET", "k", "other"]).await,
+        "READONLY"
+    ));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn wait_counts_replicas_that_acknowledged_the_writes() {
+    let primary = TestServer::start().await;
+    assert_eq!(primary.call(&["WAIT", "0", "0"]).await, Value::Integer(0));
+    let port = primary.addr().port();
+    let replica = TestServer::start_with(|builder| builder.replicaof("127.0.0.1", port)).await;
+    // The replica counts once it has synced and acknowledged the offset.
+    primary
+        .wait_until(&["WAIT", "1", "100"], |reply| *reply == Value::Integer(1))
+        .await;
+
+    primary.call(&["SET", "k", "v"]).await;
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    replica
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("v"))
+        .await;
+
+    // Asking for more replicas than there are waits out the timeout, then
+    // reports the ones that did acknowledge.
+    let start = Instant::now();
+    assert_eq!(primary.call(&["WAIT", "2", "300"]).await, Value::Integer(1));
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    assert!(is_error(
+        &replica.call(&["WAIT", "1", "0"]).await,
+        "ERR WAIT cannot be used with replica instances"
+    ));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn failover_hands_the_primary_role_to_a_replica() {
+    let primary = TestServer::start().await;
+    assert_eq!(
+        primary.call(&["FAILOVER"]).await,
+        Value::Error("ERR FAILOVER requires connected replicas.".into())
+    );
+    assert_eq!(
+        primary.call(&["FAILOVER", "ABORT"]).await,
+        Value::Error("ERR No failover in progress.".into())
+    );
+    let port = primary.addr().port();
+    let replica = TestServer::start_with(|builder| builder.replicaof("127.0.0.1", port)).await;
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "state=online")
+        })
+        .await;
+    assert_eq!(
+        primary.call(&["FAILOVER", "TO", "127.0.0.1", "1"]).await,
+        Value::Error("ERR FAILOVER target HOST and PORT is not a replica.".into())
+    );
+    assert_eq!(
+        replica.call(&["FAILOVER"]).await,
+        Value::Error("ERR FAILOVER is not valid when server is a replica.".into())
+    );
+
+    primary.call(&["SET", "k", "v"]).await;
+    let port = replica.addr().port().to_string();
+    assert_eq!(
+        primary
+            .call(&["FAILOVER", "TO", "127.0.0.1", &port, "TIMEOUT", "5000"])
+            .await,
+        Value::SimpleString("OK".into())
+    );
+    replica
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "role:master")
+        })
+        .await;
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "role:slave")
+                && info_contains(reply, "master_failover_state:no-failover")
+        })
+        .await;
+    assert_eq!(replica.call(&["GET", "k"]).await, bulk("v"));
+    // The old primary now follows the replica it handed over to.
+    replica.call(&["SET", "k", "w"]).await;
+    primary
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("w"))
+        .await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn sort_store_reaches_replicas() {
+    let (primary, replica) = TestServer::start_pair().await;
+    primary.call(&["RPUSH", "nums", "3", "1", "2"]).await;
+    assert_eq!(
+        primary
+            .call(&["SORT", "nums", "DESC", "STORE", "sorted"])
+            .await,
+        Value::Integer(3)
+    );
+    // Without STORE the sorted elements are the reply.
+    assert_eq!(
+        primary.call(&["SORT", "nums"]).await,
+        Value::Array(vec![bulk("1"), bulk("2"), bulk("3")])
+    );
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        replica.call(&["LRANGE", "sorted", "0", "-1"]).await,
+        Value::Array(vec![bulk("3"), bulk("2"), bulk("1")])
+    );
+    assert!(is_error(
+        &replica.call(&["SORT", "nums", "STORE", "copy"]).await,
+        "READONLY"
+    ));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn stream_entries_reach_replicas_under_the_same_id() {
+    let (primary, replica) = TestServer::start_pair().await;
+    let id = primary.call(&["XADD", "s", "*", "f", "v"]).await;
+    assert!(matches!(&id, Value::BulkString(_)));
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        replica.call(&["XRANGE", "s", "-", "+"]).await,
+        Value::Array(vec![Value::Array(vec![
+            id,
+            Value::Array(vec![bulk("f"), bulk("v")])
+        ])])
+    );
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn consumer_group_reads_reach_replicas() {
+    let (primary, replica) = TestServer::start_pair().await;
+    primary.call(&["XADD", "s", "1-0", "f", "v"]).await;
+    primary.call(&["XADD", "s", "2-0", "f", "v"]).await;
+    primary.call(&["XGROUP", "CREATE", "s", "g", "0"]).await;
+    primary
+        .call(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "s",
+            ">",
+        ])
+        .await;
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    let group = match replica.call(&["XINFO", "GROUPS", "s"]).await {
+        Value::Array(groups) if groups.len() == 1 => groups[0].clone(),
+        reply => panic!("unexpected XINFO GROUPS reply {:?}", reply),
+    };
+    let group = match group {
+        Value::Array(fields) => fields,
+        reply => panic!("unexpected XINFO GROUPS reply {:?}", reply),
+    };
+    assert_eq!(
+        group[..8],
+        [
+            bulk("name"),
+            bulk("g"),
+            bulk("consumers"),
+            Value::Integer(1),
+            bulk("pending"),
+            Value::Integer(1),
+            bulk("last-delivered-id"),
+            bulk("1-0"),
+        ]
+    );
+    assert!(is_error(
+        &replica
+            .call(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"])
+            .await,
+        "READONLY"
+    ));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_are_listed_at_the_address_they_announce() {
+    let primary = TestServer::start().await;
+    let port = primary.addr().port();
+    let replica = TestServer::start_with(|builder| {
+        builder
+            .replicaof("127.0.0.1", port)
+            .replica_announce("10.0.0.5", 7000)
+    })
+    .await;
+    primary
+        .wait_until(
+            &["INFO", "replication"],
+            |reply| matches!(reply, Value::BulkString(info) if info.contains("slave0:ip=10.0.0.5,port=7000,state=online")),
+        )
+        .await;
+
+    assert_eq!(
+        primary
+            .call(&[
+                "REPLCONF",
+                "listening-port",
+                "6380",
+                "capa",
+                "eof",
+                "capa",
+                "psync2"
+            ])
+            .await,
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(
+        primary.call(&["REPLCONF", "rdb-only", "1"]).await,
+        Value::Error("ERR Unrecognized REPLCONF option: rdb-only".into())
+    );
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_load_the_dataset_they_sync_from() {
+    let primary = TestServer::start().await;
+    primary.call(&["SET", "k", "v"]).await;
+    primary
+        .call(&["SET", "expiring", "v", "PX", "1000000"])
+        .await;
+    primary.call(&["RPUSH", "list", "a", "b"]).await;
+    // A server that turns into a replica drops what it held before.
+    let replica = TestServer::start().await;
+    replica.call(&["SET", "stale", "v"]).await;
+    let port = primary.addr().port().to_string();
+    assert_eq!(
+        replica.call(&["REPLICAOF", "127.0.0.1", &port]).await,
+        Value::SimpleString("OK".into())
+    );
+    replica
+        .wait_until(&["GET", "k"], |reply| {
+            *reply == Value::BulkString("v".into())
+        })
+        .await;
+    assert_eq!(replica.call(&["GET", "stale"]).await, Value::Nil);
+    assert_eq!(
+        replica.call(&["LRANGE", "list", "0", "-1"]).await,
+        Value::Array(vec![
+            Value::BulkString("a".into()),
+            Value::BulkString("b".into())
+        ])
+    );
+    assert!(matches!(
+        replica.call(&["PEXPIRETIME", "expiring"]).await,
+        Value::Integer(ms) if ms > 0
+    ));
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+/// A master that refuses a step of the handshake is never sent PSYNC.
+#[tokio::test]
+async fn handshake_stops_when_the_master_refuses_replconf() {
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = master.local_addr().unwrap().port();
+    let replica = TestServer::start_with(|builder| builder.replicaof("127.0.0.1", port)).await;
+    let (mut link, _) = master.accept().await.unwrap();
+    // PING, REPLCONF listening-port and REPLCONF capa, one reply each.
+    let replies: [&[u8]; 3] = [b"+PONG\r\n", b"+OK\r\n", b"-ERR unknown capa\r\n"];
+    let mut received = Vec::new();
+    for reply in replies {
+        let mut buf = [0; 512];
+        let n = link.read(&mut buf).await.unwrap();
+        received.extend_from_slice(&buf[..n]);
+        link.write_all(reply).await.unwrap();
+    }
+    assert!(String::from_utf8_lossy(&received).contains("capa"));
+    // The replica hangs up rather than going on to PSYNC.
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), link.read_to_end(&mut rest))
+        .await
+        .expect("replica kept the link open")
+        .unwrap();
+    assert!(!String::from_utf8_lossy(&rest).contains("PSYNC"));
+    replica.shutdown().await;
+}
+
+#[tokio::test]
+async fn full_resync_sends_the_current_dataset() {
+    let primary = TestServer::start().await;
+    primary.call(&["SET", "before", "synced"]).await;
+    let (mut link, rdb) = fake_replica(&primary).await;
+    assert!(rdb.starts_with(b"REDIS"));
+    let rdb = String::from_utf8_lossy(&rdb);
+    assert!(rdb.contains("before") && rdb.contains("synced"));
+    // What's written after the snapshot follows it as commands.
+    primary.call(&["SET", "after", "streamed"]).await;
+    read_stream_until(&mut link, "$5\r\nafter\r\n$8\r\nstreamed\r\n").await;
+    primary.shutdown().await;
+}
+
+/// Plays the master: answers the handshake, sends a snapshot and then a
+/// write, and asks the replica how much of the stream it has processed.
+#[tokio::test]
+async fn replica_applies_the_stream_its_master_sends() {
+    let source = TestServer::start().await;
+    source.call(&["SET", "from-rdb", "1"]).await;
+    source.call(&["SAVE"]).await;
+    let rdb = std::fs::read(source.dir().join("dump.rdb")).unwrap();
+    source.shutdown().await;
+
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = master.local_addr().unwrap().port();
+    let replica = TestServer::start_with(|builder| builder.replicaof("127.0.0.1", port)).await;
+    let (link, _) = master.accept().await.unwrap();
+    let mut link = BufReader::new(link);
+    // PING, REPLCONF listening-port, REPLCONF capa, then PSYNC.
+    for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n"] {
+        let mut buf = [0; 512];
+        let _ = link.read(&mut buf).await.unwrap();
+        link.write_all(reply.as_bytes()).await.unwrap();
+    }
+    read_stream_until(&mut link, "PSYNC").await;
+    let mut sync = format!("+FULLRESYNC {} 0\r\n${}\r\n", "8".repeat(40), rdb.len()).into_bytes();
+    sync.extend_from_slice(&rdb);
+    let set = "*3\r\n$3\r\nSET\r\n$4\r\nfrom\r\n$6\r\nstream\r\n";
+    sync.extend_from_slice(set.as_bytes());
+    link.write_all(&sync).await.unwrap();
+
+    replica
+        .wait_until(&["GET", "from"], |reply| *reply == bulk("stream"))
+        .await;
+    assert_eq!(replica.call(&["GET", "from-rdb"]).await, bulk("1"));
+    link.write_all(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n")
+        .await
+        .unwrap();
+    // The SET is all the replica had processed when it was asked.
+    let ack = format!(
+        "$3\r\nACK\r\n${}\r\n{}\r\n",
+        set.len().to_string().len(),
+        set.len()
+    );
+    read_stream_until(&mut link, &ack).await;
+    replica.shutdown().await;
+}
+
+#[tokio::test]
+async fn every_replica_receives_the_writes() {
+    let primary = TestServer::start().await;
+    let first = primary.start_replica().await;
+    let second = primary.start_replica().await;
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "connected_slaves:2")
+        })
+        .await;
+    primary.call(&["SET", "k", "v"]).await;
+    assert_eq!(
+        primary.call(&["WAIT", "2", "5000"]).await,
+        Value::Integer(2)
+    );
+    assert_eq!(first.call(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(second.call(&["GET", "k"]).await, bulk("v"));
+    // Losing one replica leaves the other one attached.
+    first.shutdown().await;
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "connected_slaves:1")
+        })
+        .await;
+    primary.call(&["SET", "k", "w"]).await;
+    second
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("w"))
+        .await;
+    second.shutdown().await;
+    primary.shutdown().await;
+}
+
+/// The `field:value` line of an INFO reply, parsed as a number.
+fn info_number(reply: &Value, field: &str) -> i64 {
+    let Value::BulkString(info) = reply else {
+        panic!("not an INFO reply: {:?}", reply);
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+        .unwrap_or_else(|| panic!("no {} in {:?}", field, info))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn offsets_count_the_bytes_of_the_stream() {
+    let (primary, replica) = TestServer::start_pair().await;
+    let before = info_number(
+        &primary.call(&["INFO", "replication"]).await,
+        "master_repl_offset",
+    );
+    primary.call(&["SET", "k", "v"]).await;
+    let after = info_number(
+        &primary.call(&["INFO", "replication"]).await,
+        "master_repl_offset",
+    );
+    assert_eq!(
+        after - before,
+        "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n".len() as i64
+    );
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+    // WAIT's GETACK is part of the stream too.
+    let now = info_number(
+        &primary.call(&["INFO", "replication"]).await,
+        "master_repl_offset",
+    );
+    assert!(now > after);
+    replica
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_number(reply, "master_repl_offset") == now
+        })
+        .await;
+    // The replica's acks bring the master's view of it up to date.
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, &format!("offset={},", now))
+        })
+        .await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+/// The `master_replid` of an INFO reply.
+fn replid(reply: &Value) -> String {
+    let Value::BulkString(info) = reply else {
+        panic!("not an INFO reply: {:?}", reply);
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix("master_replid:"))
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn replicaof_no_one_promotes_a_replica() {
+    let (primary, replica) = TestServer::start_pair().await;
+    primary.call(&["SET", "k", "v"]).await;
+    replica
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("v"))
+        .await;
+    let old_replid = replid(&replica.call(&["INFO", "replication"]).await);
+    assert_eq!(
+        old_replid,
+        replid(&primary.call(&["INFO", "replication"]).await)
+    );
+
+    assert_eq!(
+        replica.call(&["REPLICAOF", "NO", "ONE"]).await,
+        Value::SimpleString("OK".into())
+    );
+    let info = replica.call(&["INFO", "replication"]).await;
+    assert!(info_contains(&info, "role:master"));
+    assert_ne!(replid(&info), old_replid);
+    // It keeps the dataset, takes writes of its own and no longer follows.
+    assert_eq!(replica.call(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(
+        replica.call(&["SET", "k", "mine"]).await,
+        Value::SimpleString("OK".into())
+    );
+    primary.call(&["SET", "k", "theirs"]).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(replica.call(&["GET", "k"]).await, bulk("mine"));
+
+    // SLAVEOF turns it back into a replica, which resyncs.
+    let port = primary.addr().port().to_string();
+    assert_eq!(
+        replica.call(&["SLAVEOF", "127.0.0.1", &port]).await,
+        Value::SimpleString("OK".into())
+    );
+    replica
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("theirs"))
+        .await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn diskless_sync_shares_one_snapshot_and_skips_the_disk() {
+    let primary = TestServer::start().await;
+    primary.call(&["SET", "k", "v"]).await;
+    primary
+        .call(&["CONFIG", "SET", "repl-diskless-sync-delay", "1"])
+        .await;
+    // Both attach within the delay, so one snapshot serves the two of them.
+    let started = Instant::now();
+    let ((_first, first_rdb), (_second, second_rdb)) =
+        tokio::join!(fake_replica(&primary), fake_replica(&primary));
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(first_rdb, second_rdb);
+    assert!(!primary.dir().join("dump.rdb").exists());
+
+    // A disk-backed sync saves the snapshot and sends the file.
+    primary
+        .call(&["CONFIG", "SET", "repl-diskless-sync", "no"])
+        .await;
+    let (_third, rdb) = fake_replica(&primary).await;
+    assert_eq!(std::fs::read(primary.dir().join("dump.rdb")).unwrap(), rdb);
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn silent_replicas_are_pinged_then_dropped() {
+    let primary = TestServer::start().await;
+    primary
+        .call(&["CONFIG", "SET", "repl-ping-replica-period", "1"])
+        .await;
+    primary.call(&["CONFIG", "SET", "repl-timeout", "2"]).await;
+    let (mut link, _) = fake_replica(&primary).await;
+    let info = primary.call(&["INFO", "replication"]).await;
+    assert!(info_contains(&info, "connected_slaves:1"));
+    assert!(info_contains(
+        &info,
+        "slave0:ip=127.0.0.1,port=6380,state=online,offset=0,lag="
+    ));
+    read_stream_until(&mut link, "*1\r\n$4\r\nPING\r\n").await;
+    // It never acks, so the master gives up on it.
+    primary
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_contains(reply, "connected_slaves:0")
+        })
+        .await;
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), link.read_to_end(&mut rest))
+        .await
+        .expect("the master kept the link open")
+        .unwrap();
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn expired_and_evicted_keys_reach_replicas_as_dels() {
+    let primary = TestServer::start().await;
+    let (mut link, _) = fake_replica(&primary).await;
+    primary.call(&["SET", "short", "v", "PX", "100"]).await;
+    // Nothing touches the key, so the active expire cycle removes it.
+    read_stream_until(&mut link, "*2\r\n$3\r\nDEL\r\n$5\r\nshort\r\n").await;
+
+    primary.call(&["SET", "lazy", "v", "PX", "50"]).await;
+    primary.call(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // A write finding the key expired deletes it before going on.
+    assert_eq!(
+        primary.call(&["SADD", "lazy", "m"]).await,
+        Value::Integer(1)
+    );
+    read_stream_until(
+        &mut link,
+        "*2\r\n$3\r\nDEL\r\n$4\r\nlazy\r\n*3\r\n$4\r\nSADD\r\n",
+    )
+    .await;
+
+    primary.call(&["SET", "big", &"x".repeat(1000)]).await;
+    primary
+        .call(&["CONFIG", "SET", "maxmemory-policy", "allkeys-random"])
+        .await;
+    primary.call(&["CONFIG", "SET", "maxmemory", "1"]).await;
+    primary.call(&["SET", "small", "v"]).await;
+    read_stream_until(&mut link, "*2\r\n$3\r\nDEL\r\n$3\r\nbig\r\n").await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn replica_read_only_can_be_turned_off() {
+    let (primary, replica) = TestServer::start_pair().await;
+    assert_eq!(
+        replica.call(&["CONFIG", "GET", "replica-read-only"]).await,
+        Value::Array(vec![bulk("replica-read-only"), bulk("yes")])
+    );
+    assert!(is_error(
+        &replica.call(&["RPUSH", "list", "a"]).await,
+        "READONLY"
+    ));
+    // Reads are served either way.
+    assert_eq!(replica.call(&["GET", "k"]).await, Value::Nil);
+
+    replica
+        .call(&["CONFIG", "SET", "replica-read-only", "no"])
+        .await;
+    assert_eq!(
+        replica.call(&["SET", "local", "v"]).await,
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(replica.call(&["GET", "local"]).await, bulk("v"));
+    // The master's writes still come through.
+    primary.call(&["SET", "k", "v"]).await;
+    replica
+        .wait_until(&["GET", "k"], |reply| *reply == bulk("v"))
+        .await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_can_serve_replicas_of_their_own() {
+    let (primary, replica) = TestServer::start_pair().await;
+    primary.call(&["SET", "before", "v"]).await;
+    replica
+        .wait_until(&["GET", "before"], |reply| *reply == bulk("v"))
+        .await;
+    let sub_replica = replica.start_replica().await;
+    assert_eq!(sub_replica.call(&["GET", "before"]).await, bulk("v"));
+    assert!(info_contains(
+        &replica.call(&["INFO", "replication"]).await,
+        "connected_slaves:1"
+    ));
+
+    primary.call(&["SET", "after", "w"]).await;
+    sub_replica
+        .wait_until(&["GET", "after"], |reply| *reply == bulk("w"))
+        .await;
+    // The whole chain shares the master's history.
+    let primary_replid = replid(&primary.call(&["INFO", "replication"]).await);
+    assert_eq!(
+        replid(&sub_replica.call(&["INFO", "replication"]).await),
+        primary_replid
+    );
+    let offset = info_number(
+        &primary.call(&["INFO", "replication"]).await,
+        "master_repl_offset",
+    );
+    sub_replica
+        .wait_until(&["INFO", "replication"], |reply| {
+            info_number(reply, "master_repl_offset") == offset
+        })
+        .await;
+    sub_replica.shutdown().await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
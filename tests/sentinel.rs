@@ -0,0 +1,141 @@
+//! Runs sentinels against a primary and its replica, and checks they promote
+//! the replica once the primary goes away.
+
+use redis_starter_rust::redis_cli::Connection;
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_sentinel::{Sentinel, SentinelOptions};
+use redis_starter_rust::redis_testing::TestServer;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+/// A port nothing listens on, for sentinels that have to know each other's
+/// address before they start.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn call(addr: SocketAddr, args: &[&str]) -> Value {
+    let mut conn = Connection::connect(&addr.to_string()).await.unwrap();
+    let args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+    conn.call(args).await.unwrap()
+}
+
+/// Sends the command every few milliseconds until the reply passes `check`.
+/// Panics after 10 seconds.
+async fn wait_until(addr: SocketAddr, args: &[&str], check: impl Fn(&Value) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let reply = call(addr, args).await;
+        if check(&reply) {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "gave up waiting on {:?}, last reply {:?}",
+            args,
+            reply
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn sentinels_promote_the_replica_once_the_master_is_down() {
+    let (primary, replica) = TestServer::start_pair().await;
+    let primary_port = primary.addr().port();
+    let replica_port = replica.addr().port();
+    primary.call(&["SET", "k", "v"]).await;
+    assert_eq!(
+        primary.call(&["WAIT", "1", "5000"]).await,
+        Value::Integer(1)
+    );
+
+    let ports = [free_port(), free_port()];
+    let mut sentinels = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        let opts = SentinelOptions {
+            port: *port,
+            bind: vec!["127.0.0.1".to_string()],
+            master_port: primary_port,
+            peers: vec![format!("127.0.0.1:{}", ports[1 - i])],
+            down_after: Duration::from_millis(200),
+            failover_timeout: Duration::from_secs(1),
+            ..SentinelOptions::default()
+        };
+        sentinels.push(Sentinel::spawn(opts).await.unwrap());
+    }
+    let addrs = sentinels
+        .iter()
+        .map(Sentinel::local_addr)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        call(
+            addrs[0],
+            &["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"]
+        )
+        .await,
+        Value::Array(vec![bulk("127.0.0.1"), bulk(&primary_port.to_string())])
+    );
+    // The replica is only known from the master's INFO, so the sentinels
+    // have to see it before the master goes away.
+    for addr in &addrs {
+        wait_until(*addr, &["SENTINEL", "MASTER", "mymaster"], |reply| {
+            matches!(reply, Value::Array(fields)
+                if fields.windows(2).any(|field| *field == [bulk("num-slaves"), bulk("1")]))
+        })
+        .await;
+    }
+    let mut subscriber = Connection::connect(&addrs[0].to_string()).await.unwrap();
+    assert_eq!(
+        subscriber
+            .call(vec![b"SUBSCRIBE".to_vec(), b"+switch-master".to_vec()])
+            .await
+            .unwrap(),
+        Value::Array(vec![
+            bulk("subscribe"),
+            bulk("+switch-master"),
+            Value::Integer(1)
+        ])
+    );
+
+    primary.shutdown().await;
+    let message = tokio::time::timeout(Duration::from_secs(10), subscriber.read_reply())
+        .await
+        .expect("no +switch-master message")
+        .unwrap();
+    assert_eq!(
+        message,
+        Value::Array(vec![
+            bulk("message"),
+            bulk("+switch-master"),
+            bulk(&format!(
+                "mymaster 127.0.0.1 {} 127.0.0.1 {}",
+                primary_port, replica_port
+            )),
+        ])
+    );
+    let promoted = Value::Array(vec![bulk("127.0.0.1"), bulk(&replica_port.to_string())]);
+    for addr in &addrs {
+        wait_until(
+            *addr,
+            &["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"],
+            |reply| *reply == promoted,
+        )
+        .await;
+    }
+    let info = replica.call(&["INFO", "replication"]).await;
+    assert!(matches!(&info, Value::BulkString(info) if info.contains("role:master")));
+    assert_eq!(
+        replica.call(&["SET", "k", "w"]).await,
+        Value::SimpleString("OK".into())
+    );
+    replica.shutdown().await;
+}
@@ -0,0 +1,2038 @@
+//! Runs servers through TestServer and checks replies to real RESP requests.
+
+mod common;
+
+use redis_starter_rust::redis_evict::EvictionPolicy;
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+fn ok() -> Value {
+    Value::SimpleString("OK".into())
+}
+
+fn error(msg: &str) -> Value {
+    Value::Error(msg.to_string())
+}
+
+#[tokio::test]
+async fn set_then_get() {
+    let server = TestServer::start().await;
+    assert_eq!(server.call(&["SET", "k", "v"]).await, ok());
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(server.call(&["GET", "missing"]).await, Value::Nil);
+    assert_eq!(server.call(&["DEL", "k"]).await, Value::Integer(1));
+    assert_eq!(server.call(&["GET", "k"]).await, Value::Nil);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn keys_expire() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "short", "v", "PX", "100"]).await;
+    server.call(&["SET", "long", "v"]).await;
+    assert_eq!(
+        server.call(&["PEXPIRE", "long", "100000"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        server.call(&["PEXPIRETIME", "missing"]).await,
+        Value::Integer(-2)
+    );
+    assert!(matches!(
+        server.call(&["PEXPIRETIME", "long"]).await,
+        Value::Integer(ms) if ms > 0
+    ));
+    assert_eq!(server.call(&["GET", "short"]).await, bulk("v"));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(server.call(&["GET", "short"]).await, Value::Nil);
+    assert_eq!(server.call(&["GET", "long"]).await, bulk("v"));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn keepttl_drops_an_expiry_that_already_passed() {
+    let server = TestServer::start().await;
+    // Leave the key for SET to find, rather than the active expire cycle.
+    server.call(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await;
+    server.call(&["SET", "k", "1", "PX", "50"]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(server.call(&["SET", "k", "2", "KEEPTTL"]).await, ok());
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("2"));
+    assert_eq!(server.call(&["PEXPIRETIME", "k"]).await, Value::Integer(-1));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn expire_conditions_combine() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "k", "v"]).await;
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "10", "NX", "GT"]).await,
+        error("ERR NX and XX, GT or LT options at the same time are not compatible")
+    );
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "10", "GT", "LT"]).await,
+        error("ERR GT and LT options at the same time are not compatible")
+    );
+    // LT alone counts a persistent key as expiring never, but XX needs an
+    // expiry to be there.
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "10", "XX", "LT"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "100", "LT"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "10", "XX", "GT"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "10", "XX", "LT", "XX"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        server.call(&["EXPIRE", "k", "50", "XX", "LT"]).await,
+        Value::Integer(0)
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn random_sample_counts_are_capped() {
+    let server = TestServer::start().await;
+    server.call(&["SADD", "s", "a"]).await;
+    server.call(&["HSET", "h", "f", "v"]).await;
+    assert_eq!(
+        server
+            .call(&["SRANDMEMBER", "s", "-9223372036854775807"])
+            .await,
+        error("ERR value is out of range")
+    );
+    assert_eq!(
+        server
+            .call(&["HRANDFIELD", "h", "-600000", "WITHVALUES"])
+            .await,
+        error("ERR value is out of range")
+    );
+    assert_eq!(
+        server.call(&["SRANDMEMBER", "s", "-3"]).await,
+        Value::Array(vec![bulk("a"), bulk("a"), bulk("a")])
+    );
+    // Positive counts never repeat, so they stay bounded by the set.
+    assert_eq!(
+        server
+            .call(&["SRANDMEMBER", "s", "9223372036854775807"])
+            .await,
+        Value::Array(vec![bulk("a")])
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn restart_loads_the_rdb_file() {
+    let first = TestServer::start().await;
+    first.call(&["SET", "string", "v"]).await;
+    first.call(&["SET", "expiring", "v", "PX", "1000000"]).await;
+    first.call(&["RPUSH", "list", "a", "b"]).await;
+    assert_eq!(first.call(&["SAVE"]).await, ok());
+    first.shutdown().await;
+
+    let dir = first.dir().to_string_lossy().into_owned();
+    let second = TestServer::start_with(|builder| builder.dir(dir)).await;
+    assert_eq!(second.call(&["GET", "string"]).await, bulk("v"));
+    assert_eq!(second.call(&["GET", "expiring"]).await, bulk("v"));
+    assert!(matches!(
+        second.call(&["EXPIRETIME", "expiring"]).await,
+        Value::Integer(secs) if secs > 0
+    ));
+    assert_eq!(
+        second.call(&["LRANGE", "list", "0", "-1"]).await,
+        Value::Array(vec![bulk("a"), bulk("b")])
+    );
+    second.shutdown().await;
+}
+
+// Cluster mode is only configured from the command line, with the port the
+// node announces known up front, so these tests run the server binary.
+
+#[test]
+fn cluster_mode_redirects_keys_served_elsewhere() {
+    // Slots 0-8191 are served here, 8192-12287 by another node and the rest
+    // by no one.
+    let server = common::Server::start_with(&[
+        "--cluster-enabled",
+        "yes",
+        "--cluster-slots",
+        "0-8191",
+        "--cluster-node",
+        "8192-12287 127.0.0.1:7001",
+    ]);
+    // bar hashes to slot 5061, foo to 12182 and a to 15495.
+    assert_eq!(server.call(&["SET", "bar", "v"]), "+OK\r\n");
+    assert_eq!(server.call(&["GET", "bar"]), "$1\r\nv\r\n");
+    assert_eq!(
+        server.call(&["GET", "foo"]),
+        "-MOVED 12182 127.0.0.1:7001\r\n"
+    );
+    assert_eq!(
+        server.call(&["SET", "a", "v"]),
+        "-CLUSTERDOWN Hash slot 15495 not served\r\n"
+    );
+    assert_eq!(
+        server.call(&["DEL", "bar", "foo"]),
+        "-CROSSSLOT Keys in request don't hash to the same slot\r\n"
+    );
+    // Only the hash tag is hashed, so these share slot 5474.
+    assert_eq!(server.call(&["DEL", "{user}a", "{user}b"]), ":0\r\n");
+    // Commands without keys aren't routed.
+    assert_eq!(server.call(&["PING"]), "+PONG\r\n");
+}
+
+#[test]
+fn cluster_nodes_meet_and_share_their_slots() {
+    let first =
+        common::Server::start_with(&["--cluster-enabled", "yes", "--cluster-slots", "0-8191"]);
+    let second =
+        common::Server::start_with(&["--cluster-enabled", "yes", "--cluster-slots", "8192-16383"]);
+    let info = first.call(&["CLUSTER", "INFO"]);
+    assert!(info.contains("cluster_state:fail\r\n"), "{}", info);
+    assert!(info.contains("cluster_slots_assigned:8192\r\n"), "{}", info);
+    let myid = first.call(&["CLUSTER", "MYID"]);
+    assert!(myid.starts_with("$40\r\n"), "{}", myid);
+    let nodes = first.call(&["CLUSTER", "NODES"]);
+    assert!(nodes.contains("myself,master"), "{}", nodes);
+    assert!(nodes.contains(" 0-8191\n"), "{}", nodes);
+
+    assert_eq!(
+        first.call(&["CLUSTER", "MEET", "127.0.0.1", "nope"]),
+        "-ERR Invalid node address specified: 127.0.0.1:nope\r\n"
+    );
+    let port = second.port().to_string();
+    assert_eq!(
+        first.call(&["CLUSTER", "MEET", "127.0.0.1", &port]),
+        "+OK\r\n"
+    );
+    // Both sides learn of each other and, between them, serve every slot.
+    for node in [&first, &second] {
+        node.wait_until(&["CLUSTER", "INFO"], |info| {
+            info.contains("cluster_state:ok\r\n") && info.contains("cluster_known_nodes:2\r\n")
+        });
+    }
+    assert_eq!(
+        first.call(&["GET", "foo"]),
+        format!("-MOVED 12182 127.0.0.1:{}\r\n", port)
+    );
+    assert_eq!(second.call(&["GET", "foo"]), "$-1\r\n");
+
+    // The node table is saved to nodes.conf as it changes.
+    let saved = std::fs::read_to_string(first.dir().join("nodes.conf")).unwrap();
+    assert!(saved.contains(&format!("127.0.0.1:{}", port)), "{}", saved);
+}
+
+#[test]
+fn cluster_nodes_announce_the_address_they_are_bound_to() {
+    let bound = common::Server::start_with(&[
+        "--cluster-enabled",
+        "yes",
+        "--cluster-slots",
+        "0-16383",
+        "--bind",
+        "127.0.0.2 127.0.0.1",
+    ]);
+    let addr = format!("127.0.0.2:{}", bound.port());
+    let nodes = bound.call(&["CLUSTER", "NODES"]);
+    assert!(nodes.contains(&format!(" {}@", addr)), "{}", nodes);
+    let slots = bound.call(&["CLUSTER", "SLOTS"]);
+    assert!(slots.contains("$9\r\n127.0.0.2\r\n"), "{}", slots);
+
+    // An announced address takes precedence over the bound ones.
+    let announced = common::Server::start_with(&[
+        "--cluster-enabled",
+        "yes",
+        "--cluster-announce-ip",
+        "10.0.0.5",
+        "--bind",
+        "127.0.0.1",
+    ]);
+    assert_eq!(
+        announced.call(&["CONFIG", "GET", "cluster-announce-ip"]),
+        "*2\r\n$19\r\ncluster-announce-ip\r\n$8\r\n10.0.0.5\r\n"
+    );
+    let nodes = announced.call(&["CLUSTER", "NODES"]);
+    assert!(
+        nodes.contains(&format!(" 10.0.0.5:{}@", announced.port())),
+        "{}",
+        nodes
+    );
+}
+
+/// The id a cluster node replies to CLUSTER MYID with.
+fn cluster_myid(node: &common::Server) -> String {
+    let reply = node.call(&["CLUSTER", "MYID"]);
+    reply.lines().nth(1).unwrap().to_string()
+}
+
+#[test]
+fn keys_of_a_migrating_slot_are_asked_for_on_the_target() {
+    let source =
+        common::Server::start_with(&["--cluster-enabled", "yes", "--cluster-slots", "0-8191"]);
+    let target =
+        common::Server::start_with(&["--cluster-enabled", "yes", "--cluster-slots", "8192-16383"]);
+    let source_addr = format!("127.0.0.1:{}", source.port());
+    let target_addr = format!("127.0.0.1:{}", target.port());
+    let target_port = target.port().to_string();
+    source.call(&["CLUSTER", "MEET", "127.0.0.1", &target_port]);
+    for node in [&source, &target] {
+        node.wait_until(&["CLUSTER", "INFO"], |info| {
+            info.contains("cluster_state:ok\r\n")
+        });
+    }
+    let (source_id, target_id) = (cluster_myid(&source), cluster_myid(&target));
+    // Keys tagged {b} hash to slot 3300, which the source serves.
+    assert_eq!(source.call(&["SET", "{b}1", "v"]), "+OK\r\n");
+    assert_eq!(source.call(&["SET", "{b}2", "v"]), "+OK\r\n");
+
+    assert_eq!(
+        source.call(&["CLUSTER", "SETSLOT", "3300", "MIGRATING", "nope"]),
+        "-ERR I don't know about node nope\r\n"
+    );
+    assert_eq!(
+        source.call(&["CLUSTER", "SETSLOT", "3300", "IMPORTING", &target_id]),
+        "-ERR I'm already the owner of hash slot 3300\r\n"
+    );
+    assert_eq!(
+        target.call(&["CLUSTER", "SETSLOT", "3300", "MIGRATING", &source_id]),
+        "-ERR I'm not the owner of hash slot 3300\r\n"
+    );
+    assert_eq!(
+        target.call(&["CLUSTER", "SETSLOT", "16384", "STABLE"]),
+        "-ERR Invalid or out of range slot\r\n"
+    );
+    assert_eq!(
+        target.call(&["CLUSTER", "SETSLOT", "3300", "IMPORTING", &source_id]),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        source.call(&["CLUSTER", "SETSLOT", "3300", "MIGRATING", &target_id]),
+        "+OK\r\n"
+    );
+    let nodes = source.call(&["CLUSTER", "NODES"]);
+    assert!(
+        nodes.contains(&format!(" [3300->-{}]", target_id)),
+        "{}",
+        nodes
+    );
+    let nodes = target.call(&["CLUSTER", "NODES"]);
+    assert!(
+        nodes.contains(&format!(" [3300-<-{}]", source_id)),
+        "{}",
+        nodes
+    );
+
+    // Keys still on the source are served there, missing ones are asked for
+    // on the target.
+    assert_eq!(source.call(&["GET", "{b}1"]), "$1\r\nv\r\n");
+    let ask = format!("-ASK 3300 {}\r\n", target_addr);
+    assert_eq!(source.call(&["GET", "{b}3"]), ask);
+    assert_eq!(
+        source.call(&["MIGRATE", "127.0.0.1", &target_port, "{b}1", "0", "5000"]),
+        "+OK\r\n"
+    );
+    assert_eq!(source.call(&["GET", "{b}1"]), ask);
+    assert_eq!(
+        source.call(&["TOUCH", "{b}1", "{b}2"]),
+        "-TRYAGAIN Multiple keys request during rehashing of slot\r\n"
+    );
+
+    // The target only serves the slot to a client that sent ASKING, for one
+    // command.
+    let moved = format!("-MOVED 3300 {}\r\n", source_addr);
+    assert_eq!(target.call(&["GET", "{b}1"]), moved);
+    let mut conn = target.connect();
+    assert_eq!(
+        common::roundtrip(&mut conn, &common::request(&["ASKING"])),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        common::roundtrip(&mut conn, &common::request(&["GET", "{b}1"])),
+        "$1\r\nv\r\n"
+    );
+    assert_eq!(
+        common::roundtrip(&mut conn, &common::request(&["GET", "{b}1"])),
+        moved
+    );
+
+    // Once the keys have moved, both sides hand the slot over.
+    assert_eq!(
+        source.call(&["MIGRATE", "127.0.0.1", &target_port, "{b}2", "0", "5000"]),
+        "+OK\r\n"
+    );
+    for node in [&target, &source] {
+        assert_eq!(
+            node.call(&["CLUSTER", "SETSLOT", "3300", "NODE", &target_id]),
+            "+OK\r\n"
+        );
+        let nodes = node.call(&["CLUSTER", "NODES"]);
+        assert!(!nodes.contains('['), "{}", nodes);
+    }
+    assert_eq!(
+        source.call(&["GET", "{b}2"]),
+        format!("-MOVED 3300 {}\r\n", target_addr)
+    );
+    assert_eq!(target.call(&["GET", "{b}2"]), "$1\r\nv\r\n");
+}
+
+async fn start_with_maxmemory(policy: EvictionPolicy) -> TestServer {
+    TestServer::start_with(|builder| builder.maxmemory(1000).maxmemory_policy(policy)).await
+}
+
+/// Sets `prefix0`, `prefix1`, ... to 100 byte values until the server
+/// refuses with an OOM error, and returns how many it accepted.
+async fn fill_until_oom(server: &TestServer, prefix: &str) -> usize {
+    let val = "x".repeat(100);
+    for n in 0..1000 {
+        let reply = server
+            .call(&["SET", &format!("{}{}", prefix, n), &val])
+            .await;
+        if matches!(&reply, Value::Error(e) if e.starts_with("OOM")) {
+            return n;
+        }
+        assert_eq!(reply, ok());
+    }
+    panic!("writes were never refused");
+}
+
+#[tokio::test]
+async fn noeviction_refuses_writes_past_maxmemory() {
+    let server = start_with_maxmemory(EvictionPolicy::NoEviction).await;
+    let accepted = fill_until_oom(&server, "k").await;
+    assert!(accepted > 0);
+    assert_eq!(
+        server.call(&["SET", "other", "v"]).await,
+        error("OOM command not allowed when used memory > 'maxmemory'.")
+    );
+    // Reads and deletes still go through, and make room again.
+    assert_eq!(server.call(&["GET", "k0"]).await, bulk(&"x".repeat(100)));
+    for n in 0..accepted {
+        assert_eq!(
+            server.call(&["DEL", &format!("k{}", n)]).await,
+            Value::Integer(1)
+        );
+    }
+    assert_eq!(server.call(&["SET", "other", "v"]).await, ok());
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn allkeys_lru_evicts_the_least_recently_used_keys() {
+    let server = start_with_maxmemory(EvictionPolicy::AllKeysLru).await;
+    let val = "x".repeat(100);
+    for n in 0..30 {
+        assert_eq!(server.call(&["SET", &format!("k{}", n), &val]).await, ok());
+        // k0 is read after every write, so it's never the oldest.
+        assert_eq!(server.call(&["GET", "k0"]).await, bulk(&val));
+    }
+    assert_eq!(server.call(&["GET", "k1"]).await, Value::Nil);
+    assert_eq!(server.call(&["GET", "k29"]).await, bulk(&val));
+    let stats = server.call(&["INFO", "stats"]).await;
+    assert!(
+        matches!(&stats, Value::BulkString(info) if !info.contains("evicted_keys:0\r\n")),
+        "{:?}",
+        stats
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn volatile_ttl_evicts_the_soonest_expiring_keys_only() {
+    let server = start_with_maxmemory(EvictionPolicy::VolatileTtl).await;
+    let val = "x".repeat(300);
+    assert_eq!(
+        server.call(&["SET", "soon", &val, "PX", "100000"]).await,
+        ok()
+    );
+    assert_eq!(
+        server.call(&["SET", "later", &val, "PX", "200000"]).await,
+        ok()
+    );
+    // The key closest to expiring goes first.
+    let small = "x".repeat(100);
+    let mut n = 0;
+    while server.call(&["GET", "soon"]).await != Value::Nil {
+        assert!(n < 1000, "soon was never evicted");
+        let key = format!("keep{}", n);
+        assert_eq!(server.call(&["SET", &key, &small]).await, ok());
+        n += 1;
+    }
+    assert_eq!(server.call(&["GET", "later"]).await, bulk(&val));
+    let filled = fill_until_oom(&server, "fill").await;
+    // Keys without a TTL are never picked, so once the volatile ones are
+    // gone writes are refused instead.
+    assert_eq!(server.call(&["GET", "later"]).await, Value::Nil);
+    let keys = (0..n)
+        .map(|n| format!("keep{}", n))
+        .chain((0..filled).map(|n| format!("fill{}", n)));
+    for key in keys {
+        assert_eq!(server.call(&["GET", &key]).await, bulk(&small));
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn allkeys_lfu_keeps_frequently_used_keys() {
+    let server = start_with_maxmemory(EvictionPolicy::NoEviction).await;
+    server.call(&["SET", "hot", "v"]).await;
+    assert!(matches!(
+        server.call(&["OBJECT", "FREQ", "hot"]).await,
+        Value::Error(e) if e.starts_with("ERR An LFU maxmemory policy is not selected")
+    ));
+
+    // With a log factor of 0 every access bumps the counter.
+    assert_eq!(
+        server
+            .call(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lfu"])
+            .await,
+        ok()
+    );
+    assert_eq!(
+        server.call(&["CONFIG", "SET", "lfu-log-factor", "0"]).await,
+        ok()
+    );
+    for _ in 0..20 {
+        assert_eq!(server.call(&["GET", "hot"]).await, bulk("v"));
+    }
+    assert!(matches!(
+        server.call(&["OBJECT", "FREQ", "hot"]).await,
+        Value::Integer(freq) if freq > 20
+    ));
+    assert_eq!(
+        server.call(&["OBJECT", "FREQ", "missing"]).await,
+        Value::Nil
+    );
+
+    let val = "x".repeat(100);
+    for n in 0..30 {
+        assert_eq!(server.call(&["SET", &format!("k{}", n), &val]).await, ok());
+    }
+    // Room was made by evicting the keys that were only written once.
+    assert_eq!(server.call(&["GET", "hot"]).await, bulk("v"));
+    let mut kept = 0;
+    for n in 0..30 {
+        if server.call(&["GET", &format!("k{}", n)]).await != Value::Nil {
+            kept += 1;
+        }
+    }
+    assert!(kept < 30, "{}", kept);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn config_set_validates_values_before_applying_any() {
+    let server = TestServer::start().await;
+    let failed = |arg: &str, reason: &str| {
+        error(&format!(
+            "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+            arg, reason
+        ))
+    };
+    let cases: &[(&[&str], Value)] = &[
+        (
+            &["nosuchoption", "1"],
+            error("ERR Unknown option or number of arguments for CONFIG SET - 'nosuchoption'"),
+        ),
+        (
+            &["appendonly", "maybe"],
+            failed("appendonly", "argument must be 'yes' or 'no'"),
+        ),
+        (
+            &["rdbchecksum", "no"],
+            failed("rdbchecksum", "can't set immutable config"),
+        ),
+        (
+            &["repl-timeout", "soon"],
+            failed(
+                "repl-timeout",
+                "argument couldn't be parsed into an integer",
+            ),
+        ),
+        (
+            &["maxmemory", "lots"],
+            failed("maxmemory", "argument must be a memory value"),
+        ),
+        (&["save", "100"], failed("save", "Invalid save parameters")),
+        (
+            &["dir", "/no/such/dir"],
+            failed("dir", "No such file or directory"),
+        ),
+    ];
+    for (args, reply) in cases {
+        let mut request = vec!["CONFIG", "SET"];
+        request.extend_from_slice(args);
+        assert_eq!(&server.call(&request).await, reply, "{:?}", args);
+    }
+    assert!(matches!(
+        server.call(&["CONFIG", "SET", "maxmemory-policy", "sometimes"]).await,
+        Value::Error(e) if e.contains("argument(s) must be one of the following: noeviction, ")
+    ));
+
+    // One bad value fails the whole command, so nothing before it is set.
+    assert!(matches!(
+        server
+            .call(&["CONFIG", "SET", "maxmemory", "10mb", "repl-timeout", "x"])
+            .await,
+        Value::Error(e) if e.starts_with("ERR CONFIG SET failed")
+    ));
+    assert_eq!(
+        server.call(&["CONFIG", "GET", "maxmemory"]).await,
+        Value::Array(vec![bulk("maxmemory"), bulk("0")])
+    );
+    // Memory amounts are stored in bytes.
+    assert_eq!(
+        server
+            .call(&["CONFIG", "SET", "maxmemory", "10mb", "repl-timeout", "30"])
+            .await,
+        ok()
+    );
+    assert_eq!(
+        server.call(&["CONFIG", "GET", "maxmemory"]).await,
+        Value::Array(vec![bulk("maxmemory"), bulk("10485760")])
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn config_set_applies_changes_live() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "k", "v"]).await;
+    assert_eq!(
+        server.call(&["CONFIG", "SET", "appendonly", "yes"]).await,
+        ok()
+    );
+    let aof = server.dir().join("appendonly.aof");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !aof.exists() {
+        assert!(Instant::now() < deadline, "no AOF was written");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    server
+        .wait_until(
+            &["INFO", "persistence"],
+            |reply| matches!(reply, Value::BulkString(info) if info.contains("aof_enabled:1\r\n")),
+        )
+        .await;
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn config_get_matches_glob_patterns_against_every_parameter() {
+    let server = TestServer::start().await;
+    assert_eq!(
+        server.call(&["CONFIG", "GET", "maxmemory*"]).await,
+        Value::Array(vec![
+            bulk("maxmemory"),
+            bulk("0"),
+            bulk("maxmemory-policy"),
+            bulk("noeviction"),
+        ])
+    );
+    // Parameters that were never set report their defaults.
+    assert_eq!(
+        server
+            .call(&["CONFIG", "GET", "databases", "lfu-?ecay-time"])
+            .await,
+        Value::Array(vec![
+            bulk("databases"),
+            bulk("16"),
+            bulk("lfu-decay-time"),
+            bulk("1"),
+        ])
+    );
+    assert_eq!(
+        server.call(&["CONFIG", "GET", "nosuch*"]).await,
+        Value::Array(vec![])
+    );
+    match server.call(&["CONFIG", "GET", "*"]).await {
+        Value::Array(all) => assert!(all
+            .windows(2)
+            .any(|pair| pair == [bulk("appendfsync"), bulk("everysec")])),
+        reply => panic!("unexpected CONFIG GET reply {:?}", reply),
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn pipelined_replies_are_written_together() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    stream.write_all(&b"PING\r\n".repeat(100)).await.unwrap();
+    let mut buf = vec![0; 4096];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"+PONG\r\n".repeat(100));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn replies_are_not_held_back_by_a_blocking_command() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+    stream
+        .write_all(b"PING\r\nDEBUG SLEEP 2\r\n")
+        .await
+        .unwrap();
+    let mut buf = vec![0; 64];
+    let n = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+        .await
+        .expect("PING's reply waited for DEBUG SLEEP")
+        .unwrap();
+    assert_eq!(&buf[..n], b"+PONG\r\n");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn reset_clears_the_connection_state() {
+    let server = TestServer::start().await;
+    let mut conn = server.connect().await;
+    let mut call = async |args: &[&str]| {
+        let args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        conn.call(args).await.unwrap()
+    };
+    assert_eq!(
+        call(&["CLIENT", "SETNAME", "worker"]).await,
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(call(&["CLIENT", "GETNAME"]).await, bulk("worker"));
+    let id = call(&["CLIENT", "ID"]).await;
+    assert_eq!(call(&["RESET"]).await, Value::SimpleString("RESET".into()));
+    assert_eq!(call(&["CLIENT", "GETNAME"]).await, Value::Nil);
+    // It's still the same client.
+    assert_eq!(call(&["CLIENT", "ID"]).await, id);
+    assert!(matches!(
+        call(&["CLIENT", "SETNAME", "two words"]).await,
+        Value::Error(_)
+    ));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn command_stats_are_kept_under_the_name_the_client_used() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "k", "v"]).await;
+    server.call(&["EXPIRE", "k", "100"]).await;
+    server.call(&["EXPIRE", "k", "200"]).await;
+    server.call(&["PEXPIRE", "k", "100000"]).await;
+    server.call(&["CONFIG", "GET", "port"]).await;
+    let info = match server.call(&["INFO", "commandstats"]).await {
+        Value::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+    assert!(info.contains("cmdstat_expire:calls=2,"), "{}", info);
+    assert!(info.contains("cmdstat_pexpire:calls=1,"), "{}", info);
+    assert!(info.contains("cmdstat_config|get:calls=1,"), "{}", info);
+    assert!(!info.contains("cmdstat_pexpireat:"), "{}", info);
+    let info = match server.call(&["INFO", "latencystats"]).await {
+        Value::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+    assert!(
+        info.contains("latency_percentiles_usec_expire:"),
+        "{}",
+        info
+    );
+    assert!(
+        !info.contains("latency_percentiles_usec_pexpireat:"),
+        "{}",
+        info
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn slowlog_shows_commands_as_the_client_sent_them() {
+    let server = TestServer::start().await;
+    server
+        .call(&["CONFIG", "SET", "slowlog-log-slower-than", "0"])
+        .await;
+    let mut conn = server.connect().await;
+    let mut call = async |args: &[&str]| {
+        let args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        conn.call(args).await.unwrap()
+    };
+    call(&["CLIENT", "SETNAME", "worker"]).await;
+    call(&["SET", "e", "v"]).await;
+    call(&["EXPIRE", "e", "100"]).await;
+    call(&["SET", "k", "v", "PX", "100000"]).await;
+    let entries = match call(&["SLOWLOG", "GET", "2"]).await {
+        Value::Array(entries) => entries,
+        reply => panic!("unexpected SLOWLOG GET reply {:?}", reply),
+    };
+    let logged = entries
+        .into_iter()
+        .map(|entry| match entry {
+            Value::Array(fields) => (fields[3].clone(), fields[5].clone()),
+            entry => panic!("unexpected slow log entry {:?}", entry),
+        })
+        .collect::<Vec<_>>();
+    let args = |args: &[&str]| Value::Array(args.iter().map(|arg| bulk(arg)).collect());
+    assert_eq!(
+        logged,
+        vec![
+            (args(&["SET", "k", "v", "PX", "100000"]), bulk("worker")),
+            (args(&["EXPIRE", "e", "100"]), bulk("worker")),
+        ]
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn client_pause_holds_back_writes() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "k", "v"]).await;
+    server.call(&["CLIENT", "PAUSE", "100000", "WRITE"]).await;
+    // Reads are still served.
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("v"));
+    let write = tokio::spawn({
+        let mut conn = server.connect().await;
+        async move {
+            conn.call(vec![b"SET".to_vec(), b"k".to_vec(), b"w".to_vec()])
+                .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!write.is_finished());
+    server.call(&["CLIENT", "UNPAUSE"]).await;
+    assert_eq!(
+        write.await.unwrap().unwrap(),
+        Value::SimpleString("OK".into())
+    );
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("w"));
+
+    // A pause runs out on its own.
+    server.call(&["CLIENT", "PAUSE", "200"]).await;
+    let start = std::time::Instant::now();
+    assert_eq!(
+        server.call(&["PING"]).await,
+        Value::SimpleString("PONG".into())
+    );
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn clients_over_their_output_buffer_limit_are_disconnected() {
+    let server = TestServer::start().await;
+    server
+        .call(&[
+            "CONFIG",
+            "SET",
+            "client-output-buffer-limit",
+            "normal 1kb 0 0",
+        ])
+        .await;
+    // Classes left out keep their limits.
+    assert_eq!(
+        server
+            .call(&["CONFIG", "GET", "client-output-buffer-limit"])
+            .await,
+        Value::Array(vec![
+            bulk("client-output-buffer-limit"),
+            bulk("normal 1024 0 0 slave 268435456 67108864 60 pubsub 33554432 8388608 60"),
+        ])
+    );
+    server.call(&["SET", "small", "v"]).await;
+    server.call(&["SET", "big", &"x".repeat(2000)]).await;
+    assert_eq!(server.call(&["GET", "small"]).await, bulk("v"));
+    let mut conn = server.connect().await;
+    assert!(conn
+        .call(vec![b"GET".to_vec(), b"big".to_vec()])
+        .await
+        .is_err());
+    server
+        .wait_until(&["INFO", "stats"], |reply| {
+            matches!(reply, Value::BulkString(info)
+                if info.contains("client_output_buffer_limit_disconnections:1"))
+        })
+        .await;
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn idle_clients_are_closed_after_the_timeout() {
+    let server = TestServer::start().await;
+    assert_eq!(server.call(&["CONFIG", "SET", "timeout", "1"]).await, ok());
+    let mut idle = TcpStream::connect(server.addr()).await.unwrap();
+    let mut busy = server.connect().await;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(2500) {
+        assert_eq!(
+            busy.call(vec![b"PING".to_vec()]).await.unwrap(),
+            Value::SimpleString("PONG".into())
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    // The server closed the idle connection, so reading from it hits EOF.
+    let mut buf = [0; 64];
+    let read = tokio::time::timeout(Duration::from_secs(5), idle.read(&mut buf))
+        .await
+        .expect("the idle client was left open");
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    assert_eq!(server.call(&["CONFIG", "SET", "timeout", "0"]).await, ok());
+    let mut kept = TcpStream::connect(server.addr()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    kept.write_all(b"PING\r\n").await.unwrap();
+    let read = kept.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..read], b"+PONG\r\n");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn keyspace_stats_count_hits_misses_and_expired_keys() {
+    let server = TestServer::start().await;
+    let stat = |name: &'static str| {
+        let server = &server;
+        async move {
+            let info = match server.call(&["INFO", "stats"]).await {
+                Value::BulkString(info) => info,
+                reply => panic!("unexpected INFO reply {:?}", reply),
+            };
+            info.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .unwrap_or_else(|| panic!("{} missing from INFO", name))
+                .parse::<u64>()
+                .unwrap()
+        }
+    };
+    // The key is left for DEL to expire, rather than the active cycle.
+    server.call(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await;
+    server.call(&["SET", "k", "v"]).await;
+    server.call(&["SET", "short", "v", "PX", "10"]).await;
+    server.call(&["GET", "k"]).await;
+    server.call(&["TYPE", "k"]).await;
+    server.call(&["GET", "missing"]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.call(&["GET", "short"]).await, Value::Nil);
+    assert_eq!(server.call(&["DEL", "short"]).await, Value::Integer(0));
+    assert_eq!(stat("keyspace_hits").await, 2);
+    assert_eq!(stat("keyspace_misses").await, 2);
+    assert_eq!(stat("expired_keys").await, 1);
+
+    server.call(&["CONFIG", "RESETSTAT"]).await;
+    assert_eq!(stat("keyspace_hits").await, 0);
+    assert_eq!(stat("keyspace_misses").await, 0);
+    assert_eq!(stat("expired_keys").await, 0);
+    server.shutdown().await;
+}
+
+/// Keys and members are bytes rather than text, so ones that aren't valid
+/// UTF-8 come back exactly as they were sent.
+#[tokio::test]
+async fn keys_and_members_are_binary_safe() {
+    let server = TestServer::start().await;
+    let call = |args: &[&[u8]]| {
+        let args = args.iter().map(|arg| arg.to_vec()).collect();
+        async { server.connect().await.call(args).await.unwrap() }
+    };
+    let key: &[u8] = b"key\xff\xfe";
+    let member: &[u8] = b"\xc3\x28member";
+    let bytes = |arg: &[u8]| Value::Bytes(arg.to_vec());
+
+    assert_eq!(call(&[b"SET", key, member]).await, ok());
+    assert_eq!(call(&[b"GET", key]).await, bytes(member));
+    assert_eq!(call(&[b"GET", b"key"]).await, Value::Nil);
+    assert_eq!(call(&[b"KEYS", b"*"]).await, Value::Array(vec![bytes(key)]));
+
+    let set: &[u8] = b"set\x80";
+    assert_eq!(call(&[b"SADD", set, member, b"m"]).await, Value::Integer(2));
+    let members = match call(&[b"SMEMBERS", set]).await {
+        Value::Array(mut members) => {
+            members.sort_by_key(|member| format!("{:?}", member));
+            members
+        }
+        reply => panic!("unexpected SMEMBERS reply {:?}", reply),
+    };
+    assert_eq!(members, vec![bulk("m"), bytes(member)]);
+
+    let payload = match call(&[b"DUMP", set]).await {
+        Value::Bytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    let copy: &[u8] = b"copy\xff";
+    assert_eq!(call(&[b"RESTORE", copy, b"0", &payload]).await, ok());
+    assert_eq!(
+        call(&[b"SMISMEMBER", copy, member, b"m", b"member"]).await,
+        Value::Array(vec![
+            Value::Integer(1),
+            Value::Integer(1),
+            Value::Integer(0)
+        ])
+    );
+    assert_eq!(call(&[b"SREM", copy, member]).await, Value::Integer(1));
+    server.shutdown().await;
+}
+
+/// Sends a request whose arguments needn't be text.
+async fn call_bytes(server: &TestServer, args: &[&[u8]]) -> Value {
+    let args = args.iter().map(|arg| arg.to_vec()).collect();
+    server.connect().await.call(args).await.unwrap()
+}
+
+/// A stream entry as XRANGE replies with it.
+fn stream_entry(id: &str, fields: &[&[u8]]) -> Value {
+    let fields = fields
+        .iter()
+        .map(|field| match std::str::from_utf8(field) {
+            Ok(field) => bulk(field),
+            Err(_) => Value::Bytes(field.to_vec()),
+        })
+        .collect();
+    Value::Array(vec![bulk(id), Value::Array(fields)])
+}
+
+#[tokio::test]
+async fn streams_add_and_range_over_entries() {
+    let server = TestServer::start().await;
+    let field: &[u8] = b"f\xff";
+    assert_eq!(
+        call_bytes(&server, &[b"XADD", b"s", b"NOMKSTREAM", b"*", b"f", b"v"]).await,
+        Value::Nil
+    );
+    assert_eq!(
+        call_bytes(&server, &[b"XADD", b"s", b"1-1", b"f", b"v"]).await,
+        bulk("1-1")
+    );
+    assert_eq!(
+        call_bytes(&server, &[b"XADD", b"s", b"1-*", field, b"\x00"]).await,
+        bulk("1-2")
+    );
+    assert_eq!(
+        server.call(&["XADD", "s", "3", "a", "1", "b", "2"]).await,
+        bulk("3-0")
+    );
+    assert_eq!(
+        server.call(&["XADD", "s", "2-0", "f", "v"]).await,
+        error("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    );
+    assert_eq!(
+        server.call(&["XADD", "new", "0-0", "f", "v"]).await,
+        error("ERR The ID specified in XADD must be greater than 0-0")
+    );
+    assert_eq!(
+        server.call(&["TYPE", "new"]).await,
+        Value::SimpleString("none".into())
+    );
+    assert_eq!(
+        server.call(&["XADD", "s", "4-0", "f"]).await,
+        error("ERR wrong number of arguments for 'xadd' command")
+    );
+    assert_eq!(server.call(&["XLEN", "s"]).await, Value::Integer(3));
+    assert_eq!(
+        server.call(&["TYPE", "s"]).await,
+        Value::SimpleString("stream".into())
+    );
+
+    assert_eq!(
+        server.call(&["XRANGE", "s", "-", "+"]).await,
+        Value::Array(vec![
+            stream_entry("1-1", &[b"f", b"v"]),
+            stream_entry("1-2", &[field, b"\x00"]),
+            stream_entry("3-0", &[b"a", b"1", b"b", b"2"]),
+        ])
+    );
+    // A bare time covers every sequence number in it.
+    assert_eq!(
+        server.call(&["XRANGE", "s", "1", "1"]).await,
+        Value::Array(vec![
+            stream_entry("1-1", &[b"f", b"v"]),
+            stream_entry("1-2", &[field, b"\x00"]),
+        ])
+    );
+    assert_eq!(
+        server
+            .call(&["XRANGE", "s", "(1-1", "+", "COUNT", "1"])
+            .await,
+        Value::Array(vec![stream_entry("1-2", &[field, b"\x00"])])
+    );
+    assert_eq!(
+        server.call(&["XRANGE", "s", "x", "+"]).await,
+        error("ERR Invalid stream ID specified as stream command argument")
+    );
+
+    // Trimming keeps the last ID, so the trimmed entries can't come back.
+    assert_eq!(
+        server
+            .call(&["XADD", "s", "MINID", "=", "5", "4-0", "f", "v"])
+            .await,
+        bulk("4-0")
+    );
+    assert_eq!(server.call(&["XLEN", "s"]).await, Value::Integer(0));
+    assert_eq!(
+        server.call(&["XADD", "s", "3-1", "f", "v"]).await,
+        error("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn streams_survive_dump_restore_and_a_restart() {
+    let first = TestServer::start().await;
+    let field: &[u8] = b"f\xff";
+    for id in 1..=150 {
+        let id = format!("{}-0", id);
+        call_bytes(&first, &[b"XADD", b"s", id.as_bytes(), field, b"\x00"]).await;
+    }
+    first
+        .call(&[
+            "XADD", "s", "MAXLEN", "~", "120", "151-0", "a", "1", "b", "2",
+        ])
+        .await;
+    let all = match first.call(&["XRANGE", "s", "-", "+"]).await {
+        Value::Array(entries) => entries,
+        reply => panic!("unexpected XRANGE reply {:?}", reply),
+    };
+    assert_eq!(all.len(), 120);
+    assert_eq!(all[0], stream_entry("32-0", &[field, b"\x00"]));
+    assert_eq!(all[119], stream_entry("151-0", &[b"a", b"1", b"b", b"2"]));
+
+    let payload = match first.call(&["DUMP", "s"]).await {
+        Value::Bytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    assert_eq!(
+        call_bytes(&first, &[b"RESTORE", b"copy", b"0", &payload]).await,
+        ok()
+    );
+    assert_eq!(
+        first.call(&["XRANGE", "copy", "-", "+"]).await,
+        Value::Array(all.clone())
+    );
+    assert_eq!(first.call(&["SAVE"]).await, ok());
+    first.shutdown().await;
+
+    let dir = first.dir().to_string_lossy().into_owned();
+    let second = TestServer::start_with(|builder| builder.dir(dir)).await;
+    for key in ["s", "copy"] {
+        assert_eq!(
+            second.call(&["XRANGE", key, "-", "+"]).await,
+            Value::Array(all.clone())
+        );
+        assert_eq!(
+            second.call(&["XADD", key, "151-0", "f", "v"]).await,
+            error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+    }
+    second.shutdown().await;
+}
+
+/// The value of `field` in an XINFO reply, which RESP2 sends as a flat array
+/// of fields and values.
+fn info_field(reply: &Value, field: &str) -> Value {
+    match reply {
+        Value::Array(items) => items
+            .chunks(2)
+            .find(|pair| pair[0] == bulk(field))
+            .map(|pair| pair[1].clone())
+            .unwrap_or_else(|| panic!("no {} in {:?}", field, reply)),
+        reply => panic!("unexpected XINFO reply {:?}", reply),
+    }
+}
+
+/// The groups or consumers an XINFO GROUPS or CONSUMERS reply lists.
+fn info_list(reply: Value) -> Vec<Value> {
+    match reply {
+        Value::Array(items) => items,
+        reply => panic!("unexpected XINFO reply {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn consumer_groups_share_out_entries_and_show_in_xinfo() {
+    let first = TestServer::start().await;
+    assert_eq!(
+        first.call(&["XGROUP", "CREATE", "s", "g", "$"]).await,
+        error(
+            "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+             want to use the MKSTREAM option to create an empty stream automatically."
+        )
+    );
+    assert_eq!(
+        first
+            .call(&["XGROUP", "CREATE", "s", "late", "$", "MKSTREAM"])
+            .await,
+        ok()
+    );
+    for id in ["1-0", "2-0", "3-0"] {
+        first.call(&["XADD", "s", id, "f", id]).await;
+    }
+    assert_eq!(first.call(&["XGROUP", "CREATE", "s", "g", "0"]).await, ok());
+    assert_eq!(
+        first.call(&["XGROUP", "CREATE", "s", "g", "0"]).await,
+        error("BUSYGROUP Consumer Group name already exists")
+    );
+
+    assert_eq!(
+        first
+            .call(&[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "2",
+                "STREAMS",
+                "s",
+                ">"
+            ])
+            .await,
+        Value::Array(vec![Value::Array(vec![
+            bulk("s"),
+            Value::Array(vec![
+                stream_entry("1-0", &[b"f", b"1-0"]),
+                stream_entry("2-0", &[b"f", b"2-0"]),
+            ]),
+        ])])
+    );
+    assert_eq!(
+        first
+            .call(&["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"])
+            .await,
+        Value::Array(vec![Value::Array(vec![
+            bulk("s"),
+            Value::Array(vec![stream_entry("3-0", &[b"f", b"3-0"])]),
+        ])])
+    );
+    assert_eq!(
+        first
+            .call(&["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "s", ">"])
+            .await,
+        Value::Nil
+    );
+    // An ID reads back the consumer's own pending entries.
+    assert_eq!(
+        first
+            .call(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "1-0"])
+            .await,
+        Value::Array(vec![Value::Array(vec![
+            bulk("s"),
+            Value::Array(vec![stream_entry("2-0", &[b"f", b"2-0"])]),
+        ])])
+    );
+    assert_eq!(
+        first
+            .call(&["XREADGROUP", "GROUP", "none", "bob", "STREAMS", "s", ">"])
+            .await,
+        error("NOGROUP No such key 's' or consumer group 'none' in XREADGROUP with GROUP option")
+    );
+    assert_eq!(
+        first.call(&["XACK", "s", "g", "1-0", "9-0"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        first
+            .call(&["XGROUP", "CREATECONSUMER", "s", "g", "carol"])
+            .await,
+        Value::Integer(1)
+    );
+
+    let stream = first.call(&["XINFO", "STREAM", "s"]).await;
+    assert_eq!(info_field(&stream, "length"), Value::Integer(3));
+    assert_eq!(info_field(&stream, "last-generated-id"), bulk("3-0"));
+    assert_eq!(info_field(&stream, "entries-added"), Value::Integer(3));
+    assert_eq!(info_field(&stream, "groups"), Value::Integer(2));
+    assert_eq!(
+        info_field(&stream, "first-entry"),
+        stream_entry("1-0", &[b"f", b"1-0"])
+    );
+    assert_eq!(
+        info_field(&stream, "last-entry"),
+        stream_entry("3-0", &[b"f", b"3-0"])
+    );
+    assert_eq!(
+        first.call(&["XINFO", "STREAM", "missing"]).await,
+        error("ERR no such key")
+    );
+
+    // The payload and the RDB file both keep the groups.
+    let payload = match first.call(&["DUMP", "s"]).await {
+        Value::Bytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    assert_eq!(
+        call_bytes(&first, &[b"RESTORE", b"copy", b"0", &payload]).await,
+        ok()
+    );
+    assert_eq!(first.call(&["SAVE"]).await, ok());
+    first.shutdown().await;
+    let dir = first.dir().to_string_lossy().into_owned();
+    let second = TestServer::start_with(|builder| builder.dir(dir)).await;
+
+    for key in ["s", "copy"] {
+        let groups = info_list(second.call(&["XINFO", "GROUPS", key]).await);
+        assert_eq!(groups.len(), 2);
+        let g = &groups[0];
+        assert_eq!(info_field(g, "name"), bulk("g"));
+        assert_eq!(info_field(g, "consumers"), Value::Integer(3));
+        assert_eq!(info_field(g, "pending"), Value::Integer(2));
+        assert_eq!(info_field(g, "last-delivered-id"), bulk("3-0"));
+        assert_eq!(info_field(g, "entries-read"), Value::Integer(3));
+        assert_eq!(info_field(g, "lag"), Value::Integer(0));
+        // Created before any entry was added, so it has all three to read.
+        let late = &groups[1];
+        assert_eq!(info_field(late, "name"), bulk("late"));
+        assert_eq!(info_field(late, "entries-read"), Value::Integer(0));
+        assert_eq!(info_field(late, "lag"), Value::Integer(3));
+
+        let consumers = info_list(second.call(&["XINFO", "CONSUMERS", key, "g"]).await);
+        let pending = consumers
+            .iter()
+            .map(|consumer| {
+                (
+                    info_field(consumer, "name"),
+                    info_field(consumer, "pending"),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pending,
+            vec![
+                (bulk("alice"), Value::Integer(1)),
+                (bulk("bob"), Value::Integer(1)),
+                (bulk("carol"), Value::Integer(0)),
+            ]
+        );
+        assert!(matches!(
+            info_field(&consumers[0], "inactive"),
+            Value::Integer(ms) if ms >= 0
+        ));
+        assert_eq!(info_field(&consumers[2], "inactive"), Value::Integer(-1));
+    }
+    assert_eq!(
+        second.call(&["XINFO", "CONSUMERS", "s", "none"]).await,
+        error("NOGROUP No such consumer group 'none' for key name 's'")
+    );
+    assert_eq!(
+        second
+            .call(&["XGROUP", "DELCONSUMER", "s", "g", "bob"])
+            .await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        second
+            .call(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", "0"])
+            .await,
+        Value::Array(vec![Value::Array(vec![
+            bulk("s"),
+            Value::Array(vec![stream_entry("2-0", &[b"f", b"2-0"])]),
+        ])])
+    );
+    assert_eq!(
+        second.call(&["XGROUP", "DESTROY", "s", "g"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        info_list(second.call(&["XINFO", "GROUPS", "s"]).await).len(),
+        1
+    );
+    second.shutdown().await;
+}
+
+fn integer(reply: Value) -> i64 {
+    match reply {
+        Value::Integer(num) => num,
+        reply => panic!("not an integer: {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn memory_usage_grows_with_the_value() {
+    let server = TestServer::start().await;
+    assert!(matches!(
+        server.call(&["MEMORY", "DOCTOR"]).await,
+        Value::BulkString(report) if report.contains("this instance is empty")
+    ));
+    assert_eq!(
+        server.call(&["MEMORY", "USAGE", "missing"]).await,
+        Value::Nil
+    );
+    server.call(&["SET", "small", "v"]).await;
+    server.call(&["SET", "large", &"v".repeat(1000)]).await;
+    let small = integer(server.call(&["MEMORY", "USAGE", "small"]).await);
+    let large = integer(server.call(&["MEMORY", "USAGE", "large"]).await);
+    assert!(small > 1);
+    assert!(large >= 1000 && large > small);
+
+    let members = (0..100).map(|n| n.to_string()).collect::<Vec<_>>();
+    let mut sadd = vec!["SADD", "set"];
+    sadd.extend(members.iter().map(String::as_str));
+    server.call(&sadd).await;
+    // SAMPLES 0 looks at every member, and a smaller sample is scaled up to
+    // the whole set.
+    let all = integer(
+        server
+            .call(&["MEMORY", "USAGE", "set", "SAMPLES", "0"])
+            .await,
+    );
+    assert!(all > 100);
+    assert!(
+        integer(
+            server
+                .call(&["MEMORY", "USAGE", "set", "SAMPLES", "5"])
+                .await
+        ) > 100
+    );
+
+    let stats = server.call(&["MEMORY", "STATS"]).await;
+    assert_eq!(info_field(&stats, "keys.count"), Value::Integer(3));
+    assert!(integer(info_field(&stats, "total.allocated")) > large + all);
+    assert!(matches!(
+        server.call(&["MEMORY", "DOCTOR"]).await,
+        Value::BulkString(report) if report.contains("can't find any memory issue")
+    ));
+    server.shutdown().await;
+}
+
+async fn encoding(server: &TestServer, key: &str) -> Value {
+    server.call(&["OBJECT", "ENCODING", key]).await
+}
+
+#[tokio::test]
+async fn object_reports_encodings_idle_times_and_refcounts() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "int", "12345"]).await;
+    server.call(&["SET", "short", "hello"]).await;
+    server.call(&["SET", "long", &"x".repeat(45)]).await;
+    server.call(&["SADD", "ints", "1", "2", "3"]).await;
+    server.call(&["SADD", "words", "a", "b"]).await;
+    server.call(&["HSET", "hash", "f", &"x".repeat(65)]).await;
+    server.call(&["RPUSH", "list", "a"]).await;
+    assert_eq!(encoding(&server, "int").await, bulk("int"));
+    assert_eq!(encoding(&server, "short").await, bulk("embstr"));
+    assert_eq!(encoding(&server, "long").await, bulk("raw"));
+    assert_eq!(encoding(&server, "ints").await, bulk("intset"));
+    assert_eq!(encoding(&server, "words").await, bulk("listpack"));
+    assert_eq!(encoding(&server, "hash").await, bulk("hashtable"));
+    assert_eq!(encoding(&server, "list").await, bulk("listpack"));
+    assert_eq!(encoding(&server, "missing").await, Value::Nil);
+
+    // Small integers are shared objects.
+    server.call(&["SET", "shared", "7"]).await;
+    assert_eq!(
+        server.call(&["OBJECT", "REFCOUNT", "shared"]).await,
+        Value::Integer(i32::MAX as i64)
+    );
+    assert_eq!(
+        server.call(&["OBJECT", "REFCOUNT", "short"]).await,
+        Value::Integer(1)
+    );
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(matches!(
+        server.call(&["OBJECT", "IDLETIME", "short"]).await,
+        Value::Integer(idle) if idle >= 1
+    ));
+    // Reading the key makes it recently used again.
+    server.call(&["GET", "short"]).await;
+    assert_eq!(
+        server.call(&["OBJECT", "IDLETIME", "short"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        server.call(&["OBJECT", "IDLETIME", "missing"]).await,
+        Value::Nil
+    );
+    server.shutdown().await;
+}
+
+/// The section headers of an INFO reply, in order.
+fn info_sections(reply: &Value) -> Vec<String> {
+    match reply {
+        Value::BulkString(info) => info
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .map(str::to_string)
+            .collect(),
+        reply => panic!("not an INFO reply: {:?}", reply),
+    }
+}
+
+fn info_line(reply: &Value, field: &str) -> String {
+    match reply {
+        Value::BulkString(info) => info
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}:", field)))
+            .unwrap_or_else(|| panic!("no {} in {:?}", field, info))
+            .to_string(),
+        reply => panic!("not an INFO reply: {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn info_reports_the_sections_asked_for() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "a", "1"]).await;
+    server.call(&["SET", "b", "2", "PX", "100000"]).await;
+    let info = server.call(&["INFO"]).await;
+    assert_eq!(
+        info_sections(&info),
+        [
+            "Server",
+            "Clients",
+            "Memory",
+            "Persistence",
+            "Stats",
+            "Replication",
+            "Cluster",
+            "Keyspace"
+        ]
+    );
+    assert_eq!(info_line(&info, "connected_clients"), "1");
+    assert_eq!(
+        info_line(&info, "tcp_port"),
+        server.addr().port().to_string()
+    );
+    assert!(info_line(&info, "used_memory").parse::<u64>().unwrap() > 0);
+    assert!(info_line(&info, "db0").starts_with("keys=2,expires=1,avg_ttl="));
+    // Commands are counted as they complete, the INFO asking included.
+    let processed = info_line(&info, "total_commands_processed")
+        .parse::<u64>()
+        .unwrap();
+    let stats = server.call(&["INFO", "stats"]).await;
+    assert_eq!(
+        info_line(&stats, "total_commands_processed"),
+        (processed + 1).to_string()
+    );
+
+    assert_eq!(info_sections(&stats), ["Stats"]);
+    assert_eq!(
+        info_sections(&server.call(&["INFO", "keyspace", "server"]).await),
+        ["Server", "Keyspace"]
+    );
+    assert!(info_sections(&server.call(&["INFO", "all"]).await).contains(&"Commandstats".into()));
+    assert!(!info_sections(&info).contains(&"Commandstats".into()));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn bind_listens_on_every_address_given() {
+    let server = TestServer::start_with(|builder| builder.bind("127.0.0.1 ::1")).await;
+    let port = server.addr().port();
+    assert_eq!(
+        info_line(&server.call(&["INFO", "server"]).await, "listener0"),
+        format!("name=tcp,bind=127.0.0.1,bind=::1,port={}", port)
+    );
+    for addr in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n", "over {}", addr);
+    }
+    server.shutdown().await;
+}
+
+/// One of this host's addresses other than loopback, found by asking which
+/// one a UDP socket would send from. Connecting a UDP socket sends nothing.
+fn external_ip() -> Option<std::net::IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    Some(socket.local_addr().ok()?.ip()).filter(|ip| !ip.is_loopback())
+}
+
+/// Connects to `server` from `from`, sends PING and reads until the server
+/// closes the connection or 100ms pass.
+async fn ping_from(server: &TestServer, from: std::net::IpAddr) -> String {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind((from, 0).into()).unwrap();
+    let mut stream = socket.connect(server.addr()).await.unwrap();
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_millis(100), stream.read_to_end(&mut reply)).await;
+    String::from_utf8_lossy(&reply).into_owned()
+}
+
+#[tokio::test]
+async fn protected_mode_turns_away_clients_from_other_hosts() {
+    // Without an address to connect from other than loopback, there's no
+    // client protected mode would turn away.
+    let external = match external_ip() {
+        Some(external) => external,
+        None => return,
+    };
+    let server =
+        TestServer::start_with(|builder| builder.bind("127.0.0.1 -::1").protected_mode(true)).await;
+    assert!(ping_from(&server, external)
+        .await
+        .starts_with("-DENIED Redis is running in protected mode"));
+    assert_eq!(
+        ping_from(&server, "127.0.0.1".parse().unwrap()).await,
+        "+PONG\r\n"
+    );
+    server
+        .call(&["CONFIG", "SET", "protected-mode", "no"])
+        .await;
+    assert_eq!(ping_from(&server, external).await, "+PONG\r\n");
+    server.shutdown().await;
+
+    // Binding explicitly is taken as meaning to be reached.
+    let server =
+        TestServer::start_with(|builder| builder.bind("127.0.0.1").protected_mode(true)).await;
+    assert_eq!(ping_from(&server, external).await, "+PONG\r\n");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn clients_past_maxclients_are_refused() {
+    let server = TestServer::start().await;
+    server.call(&["CONFIG", "SET", "maxclients", "2"]).await;
+    let mut first = server.connect().await;
+    let second = server.connect().await;
+    let loopback = "127.0.0.1".parse().unwrap();
+    assert_eq!(
+        ping_from(&server, loopback).await,
+        "-ERR max number of clients reached\r\n"
+    );
+    let info = first
+        .call(vec![b"INFO".to_vec(), b"clients".to_vec()])
+        .await
+        .unwrap();
+    assert_eq!(info_line(&info, "connected_clients"), "2");
+    assert_eq!(info_line(&info, "maxclients"), "2");
+    assert_eq!(info_line(&info, "rejected_connections"), "1");
+    // A slot frees up once a client leaves.
+    drop(second);
+    server
+        .wait_until(&["INFO", "clients"], |reply| {
+            info_line(reply, "connected_clients") == "2"
+        })
+        .await;
+    assert_eq!(ping_from(&server, loopback).await, "+PONG\r\n");
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn shutdown_saves_unless_told_not_to() {
+    let first = TestServer::start().await;
+    first.call(&["SET", "k", "v"]).await;
+    first.call(&["CONFIG", "SET", "save", "3600 1"]).await;
+    // The connection is closed without a reply, and the server with it.
+    let mut conn = first.connect().await;
+    assert!(conn
+        .call(vec![b"SHUTDOWN".to_vec(), b"NOSAVE".to_vec()])
+        .await
+        .is_err());
+    assert!(!first.dir().join("dump.rdb").exists());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(first.addr()).await.is_ok() {
+        assert!(Instant::now() < deadline, "still accepting connections");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let dir = first.dir().to_string_lossy().into_owned();
+    let second = TestServer::start_with(|builder| builder.dir(dir.clone())).await;
+    assert_eq!(second.call(&["GET", "k"]).await, Value::Nil);
+    second.call(&["SET", "k", "v"]).await;
+    let mut conn = second.connect().await;
+    assert!(conn
+        .call(vec![b"SHUTDOWN".to_vec(), b"SAVE".to_vec()])
+        .await
+        .is_err());
+
+    let third = TestServer::start_with(|builder| builder.dir(dir)).await;
+    assert_eq!(third.call(&["GET", "k"]).await, bulk("v"));
+    third.shutdown().await;
+}
+
+#[test]
+fn sigterm_saves_and_exits_cleanly() {
+    let mut server = common::Server::start_with(&[]);
+    server.call(&["CONFIG", "SET", "save", "3600 1"]);
+    assert_eq!(server.call(&["SET", "k", "v"]), "+OK\r\n");
+    assert!(server.terminate().success());
+    assert!(server.dir().join("dump.rdb").exists());
+}
+
+fn array(reply: Value) -> Vec<Value> {
+    match reply {
+        Value::Array(items) => items,
+        reply => panic!("not an array: {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn latency_monitor_records_slow_commands() {
+    let server = TestServer::start().await;
+    assert!(matches!(
+        server.call(&["LATENCY", "DOCTOR"]).await,
+        Value::BulkString(report) if report.starts_with("Latency monitoring is disabled")
+    ));
+    // Nothing is recorded while the threshold is 0.
+    server.call(&["DEBUG", "SLEEP", "0.05"]).await;
+    assert_eq!(
+        server.call(&["LATENCY", "LATEST"]).await,
+        Value::Array(vec![])
+    );
+
+    server
+        .call(&["CONFIG", "SET", "latency-monitor-threshold", "20"])
+        .await;
+    server.call(&["PING"]).await;
+    assert_eq!(
+        server.call(&["LATENCY", "LATEST"]).await,
+        Value::Array(vec![])
+    );
+    server.call(&["DEBUG", "SLEEP", "0.05"]).await;
+    let events = array(server.call(&["LATENCY", "LATEST"]).await);
+    assert_eq!(events.len(), 1);
+    let event = array(events[0].clone());
+    assert_eq!(event[0], bulk("command"));
+    let time = integer(event[1].clone());
+    let latency = integer(event[2].clone());
+    let max = integer(event[3].clone());
+    assert!(latency >= 50 && max == latency);
+    assert_eq!(
+        server.call(&["LATENCY", "HISTORY", "command"]).await,
+        Value::Array(vec![Value::Array(vec![
+            Value::Integer(time),
+            Value::Integer(latency)
+        ])])
+    );
+    assert!(matches!(
+        server.call(&["LATENCY", "DOCTOR"]).await,
+        Value::BulkString(report) if report.contains("1. command: 1 latency spikes")
+    ));
+    assert_eq!(
+        server.call(&["LATENCY", "RESET", "fork", "command"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        server.call(&["LATENCY", "HISTORY", "command"]).await,
+        Value::Array(vec![])
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn debug_subcommands_control_the_server() {
+    let server = TestServer::start().await;
+    let started = Instant::now();
+    assert_eq!(server.call(&["DEBUG", "SLEEP", "0.1"]).await, ok());
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    assert_eq!(
+        server.call(&["DEBUG", "OBJECT", "k"]).await,
+        error("ERR no such key")
+    );
+    server.call(&["SET", "k", "hello"]).await;
+    assert!(matches!(
+        server.call(&["DEBUG", "OBJECT", "k"]).await,
+        Value::SimpleString(info)
+            if info.starts_with("Value at:0x")
+                && info.contains(" refcount:1 encoding:embstr ")
+                && info.contains(" lru_seconds_idle:0")
+    ));
+
+    // With active expiry off, an expired key nobody touches stays put.
+    assert_eq!(
+        server.call(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await,
+        ok()
+    );
+    server.call(&["SET", "short", "v", "PX", "10"]).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let keyspace = server.call(&["INFO", "keyspace"]).await;
+    assert!(info_line(&keyspace, "db0").starts_with("keys=2,"));
+    server.call(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await;
+    server
+        .wait_until(&["INFO", "keyspace"], |reply| {
+            info_line(reply, "db0").starts_with("keys=1,")
+        })
+        .await;
+
+    let replid = info_line(
+        &server.call(&["INFO", "replication"]).await,
+        "master_replid",
+    );
+    assert_eq!(server.call(&["DEBUG", "CHANGE-REPL-ID"]).await, ok());
+    assert_ne!(
+        info_line(
+            &server.call(&["INFO", "replication"]).await,
+            "master_replid"
+        ),
+        replid
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn touch_and_unlink_count_the_keys_that_exist() {
+    let server = TestServer::start().await;
+    server.call(&["SET", "a", "1"]).await;
+    let members = (0..1000).map(|n| n.to_string()).collect::<Vec<_>>();
+    let mut sadd = vec!["SADD", "big"];
+    sadd.extend(members.iter().map(String::as_str));
+    server.call(&sadd).await;
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(
+        server.call(&["TOUCH", "a", "missing", "a"]).await,
+        Value::Integer(2)
+    );
+    // TOUCH marks the key as used, like reading it would.
+    assert_eq!(
+        server.call(&["OBJECT", "IDLETIME", "a"]).await,
+        Value::Integer(0)
+    );
+    assert!(matches!(
+        server.call(&["OBJECT", "IDLETIME", "big"]).await,
+        Value::Integer(idle) if idle >= 1
+    ));
+
+    // The big set is freed in the background, but gone from the keyspace
+    // straight away.
+    assert_eq!(
+        server.call(&["UNLINK", "a", "big", "missing"]).await,
+        Value::Integer(2)
+    );
+    assert_eq!(
+        server.call(&["TYPE", "big"]).await,
+        Value::SimpleString("none".into())
+    );
+    assert_eq!(server.call(&["TOUCH", "a", "big"]).await, Value::Integer(0));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn sintercard_and_smismember() {
+    let server = TestServer::start().await;
+    server.call(&["SADD", "a", "1", "2", "3", "4"]).await;
+    server.call(&["SADD", "b", "2", "3", "4", "5"]).await;
+    server.call(&["SET", "str", "v"]).await;
+    assert_eq!(
+        server.call(&["SINTERCARD", "2", "a", "b"]).await,
+        Value::Integer(3)
+    );
+    assert_eq!(
+        server
+            .call(&["SINTERCARD", "2", "a", "b", "LIMIT", "2"])
+            .await,
+        Value::Integer(2)
+    );
+    // LIMIT 0 means no limit, and a missing key is an empty set.
+    assert_eq!(
+        server
+            .call(&["SINTERCARD", "2", "a", "b", "LIMIT", "0"])
+            .await,
+        Value::Integer(3)
+    );
+    assert_eq!(
+        server.call(&["SINTERCARD", "2", "a", "missing"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        server.call(&["SINTERCARD", "0", "a"]).await,
+        error("ERR numkeys should be greater than 0")
+    );
+    assert!(matches!(
+        server.call(&["SINTERCARD", "2", "a", "str"]).await,
+        Value::Error(e) if e.starts_with("WRONGTYPE")
+    ));
+
+    assert_eq!(
+        server.call(&["SMISMEMBER", "a", "1", "5", "4"]).await,
+        Value::Array(vec![
+            Value::Integer(1),
+            Value::Integer(0),
+            Value::Integer(1)
+        ])
+    );
+    assert_eq!(
+        server.call(&["SMISMEMBER", "missing", "1"]).await,
+        Value::Array(vec![Value::Integer(0)])
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_clients_see_every_write() {
+    let server = TestServer::start().await;
+    let mut clients = Vec::new();
+    for client in 0..16 {
+        let mut conn = server.connect().await;
+        clients.push(tokio::spawn(async move {
+            for n in 0..100 {
+                let key = format!("{}:{}", client, n).into_bytes();
+                let set = vec![b"SET".to_vec(), key.clone(), key];
+                assert_eq!(conn.call(set).await.unwrap(), ok());
+            }
+            // Every client appends to the same list.
+            let push = vec![
+                b"RPUSH".to_vec(),
+                b"list".to_vec(),
+                client.to_string().into_bytes(),
+            ];
+            conn.call(push).await.unwrap();
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+    match server.call(&["KEYS", "*"]).await {
+        Value::Array(keys) => assert_eq!(keys.len(), 16 * 100 + 1),
+        reply => panic!("unexpected KEYS reply {:?}", reply),
+    }
+    for client in 0..16 {
+        let key = format!("{}:99", client);
+        assert_eq!(server.call(&["GET", &key]).await, bulk(&key));
+    }
+    match server.call(&["LRANGE", "list", "0", "-1"]).await {
+        Value::Array(items) => assert_eq!(items.len(), 16),
+        reply => panic!("unexpected LRANGE reply {:?}", reply),
+    }
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn commands_are_described_by_the_registry() {
+    let server = TestServer::start().await;
+    let listed = array(server.call(&["COMMAND", "LIST"]).await);
+    assert!(listed.contains(&bulk("get")) && listed.contains(&bulk("xadd")));
+    assert_eq!(
+        server.call(&["COMMAND", "COUNT"]).await,
+        Value::Integer(listed.len() as i64)
+    );
+
+    let info = array(
+        server
+            .call(&["COMMAND", "INFO", "SET", "del", "nosuch"])
+            .await,
+    );
+    let set = array(info[0].clone());
+    assert_eq!(set[..2], [bulk("set"), Value::Integer(-3)]);
+    assert_eq!(
+        set[2],
+        Value::Array(vec![
+            Value::SimpleString("write".into()),
+            Value::SimpleString("denyoom".into())
+        ])
+    );
+    assert_eq!(
+        set[3..6],
+        [Value::Integer(1), Value::Integer(1), Value::Integer(1)]
+    );
+    // Every argument after the name is a key.
+    let del = array(info[1].clone());
+    assert_eq!(
+        del[3..6],
+        [Value::Integer(1), Value::Integer(-1), Value::Integer(1)]
+    );
+    assert_eq!(info[2], Value::Nil);
+    // Containers list their subcommands.
+    let config = array(array(server.call(&["COMMAND", "INFO", "config"]).await).remove(0));
+    assert!(array(config[9].clone())
+        .iter()
+        .any(|sub| array(sub.clone())[0] == bulk("config|get")));
+
+    // Names are matched whatever their case, and arities are checked before
+    // anything runs.
+    assert_eq!(server.call(&["sEt", "k", "v"]).await, ok());
+    assert_eq!(server.call(&["Get", "k"]).await, bulk("v"));
+    assert_eq!(
+        server.call(&["GET", "k", "extra"]).await,
+        error("ERR wrong number of arguments for 'get' command")
+    );
+    assert_eq!(
+        server.call(&["CONFIG", "NOPE"]).await,
+        error("ERR unknown subcommand 'NOPE'. Try CONFIG HELP.")
+    );
+    assert!(matches!(
+        server.call(&["NOSUCH", "a"]).await,
+        Value::Error(e) if e.starts_with("ERR unknown command 'NOSUCH'")
+    ));
+    server.shutdown().await;
+}
+
+#[test]
+fn logfile_gets_events_at_the_configured_level() {
+    let log = std::env::temp_dir().join(format!("redis-rs-test-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let server =
+        common::Server::start_with(&["--loglevel", "warning", "--logfile", log.to_str().unwrap()]);
+    assert_eq!(server.call(&["PING"]), "+PONG\r\n");
+    let contents = std::fs::read_to_string(&log).unwrap();
+    assert!(!contents.contains("Ready to accept connections"));
+    assert!(!contents.contains("Accepted client"));
+
+    // Connections are logged at verbose, within a span naming the client.
+    assert_eq!(
+        server.call(&["CONFIG", "SET", "loglevel", "verbose"]),
+        "+OK\r\n"
+    );
+    assert_eq!(server.call(&["PING"]), "+PONG\r\n");
+    let contents = std::fs::read_to_string(&log).unwrap();
+    assert!(contents
+        .lines()
+        .any(|line| line.contains("client{id=") && line.contains("Accepted client")));
+    drop(server);
+    std::fs::remove_file(&log).unwrap();
+}
+
+/// Sends a GET for `path` to the metrics endpoint and returns the response.
+async fn http_get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    resp
+}
+
+#[tokio::test]
+async fn metrics_are_served_over_http() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = TestServer::start_with(|builder| builder.metrics_port(port)).await;
+    server.call(&["SET", "a", "1"]).await;
+    server.call(&["SET", "b", "2", "PX", "100000"]).await;
+    let resp = http_get(port, "/metrics").await;
+    let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(head.contains(&format!("Content-Length: {}", body.len())));
+    let lines = body.lines().collect::<Vec<_>>();
+    for line in [
+        "# TYPE redis_commands_processed_total counter",
+        "redis_commands_processed_total 2",
+        "redis_command_calls_total{cmd=\"set\"} 2",
+        "redis_command_duration_seconds_bucket{cmd=\"set\",le=\"+Inf\"} 2",
+        "redis_command_duration_seconds_count{cmd=\"set\"} 2",
+        "redis_keys 2",
+        "redis_expiring_keys 1",
+        "redis_replica 0",
+        "redis_connected_replicas 0",
+    ] {
+        assert!(lines.contains(&line), "no {:?} in {}", line, body);
+    }
+
+    // Every replica gets a series of its own.
+    let replica = server.start_replica().await;
+    let body = http_get(port, "/metrics").await;
+    assert!(body.contains("redis_connected_replicas 1\n"));
+    assert!(body.contains("redis_replica_lag_bytes{"));
+    assert!(http_get(port, "/other")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    replica.shutdown().await;
+    server.shutdown().await;
+}
@@ -0,0 +1,221 @@
+//! Serves clients and replicas over TLS, with certificates signed by a CA
+//! generated for each test.
+
+use bytes::BytesMut;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use redis_starter_rust::redis_io::TlsAuthClients;
+use redis_starter_rust::redis_resp::Value;
+use redis_starter_rust::redis_testing::TestServer;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+static NEXT_CERT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A CA and a certificate it signed for the servers, and another for
+/// clients, written out as PEM files.
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    fn generate() -> Certs {
+        let dir = std::env::temp_dir().join(format!(
+            "redis-rs-tls-{}-{}",
+            std::process::id(),
+            NEXT_CERT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+        for name in ["server", "client"] {
+            let key = KeyPair::generate().unwrap();
+            let names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+            let cert = CertificateParams::new(names)
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            std::fs::write(dir.join(format!("{}.crt", name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+        Certs { dir }
+    }
+
+    fn path(&self, file_name: &str) -> String {
+        self.dir.join(file_name).to_string_lossy().into_owned()
+    }
+
+    /// Starts a server serving TLS with the server certificate.
+    async fn start_server(&self, auth_clients: TlsAuthClients) -> TestServer {
+        TestServer::start_with(|builder| {
+            builder
+                .tls_port(0)
+                .tls_files(
+                    self.path("server.crt"),
+                    self.path("server.key"),
+                    self.path("ca.crt"),
+                )
+                .tls_auth_clients(auth_clients)
+        })
+        .await
+    }
+
+    /// Connects to the server's TLS port, presenting the client certificate
+    /// if `with_cert` is set.
+    async fn connect(&self, server: &TestServer, with_cert: bool) -> std::io::Result<Client> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(self.path("ca.crt")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match with_cert {
+            true => {
+                let certs = CertificateDer::pem_file_iter(self.path("client.crt"))
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                let key = PrivateKeyDer::from_pem_file(self.path("client.key")).unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            }
+            false => builder.with_no_client_auth(),
+        };
+        let stream = TcpStream::connect(server.tls_addr().unwrap()).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await?;
+        Ok(Client(stream))
+    }
+}
+
+impl Drop for Certs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Client(TlsStream<TcpStream>);
+
+impl Client {
+    /// Sends one command and returns the reply. Errors when the server
+    /// closes the connection, as it does once a handshake it refused ends.
+    async fn call(&mut self, args: &[&str]) -> std::io::Result<Value> {
+        let req = Value::Array(args.iter().map(|arg| Value::bulk(*arg)).collect());
+        self.0.write_all(&req.serialize()).await?;
+        let mut buf = BytesMut::new();
+        loop {
+            if let Some((value, _)) = Value::parse(&buf).unwrap() {
+                return Ok(value);
+            }
+            if self.0.read_buf(&mut buf).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+fn bulk(str: &str) -> Value {
+    Value::BulkString(str.to_string())
+}
+
+#[tokio::test]
+async fn serves_clients_over_tls() {
+    let certs = Certs::generate();
+    let server = certs.start_server(TlsAuthClients::Yes).await;
+    let mut client = certs.connect(&server, true).await.unwrap();
+    assert_eq!(
+        client.call(&["SET", "k", "v"]).await.unwrap(),
+        Value::SimpleString("OK".to_string())
+    );
+    assert_eq!(client.call(&["GET", "k"]).await.unwrap(), bulk("v"));
+    // The plain port keeps serving the same dataset.
+    assert_eq!(server.call(&["GET", "k"]).await, bulk("v"));
+    assert_eq!(
+        server.call(&["CONFIG", "GET", "tls-port"]).await,
+        Value::Array(vec![
+            bulk("tls-port"),
+            bulk(&server.tls_addr().unwrap().port().to_string())
+        ])
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn client_certificates_are_required_unless_optional() {
+    let certs = Certs::generate();
+    let server = certs.start_server(TlsAuthClients::Yes).await;
+    // TLS 1.3 clients only learn their certificate was refused on their
+    // first read.
+    let refused = match certs.connect(&server, false).await {
+        Ok(mut client) => client.call(&["PING"]).await.is_err(),
+        Err(_) => true,
+    };
+    assert!(refused, "client without a certificate was served");
+    server.shutdown().await;
+
+    let server = certs.start_server(TlsAuthClients::Optional).await;
+    let mut client = certs.connect(&server, false).await.unwrap();
+    assert_eq!(
+        client.call(&["PING"]).await.unwrap(),
+        Value::SimpleString("PONG".to_string())
+    );
+    let mut client = certs.connect(&server, true).await.unwrap();
+    assert_eq!(
+        client.call(&["PING"]).await.unwrap(),
+        Value::SimpleString("PONG".to_string())
+    );
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_sync_over_tls() {
+    let certs = Certs::generate();
+    let primary = certs.start_server(TlsAuthClients::Yes).await;
+    primary.call(&["SET", "before", "1"]).await;
+    let tls_port = primary.tls_addr().unwrap().port();
+    // The replica presents the server certificate, which the primary
+    // requires.
+    let replica = TestServer::start_with(|builder| {
+        builder
+            .replicaof("127.0.0.1", tls_port)
+            .tls_files(
+                certs.path("server.crt"),
+                certs.path("server.key"),
+                certs.path("ca.crt"),
+            )
+            .tls_replication(true)
+    })
+    .await;
+    wait_for(&replica, "before", "1").await;
+    primary.call(&["SET", "after", "2"]).await;
+    wait_for(&replica, "after", "2").await;
+    replica.shutdown().await;
+    primary.shutdown().await;
+}
+
+async fn wait_for(server: &TestServer, key: &str, val: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.call(&["GET", key]).await != bulk(val) {
+        assert!(
+            Instant::now() < deadline,
+            "{} never reached the replica",
+            key
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}